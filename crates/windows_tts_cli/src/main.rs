@@ -10,22 +10,28 @@
 //!   - [About Extended Linguistic Services - Win32 apps | Microsoft Learn](https://learn.microsoft.com/pl-pl/windows/win32/intl/about-extended-linguistic-services)
 //!   - [Requesting Text Recognition - Win32 apps | Microsoft Learn](https://learn.microsoft.com/pl-pl/windows/win32/intl/requesting-text-recognition)
 
-use std::{marker::PhantomData, path::PathBuf, ptr::null_mut, time::Duration};
+use std::{
+    marker::PhantomData,
+    path::PathBuf,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use anyhow::{bail, Context};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use windows::{
-    core::{Interface, GUID, HSTRING, PCWSTR},
+    core::{Interface, GUID, HSTRING, PCWSTR, PWSTR},
     Media::{
         Playback::{MediaPlayer, MediaPlayerAudioCategory, MediaPlayerState},
-        SpeechSynthesis::{SpeechSynthesizer, VoiceInformation},
+        SpeechSynthesis::{SpeechSynthesizer, VoiceGender, VoiceInformation},
     },
     Storage::Streams::{DataReader, IInputStream, IRandomAccessStream},
     Win32::{
         Globalization::{
-            MappingFreePropertyBag, MappingFreeServices, MappingGetServices, MappingRecognizeText,
-            ELS_GUID_LANGUAGE_DETECTION, MAPPING_ENUM_OPTIONS, MAPPING_PROPERTY_BAG,
-            MAPPING_SERVICE_INFO,
+            LCIDToLocaleName, MappingFreePropertyBag, MappingFreeServices, MappingGetServices,
+            MappingRecognizeText, ELS_GUID_LANGUAGE_DETECTION, LOCALE_NAME_MAX_LENGTH,
+            MAPPING_ENUM_OPTIONS, MAPPING_PROPERTY_BAG, MAPPING_SERVICE_INFO,
         },
         Media::Speech::{
             ISpObjectToken, ISpObjectTokenCategory, ISpVoice, SpObjectTokenCategory, SpVoice,
@@ -35,6 +41,22 @@ use windows::{
     },
 };
 
+/// Set when stdout is used for JSON output, then [`status!`] prints to stderr
+/// instead.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Like [`println!`] but prints to stderr when stdout should only contain
+/// JSON output, see [`STATUS_TO_STDERR`].
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
 pub fn to_utf16(s: &str) -> Vec<u16> {
     use std::ffi::OsStr;
     use std::os::windows::ffi::OsStrExt;
@@ -59,15 +81,46 @@ pub fn is_windows_10() -> anyhow::Result<bool> {
     Ok(version.dwBuildNumber >= 10240)
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct DetectedLanguage {
     pub start: usize,
     pub end: usize,
     pub languages: Vec<String>,
 }
 
+/// A detected language range together with the text it covers.
+#[cfg(feature = "json")]
+#[derive(Debug, serde::Serialize)]
+pub struct DetectedText<'a> {
+    #[serde(flatten)]
+    pub detected: &'a DetectedLanguage,
+    pub text: String,
+}
+
+/// A part of the spoken text together with the language and voice that was
+/// used to speak it.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TranscriptSpan {
+    /// Inclusive start index, the first UTF-16 character this span covers.
+    pub start: usize,
+    /// Inclusive end index, the last UTF-16 character this span covers.
+    pub end: usize,
+    pub text: String,
+    /// The detected languages, with the most certain languages earlier in the
+    /// list.
+    pub languages: Vec<String>,
+    /// Display name of the selected voice.
+    pub voice: String,
+}
+
 /// Language detection service info.
 pub struct DetectionService {
-    service: *mut MAPPING_SERVICE_INFO,
+    /// All services returned by `MappingGetServices`, freed when dropped.
+    services: *mut MAPPING_SERVICE_INFO,
+    /// Index of the language detection service in `services`.
+    index: usize,
 }
 impl DetectionService {
     pub fn new() -> anyhow::Result<Self> {
@@ -86,22 +139,24 @@ impl DetectionService {
         unsafe { MappingGetServices(Some(&options), &mut services_ptr, &mut len) }
             .context("MappingGetServices failed")?;
 
+        if services_ptr.is_null() || len == 0 {
+            bail!("No Language Detection service found");
+        }
+        let services = unsafe { std::slice::from_raw_parts(services_ptr, len as usize) };
         // This object will call `MappingFreeServices` later:
-        let service = DetectionService {
-            service: services_ptr,
+        let mut service = DetectionService {
+            services: services_ptr,
+            index: 0,
         };
-        let services = unsafe { std::slice::from_raw_parts(services_ptr, len as usize) };
-        let first = services[0];
-        if first.guid != ELS_GUID_LANGUAGE_DETECTION {
-            bail!("Incorrect GUID for language detection service");
-        }
-        // for service in services {
-        //     eprintln!("Service info: {}", unsafe {
-        //         service.pszDescription.to_string()?
-        //     });
-        // }
-        if len != 1 {
-            bail!("More than one Language Detection service found");
+        service.index = services
+            .iter()
+            .position(|info| info.guid == ELS_GUID_LANGUAGE_DETECTION)
+            .context("Incorrect GUID for language detection service")?;
+        if services.len() > 1 {
+            eprintln!(
+                "Warning: found {} Extended Linguistic Services services, using the Language Detection service",
+                services.len()
+            );
         }
         Ok(service)
     }
@@ -114,7 +169,7 @@ impl DetectionService {
         unsafe {
             MappingRecognizeText(
                 // Note: can't have called MappingFreeServices before this point
-                self.service,
+                self.services.add(self.index),
                 // text without trailing nuls:
                 text_utf16.strip_suffix(&[0]).unwrap_or(text_utf16),
                 0,
@@ -169,7 +224,8 @@ impl DetectionService {
 }
 impl Drop for DetectionService {
     fn drop(&mut self) {
-        unsafe { MappingFreeServices(self.service) }.expect("MappingFreeServices failed");
+        // Frees the whole array, not only the service we used:
+        unsafe { MappingFreeServices(self.services) }.expect("MappingFreeServices failed");
     }
 }
 
@@ -202,6 +258,40 @@ impl Drop for HasCoInitialized {
     }
 }
 
+/// A string allocated by COM (for example returned by SAPI) that is freed with
+/// `CoTaskMemFree` when dropped.
+pub struct CoTaskString(PWSTR);
+impl CoTaskString {
+    /// # Safety
+    ///
+    /// The string must be null or allocated with `CoTaskMemAlloc` and not be
+    /// freed by anyone else.
+    pub unsafe fn new(value: PWSTR) -> Self {
+        Self(value)
+    }
+    pub fn is_null(&self) -> bool {
+        self.0.is_null()
+    }
+    pub fn to_string(&self) -> anyhow::Result<String> {
+        if self.0.is_null() {
+            bail!("COM string was null");
+        }
+        Ok(unsafe { self.0.to_string() }?)
+    }
+}
+impl Drop for CoTaskString {
+    fn drop(&mut self) {
+        unsafe { CoTaskMemFree(Some(self.0.as_ptr().cast())) };
+    }
+}
+
+/// Get the token id of a voice, for example
+/// `HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\TTS_MS_EN-US_ZIRA_11.0`.
+pub fn voice_id(voice: &ISpObjectToken) -> anyhow::Result<String> {
+    let id = unsafe { CoTaskString::new(voice.GetId().context("Failed to get voice token id")?) };
+    id.to_string()
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VoiceCategoryId {
     Default,
@@ -249,23 +339,101 @@ impl VoiceCategoryId {
             .collect::<Result<Vec<_>, _>>()?)
     }
 
-    /// This doesn't work correctly for [`VoiceCategoryId::Modern`].
+    /// Token id of the default voice, for example
+    /// `HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\TTS_MS_EN-US_ZIRA_11.0`.
+    ///
+    /// `ISpObjectTokenCategory::GetDefaultTokenId` only knows about the legacy
+    /// default voice, so the default of [`VoiceCategoryId::Modern`] is read
+    /// using the modern API's `SpeechSynthesizer::DefaultVoice` instead. Its
+    /// id is also a token id.
     pub fn default_voice_id(self) -> anyhow::Result<String> {
+        if self == VoiceCategoryId::Modern {
+            let voice = SpeechSynthesizer::DefaultVoice()
+                .context("Failed to get default voice from SpeechSynthesizer")?;
+            return Ok(voice
+                .Id()
+                .context("Failed to get id of default voice")?
+                .to_string_lossy());
+        }
+
         let otc: ISpObjectTokenCategory = self.create_category_token_with_id()?;
 
-        let token_id = unsafe { otc.GetDefaultTokenId() }
-            .context("Failed to call GetDefaultTokenId for ISpObjectTokenCategory")?;
+        let token_id = unsafe {
+            CoTaskString::new(
+                otc.GetDefaultTokenId()
+                    .context("Failed to call GetDefaultTokenId for ISpObjectTokenCategory")?,
+            )
+        };
 
         if token_id.is_null() {
             bail!("No default voice token");
         }
 
-        let token_id_str = unsafe { token_id.to_string() };
+        token_id.to_string()
+    }
+}
 
-        unsafe { CoTaskMemFree(Some(token_id.as_ptr().cast())) };
+/// Read an attribute of a voice token, for example `"Name"` or `"Language"`.
+pub fn voice_attribute(voice: &ISpObjectToken, attribute: &str) -> anyhow::Result<String> {
+    let attributes = unsafe { voice.OpenKey(windows::core::w!("Attributes")) }
+        .context("Failed to open attributes of voice token")?;
+    let value = unsafe {
+        CoTaskString::new(
+            attributes
+                .GetStringValue(&HSTRING::from(attribute))
+                .with_context(|| format!("Failed to read {attribute} attribute of voice token"))?,
+        )
+    };
+    value.to_string()
+}
 
-        Ok(token_id_str?)
+/// Read the `Name` attribute of a voice token, for example `"Microsoft David
+/// Desktop"`.
+pub fn voice_name(voice: &ISpObjectToken) -> anyhow::Result<String> {
+    voice_attribute(voice, "Name")
+}
+
+/// Convert the hexadecimal language ids used by legacy voices (for example
+/// `"409;9"`) to a locale name like `"en-US"`. Returns the attribute as is if
+/// it can't be converted.
+fn legacy_language_name(attribute: &str) -> String {
+    let Some(lcid) = attribute
+        .split(';')
+        .next()
+        .and_then(|lcid| u32::from_str_radix(lcid.trim(), 16).ok())
+    else {
+        return attribute.to_owned();
+    };
+    let mut buffer = [0_u16; LOCALE_NAME_MAX_LENGTH as usize];
+    let len = unsafe { LCIDToLocaleName(lcid, Some(&mut buffer), 0) };
+    if len <= 1 {
+        return attribute.to_owned();
     }
+    // Length includes the trailing nul character:
+    String::from_utf16_lossy(&buffer[..len as usize - 1])
+}
+
+/// Find a legacy voice by its token id or by its name. Names are compared
+/// case-insensitively.
+pub fn find_voice(name_or_id: &str) -> anyhow::Result<ISpObjectToken> {
+    let mut available = Vec::new();
+    for category_id in [VoiceCategoryId::Default, VoiceCategoryId::Modern] {
+        let voices = category_id
+            .enum_voices()
+            .context("Failed to enumerate voices")?;
+        for voice in voices {
+            let id = voice_id(&voice)?;
+            let name = voice_name(&voice).unwrap_or_default();
+            if id.eq_ignore_ascii_case(name_or_id) || name.eq_ignore_ascii_case(name_or_id) {
+                return Ok(voice);
+            }
+            available.push(if name.is_empty() { id } else { name });
+        }
+    }
+    bail!(
+        "No voice matched \"{name_or_id}\", available voices are:\n\t{}",
+        available.join("\n\t")
+    );
 }
 
 /// This speaks some text aloud.
@@ -290,7 +458,7 @@ pub fn speak(text_utf16: &[u16], voice_token: Option<&ISpObjectToken>) -> anyhow
 
 fn print_legacy_voices() -> anyhow::Result<()> {
     for category_id in [VoiceCategoryId::Default, VoiceCategoryId::Modern] {
-        println!(
+        status!(
             "\nAll voices found using legacy API ({category_id:?} voice category registry key):"
         );
 
@@ -298,27 +466,140 @@ fn print_legacy_voices() -> anyhow::Result<()> {
             .enum_voices()
             .context("Failed to enumerate voices")?;
 
-        println!(
-            "Default voice{}: {}",
-            if category_id == VoiceCategoryId::Modern {
-                " (incorrect)"
-            } else {
-                ""
-            },
-            category_id.default_voice_id()?
-        );
+        let default_voice_id = category_id.default_voice_id()?;
+        status!("Default voice: {default_voice_id}");
 
         for voice in &voices {
-            println!("Voice Id: {}", unsafe { voice.GetId()?.to_string()? });
+            status!("Voice Id: {}", voice_id(voice)?);
         }
-        println!("\n");
+        status!("\n");
     }
     Ok(())
 }
 
+/// Write 16-bit PCM samples to a WAV file. Samples for different channels
+/// should be interleaved.
+///
+/// # References
+///
+/// - [WAVE PCM soundfile format](http://soundfile.sapp.org/doc/WaveFormat/)
+pub fn write_wav(
+    path: &std::path::Path,
+    num_channels: u16,
+    sample_rate: u32,
+    samples: &[i16],
+) -> anyhow::Result<()> {
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = num_channels * BITS_PER_SAMPLE / 8;
+    let data_len = u32::try_from(samples.len() * 2).context("Too much audio for a WAV file")?;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16_u32.to_le_bytes());
+    wav.extend_from_slice(&1_u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&num_channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * u32::from(block_align)).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    std::fs::write(path, wav).with_context(|| format!("Failed to write WAV file at {path:?}"))
+}
+
+/// Info about an installed voice, used by [`Command::ListVoices`].
+struct ListedVoice {
+    /// The APIs that can use this voice.
+    sources: Vec<&'static str>,
+    id: String,
+    name: String,
+    language: String,
+    gender: String,
+}
+
+/// Print all voices that can be used by the legacy and modern APIs. Voices
+/// from the OneCore registry key are usually also available to the modern API
+/// and are only printed once.
+fn list_voices() -> anyhow::Result<()> {
+    let mut listed: Vec<ListedVoice> = Vec::new();
+    for (category_id, source) in [
+        (VoiceCategoryId::Default, "legacy"),
+        (VoiceCategoryId::Modern, "legacy OneCore"),
+    ] {
+        let voices = category_id
+            .enum_voices()
+            .context("Failed to enumerate voices")?;
+        for voice in &voices {
+            listed.push(ListedVoice {
+                sources: vec![source],
+                id: voice_id(voice)?,
+                name: voice_name(voice).unwrap_or_default(),
+                language: voice_attribute(voice, "Language")
+                    .map(|language| legacy_language_name(&language))
+                    .unwrap_or_default(),
+                gender: voice_attribute(voice, "Gender").unwrap_or_default(),
+            });
+        }
+    }
+
+    if is_windows_10()? {
+        for voice in &SpeechSynthesizer::AllVoices()? {
+            let id = voice.Id()?.to_string_lossy();
+            if let Some(existing) = listed
+                .iter_mut()
+                .find(|listed| listed.id.eq_ignore_ascii_case(&id))
+            {
+                existing.sources.push("modern");
+                continue;
+            }
+            listed.push(ListedVoice {
+                sources: vec!["modern"],
+                id,
+                name: voice.DisplayName()?.to_string_lossy(),
+                language: voice.Language()?.to_string_lossy(),
+                gender: match voice.Gender()? {
+                    VoiceGender::Male => "Male".to_owned(),
+                    VoiceGender::Female => "Female".to_owned(),
+                    other => format!("{other:?}"),
+                },
+            });
+        }
+    }
+
+    for voice in &listed {
+        println!("Voice: {}", voice.name);
+        println!("\tsource: {}", voice.sources.join(", "));
+        println!("\tid: {}", voice.id);
+        println!("\tlanguage: {}", voice.language);
+        println!("\tgender: {}", voice.gender);
+        println!();
+    }
+    Ok(())
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print info about all installed voices, for both the legacy and modern
+    /// APIs. The printed names and ids can be used with `--voice`.
+    ListVoices,
+}
+
 /// Uses Windows APIs for text-to-speech.
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Skip the legacy text-to-speech output.
     #[clap(long)]
     no_legacy: bool,
@@ -327,14 +608,34 @@ struct Args {
     #[clap(long)]
     no_modern: bool,
 
-    /// Write modern text-to-speech output to a file.
+    /// Write modern text-to-speech output to a WAV file. A `.wav` extension
+    /// is added if the path doesn't have an extension.
     #[clap(long)]
     write_modern_to_file: Option<PathBuf>,
 
+    /// Name or token id of the legacy voice to use. By default the voice
+    /// selected in Windows' Control Panel is used.
+    #[clap(long)]
+    voice: Option<String>,
+
     /// Print info about all installed voices.
     #[clap(long)]
     print_all_voices: bool,
 
+    /// Print a JSON transcript where the text is split into spans labeled with
+    /// the detected language and selected modern voice, instead of speaking
+    /// the text. Only the JSON is written to stdout, other output is written
+    /// to stderr.
+    #[cfg(feature = "json")]
+    #[clap(long)]
+    transcript: bool,
+
+    /// Print the detected languages as a JSON array and exit without speaking
+    /// the text.
+    #[cfg(feature = "json")]
+    #[clap(long)]
+    json: bool,
+
     /// Path to piper model config.
     ///
     /// If you download a model using:
@@ -349,35 +650,89 @@ struct Args {
     #[clap(long)]
     piper_config_path: Option<std::path::PathBuf>,
 
+    /// Write the audio from the piper model to a WAV file instead of playing
+    /// it.
+    #[cfg(feature = "piper-rs")]
+    #[clap(long)]
+    output: Option<std::path::PathBuf>,
+
+    /// Read the text that should be converted to speech from stdin instead
+    /// of from command line arguments. The input should be UTF-8 encoded.
+    ///
+    /// This is also enabled if the only text argument is `-`.
+    #[clap(long)]
+    stdin: bool,
+
     /// Text that should be converted to speech.
     text: Vec<String>,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let text = args.text.join(" ");
-    if text.is_empty() {
-        bail!("Should specify text to read as command line arguments");
+    if let Some(Command::ListVoices) = args.command {
+        let _com_init = HasCoInitialized::new()
+            .context("Failed to initialize COM library for current thread")?;
+        return list_voices();
+    }
+    let text = if args.stdin || args.text == ["-"] {
+        if args.stdin && !args.text.is_empty() {
+            bail!("Can't specify text as command line arguments when reading from stdin");
+        }
+        std::io::read_to_string(std::io::stdin()).context("Failed to read text from stdin")?
+    } else {
+        args.text.join(" ")
+    };
+    if text.trim().is_empty() {
+        bail!("Should specify text to read as command line arguments or using --stdin");
     }
-    println!("Text-to-speech for:\n{text}\n");
-
     let text_utf16 = to_utf16(&text);
+    #[cfg(feature = "json")]
+    let transcript_only = args.transcript;
+    #[cfg(not(feature = "json"))]
+    let transcript_only = false;
+    // Only the JSON transcript should be written to stdout:
+    STATUS_TO_STDERR.store(transcript_only, Ordering::Relaxed);
+
+    #[cfg(feature = "json")]
+    if args.json {
+        let detected_language_ranges = DetectionService::new()
+            .context("Failed to find language detection service")?
+            .recognize_text(&text_utf16)
+            .context("Failed to recognize text language")?;
+        let detected_text = detected_language_ranges
+            .iter()
+            .map(|detected| DetectedText {
+                detected,
+                text: String::from_utf16_lossy(
+                    text_utf16
+                        .get(detected.start..=detected.end)
+                        .unwrap_or_default(),
+                ),
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&detected_text)?);
+        return Ok(());
+    }
+
+    status!("Text-to-speech for:\n{text}\n");
 
     let _com_init =
         HasCoInitialized::new().context("Failed to initialize COM library for current thread")?;
 
     // Legacy SAPI:
-    if !args.no_legacy {
+    if !args.no_legacy && !transcript_only {
         if args.print_all_voices {
             print_legacy_voices()?;
         }
 
-        speak(&text_utf16, None)?;
+        let voice_token = args.voice.as_deref().map(find_voice).transpose()?;
 
-        println!("Finished with legacy voice output\n");
+        speak(&text_utf16, voice_token.as_ref())?;
+
+        status!("Finished with legacy voice output\n");
     }
 
-    if !args.no_modern {
+    if !args.no_modern || transcript_only {
         if !is_windows_10()? {
             eprintln!("Modern text-to-speech API is only available in Windows 10 or newer");
             std::process::exit(2);
@@ -388,34 +743,44 @@ fn main() -> anyhow::Result<()> {
             .recognize_text(&text_utf16)
             .context("Failed to recognize text language")?;
 
-        println!(
+        status!(
             "Count of detected Language ranges: {}",
             detected_language_ranges.len()
         );
+        #[cfg(feature = "json")]
+        let mut transcript = Vec::new();
         for lang_detection in detected_language_ranges {
-            let text_utf16 = &text_utf16[lang_detection.start..=lang_detection.end];
-            println!(
+            let Some(text_utf16) = text_utf16.get(lang_detection.start..=lang_detection.end) else {
+                eprintln!(
+                    "Warning: skipping detected language range {}-{} that is outside the text",
+                    lang_detection.start, lang_detection.end
+                );
+                continue;
+            };
+            status!(
                 "First range of text ({}-{}): {}",
                 lang_detection.start,
                 lang_detection.end,
                 String::from_utf16_lossy(text_utf16)
             );
-            println!(
+            status!(
                 "\tDetected possible languages (prefer earlier ones): {:?}",
                 lang_detection.languages
             );
 
             let synth = SpeechSynthesizer::new()?;
             let default_voice = synth.Voice()?;
+            #[cfg(feature = "json")]
+            let mut selected_voice = default_voice.clone();
             let all_voices = SpeechSynthesizer::AllVoices()?;
 
             if args.print_all_voices {
-                println!("\nAll voices:");
+                status!("\nAll voices:");
                 for voice in &all_voices {
-                    println!("Voice: {}", voice.DisplayName()?.to_string_lossy());
-                    println!("\tid: {}", voice.Id()?.to_string_lossy());
-                    println!("\tLang: {}", voice.Language()?.to_string_lossy());
-                    println!();
+                    status!("Voice: {}", voice.DisplayName()?.to_string_lossy());
+                    status!("\tid: {}", voice.Id()?.to_string_lossy());
+                    status!("\tLang: {}", voice.Language()?.to_string_lossy());
+                    status!();
                 }
             }
 
@@ -429,36 +794,52 @@ fn main() -> anyhow::Result<()> {
                 };
 
                 if right_lang(&default_voice)? {
-                    println!(
+                    status!(
                         "Default voice \"{}\" matches the wanted language",
                         default_voice.DisplayName()?.to_string_lossy()
                     );
                     break;
                 } else {
-                    println!(
+                    status!(
                         "Default voice doesn't match language {wanted_lang}, find one that does"
                     );
 
                     for voice in &all_voices {
                         if right_lang(&voice)? {
-                            println!("Selected voice: {}", voice.DisplayName()?.to_string_lossy());
+                            status!("Selected voice: {}", voice.DisplayName()?.to_string_lossy());
                             synth.SetVoice(&voice)?;
+                            #[cfg(feature = "json")]
+                            {
+                                selected_voice = voice;
+                            }
                             break 'find_lang; // Break out of two loops
                         }
                     }
                 }
 
-                println!(
+                status!(
                     "No voice for the detected language \"{wanted_lang}\", \
                     checking for less likely languages"
                 );
             }
-            println!();
+            status!();
+
+            #[cfg(feature = "json")]
+            if args.transcript {
+                transcript.push(TranscriptSpan {
+                    start: lang_detection.start,
+                    end: lang_detection.end,
+                    text: String::from_utf16_lossy(text_utf16),
+                    languages: lang_detection.languages,
+                    voice: selected_voice.DisplayName()?.to_string_lossy(),
+                });
+                continue;
+            }
 
             let stream = synth
                 .SynthesizeTextToStreamAsync(&HSTRING::from_wide(text_utf16))?
                 .get()?;
-            println!("Stream context type: {}", stream.ContentType()?);
+            status!("Stream context type: {}", stream.ContentType()?);
             if let Some(file_path) = &args.write_modern_to_file {
                 // https://stackoverflow.com/questions/59061345/how-to-save-speechsynthesis-audio-to-a-mp3-file-in-a-uwp-application
                 // https://stackoverflow.com/questions/65737953/how-to-save-audio-from-using-windows-media-speechsynthesis
@@ -472,7 +853,23 @@ fn main() -> anyhow::Result<()> {
                 let mut buffer = vec![0; size as usize];
                 reader.ReadBytes(buffer.as_mut_slice())?;
 
-                std::fs::write(file_path.with_extension(".wav"), buffer)?;
+                let file_path = if file_path.extension().is_none() {
+                    file_path.with_extension("wav")
+                } else {
+                    file_path.clone()
+                };
+                if buffer.starts_with(b"RIFF") {
+                    std::fs::write(&file_path, buffer)
+                        .with_context(|| format!("Failed to write WAV file at {file_path:?}"))?;
+                } else {
+                    // Raw audio data in the format that the synthesizer uses by
+                    // default (16kHz 16Bit mono):
+                    let samples = buffer
+                        .chunks_exact(2)
+                        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+                        .collect::<Vec<_>>();
+                    write_wav(&file_path, 1, 16_000, &samples)?;
+                }
             } else {
                 let stream: IRandomAccessStream = stream.cast()?;
 
@@ -491,7 +888,13 @@ fn main() -> anyhow::Result<()> {
             }
         }
 
-        println!("Finished with modern voice output\n");
+        #[cfg(feature = "json")]
+        if args.transcript {
+            println!("{}", serde_json::to_string_pretty(&transcript)?);
+            return Ok(());
+        }
+
+        status!("Finished with modern voice output\n");
     }
 
     #[cfg(feature = "natural-tts")]
@@ -510,7 +913,7 @@ fn main() -> anyhow::Result<()> {
             .map_err(|e| anyhow::anyhow!(e.to_string()))?;
         std::thread::sleep(Duration::from_millis(1000));
 
-        println!("Finished with MSEdge voice output\n");
+        status!("Finished with MSEdge voice output\n");
     }
 
     #[cfg(feature = "piper-rs")]
@@ -523,6 +926,9 @@ fn main() -> anyhow::Result<()> {
                 "Piper TTS doesn't work unless --piper-config-path argument is specified",
             )?)
             .context("Failed to load piper config")?;
+        let audio_info = model
+            .audio_output_info()
+            .context("Failed to get piper audio format info")?;
         // Set speaker ID
         // if let Some(sid) = sid {
         //     let sid = sid.parse::<i64>().expect("Speaker ID should be number!");
@@ -538,16 +944,55 @@ fn main() -> anyhow::Result<()> {
             samples.append(&mut result.unwrap().into_vec());
         }
 
+        if let Some(output) = &args.output {
+            let samples = samples
+                .iter()
+                .map(|&sample| (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16)
+                .collect::<Vec<_>>();
+            write_wav(
+                output,
+                audio_info.num_channels as u16,
+                audio_info.sample_rate as u32,
+                &samples,
+            )?;
+            status!("Wrote piper output to {output:?}\n");
+            return Ok(());
+        }
+
         let (_stream, handle) =
             rodio::OutputStream::try_default().context("Failed to create audio output stream")?;
         let sink = rodio::Sink::try_new(&handle).unwrap();
 
-        let buf = SamplesBuffer::new(1, 22050, samples);
+        let buf = SamplesBuffer::new(
+            audio_info.num_channels as u16,
+            audio_info.sample_rate as u32,
+            samples,
+        );
         sink.append(buf);
 
         sink.sleep_until_end();
-        println!("Finished with Piper neural network text-to-speech model\n");
+        status!("Finished with Piper neural network text-to-speech model\n");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modern_default_voice_is_used_by_new_synthesizers() {
+        let _com = HasCoInitialized::new().unwrap();
+        let default_voice_id = VoiceCategoryId::Modern.default_voice_id().unwrap();
+        let synth_voice_id = SpeechSynthesizer::new()
+            .and_then(|synth| synth.Voice())
+            .and_then(|voice| voice.Id())
+            .unwrap()
+            .to_string_lossy();
+        assert!(
+            synth_voice_id.eq_ignore_ascii_case(&default_voice_id),
+            "SpeechSynthesizer uses {synth_voice_id} instead of the default voice {default_voice_id}"
+        );
+    }
+}
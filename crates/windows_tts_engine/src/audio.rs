@@ -0,0 +1,237 @@
+//! Write audio to an [`ISpTTSEngineSite`] in chunks while checking for
+//! actions requested by the client, for example to stop speaking.
+//!
+//! # References
+//!
+//! - [ISpTTSEngineSite::Write (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms717229(v=vs.85))
+//! - [ISpTTSEngineSite::GetActions (SAPI 5.4) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ee431802(v=vs.85))
+
+use windows::Win32::{
+    Foundation::E_FAIL,
+    Media::Speech::{
+        ISpTTSEngineSite, SPVES_ABORT, SPVES_CONTINUE, SPVES_RATE, SPVES_SKIP, SPVES_VOLUME,
+    },
+};
+use windows_core::Error as WinError;
+
+/// Actions requested by the client, returned by
+/// `ISpTTSEngineSite::GetActions` as bitflags.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct SiteActions(i32);
+impl SiteActions {
+    pub const fn from_bits(bits: i32) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(self) -> i32 {
+        self.0
+    }
+    const fn contains(self, action: i32) -> bool {
+        self.0 & action != 0
+    }
+    /// Combine actions that were returned by several calls to `GetActions`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// No actions were requested, so the engine should keep speaking.
+    pub const fn is_continue(self) -> bool {
+        self.0 == SPVES_CONTINUE.0
+    }
+    /// The engine should stop speaking and return from `speak` as soon as
+    /// possible.
+    pub const fn abort(self) -> bool {
+        self.contains(SPVES_ABORT.0)
+    }
+    /// The client wants to skip some sentences, see
+    /// `ISpTTSEngineSite::GetSkipInfo`.
+    pub const fn skip(self) -> bool {
+        self.contains(SPVES_SKIP.0)
+    }
+    /// The rate changed, see `ISpTTSEngineSite::GetRate`.
+    pub const fn rate_changed(self) -> bool {
+        self.contains(SPVES_RATE.0)
+    }
+    /// The volume changed, see `ISpTTSEngineSite::GetVolume`.
+    pub const fn volume_changed(self) -> bool {
+        self.contains(SPVES_VOLUME.0)
+    }
+}
+impl std::fmt::Debug for SiteActions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SiteActions")
+            .field("bits", &format_args!("{:#x}", self.0))
+            .field("abort", &self.abort())
+            .field("skip", &self.skip())
+            .field("rate_changed", &self.rate_changed())
+            .field("volume_changed", &self.volume_changed())
+            .finish()
+    }
+}
+
+/// Buffers audio and writes it to an [`ISpTTSEngineSite`] in chunks of at
+/// most [`max_chunk_len`](Self::max_chunk_len) bytes. The client's actions
+/// are checked after every chunk, so smaller chunks mean that the engine
+/// reacts faster when the client wants to stop speaking.
+///
+/// Audio that doesn't fill a whole chunk is kept until more is written or
+/// [`flush`](Self::flush) is called, so engines must call `flush` before
+/// returning from `speak`.
+#[derive(Debug)]
+pub struct AudioWriter<'a> {
+    site: &'a ISpTTSEngineSite,
+    buffer: Vec<u8>,
+    max_chunk_len: usize,
+    /// Number of bytes that the site accepted.
+    written: u64,
+}
+impl<'a> AudioWriter<'a> {
+    /// Default value for [`max_chunk_len`](Self::max_chunk_len).
+    pub const DEFAULT_MAX_CHUNK_LEN: usize = 4096;
+
+    pub fn new(site: &'a ISpTTSEngineSite) -> Self {
+        Self::with_max_chunk_len(site, Self::DEFAULT_MAX_CHUNK_LEN)
+    }
+    /// Write chunks of at most `max_chunk_len` bytes. Should be a multiple of
+    /// the audio format's block alignment.
+    pub fn with_max_chunk_len(site: &'a ISpTTSEngineSite, max_chunk_len: usize) -> Self {
+        let max_chunk_len = max_chunk_len.max(1);
+        Self {
+            site,
+            buffer: Vec::with_capacity(max_chunk_len),
+            max_chunk_len,
+            written: 0,
+        }
+    }
+
+    pub fn max_chunk_len(&self) -> usize {
+        self.max_chunk_len
+    }
+    /// Offset in bytes of the next audio that is written, including audio
+    /// that is still buffered. This is the offset that events should use.
+    pub fn audio_offset(&self) -> u64 {
+        self.written + self.buffer.len() as u64
+    }
+
+    /// Check which actions the client has requested without writing audio.
+    pub fn actions(&self) -> SiteActions {
+        SiteActions::from_bits(unsafe { self.site.GetActions() } as i32)
+    }
+
+    /// Buffer audio and write every full chunk to the site. Returns the
+    /// actions requested while writing, or the current actions if nothing was
+    /// written yet.
+    ///
+    /// If the client asks to abort then the buffered audio is discarded.
+    pub fn write(&mut self, data: &[u8]) -> windows_core::Result<SiteActions> {
+        self.buffer.extend_from_slice(data);
+        self.write_chunks(self.max_chunk_len)
+    }
+    /// Write `len` zeroed bytes, which is silence for PCM audio.
+    pub fn write_silence(&mut self, len: usize) -> windows_core::Result<SiteActions> {
+        self.buffer.resize(self.buffer.len() + len, 0);
+        self.write_chunks(self.max_chunk_len)
+    }
+    /// Write all buffered audio to the site.
+    pub fn flush(&mut self) -> windows_core::Result<SiteActions> {
+        self.write_chunks(1)
+    }
+
+    /// Write chunks while at least `min_len` bytes are buffered.
+    fn write_chunks(&mut self, min_len: usize) -> windows_core::Result<SiteActions> {
+        let mut actions = None::<SiteActions>;
+        while !self.buffer.is_empty() && self.buffer.len() >= min_len {
+            let len = self.buffer.len().min(self.max_chunk_len);
+            self.write_chunk(len)?;
+            let new_actions = self.actions();
+            let actions = actions.get_or_insert_default();
+            *actions = actions.union(new_actions);
+            if actions.abort() {
+                self.buffer.clear();
+            }
+        }
+        Ok(actions.unwrap_or_else(|| self.actions()))
+    }
+    /// Write the first `len` buffered bytes, even if the site only accepts
+    /// part of them at a time.
+    ///
+    /// Fails if the site returns an error or stops accepting audio. The bytes
+    /// that the site accepted are removed from the buffer either way, so that
+    /// [`audio_offset`](Self::audio_offset) stays correct.
+    fn write_chunk(&mut self, len: usize) -> windows_core::Result<()> {
+        let mut offset = 0;
+        let result = loop {
+            let remaining = &self.buffer[offset..len];
+            if remaining.is_empty() {
+                break Ok(());
+            }
+            let written_bytes = match unsafe {
+                self.site
+                    .Write(remaining.as_ptr().cast(), remaining.len() as u32)
+            } {
+                Ok(0) => {
+                    break Err(WinError::new(
+                        E_FAIL,
+                        format!(
+                            "Output site didn't accept {} bytes of audio",
+                            remaining.len()
+                        ),
+                    ))
+                }
+                Ok(written_bytes) => (written_bytes as usize).min(remaining.len()),
+                Err(e) => break Err(e),
+            };
+            offset += written_bytes;
+            self.written += written_bytes as u64;
+        };
+        self.buffer.drain(..offset);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestSite;
+
+    fn bytes(len: u8) -> Vec<u8> {
+        (0..len).collect()
+    }
+
+    #[test]
+    fn partial_writes_are_retried() {
+        let (site, log) = TestSite::new(3, None);
+        let mut writer = AudioWriter::with_max_chunk_len(&site, 8);
+        assert!(writer.write(&bytes(10)).unwrap().is_continue());
+        assert_eq!(writer.audio_offset(), 10);
+        writer.flush().unwrap();
+        assert_eq!(writer.audio_offset(), 10);
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.output, bytes(10));
+        // A chunk of 8 bytes followed by the flushed 2 bytes:
+        assert_eq!(log.write_sizes, [8, 5, 2, 2]);
+    }
+
+    #[test]
+    fn site_that_accepts_nothing_is_an_error() {
+        let (site, log) = TestSite::new(0, None);
+        let mut writer = AudioWriter::with_max_chunk_len(&site, 4);
+        writer.write(&bytes(2)).unwrap();
+        assert!(writer.flush().is_err());
+        assert_eq!(writer.audio_offset(), 2);
+        assert_eq!(log.lock().unwrap().write_sizes, [2]);
+    }
+
+    #[test]
+    fn abort_discards_buffered_audio() {
+        let (site, log) = TestSite::new(u32::MAX, Some(1));
+        let mut writer = AudioWriter::with_max_chunk_len(&site, 4);
+        assert!(writer.write(&bytes(12)).unwrap().abort());
+        assert_eq!(writer.audio_offset(), 4);
+        assert!(writer.flush().unwrap().abort());
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.output, bytes(4));
+        assert_eq!(log.write_sizes, [4]);
+    }
+}
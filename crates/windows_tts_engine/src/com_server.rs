@@ -12,6 +12,7 @@
 
 use crate::{
     utils::{display_guid, get_current_dll_path, safe_catch_unwind, to_utf16},
+    voices::{should_set_default_voice, VoiceCategory, VoiceKeyData},
     SafeTtsEngine,
 };
 use std::{
@@ -23,15 +24,15 @@ use std::{
 
 use windows::Win32::{
     Foundation::{
-        CLASS_E_CLASSNOTAVAILABLE, ERROR_FILE_NOT_FOUND, E_INVALIDARG, E_POINTER, E_UNEXPECTED,
-        MAX_PATH, S_FALSE, S_OK,
+        BOOL, CLASS_E_CLASSNOTAVAILABLE, ERROR_FILE_NOT_FOUND, E_INVALIDARG, E_POINTER,
+        E_UNEXPECTED, MAX_PATH, S_FALSE, S_OK,
     },
     System::{
         Com::IClassFactory,
         Ole::SELFREG_E_CLASS,
         Registry::{
-            RegCreateKeyExW, RegDeleteKeyExW, RegSetValueExW, HKEY_CLASSES_ROOT, KEY_SET_VALUE,
-            REG_SZ,
+            RegCreateKeyExW, RegDeleteKeyExW, RegSetValueExW, HKEY, HKEY_CLASSES_ROOT,
+            HKEY_CURRENT_USER, KEY_SET_VALUE, REG_SZ,
         },
     },
 };
@@ -60,19 +61,71 @@ pub trait SafeTtsComServer: ComServer {
     /// The type of the text-to-speech engine.
     type TtsEngine: SafeTtsEngine;
 
+    /// Descriptive name of the COM class, see [`ComClassInfo::class_name`].
+    const CLASS_NAME: &'static str;
+
     /// Create a text-to-speech engine.
     fn create_engine() -> Self::TtsEngine;
 
+    /// The voices that [`register_engine`] registers for this engine. The
+    /// first voice is selected as the default voice if
+    /// [`should_set_default_voice`] returns `true`.
+    fn voices() -> Vec<VoiceKeyData>;
+
     /// Register the COM Class id
-    /// [`CLSID_TTS_ENGINE`](SafeTtsComServer::CLSID_TTS_ENGINE) with Windows
-    /// using [`ComClassInfo::register`]. Also register the text-to-speech
-    /// voice/engine with Windows using
-    /// [`voices::VoiceKeyData`](crate::voices::VoiceKeyData).
-    fn register_server();
+    /// [`CLSID_TTS_ENGINE`](SafeTtsComServer::CLSID_TTS_ENGINE) and the
+    /// [`voices`](SafeTtsComServer::voices) for all users using
+    /// [`register_engine`].
+    ///
+    /// Errors are logged and reported to `regsvr32`.
+    fn register_server() -> windows_core::Result<()> {
+        register_engine(
+            Self::CLSID_TTS_ENGINE,
+            Self::CLASS_NAME,
+            &Self::voices(),
+            RegistrationScope::Machine,
+        )
+    }
 
     /// Undo the actions made by
     /// [`register_server`](SafeTtsComServer::register_server).
-    fn unregister_server();
+    fn unregister_server() -> windows_core::Result<()> {
+        unregister_engine(Self::CLSID_TTS_ENGINE, RegistrationScope::Machine)
+    }
+
+    /// Called by `DllInstall` when the DLL is registered with
+    /// `regsvr32 /n /i:<cmdline>`. `per_user` is `true` if the COM class and
+    /// voices should be registered for the current user only, which doesn't
+    /// require admin rights. See [`RegistrationScope::from_install_cmdline`]
+    /// for the supported command lines.
+    ///
+    /// The default implementation calls
+    /// [`register_server`](SafeTtsComServer::register_server) for per-machine
+    /// installs and [`register_engine`] with [`RegistrationScope::User`] for
+    /// per-user installs.
+    fn install(per_user: bool, _cmdline: &str) -> windows_core::Result<()> {
+        if per_user {
+            register_engine(
+                Self::CLSID_TTS_ENGINE,
+                Self::CLASS_NAME,
+                &Self::voices(),
+                RegistrationScope::User,
+            )
+        } else {
+            Self::register_server()
+        }
+    }
+
+    /// Called by `DllInstall` when the DLL is unregistered with
+    /// `regsvr32 /n /u /i:<cmdline>`. Should undo the actions made by
+    /// [`install`](SafeTtsComServer::install).
+    fn uninstall(per_user: bool, _cmdline: &str) -> windows_core::Result<()> {
+        if per_user {
+            unregister_engine(Self::CLSID_TTS_ENGINE, RegistrationScope::User)
+        } else {
+            Self::unregister_server()
+        }
+    }
 
     /// Called once. Can be used to for example setup logging.
     fn initialize() {}
@@ -131,8 +184,8 @@ where
     fn DllCanUnloadNow() -> windows::core::HRESULT {
         safe_catch_unwind(|| {
             safe_init_once::<Self>();
-            // Since we aren't tracking module references (yet), it's never safe to unload this
-            // module
+            // Alive COM objects and IClassFactory::LockServer calls each hold a clone of the
+            // module reference
             if Arc::strong_count(module_ref()) == 1 {
                 // It is safe to unload this module
                 log::debug!("DllCanUnloadNow -> true");
@@ -151,8 +204,13 @@ where
         safe_catch_unwind(|| {
             safe_init_once::<Self>();
             log::debug!("DllRegisterServer");
-            Self::register_server();
-            S_OK
+            match Self::register_server() {
+                Ok(()) => S_OK,
+                Err(e) => {
+                    log::error!("DllRegisterServer failed: {e}");
+                    SELFREG_E_CLASS
+                }
+            }
         })
         .unwrap_or(SELFREG_E_CLASS)
     }
@@ -161,13 +219,160 @@ where
         safe_catch_unwind(|| {
             safe_init_once::<Self>();
             log::debug!("DllUnregisterServer");
-            Self::unregister_server();
-            S_OK
+            match Self::unregister_server() {
+                Ok(()) => S_OK,
+                Err(e) => {
+                    log::error!("DllUnregisterServer failed: {e}");
+                    SELFREG_E_CLASS
+                }
+            }
+        })
+        .unwrap_or(SELFREG_E_CLASS)
+    }
+
+    unsafe fn DllInstall(install: BOOL, cmdline: PCWSTR) -> windows::core::HRESULT {
+        safe_catch_unwind(|| {
+            safe_init_once::<Self>();
+            let cmdline = if cmdline.is_null() {
+                String::new()
+            } else {
+                match unsafe { cmdline.to_string() } {
+                    Ok(cmdline) => cmdline,
+                    Err(e) => {
+                        log::error!("DllInstall got an invalid command line: {e}");
+                        return E_INVALIDARG;
+                    }
+                }
+            };
+            let install = install.as_bool();
+            log::debug!("DllInstall(install: {install}, cmdline: {cmdline:?})");
+            let Some(scope) = RegistrationScope::from_install_cmdline(&cmdline) else {
+                log::error!("DllInstall got an unknown command line: {cmdline:?}");
+                return E_INVALIDARG;
+            };
+            let per_user = scope == RegistrationScope::User;
+            let result = if install {
+                Self::install(per_user, &cmdline)
+            } else {
+                Self::uninstall(per_user, &cmdline)
+            };
+            match result {
+                Ok(()) => S_OK,
+                Err(e) => {
+                    log::error!("DllInstall failed: {e}");
+                    e.code()
+                }
+            }
         })
         .unwrap_or(SELFREG_E_CLASS)
     }
 }
 
+/// Where COM classes and voices are registered, see
+/// [`SafeTtsComServer::install`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationScope {
+    /// For all users, in `HKEY_LOCAL_MACHINE`. Requires admin rights.
+    Machine,
+    /// For the current user, in `HKEY_CURRENT_USER`.
+    User,
+}
+impl RegistrationScope {
+    /// Parse the command line that `regsvr32 /i:<cmdline>` passes to
+    /// `DllInstall`. The comparison ignores case and surrounding whitespace:
+    ///
+    /// - `user` or `peruser`: [`RegistrationScope::User`], for example
+    ///   `regsvr32 /n /i:user windows_tts_engine.dll`.
+    /// - empty, `machine` or `permachine`: [`RegistrationScope::Machine`], for
+    ///   example `regsvr32 /n /i windows_tts_engine.dll`.
+    ///
+    /// Use `/n` so that `regsvr32` doesn't also call `DllRegisterServer`, which
+    /// always registers for all users. Add `/u` to uninstall.
+    pub fn from_install_cmdline(cmdline: &str) -> Option<Self> {
+        match cmdline.trim().to_ascii_lowercase().as_str() {
+            "" | "machine" | "permachine" => Some(Self::Machine),
+            "user" | "peruser" => Some(Self::User),
+            _ => None,
+        }
+    }
+    /// The registry key that COM classes are registered in, and the path of
+    /// its `Classes` sub key.
+    fn classes_key(self) -> (HKEY, &'static str) {
+        match self {
+            Self::Machine => (HKEY_CLASSES_ROOT, ""),
+            Self::User => (HKEY_CURRENT_USER, "Software\\Classes\\"),
+        }
+    }
+    /// The standard voice categories for this scope.
+    pub fn voice_categories(self) -> &'static [VoiceCategory<'static>] {
+        match self {
+            Self::Machine => &[VoiceCategory::VOICES, VoiceCategory::ONECORE_VOICES],
+            Self::User => &[VoiceCategory::VOICES_CURRENT_USER],
+        }
+    }
+}
+
+/// Register a text-to-speech engine's COM class and its voices in `scope`.
+/// The class is created by the current DLL.
+///
+/// The first of the `voices` is selected as the default voice if
+/// [`should_set_default_voice`] returns `true`. Failing to select it is only
+/// logged.
+pub fn register_engine(
+    clsid: GUID,
+    class_name: &str,
+    voices: &[VoiceKeyData],
+    scope: RegistrationScope,
+) -> windows_core::Result<()> {
+    ComClassInfo {
+        clsid,
+        class_name: Some(class_name.into()),
+        threading_model: ComThreadingModel::Apartment,
+        server_path: ComServerPath::CurrentModule,
+    }
+    .register_in(scope)?;
+
+    for voice in voices {
+        voice.register_in_categories(scope.voice_categories())?;
+    }
+    if let Some(voice) = voices.first().filter(|_| should_set_default_voice()) {
+        log::debug!("Selecting {:?} as the default voice", voice.key_name);
+        // Only the classic category since OneCore defaults aren't
+        // handled correctly by SAPI:
+        let category = scope.voice_categories()[0];
+        if let Err(e) = voice.set_as_default(category.category_id) {
+            log::warn!(
+                "Failed to select {:?} as the default voice: {e}",
+                voice.key_name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Undo the actions made by [`register_engine`]. Removes all voices that use
+/// `clsid`, including voices from older versions that used other key names.
+pub fn unregister_engine(clsid: GUID, scope: RegistrationScope) -> windows_core::Result<()> {
+    // Remove as much as possible even if something fails:
+    let mut result = Ok(());
+    for category in scope.voice_categories() {
+        match VoiceKeyData::remove_all_for_class_id(category.tokens_key, clsid) {
+            Ok(removed) => log::debug!(
+                "Unregistered voices {removed:?} from {}",
+                category.category_id
+            ),
+            Err(e) => {
+                log::error!(
+                    "Failed to unregister voices from {}: {e}",
+                    category.category_id
+                );
+                result = result.and(Err(e));
+            }
+        }
+    }
+    result.and(ComClassInfo::unregister_class_id_in(clsid, scope))
+}
+
 /// Entry points for a DLL COM Server.
 ///
 /// Export the functions from a DLL using [`dll_com_server_fns`]
@@ -204,6 +409,14 @@ pub unsafe trait ComServer: Send + Sync + 'static {
     fn DllRegisterServer() -> windows::core::HRESULT;
     /// Use `regsvr32.exe` with the DLL path and the `/u` flag to invoke this.
     fn DllUnregisterServer() -> windows::core::HRESULT;
+    /// Use `regsvr32.exe` with the DLL path and the `/i:<cmdline>` flag to
+    /// invoke this, `/u` sets `install` to false. See
+    /// [`RegistrationScope::from_install_cmdline`].
+    ///
+    /// # Safety
+    ///
+    /// `cmdline` is null or a valid nul terminated string.
+    unsafe fn DllInstall(install: BOOL, cmdline: PCWSTR) -> windows::core::HRESULT;
 }
 
 /// Provide with a type that implements [`ComServer`]. Generates `no_mangle`
@@ -241,6 +454,17 @@ macro_rules! _dll_export_com_server_fns {
         pub extern "stdcall" fn DllUnregisterServer() -> $crate::windows::core::HRESULT {
             <$server as $crate::com_server::ComServer>::DllUnregisterServer()
         }
+
+        /// # References
+        ///
+        /// Signature from: [DllInstall function (shlwapi.h) - Win32 apps | Microsoft Learn](https://learn.microsoft.com/en-us/windows/win32/api/shlwapi/nf-shlwapi-dllinstall)
+        #[no_mangle]
+        pub unsafe extern "stdcall" fn DllInstall(
+            binstall: $crate::windows::Win32::Foundation::BOOL,
+            pszcmdline: $crate::windows::core::PCWSTR,
+        ) -> $crate::windows::core::HRESULT {
+            <$server as $crate::com_server::ComServer>::DllInstall(binstall, pszcmdline)
+        }
     };
 }
 pub use _dll_export_com_server_fns as dll_export_com_server_fns;
@@ -339,6 +563,19 @@ impl std::fmt::Display for ComClassRegisterError {
     }
 }
 impl std::error::Error for ComClassRegisterError {}
+impl From<ComClassRegisterError> for WinError {
+    fn from(value: ComClassRegisterError) -> Self {
+        let code = match &value {
+            ComClassRegisterError::CreateRegisterKey(error)
+            | ComClassRegisterError::ComClassName(error)
+            | ComClassRegisterError::CreateInprocServer32(error)
+            | ComClassRegisterError::GetCurrentModelPath(error)
+            | ComClassRegisterError::InprocServer32Path(error)
+            | ComClassRegisterError::ThreadingModel(error) => error.code(),
+        };
+        WinError::new(code, value.to_string())
+    }
+}
 
 /// Info required to register a COM Class.
 #[derive(Debug, Clone)]
@@ -361,13 +598,21 @@ impl ComClassInfo<'_> {
             server_path: self.server_path.into_owned(),
         }
     }
+    /// Register the COM class for all users.
     pub fn register(&self) -> Result<(), ComClassRegisterError> {
-        let class_path = to_utf16(format!("CLSID\\{{{}}}", display_guid(self.clsid)));
+        self.register_in(RegistrationScope::Machine)
+    }
+    pub fn register_in(&self, scope: RegistrationScope) -> Result<(), ComClassRegisterError> {
+        let (classes_key, classes_path) = scope.classes_key();
+        let class_path = to_utf16(format!(
+            "{classes_path}CLSID\\{{{}}}",
+            display_guid(self.clsid)
+        ));
 
         let mut key = Default::default();
         unsafe {
             RegCreateKeyExW(
-                HKEY_CLASSES_ROOT,
+                classes_key,
                 PCWSTR::from_raw(class_path.as_ptr()),
                 None,
                 None,
@@ -462,12 +707,20 @@ impl ComClassInfo<'_> {
         }
         Ok(())
     }
+    /// Unregister a COM class that was registered for all users.
     pub fn unregister_class_id(clsid: GUID) -> windows::core::Result<()> {
+        Self::unregister_class_id_in(clsid, RegistrationScope::Machine)
+    }
+    pub fn unregister_class_id_in(
+        clsid: GUID,
+        scope: RegistrationScope,
+    ) -> windows::core::Result<()> {
+        let (classes_key, classes_path) = scope.classes_key();
         let class_sub_key_path = to_utf16(format!(
-            "CLSID\\{{{}}}\\InprocServer32",
+            "{classes_path}CLSID\\{{{}}}\\InprocServer32",
             display_guid(clsid)
         ));
-        let class_key_path = to_utf16(format!("CLSID\\{{{}}}", display_guid(clsid)));
+        let class_key_path = to_utf16(format!("{classes_path}CLSID\\{{{}}}", display_guid(clsid)));
 
         // Note: order matters since sub keys must be deleted first.
         let keys_to_delete = [
@@ -476,7 +729,7 @@ impl ComClassInfo<'_> {
         ];
 
         for key_to_delete in keys_to_delete {
-            let result = unsafe { RegDeleteKeyExW(HKEY_CLASSES_ROOT, key_to_delete, 0, None) };
+            let result = unsafe { RegDeleteKeyExW(classes_key, key_to_delete, 0, None) };
             if result != ERROR_FILE_NOT_FOUND {
                 result.ok()?;
             }
@@ -484,3 +737,68 @@ impl ComClassInfo<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::SilentEngine;
+
+    struct TestServer;
+    impl SafeTtsComServer for TestServer {
+        const CLSID_TTS_ENGINE: GUID = GUID::from_u128(0x8f3c_2a1e_5b6d_4c7f_9e0a_1b2c3d4e5f60);
+
+        const CLASS_NAME: &'static str = "windows_tts_engine_tests";
+
+        type TtsEngine = SilentEngine;
+
+        fn create_engine() -> Self::TtsEngine {
+            SilentEngine::default()
+        }
+        fn voices() -> Vec<VoiceKeyData> {
+            Vec::new()
+        }
+    }
+
+    fn class_object() -> IClassFactory {
+        let mut ppv = ptr::null_mut();
+        let hr = unsafe {
+            TestServer::DllGetClassObject(
+                &TestServer::CLSID_TTS_ENGINE,
+                &IClassFactory::IID,
+                &mut ppv,
+            )
+        };
+        assert_eq!(hr, S_OK);
+        unsafe { IClassFactory::from_raw(ppv) }
+    }
+
+    // Note: this is the only test that uses the global module reference, so
+    // no other test can affect what `DllCanUnloadNow` returns.
+    #[test]
+    fn can_unload_only_without_factories_or_locks() {
+        assert_eq!(TestServer::DllCanUnloadNow(), S_OK);
+
+        let factory = class_object();
+        assert_eq!(TestServer::DllCanUnloadNow(), S_FALSE);
+        unsafe { factory.LockServer(true) }.unwrap();
+        drop(factory);
+        // The lock outlives the factory:
+        assert_eq!(TestServer::DllCanUnloadNow(), S_FALSE);
+
+        let factory = class_object();
+        unsafe { factory.LockServer(false) }.unwrap();
+        assert_eq!(TestServer::DllCanUnloadNow(), S_FALSE);
+        drop(factory);
+        assert_eq!(TestServer::DllCanUnloadNow(), S_OK);
+    }
+
+    #[test]
+    fn class_object_rejects_unknown_class_ids() {
+        let mut ppv = ptr::null_mut();
+        let hr = unsafe {
+            TestServer::DllGetClassObject(&GUID::zeroed(), &IClassFactory::IID, &mut ppv)
+        };
+        assert_eq!(hr, CLASS_E_CLASSNOTAVAILABLE);
+        assert!(ppv.is_null());
+    }
+}
@@ -1,9 +1,7 @@
-#[cfg(feature = "lingua")]
-use std::str::FromStr;
-use std::{ptr::null_mut, string::FromUtf16Error};
+use std::{ptr::null_mut, str::FromStr, string::FromUtf16Error};
 
 use windows::{
-    core::{Error as WinError, GUID},
+    core::{Error as WinError, GUID, PWSTR},
     Win32::Globalization::{
         MappingFreePropertyBag, MappingFreeServices, MappingGetServices, MappingRecognizeText,
         ELS_GUID_LANGUAGE_DETECTION, MAPPING_ENUM_OPTIONS, MAPPING_PROPERTY_BAG,
@@ -14,21 +12,152 @@ use windows::{
 #[cfg(feature = "lingua")]
 use lingua::{IsoCode639_1, IsoCode639_3, Language, LanguageDetector, LanguageDetectorBuilder};
 
+use crate::utils::{display_guid, to_utf16};
+
+/// Language codes that mean the same language as another code. Maps
+/// deprecated ISO 639-1 codes, ISO 639-2/3 codes and individual languages of
+/// macrolanguages to a two letter ISO 639-1 code.
+///
+/// Norwegian is the exception: `no` usually means Bokmål, but Bokmål and
+/// Nynorsk are written differently, so `no` is mapped to `nb` and `nn` is kept
+/// separate.
+const LANGUAGE_ALIASES: &[(&str, &str)] = &[
+    // Deprecated ISO 639-1 codes:
+    ("iw", "he"),
+    ("in", "id"),
+    ("ji", "yi"),
+    ("jw", "jv"),
+    ("mo", "ro"),
+    // Macrolanguages and their most common individual language:
+    ("cmn", "zh"),
+    ("arb", "ar"),
+    ("pes", "fa"),
+    ("zsm", "ms"),
+    ("swh", "sw"),
+    ("ekk", "et"),
+    ("lvs", "lv"),
+    ("no", "nb"),
+    ("fil", "tl"),
+    // Three letter codes (ISO 639-2/T, ISO 639-2/B and ISO 639-3):
+    ("ara", "ar"),
+    ("bul", "bg"),
+    ("cat", "ca"),
+    ("ces", "cs"),
+    ("cze", "cs"),
+    ("chi", "zh"),
+    ("zho", "zh"),
+    ("dan", "da"),
+    ("deu", "de"),
+    ("ger", "de"),
+    ("ell", "el"),
+    ("gre", "el"),
+    ("eng", "en"),
+    ("est", "et"),
+    ("fas", "fa"),
+    ("per", "fa"),
+    ("fin", "fi"),
+    ("fra", "fr"),
+    ("fre", "fr"),
+    ("heb", "he"),
+    ("hin", "hi"),
+    ("hrv", "hr"),
+    ("hun", "hu"),
+    ("ind", "id"),
+    ("ita", "it"),
+    ("jpn", "ja"),
+    ("kor", "ko"),
+    ("lav", "lv"),
+    ("lit", "lt"),
+    ("may", "ms"),
+    ("msa", "ms"),
+    ("nld", "nl"),
+    ("dut", "nl"),
+    ("nob", "nb"),
+    ("nno", "nn"),
+    ("nor", "nb"),
+    ("pol", "pl"),
+    ("por", "pt"),
+    ("ron", "ro"),
+    ("rum", "ro"),
+    ("rus", "ru"),
+    ("slk", "sk"),
+    ("slo", "sk"),
+    ("slv", "sl"),
+    ("spa", "es"),
+    ("srp", "sr"),
+    ("swa", "sw"),
+    ("swe", "sv"),
+    ("tgl", "tl"),
+    ("tha", "th"),
+    ("tur", "tr"),
+    ("ukr", "uk"),
+    ("vie", "vi"),
+];
+
+/// Convert a range of byte indices into `text` to the same range in the UTF-16
+/// encoding of `text`, as an inclusive `(start, end)` pair.
+///
+/// Byte indices inside a multi-byte character are widened to include the
+/// whole character, and indices past the end of the text are clamped, so the
+/// result can always be used to slice the text. Returns `None` if the range is
+/// empty.
+#[cfg(feature = "lingua")]
+fn byte_range_to_utf16(text: &str, start: usize, end: usize) -> Option<(usize, usize)> {
+    let mut start = start.min(text.len());
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = end.clamp(start, text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    let utf16_start = text[..start].encode_utf16().count();
+    let utf16_len = text[start..end].encode_utf16().count();
+    (utf16_len > 0).then(|| (utf16_start, utf16_start + utf16_len - 1))
+}
+
+/// Clamp an inclusive range returned by `MappingRecognizeText` to a text with
+/// `text_len` UTF-16 code units. Returns `None` if the range starts after the
+/// end of the text.
+fn clamp_detected_range(start: u32, end: u32, text_len: usize) -> Option<(usize, usize)> {
+    let last_index = text_len.checked_sub(1)?;
+    let start = start as usize;
+    let end = (end as usize).min(last_index);
+    (start <= end).then_some((start, end))
+}
+
+/// Replace a language subtag like `"eng"` or `"iw"` with the code it is an
+/// alias of (`"en"` and `"he"`).
+fn canonical_language(language: &str) -> &str {
+    LANGUAGE_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(language))
+        .map_or(language, |(_, canonical)| canonical)
+}
+
+/// Check if two language codes refer to the same language. Known synonyms
+/// like `"he"` and `"iw"` or `"en"` and `"eng"` are treated as equal.
 pub fn equal_language_codes(first: &str, second: &str) -> bool {
     const SEPARATORS: [char; 2] = ['_', '-'];
 
-    if first.contains(SEPARATORS) && second.contains(SEPARATORS) {
+    fn split(code: &str) -> (&str, Option<&str>) {
+        code.split_once(SEPARATORS)
+            .map_or((code, None), |(language, rest)| (language, Some(rest)))
+    }
+    let (first_language, first_rest) = split(first);
+    let (second_language, second_rest) = split(second);
+
+    if !canonical_language(first_language).eq_ignore_ascii_case(canonical_language(second_language))
+    {
+        return false;
+    }
+    match (first_rest, second_rest) {
         // Only care about suffixes like `US` if both codes contain them `en-US`.
-        first == second
-    } else {
-        first
-            .split_once(SEPARATORS)
-            .map(|(prefix, _)| prefix)
-            .unwrap_or(first)
-            == second
-                .split_once(SEPARATORS)
-                .map(|(prefix, _)| prefix)
-                .unwrap_or(second)
+        (Some(first_rest), Some(second_rest)) => first_rest
+            .split(SEPARATORS)
+            .map(str::to_ascii_lowercase)
+            .eq(second_rest.split(SEPARATORS).map(str::to_ascii_lowercase)),
+        _ => true,
     }
 }
 
@@ -47,8 +176,8 @@ where
 #[derive(Debug)]
 pub enum DetectionError {
     MappingGetServices(WinError),
+    NoServiceFound,
     InvalidServiceGuid,
-    MultipleServicesFound,
     MappingRecognizeText(WinError),
     LanguageInvalidUtf16(FromUtf16Error),
     MappingFreePropertyBag(WinError),
@@ -59,12 +188,12 @@ impl std::fmt::Display for DetectionError {
             DetectionError::MappingGetServices(error) => {
                 write!(f, "MappingGetServices failed: {error}")
             }
+            DetectionError::NoServiceFound => {
+                write!(f, "No Extended Linguistic Services service was found")
+            }
             DetectionError::InvalidServiceGuid => {
                 write!(f, "Incorrect GUID for language detection service")
             }
-            DetectionError::MultipleServicesFound => {
-                write!(f, "More than one Language Detection service found")
-            }
             DetectionError::MappingRecognizeText(error) => {
                 write!(f, "MappingRecognizeText failed: {error}")
             }
@@ -79,6 +208,7 @@ impl std::fmt::Display for DetectionError {
 }
 impl std::error::Error for DetectionError {}
 
+#[derive(Debug, Clone)]
 pub struct DetectedLanguage {
     /// Inclusive start index, the first UTF-16 character this range covers.
     pub start: usize,
@@ -87,6 +217,12 @@ pub struct DetectedLanguage {
     /// The identified languages, with the most certain languages earlier in the
     /// list.
     pub languages: Vec<String>,
+    /// Candidate languages with confidence values between `0.0` and `1.0`,
+    /// sorted with the most likely language first.
+    ///
+    /// Only the `lingua` backend of [`LinguaDetectionService`] computes these,
+    /// for Microsoft's language detection this is always empty.
+    pub scores: Vec<(String, f64)>,
 }
 impl DetectedLanguage {
     /// Get the index of a voice's language in the found
@@ -96,23 +232,136 @@ impl DetectedLanguage {
             .iter()
             .position(|detected| equal_language_codes(detected, lang_code))
     }
+
+    /// Merge consecutive ranges whose most likely languages are equal (as
+    /// determined by [`equal_language_codes`]).
+    ///
+    /// A merged range spans from the start of the first range to the end of
+    /// the last range. Its language list contains all languages from the
+    /// merged ranges, ordered by the first range's priorities with any extra
+    /// languages from later ranges appended in the order they were found.
+    ///
+    /// This avoids switching voices in the middle of a sentence when
+    /// language detection returns many small ranges.
+    pub fn coalesce(ranges: Vec<DetectedLanguage>) -> Vec<DetectedLanguage> {
+        let mut merged: Vec<DetectedLanguage> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            if let Some(previous) = merged.last_mut() {
+                let same_language = match (previous.languages.first(), range.languages.first()) {
+                    (Some(first), Some(second)) => equal_language_codes(first, second),
+                    (None, None) => true,
+                    _ => false,
+                };
+                if same_language {
+                    previous.end = previous.end.max(range.end);
+                    for lang in range.languages {
+                        if !previous.languages.contains(&lang) {
+                            previous.languages.push(lang);
+                        }
+                    }
+                    for (lang, score) in range.scores {
+                        if !previous.scores.iter().any(|(known, _)| *known == lang) {
+                            previous.scores.push((lang, score));
+                        }
+                    }
+                    continue;
+                }
+            }
+            merged.push(range);
+        }
+        merged
+    }
+
+    /// Make sorted ranges that were detected in a text of length `len` cover
+    /// the whole text, so that no text is skipped when it is spoken.
+    ///
+    /// Text that language detection couldn't classify (for example numbers or
+    /// symbols) is added to the previous range, or to the next range if it
+    /// is at the start of the text. If nothing was detected then a single
+    /// range without any languages is returned, which is spoken using the
+    /// voice's default language.
+    pub fn cover(mut ranges: Vec<DetectedLanguage>, len: usize) -> Vec<DetectedLanguage> {
+        if len == 0 {
+            return ranges;
+        }
+        if ranges.is_empty() {
+            return vec![DetectedLanguage {
+                start: 0,
+                end: len - 1,
+                languages: Vec::new(),
+                scores: Vec::new(),
+            }];
+        }
+        ranges[0].start = 0;
+        for index in 1..ranges.len() {
+            ranges[index - 1].end = ranges[index].start.saturating_sub(1);
+        }
+        if let Some(last) = ranges.last_mut() {
+            last.end = len - 1;
+        }
+        ranges
+    }
+}
+
+/// Identifies an Extended Linguistic Services (ELS) service.
+///
+/// # References
+///
+/// - [Extended Linguistic Services - Win32 apps | Microsoft Learn](https://learn.microsoft.com/en-us/windows/win32/intl/extended-linguistic-services)
+#[derive(Debug, Clone, Copy)]
+pub enum ElsServiceId<'a> {
+    /// Select a service by its unique id, for example
+    /// [`ELS_GUID_LANGUAGE_DETECTION`].
+    Guid(GUID),
+    /// Select a service by its category name. Windows provides services in
+    /// the following categories:
+    ///
+    /// - `"Language Detection"`: Microsoft Language Detection.
+    /// - `"Script Detection"`: Microsoft Script Detection.
+    /// - `"Transliteration"`: Microsoft transliteration services, for example
+    ///   Cyrillic to Latin. This category contains multiple services.
+    Category(&'a str),
 }
 
 /// Language detection service handle for Microsoft Language Detection.
 pub struct DetectionService {
-    service: *mut MAPPING_SERVICE_INFO,
+    /// All services returned by `MappingGetServices`, freed when dropped.
+    services: *mut MAPPING_SERVICE_INFO,
+    /// Index of the service we use in [`Self::services`].
+    index: usize,
 }
+// SAFETY: the services are only read after `MappingGetServices` returns and
+// ELS functions don't need to be called on the thread that got the services.
+unsafe impl Send for DetectionService {}
 impl DetectionService {
     pub fn new() -> Result<Self, DetectionError> {
-        // Can use utf16 category but we use GUID directly
-        // let mut _category = windows::core::w!("Language Detection");
+        Self::with_category(ElsServiceId::Guid(ELS_GUID_LANGUAGE_DETECTION))
+    }
+
+    /// Find a specific ELS service. If several services are found then the
+    /// one with the requested GUID is used, or the first one when searching
+    /// by category.
+    ///
+    /// Note that [`DetectionService::recognize_text`] expects the service to
+    /// return results in the same format as Microsoft Language Detection.
+    pub fn with_category(service_id: ElsServiceId<'_>) -> Result<Self, DetectionError> {
+        let mut category_buffer;
 
         // https://learn.microsoft.com/pl-pl/windows/win32/intl/enumerating-and-freeing-services
-        let options = MAPPING_ENUM_OPTIONS {
-            Size: size_of::<MAPPING_ENUM_OPTIONS>(),
-            // pszCategory: PWSTR::from_raw(_category.as_mut_ptr()),
-            pGuid: &ELS_GUID_LANGUAGE_DETECTION as *const GUID as *mut GUID,
-            ..Default::default() // <- All other fields are zeroed
+        let options = match service_id {
+            ElsServiceId::Guid(ref guid) => MAPPING_ENUM_OPTIONS {
+                Size: size_of::<MAPPING_ENUM_OPTIONS>(),
+                pGuid: guid as *const GUID as *mut GUID,
+                ..Default::default() // <- All other fields are zeroed
+            },
+            ElsServiceId::Category(category) => {
+                category_buffer = to_utf16(category);
+                MAPPING_ENUM_OPTIONS {
+                    Size: size_of::<MAPPING_ENUM_OPTIONS>(),
+                    pszCategory: PWSTR::from_raw(category_buffer.as_mut_ptr()),
+                    ..Default::default() // <- All other fields are zeroed
+                }
+            }
         };
         let mut services_ptr: *mut MAPPING_SERVICE_INFO = null_mut();
         let mut len = 0;
@@ -120,16 +369,30 @@ impl DetectionService {
             .map_err(DetectionError::MappingGetServices)?;
 
         // This object will call `MappingFreeServices` later:
-        let service = DetectionService {
-            service: services_ptr,
+        let mut service = DetectionService {
+            services: services_ptr,
+            index: 0,
         };
-        let services = unsafe { std::slice::from_raw_parts(services_ptr, len as usize) };
-        let first = services[0];
-        if first.guid != ELS_GUID_LANGUAGE_DETECTION {
-            return Err(DetectionError::InvalidServiceGuid);
+        let services = if services_ptr.is_null() {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(services_ptr, len as usize) }
+        };
+        if services.is_empty() {
+            return Err(DetectionError::NoServiceFound);
+        }
+        if let ElsServiceId::Guid(guid) = service_id {
+            service.index = services
+                .iter()
+                .position(|info| info.guid == guid)
+                .ok_or(DetectionError::InvalidServiceGuid)?;
         }
-        if len != 1 {
-            return Err(DetectionError::MultipleServicesFound);
+        if services.len() > 1 {
+            log::warn!(
+                "Found {} Extended Linguistic Services services, using the one with GUID {}",
+                services.len(),
+                display_guid(services[service.index].guid)
+            );
         }
         Ok(service)
     }
@@ -138,6 +401,9 @@ impl DetectionService {
         &self,
         text_utf16: &[u16],
     ) -> Result<Vec<DetectedLanguage>, DetectionError> {
+        // text without trailing nuls:
+        let text_utf16 = text_utf16.strip_suffix(&[0]).unwrap_or(text_utf16);
+
         let mut prop_bag = MAPPING_PROPERTY_BAG {
             Size: size_of::<MAPPING_PROPERTY_BAG>(),
             ..Default::default()
@@ -145,9 +411,8 @@ impl DetectionService {
         unsafe {
             MappingRecognizeText(
                 // Note: can't have called MappingFreeServices before this point
-                self.service,
-                // text without trailing nuls:
-                text_utf16.strip_suffix(&[0]).unwrap_or(text_utf16),
+                self.services.add(self.index),
+                text_utf16,
                 0,
                 None,
                 &mut prop_bag,
@@ -172,10 +437,25 @@ impl DetectionService {
                 .collect::<Result<Vec<String>, _>>()
                 .map_err(DetectionError::LanguageInvalidUtf16)?;
 
+            // The returned indexes aren't always inside the text we passed in,
+            // so clamp them to make sure callers can slice the text safely:
+            let Some((start, end)) =
+                clamp_detected_range(range.dwStartIndex, range.dwEndIndex, text_utf16.len())
+            else {
+                log::warn!(
+                    "Ignoring detected language range {}..={} for text of length {}",
+                    range.dwStartIndex,
+                    range.dwEndIndex,
+                    text_utf16.len()
+                );
+                continue;
+            };
+
             detected.push(DetectedLanguage {
-                start: range.dwStartIndex as usize,
-                end: range.dwEndIndex as usize,
+                start,
+                end,
                 languages,
+                scores: Vec::new(),
             })
         }
 
@@ -186,8 +466,12 @@ impl DetectionService {
 }
 impl Drop for DetectionService {
     fn drop(&mut self) {
+        if self.services.is_null() {
+            return;
+        }
+        // Frees the whole array, not only the service we used:
         // TODO: log error
-        _ = unsafe { MappingFreeServices(self.service) };
+        _ = unsafe { MappingFreeServices(self.services) };
     }
 }
 
@@ -197,57 +481,207 @@ enum LinguaDetectionServiceState {
     Microsoft(DetectionService),
 }
 
+/// Which library a [`LinguaDetectionService`] uses to detect languages.
+///
+/// Can be parsed from `"microsoft"` or `"lingua"` (ignoring case), which is how
+/// it is stored in the
+/// [`LANGUAGE_DETECTOR_VALUE`](crate::voices::LANGUAGE_DETECTOR_VALUE) value
+/// of a voice token.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DetectorBackend {
+    /// Microsoft's Extended Linguistic Services, see [`DetectionService`].
+    #[default]
+    Microsoft,
+    /// The [`lingua`] crate. Only available if the `lingua` Cargo feature is
+    /// enabled.
+    Lingua,
+}
+impl DetectorBackend {
+    /// The name that [`from_str`](Self::from_str) parses.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Microsoft => "microsoft",
+            Self::Lingua => "lingua",
+        }
+    }
+
+    /// `false` if support for this backend wasn't compiled in.
+    pub fn is_available(self) -> bool {
+        match self {
+            Self::Microsoft => true,
+            Self::Lingua => cfg!(feature = "lingua"),
+        }
+    }
+}
+impl FromStr for DetectorBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "microsoft" | "ms" | "els" => Self::Microsoft,
+            "lingua" => Self::Lingua,
+            other => return Err(format!("unknown language detector {other:?}")),
+        })
+    }
+}
+
 /// Language detection using the [`lingua`] crate or using the Microsoft
 /// Language Detection ([`DetectionService`]).
 pub struct LinguaDetectionService {
     state: LinguaDetectionServiceState,
+    /// Text with fewer non-whitespace characters than this isn't detected
+    /// since detection is unreliable for short text. Instead the whole text is
+    /// returned as a single range without any languages, so that the voice's
+    /// default language is used.
+    pub min_text_len: usize,
+    /// Languages detected by the [`lingua`] backend with a lower confidence
+    /// than this (between `0.0` and `1.0`) are ignored and the range gets the
+    /// voice's default language instead. Not used by the Microsoft backend.
+    pub min_confidence: f64,
 }
 impl LinguaDetectionService {
+    /// Default value for [`min_text_len`](Self::min_text_len).
+    pub const DEFAULT_MIN_TEXT_LEN: usize = 12;
+    /// Default value for [`min_confidence`](Self::min_confidence).
+    pub const DEFAULT_MIN_CONFIDENCE: f64 = 0.5;
+
+    fn from_state(state: LinguaDetectionServiceState) -> Self {
+        Self {
+            state,
+            min_text_len: Self::DEFAULT_MIN_TEXT_LEN,
+            min_confidence: Self::DEFAULT_MIN_CONFIDENCE,
+        }
+    }
+
+    /// Detector for the specified language codes, for example `"en-US"` or
+    /// `"sv"`. Unknown languages are ignored.
+    #[cfg(feature = "lingua")]
+    fn lingua_builder<S: AsRef<str>>(languages: &[S]) -> LanguageDetectorBuilder {
+        let languages: Vec<Language> = languages
+            .iter()
+            .map(AsRef::as_ref)
+            // ignore suffix in codes like "en-US"
+            .map(|lang| {
+                lang.split_once(['_', '-'])
+                    .map(|(prefix, _)| prefix)
+                    .unwrap_or(lang)
+            })
+            .filter_map(|lang| match IsoCode639_1::from_str(lang) {
+                Ok(v) => Some(Language::from_iso_code_639_1(&v)),
+                Err(_) => match IsoCode639_3::from_str(lang) {
+                    Ok(v) => Some(Language::from_iso_code_639_3(&v)),
+                    Err(_) => {
+                        log::warn!("Failed to identify language {lang:?}");
+                        None
+                    }
+                },
+            })
+            .collect();
+        LanguageDetectorBuilder::from_languages(&languages)
+    }
+
     /// Use [`lingua`] for language detection if the `lingua` Cargo feature is enabled, otherwise use
     /// [`DetectionService`] for language detection.
+    ///
+    /// The language models are loaded the first time they are needed, so the
+    /// first call to [`recognize_text`](Self::recognize_text) can be slow. Use
+    /// [`with_preloaded`](Self::with_preloaded) if the detector is kept
+    /// around.
     pub fn with_lingua<S: AsRef<str>>(_languages: &[S]) -> Result<Self, DetectionError> {
         #[cfg(feature = "lingua")]
         {
-            let languages: Vec<Language> = _languages
-                .iter()
-                .map(AsRef::as_ref)
-                // ignore suffix in codes like "en-US"
-                .map(|lang| {
-                    lang.split_once(['_', '-'])
-                        .map(|(prefix, _)| prefix)
-                        .unwrap_or(lang)
-                })
-                .filter_map(|lang| match IsoCode639_1::from_str(lang) {
-                    Ok(v) => Some(Language::from_iso_code_639_1(&v)),
-                    Err(_) => match IsoCode639_3::from_str(lang) {
-                        Ok(v) => Some(Language::from_iso_code_639_3(&v)),
-                        Err(_) => {
-                            log::warn!("Failed to identify language {lang:?}");
-                            None
-                        }
-                    },
-                })
-                .collect();
-            Ok(Self {
-                state: LinguaDetectionServiceState::Lingua(Box::new(
-                    LanguageDetectorBuilder::from_languages(&languages).build(),
-                )),
-            })
+            Ok(Self::from_state(LinguaDetectionServiceState::Lingua(
+                Box::new(Self::lingua_builder(_languages).build()),
+            )))
         }
 
         #[cfg(not(feature = "lingua"))]
         Self::with_microsoft_language_detection()
     }
+    /// Like [`with_lingua`](Self::with_lingua) but loads all language models
+    /// immediately, which can take hundreds of milliseconds. Engines should
+    /// create this once, for example in
+    /// [`SafeTtsEngine::set_object_token`](crate::SafeTtsEngine::set_object_token),
+    /// rather than for every call to `speak`.
+    pub fn with_preloaded<S: AsRef<str>>(_languages: &[S]) -> Result<Self, DetectionError> {
+        #[cfg(feature = "lingua")]
+        {
+            Ok(Self::from_state(LinguaDetectionServiceState::Lingua(
+                Box::new(
+                    Self::lingua_builder(_languages)
+                        .with_preloaded_language_models()
+                        .build(),
+                ),
+            )))
+        }
+
+        #[cfg(not(feature = "lingua"))]
+        Self::with_microsoft_language_detection()
+    }
+    /// Use the specified backend for language detection. The `languages` are
+    /// only used by the [`lingua`] backend, whose models are preloaded, see
+    /// [`with_preloaded`](Self::with_preloaded).
+    ///
+    /// Falls back to the Microsoft backend and logs a warning if the `lingua`
+    /// Cargo feature isn't enabled.
+    pub fn with_backend<S: AsRef<str>>(
+        backend: DetectorBackend,
+        languages: &[S],
+    ) -> Result<Self, DetectionError> {
+        match backend {
+            DetectorBackend::Lingua if backend.is_available() => Self::with_preloaded(languages),
+            DetectorBackend::Lingua => {
+                log::warn!(
+                    "The lingua language detector wasn't compiled in, using Microsoft Language Detection"
+                );
+                Self::with_microsoft_language_detection()
+            }
+            DetectorBackend::Microsoft => Self::with_microsoft_language_detection(),
+        }
+    }
     pub fn with_microsoft_language_detection() -> Result<Self, DetectionError> {
-        Ok(Self {
-            state: LinguaDetectionServiceState::Microsoft(DetectionService::new()?),
-        })
+        Ok(Self::from_state(LinguaDetectionServiceState::Microsoft(
+            DetectionService::new()?,
+        )))
+    }
+
+    /// The backend that is actually used, which might differ from the one
+    /// passed to [`with_backend`](Self::with_backend).
+    pub fn backend(&self) -> DetectorBackend {
+        match self.state {
+            #[cfg(feature = "lingua")]
+            LinguaDetectionServiceState::Lingua(_) => DetectorBackend::Lingua,
+            LinguaDetectionServiceState::Microsoft(_) => DetectorBackend::Microsoft,
+        }
     }
 
     pub fn recognize_text(
         &self,
         text_utf16: &[u16],
     ) -> Result<Vec<DetectedLanguage>, DetectionError> {
+        let text_len = char::decode_utf16(text_utf16.iter().copied())
+            .filter(|c| !matches!(c, Ok(c) if c.is_whitespace() || *c == '\0'))
+            .count();
+        if text_len < self.min_text_len {
+            log::debug!(
+                "Skipped language detection for short text ({text_len} < {} characters)",
+                self.min_text_len
+            );
+            let len = text_utf16
+                .iter()
+                .rposition(|&c| c != 0)
+                .map_or(0, |last| last + 1);
+            return Ok(if len == 0 {
+                Vec::new()
+            } else {
+                vec![DetectedLanguage {
+                    start: 0,
+                    end: len - 1,
+                    languages: Vec::new(),
+                    scores: Vec::new(),
+                }]
+            });
+        }
         match &self.state {
             #[cfg(feature = "lingua")]
             LinguaDetectionServiceState::Lingua(detector) => {
@@ -255,17 +689,65 @@ impl LinguaDetectionService {
                 let result = detector.detect_multiple_languages_of(text.as_str());
                 Ok(result
                     .into_iter()
-                    .map(|detected| {
-                        let start = text[..detected.start_index()].encode_utf16().count();
-                        let len = text[detected.start_index()..detected.end_index()]
-                            .encode_utf16()
-                            .count();
-                        let end = start + len - 1;
-                        DetectedLanguage {
+                    .filter_map(|detected| {
+                        let Some((start, end)) = byte_range_to_utf16(
+                            &text,
+                            detected.start_index(),
+                            detected.end_index(),
+                        ) else {
+                            log::debug!(
+                                "Ignored empty range {}..{} detected as {}",
+                                detected.start_index(),
+                                detected.end_index(),
+                                detected.language()
+                            );
+                            return None;
+                        };
+                        // Replacement characters for invalid UTF-16 have the
+                        // same length, but clamp just in case:
+                        let end = end.min(text_utf16.len() - 1);
+                        if start > end {
+                            return None;
+                        }
+                        let detected_text = String::from_utf16_lossy(&text_utf16[start..=end]);
+                        // Sorted with the most likely language first:
+                        let confidence_values =
+                            detector.compute_language_confidence_values(detected_text.as_str());
+                        let confidence = confidence_values
+                            .iter()
+                            .find(|(language, _)| *language == detected.language())
+                            .map_or(0.0, |(_, confidence)| *confidence);
+                        let scores: Vec<(String, f64)> = confidence_values
+                            .into_iter()
+                            .filter(|(_, confidence)| *confidence > 0.0)
+                            .map(|(language, confidence)| {
+                                (language.iso_code_639_1().to_string(), confidence)
+                            })
+                            .collect();
+                        let languages = if confidence < self.min_confidence {
+                            log::debug!(
+                                "Ignored detected language {} with low confidence {confidence:.2}",
+                                detected.language()
+                            );
+                            Vec::new()
+                        } else {
+                            // Other likely languages are fallbacks in case
+                            // there is no voice for the detected one:
+                            let detected = detected.language().iso_code_639_1().to_string();
+                            let others = scores
+                                .iter()
+                                .filter(|(lang, confidence)| {
+                                    *lang != detected && *confidence >= self.min_confidence
+                                })
+                                .map(|(lang, _)| lang.clone());
+                            std::iter::once(detected.clone()).chain(others).collect()
+                        };
+                        Some(DetectedLanguage {
                             start,
                             end,
-                            languages: vec![detected.language().iso_code_639_1().to_string()],
-                        }
+                            languages,
+                            scores,
+                        })
                     })
                     .collect())
             }
@@ -275,3 +757,194 @@ impl LinguaDetectionService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str) -> Vec<u16> {
+        text.encode_utf16().collect()
+    }
+
+    fn detected(start: usize, end: usize, languages: &[&str]) -> DetectedLanguage {
+        DetectedLanguage {
+            start,
+            end,
+            languages: languages.iter().map(|lang| lang.to_string()).collect(),
+            scores: Vec::new(),
+        }
+    }
+
+    /// The parts of detected ranges that tests compare.
+    fn spans(ranges: &[DetectedLanguage]) -> Vec<(usize, usize, Vec<&str>)> {
+        ranges
+            .iter()
+            .map(|range| {
+                let languages = range.languages.iter().map(String::as_str).collect();
+                (range.start, range.end, languages)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn canonical_language_resolves_aliases() {
+        assert_eq!(canonical_language("iw"), "he");
+        assert_eq!(canonical_language("ENG"), "en");
+        assert_eq!(canonical_language("cmn"), "zh");
+        assert_eq!(canonical_language("no"), "nb");
+        assert_eq!(canonical_language("nno"), "nn");
+        // Unknown and already canonical codes are kept as is:
+        assert_eq!(canonical_language("sv"), "sv");
+        assert_eq!(canonical_language("xyz"), "xyz");
+    }
+
+    #[test]
+    fn equal_language_codes_ignore_missing_regions() {
+        assert!(equal_language_codes("en", "en-US"));
+        assert!(equal_language_codes("en_us", "en-US"));
+        assert!(equal_language_codes("zh-Hant-TW", "zh-hant-tw"));
+        assert!(!equal_language_codes("en-US", "en-GB"));
+        assert!(!equal_language_codes("en", "sv"));
+    }
+
+    #[test]
+    fn equal_language_codes_resolve_aliases() {
+        assert!(equal_language_codes("he-IL", "iw"));
+        assert!(equal_language_codes("eng", "en-GB"));
+        assert!(equal_language_codes("cmn-CN", "zh-cn"));
+    }
+
+    #[test]
+    fn norwegian_macrolanguage_only_matches_bokmal() {
+        assert!(equal_language_codes("no", "nb-NO"));
+        assert!(equal_language_codes("nor", "nb"));
+        assert!(!equal_language_codes("no", "nn-NO"));
+        assert!(!equal_language_codes("nb", "nn"));
+        assert!(equal_language_codes("nno", "nn"));
+    }
+
+    #[test]
+    fn select_service_by_category_name() {
+        let service = DetectionService::with_category(ElsServiceId::Category("Language Detection"))
+            .expect("Windows provides a language detection service");
+        let guid = unsafe { (*service.services.add(service.index)).guid };
+        assert_eq!(guid, ELS_GUID_LANGUAGE_DETECTION);
+
+        let detected = service
+            .recognize_text(&utf16("This is a sentence written in English."))
+            .unwrap();
+        assert!(
+            detected
+                .iter()
+                .any(|range| range.get_priority("en").is_some()),
+            "{detected:?}"
+        );
+    }
+
+    #[test]
+    fn select_service_by_unknown_category_name() {
+        let result = DetectionService::with_category(ElsServiceId::Category("No Such Category"));
+        assert!(matches!(
+            result,
+            Err(DetectionError::NoServiceFound | DetectionError::MappingGetServices(_))
+        ));
+    }
+
+    #[test]
+    fn detected_range_inside_text_is_kept() {
+        assert_eq!(clamp_detected_range(2, 5, 10), Some((2, 5)));
+    }
+
+    #[test]
+    fn detected_range_end_past_text_is_clamped() {
+        // The engines append a space after every fragment, ELS can report a
+        // range that includes characters after it:
+        assert_eq!(clamp_detected_range(3, 12, 10), Some((3, 9)));
+    }
+
+    #[test]
+    fn detected_range_outside_text_is_dropped() {
+        assert_eq!(clamp_detected_range(10, 12, 10), None);
+        assert_eq!(clamp_detected_range(0, 0, 0), None);
+    }
+
+    #[test]
+    fn coalesce_merges_ranges_with_the_same_language() {
+        let ranges = vec![
+            detected(0, 3, &["en"]),
+            detected(4, 7, &["en-US", "de"]),
+            detected(8, 9, &["sv"]),
+            detected(10, 11, &[]),
+            detected(12, 13, &[]),
+        ];
+        assert_eq!(
+            spans(&DetectedLanguage::coalesce(ranges)),
+            [
+                (0, 7, vec!["en", "en-US", "de"]),
+                (8, 9, vec!["sv"]),
+                (10, 13, vec![]),
+            ]
+        );
+    }
+
+    #[cfg(feature = "lingua")]
+    mod byte_ranges {
+        use super::*;
+
+        /// `é` is 2 bytes and `😀` is 4 bytes and 2 UTF-16 code units.
+        const TEXT: &str = "aé😀b";
+
+        #[test]
+        fn whole_text() {
+            assert_eq!(byte_range_to_utf16(TEXT, 0, TEXT.len()), Some((0, 4)));
+        }
+
+        #[test]
+        fn indices_inside_a_char_include_the_whole_char() {
+            // Start in the middle of `é`:
+            assert_eq!(byte_range_to_utf16(TEXT, 2, 3), Some((1, 1)));
+            // End in the middle of `😀`:
+            assert_eq!(byte_range_to_utf16(TEXT, 3, 5), Some((2, 3)));
+            assert_eq!(byte_range_to_utf16(TEXT, 4, 7), Some((2, 3)));
+        }
+
+        #[test]
+        fn astral_chars_use_two_code_units() {
+            assert_eq!(byte_range_to_utf16(TEXT, 3, 7), Some((2, 3)));
+            assert_eq!(byte_range_to_utf16(TEXT, 7, 8), Some((4, 4)));
+        }
+
+        #[test]
+        fn out_of_range_indices_are_clamped() {
+            assert_eq!(byte_range_to_utf16(TEXT, 7, 100), Some((4, 4)));
+            assert_eq!(byte_range_to_utf16(TEXT, 100, 200), None);
+        }
+
+        #[test]
+        fn empty_ranges_are_dropped() {
+            assert_eq!(byte_range_to_utf16(TEXT, 3, 3), None);
+            assert_eq!(byte_range_to_utf16(TEXT, 5, 3), None);
+            assert_eq!(byte_range_to_utf16("", 0, 0), None);
+        }
+    }
+
+    #[test]
+    fn cover_extends_ranges_over_gaps() {
+        // "12 Hello, Welt!" where only the words were detected:
+        let ranges = vec![detected(3, 7, &["en"]), detected(10, 13, &["de"])];
+        assert_eq!(
+            spans(&DetectedLanguage::cover(ranges, 15)),
+            [(0, 9, vec!["en"]), (10, 14, vec!["de"])]
+        );
+    }
+
+    #[test]
+    fn cover_falls_back_to_a_range_without_languages() {
+        assert_eq!(
+            spans(&DetectedLanguage::cover(Vec::new(), 4)),
+            [(0, 3, vec![])]
+        );
+        // Empty text has nothing to cover:
+        assert!(DetectedLanguage::cover(Vec::new(), 0).is_empty());
+    }
+}
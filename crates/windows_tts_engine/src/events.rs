@@ -0,0 +1,597 @@
+//! Send events such as word boundaries to the client through
+//! [`ISpTTSEngineSite`].
+//!
+//! # Parameter layout
+//!
+//! | Event                    | `wParam`                        | `lParam`                                   |
+//! |--------------------------|---------------------------------|--------------------------------------------|
+//! | `SPEI_WORD_BOUNDARY`     | Length of the word              | Offset of the word in the original text    |
+//! | `SPEI_SENTENCE_BOUNDARY` | Length of the sentence          | Offset of the sentence in the original text |
+//! | `SPEI_TTS_BOOKMARK`      | Bookmark name parsed as integer | Bookmark name as a nul terminated string   |
+//! | `SPEI_VISEME`            | Duration in ms (high word) and next viseme (low word) | Current viseme (low word) |
+//! | `SPEI_PHONEME`           | Duration in ms (high word) and next phoneme (low word) | Features (high word) and current phoneme (low word) |
+//! | `SPEI_START_INPUT_STREAM` | `0`                            | `0`                                        |
+//! | `SPEI_END_INPUT_STREAM`  | `0`                             | `0`                                        |
+//!
+//! Offsets and lengths are counted in UTF-16 code units of the text that was
+//! passed to `ISpVoice::Speak`, see [`SourceRange`].
+//!
+//! # References
+//!
+//! - [SPEVENT (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720165(v=vs.85))
+//! - [SPEVENTENUM (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720188(v=vs.85))
+//! - [SPVISEMES (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720881(v=vs.85))
+//! - [American English Phoneme Representation (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms717239(v=vs.85))
+
+use std::ops::Range;
+
+use windows::Win32::{
+    Foundation::{LPARAM, WPARAM},
+    Media::Speech::{
+        ISpTTSEngineSite, SPEI_END_INPUT_STREAM, SPEI_PHONEME, SPEI_SENTENCE_BOUNDARY,
+        SPEI_START_INPUT_STREAM, SPEI_TTS_BOOKMARK, SPEI_VISEME, SPEI_WORD_BOUNDARY,
+        SPET_LPARAM_IS_STRING, SPET_LPARAM_IS_UNDEFINED, SPEVENT, SPEVENTENUM, SPEVENTLPARAMTYPE,
+        SPVISEMES, SP_VISEME_0, SP_VISEME_1, SP_VISEME_12, SP_VISEME_13, SP_VISEME_14,
+        SP_VISEME_15, SP_VISEME_16, SP_VISEME_18, SP_VISEME_19, SP_VISEME_20, SP_VISEME_21,
+        SP_VISEME_4, SP_VISEME_6, SP_VISEME_7, SP_VISEME_8,
+    },
+};
+
+use crate::{
+    text::{sentence_ranges, word_ranges, JoinedText, SourceRange},
+    SpeechFormat,
+};
+
+/// Guess the mouth shape used to pronounce a character, based on how the
+/// letter is usually pronounced in English. Characters that aren't letters
+/// are treated as silence.
+pub fn letter_viseme(c: char) -> SPVISEMES {
+    match c.to_ascii_lowercase() {
+        'a' => SP_VISEME_1,
+        'e' => SP_VISEME_4,
+        'i' | 'y' => SP_VISEME_6,
+        'o' => SP_VISEME_8,
+        'u' | 'w' => SP_VISEME_7,
+        'h' => SP_VISEME_12,
+        'r' => SP_VISEME_13,
+        'l' => SP_VISEME_14,
+        's' | 'z' | 'x' => SP_VISEME_15,
+        'j' => SP_VISEME_16,
+        'f' | 'v' => SP_VISEME_18,
+        'd' | 't' | 'n' => SP_VISEME_19,
+        'c' | 'g' | 'k' | 'q' => SP_VISEME_20,
+        'p' | 'b' | 'm' => SP_VISEME_21,
+        _ if c.is_alphabetic() => SP_VISEME_1,
+        _ => SP_VISEME_0,
+    }
+}
+
+/// SAPI's American English phoneme id for silence (`_`).
+pub const PHONEME_SILENCE: u16 = 7;
+
+/// Guess the SAPI American English phoneme id used to pronounce a character,
+/// based on how the letter is usually pronounced in English. Characters that
+/// aren't letters are treated as [`PHONEME_SILENCE`].
+pub fn letter_phoneme(c: char) -> u16 {
+    match c.to_ascii_lowercase() {
+        'a' => 11,                    // ae
+        'b' => 17,                    // b
+        'c' | 'k' | 'q' => 30,        // k
+        'd' => 19,                    // d
+        'e' => 21,                    // eh
+        'f' => 24,                    // f
+        'g' => 25,                    // g
+        'h' => 26,                    // h
+        'i' => 27,                    // ih
+        'j' => 29,                    // jh
+        'l' => 31,                    // l
+        'm' => 32,                    // m
+        'n' => 33,                    // n
+        'o' => 13,                    // ao
+        'p' => 37,                    // p
+        'r' => 38,                    // r
+        's' | 'x' => 39,              // s
+        't' => 41,                    // t
+        'u' => 12,                    // ah
+        'v' => 45,                    // v
+        'w' => 46,                    // w
+        'y' => 47,                    // y
+        'z' => 48,                    // z
+        _ if c.is_alphabetic() => 15, // ax
+        _ => PHONEME_SILENCE,
+    }
+}
+
+/// Create an event that should be written at a specific byte offset in the
+/// audio stream.
+pub fn new_event(
+    event_id: SPEVENTENUM,
+    lparam_type: SPEVENTLPARAMTYPE,
+    audio_offset: u64,
+    wparam: usize,
+    lparam: isize,
+) -> SPEVENT {
+    SPEVENT {
+        // `eEventId` is the low 16 bits and `elParamType` the high 16 bits:
+        _bitfield: (event_id.0 & 0xFFFF) | (lparam_type.0 << 16),
+        // Filled in by SAPI:
+        ulStreamNum: 0,
+        ullAudioStreamOffset: audio_offset,
+        wParam: WPARAM(wparam),
+        lParam: LPARAM(lparam),
+    }
+}
+
+fn viseme_event(
+    audio_offset: u64,
+    duration_ms: u16,
+    current: SPVISEMES,
+    next: SPVISEMES,
+) -> SPEVENT {
+    new_event(
+        SPEI_VISEME,
+        SPET_LPARAM_IS_UNDEFINED,
+        audio_offset,
+        (usize::from(duration_ms) << 16) | (next.0 as usize & 0xFFFF),
+        current.0 as isize & 0xFFFF,
+    )
+}
+
+fn phoneme_event(audio_offset: u64, duration_ms: u16, current: u16, next: u16) -> SPEVENT {
+    new_event(
+        SPEI_PHONEME,
+        SPET_LPARAM_IS_UNDEFINED,
+        audio_offset,
+        (usize::from(duration_ms) << 16) | usize::from(next),
+        // No stress or emphasis features (`SPVFEATURE`) in the high word:
+        isize::from(current),
+    )
+}
+
+/// A copy of an event that an engine sent, used when the engine is run
+/// without SAPI, see [`TtsVoice::speak_with_events`](crate::TtsVoice::speak_with_events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectedEvent {
+    pub event_id: SPEVENTENUM,
+    /// Byte offset in the audio stream where the event occurs.
+    pub audio_offset: u64,
+    pub wparam: usize,
+    /// `lParam` of the event. Is `0` if it pointed to a string, which is
+    /// stored in [`Self::text`] instead.
+    pub lparam: isize,
+    /// The string that `lParam` pointed to, for example a bookmark's name.
+    pub text: Option<String>,
+}
+impl CollectedEvent {
+    /// Copy an event, including the string its `lParam` points to.
+    ///
+    /// # Safety
+    ///
+    /// If the event's `lParam` is a string then it must be null or point to a
+    /// valid nul terminated UTF-16 string.
+    pub unsafe fn from_event(event: &SPEVENT) -> Self {
+        let lparam_type = SPEVENTLPARAMTYPE(event._bitfield >> 16);
+        let (lparam, text) = if lparam_type == SPET_LPARAM_IS_STRING {
+            let text = (event.lParam.0 != 0).then(|| {
+                let text = windows_core::PCWSTR::from_raw(event.lParam.0 as *const u16);
+                String::from_utf16_lossy(unsafe { text.as_wide() })
+            });
+            (0, text)
+        } else {
+            (event.lParam.0, None)
+        };
+        Self {
+            event_id: SPEVENTENUM(event._bitfield & 0xFFFF),
+            audio_offset: event.ullAudioStreamOffset,
+            wparam: event.wParam.0,
+            lparam,
+            text,
+        }
+    }
+}
+
+/// Sends events to an [`ISpTTSEngineSite`] while skipping events the client
+/// isn't interested in.
+#[derive(Debug, Clone)]
+pub struct EventSink<'a> {
+    site: &'a ISpTTSEngineSite,
+    /// Bit flags where bit `n` is set if the client wants events with id `n`.
+    interest: u64,
+}
+impl<'a> EventSink<'a> {
+    pub fn new(site: &'a ISpTTSEngineSite) -> windows_core::Result<Self> {
+        let mut interest = 0;
+        unsafe { site.GetEventInterest(&mut interest) }?;
+        Ok(Self { site, interest })
+    }
+
+    /// Check if the client wants to receive a specific kind of event.
+    pub fn is_interested(&self, event_id: SPEVENTENUM) -> bool {
+        self.interest & (1u64 << event_id.0) != 0
+    }
+
+    /// Send events to the client. Events that the client isn't interested in
+    /// are ignored.
+    pub fn add_events(&self, events: &[SPEVENT]) -> windows_core::Result<()> {
+        let events = events
+            .iter()
+            .filter(|event| self.is_interested(SPEVENTENUM(event._bitfield & 0xFFFF)))
+            .copied()
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            return Ok(());
+        }
+        unsafe { self.site.AddEvents(events.as_ptr(), events.len() as u32) }
+    }
+
+    /// Notify the client that synthesis of the input text started. Should be
+    /// sent before any audio is written.
+    pub fn start_input_stream(&self) -> windows_core::Result<()> {
+        self.add_events(&[new_event(
+            SPEI_START_INPUT_STREAM,
+            SPET_LPARAM_IS_UNDEFINED,
+            0,
+            0,
+            0,
+        )])
+    }
+
+    /// Notify the client that all audio for the input text has been written,
+    /// `audio_offset` should be the total number of written bytes.
+    pub fn end_input_stream(&self, audio_offset: u64) -> windows_core::Result<()> {
+        self.add_events(&[new_event(
+            SPEI_END_INPUT_STREAM,
+            SPET_LPARAM_IS_UNDEFINED,
+            audio_offset,
+            0,
+            0,
+        )])
+    }
+
+    /// Notify the client that a word will be spoken once the audio reaches
+    /// `audio_offset`.
+    pub fn word_boundary(&self, audio_offset: u64, word: SourceRange) -> windows_core::Result<()> {
+        self.add_events(&[new_event(
+            SPEI_WORD_BOUNDARY,
+            SPET_LPARAM_IS_UNDEFINED,
+            audio_offset,
+            word.len as usize,
+            word.offset as isize,
+        )])
+    }
+
+    /// Notify the client that a sentence will be spoken once the audio
+    /// reaches `audio_offset`.
+    pub fn sentence_boundary(
+        &self,
+        audio_offset: u64,
+        sentence: SourceRange,
+    ) -> windows_core::Result<()> {
+        self.add_events(&[new_event(
+            SPEI_SENTENCE_BOUNDARY,
+            SPET_LPARAM_IS_UNDEFINED,
+            audio_offset,
+            sentence.len as usize,
+            sentence.offset as isize,
+        )])
+    }
+
+    /// Notify the client that the audio reached a bookmark.
+    ///
+    /// Like `_wtol`, the numeric value is parsed from the leading digits of the
+    /// name and is `0` if there are none.
+    pub fn bookmark(&self, audio_offset: u64, name: &[u16]) -> windows_core::Result<()> {
+        let name_nul_terminated = name
+            .iter()
+            .copied()
+            .chain(std::iter::once(0))
+            .collect::<Vec<u16>>();
+        let trimmed = String::from_utf16_lossy(name);
+        let trimmed = trimmed.trim_start();
+        let digits_end = trimmed
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && matches!(c, '+' | '-'))))
+            .map_or(trimmed.len(), |(i, _)| i);
+        let value = trimmed[..digits_end].parse::<i32>().unwrap_or(0);
+
+        // SAPI copies the string so it only needs to live until `AddEvents`
+        // returns:
+        self.add_events(&[new_event(
+            SPEI_TTS_BOOKMARK,
+            SPET_LPARAM_IS_STRING,
+            audio_offset,
+            value as usize,
+            name_nul_terminated.as_ptr() as isize,
+        )])
+    }
+
+    /// Notify the client about the mouth shape at `audio_offset`.
+    pub fn viseme(
+        &self,
+        audio_offset: u64,
+        duration_ms: u16,
+        current: SPVISEMES,
+        next: SPVISEMES,
+    ) -> windows_core::Result<()> {
+        self.add_events(&[viseme_event(audio_offset, duration_ms, current, next)])
+    }
+
+    /// Send viseme events for the text inside `text_range` of the joined
+    /// text, assuming that the characters are spread evenly over `audio_len`
+    /// bytes of audio starting at `audio_offset`. Visemes are guessed using
+    /// [`letter_viseme`].
+    ///
+    /// This is useful for engines that don't know which phonemes they speak.
+    pub fn estimated_visemes(
+        &self,
+        joined_text: &JoinedText<'_>,
+        text_range: Range<usize>,
+        audio_offset: u64,
+        audio_len: u64,
+        wave_format: &SpeechFormat,
+    ) -> windows_core::Result<()> {
+        let SpeechFormat::Wave(info) = wave_format else {
+            return Ok(());
+        };
+        if !self.is_interested(SPEI_VISEME) || text_range.is_empty() {
+            return Ok(());
+        }
+        let text_len = text_range.len() as u64;
+        let block_align = u64::from(info.nBlockAlign.max(1));
+        let bytes_per_sec = u64::from(info.nAvgBytesPerSec.max(1));
+
+        // Start offset of each viseme, with repeated visemes merged:
+        let mut visemes: Vec<(u64, SPVISEMES)> = Vec::new();
+        let mut index = 0;
+        for c in char::decode_utf16(joined_text.utf16()[text_range].iter().copied()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            let viseme = letter_viseme(c);
+            if visemes.last().is_none_or(|&(_, last)| last != viseme) {
+                let offset_in_range = audio_len * index / text_len;
+                visemes.push((offset_in_range / block_align * block_align, viseme));
+            }
+            index += c.len_utf16() as u64;
+        }
+
+        let events = visemes
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, viseme))| {
+                let (end, next) = visemes
+                    .get(i + 1)
+                    .copied()
+                    .unwrap_or((audio_len, SP_VISEME_0));
+                let duration_ms = (end - offset) * 1000 / bytes_per_sec;
+                viseme_event(
+                    audio_offset + offset,
+                    duration_ms.min(u64::from(u16::MAX)) as u16,
+                    viseme,
+                    next,
+                )
+            })
+            .collect::<Vec<_>>();
+        self.add_events(&events)
+    }
+
+    /// Notify the client about the phoneme spoken at `audio_offset`. Phoneme
+    /// ids are SAPI's American English phoneme ids, for example
+    /// [`PHONEME_SILENCE`].
+    pub fn phoneme(
+        &self,
+        audio_offset: u64,
+        duration_ms: u16,
+        current: u16,
+        next: u16,
+    ) -> windows_core::Result<()> {
+        self.add_events(&[phoneme_event(audio_offset, duration_ms, current, next)])
+    }
+
+    /// Send phoneme events for the text inside `text_range` of the joined
+    /// text, assuming that the characters are spread evenly over `audio_len`
+    /// bytes of audio starting at `audio_offset`. Phonemes are guessed using
+    /// [`letter_phoneme`].
+    ///
+    /// This is only a rough approximation for engines that don't expose the
+    /// phonemes they speak: letters don't map one to one to phonemes and the
+    /// timing ignores that some phonemes are longer than others.
+    pub fn estimated_phonemes(
+        &self,
+        joined_text: &JoinedText<'_>,
+        text_range: Range<usize>,
+        audio_offset: u64,
+        audio_len: u64,
+        wave_format: &SpeechFormat,
+    ) -> windows_core::Result<()> {
+        let SpeechFormat::Wave(info) = wave_format else {
+            return Ok(());
+        };
+        if !self.is_interested(SPEI_PHONEME) || text_range.is_empty() {
+            return Ok(());
+        }
+        let text_len = text_range.len() as u64;
+        let block_align = u64::from(info.nBlockAlign.max(1));
+        let bytes_per_sec = u64::from(info.nAvgBytesPerSec.max(1));
+
+        // Start offset of each phoneme, with repeated phonemes (like the two
+        // letters in "ll") merged:
+        let mut phonemes: Vec<(u64, u16)> = Vec::new();
+        let mut index = 0;
+        for c in char::decode_utf16(joined_text.utf16()[text_range].iter().copied()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            let phoneme = letter_phoneme(c);
+            if phonemes.last().is_none_or(|&(_, last)| last != phoneme) {
+                let offset_in_range = audio_len * index / text_len;
+                phonemes.push((offset_in_range / block_align * block_align, phoneme));
+            }
+            index += c.len_utf16() as u64;
+        }
+
+        let events = phonemes
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, phoneme))| {
+                let (end, next) = phonemes
+                    .get(i + 1)
+                    .copied()
+                    .unwrap_or((audio_len, PHONEME_SILENCE));
+                let duration_ms = (end - offset) * 1000 / bytes_per_sec;
+                phoneme_event(
+                    audio_offset + offset,
+                    duration_ms.min(u64::from(u16::MAX)) as u16,
+                    phoneme,
+                    next,
+                )
+            })
+            .collect::<Vec<_>>();
+        self.add_events(&events)
+    }
+
+    /// Send word boundary events for all words inside `text_range` of the
+    /// joined text, assuming that the words are spread evenly (by character
+    /// count) over `audio_len` bytes of audio starting at `audio_offset`.
+    ///
+    /// This is useful for engines that don't know when each word is spoken.
+    pub fn estimated_word_boundaries(
+        &self,
+        joined_text: &JoinedText<'_>,
+        text_range: Range<usize>,
+        audio_offset: u64,
+        audio_len: u64,
+        block_align: u16,
+    ) -> windows_core::Result<()> {
+        if !self.is_interested(SPEI_WORD_BOUNDARY) {
+            return Ok(());
+        }
+        self.estimated_boundaries(
+            joined_text,
+            text_range,
+            audio_offset,
+            audio_len,
+            block_align,
+            word_ranges,
+            Self::word_boundary,
+        )
+    }
+
+    /// Like [`estimated_word_boundaries`](Self::estimated_word_boundaries) but
+    /// sends sentence boundary events for the sentences found by
+    /// [`sentence_ranges`].
+    pub fn estimated_sentence_boundaries(
+        &self,
+        joined_text: &JoinedText<'_>,
+        text_range: Range<usize>,
+        audio_offset: u64,
+        audio_len: u64,
+        block_align: u16,
+    ) -> windows_core::Result<()> {
+        if !self.is_interested(SPEI_SENTENCE_BOUNDARY) {
+            return Ok(());
+        }
+        self.estimated_boundaries(
+            joined_text,
+            text_range,
+            audio_offset,
+            audio_len,
+            block_align,
+            sentence_ranges,
+            Self::sentence_boundary,
+        )
+    }
+
+    #[expect(
+        clippy::too_many_arguments,
+        reason = "shared by the public estimated_*_boundaries methods"
+    )]
+    fn estimated_boundaries(
+        &self,
+        joined_text: &JoinedText<'_>,
+        text_range: Range<usize>,
+        audio_offset: u64,
+        audio_len: u64,
+        block_align: u16,
+        find_ranges: fn(&[u16]) -> Vec<Range<usize>>,
+        send_event: fn(&Self, u64, SourceRange) -> windows_core::Result<()>,
+    ) -> windows_core::Result<()> {
+        if text_range.is_empty() {
+            return Ok(());
+        }
+        let text_len = text_range.len() as u64;
+        let block_align = u64::from(block_align.max(1));
+        for range in find_ranges(&joined_text.utf16()[text_range.clone()]) {
+            let Some(source) = joined_text.source_range(
+                text_range.start + range.start,
+                text_range.start + range.end - 1,
+            ) else {
+                continue;
+            };
+            let offset_in_range = audio_len * range.start as u64 / text_len;
+            send_event(
+                self,
+                audio_offset + offset_in_range / block_align * block_align,
+                source,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{memory_site, speak_state, TestFrags},
+        SpeakFlags,
+    };
+
+    /// Send estimated boundary events for the whole joined text and return
+    /// the collected events.
+    fn estimated_events(
+        joined: &JoinedText<'_>,
+        send: fn(&EventSink<'_>, &JoinedText<'_>, Range<usize>) -> windows_core::Result<()>,
+    ) -> Vec<CollectedEvent> {
+        let (site, _, events) = memory_site(None, true);
+        let sink = EventSink::new(&site).unwrap();
+        send(&sink, joined, 0..joined.utf16().len()).unwrap();
+        let events = events.lock().unwrap();
+        events.clone()
+    }
+
+    #[test]
+    fn word_boundary_length_matches_segmented_word() {
+        let frags = TestFrags::new(&[("Hello there,", speak_state()), ("world", speak_state())]);
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        let events = estimated_events(&joined, |sink, joined, range| {
+            sink.estimated_word_boundaries(joined, range, 0, 1000, 2)
+        });
+
+        let words = word_ranges(joined.utf16());
+        assert_eq!(events.len(), words.len());
+        for (event, word) in events.iter().zip(words) {
+            assert_eq!(event.event_id, SPEI_WORD_BOUNDARY);
+            assert_eq!(event.lparam as usize, word.start);
+            assert_eq!(event.wparam, word.len());
+            assert_eq!(event.audio_offset % 2, 0, "offsets are block aligned");
+        }
+    }
+
+    #[test]
+    fn sentence_boundary_length_matches_segmented_sentence() {
+        let frags = TestFrags::speak("First one. Second one!");
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        let events = estimated_events(&joined, |sink, joined, range| {
+            sink.estimated_sentence_boundaries(joined, range, 0, 1000, 2)
+        });
+
+        let lengths = events
+            .iter()
+            .map(|event| (event.event_id, event.lparam, event.wparam))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lengths,
+            [
+                (SPEI_SENTENCE_BOUNDARY, 0, "First one.".len()),
+                (SPEI_SENTENCE_BOUNDARY, 11, "Second one!".len()),
+            ]
+        );
+    }
+}
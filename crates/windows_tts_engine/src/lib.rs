@@ -8,21 +8,44 @@
 //! - Installing an engine: [Sample Engines (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720179(v=vs.85))
 //! - [System.Speech.Synthesis.TtsEngine Namespace | Microsoft Learn](https://learn.microsoft.com/en-us/dotnet/api/system.speech.synthesis.ttsengine?view=net-9.0-pp)
 
-use std::{mem::ManuallyDrop, panic::AssertUnwindSafe, sync::Arc};
+use std::{
+    cell::RefCell,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use utils::safe_catch_unwind;
 use windows::Win32::Media::{
-    Audio::WAVEFORMATEX,
-    Speech::{ISpObjectToken, ISpTTSEngineSite, SPVSTATE, SPVTEXTFRAG},
+    Audio::{WAVEFORMATEX, WAVE_FORMAT_PCM},
+    Multimedia::WAVE_FORMAT_IEEE_FLOAT,
+    Speech::{
+        ISpObjectToken, ISpTTSEngineSite, SPVA_Bookmark, SPVA_ParseUnknownTag, SPVA_Pronounce,
+        SPVA_Section, SPVA_Silence, SPVA_Speak, SPVA_SpellOut, SPEAKFLAGS, SPF_IS_NOT_XML,
+        SPF_IS_XML, SPF_NLP_SPEAK_PUNC, SPF_PURGEBEFORESPEAK, SPVACTIONS, SPVSTATE, SPVTEXTFRAG,
+    },
 };
-use windows_core::GUID;
+use windows_core::{GUID, PCWSTR};
 
+pub mod audio;
 pub mod com_server;
 pub mod detect_languages;
+pub mod events;
 pub mod logging;
+pub mod ssml;
+pub mod text;
+pub mod token;
 pub mod utils;
 pub mod voices;
 
+#[cfg(test)]
+mod test_utils;
+
 // Re-export of `windows` crate.
 pub use windows;
 
@@ -53,6 +76,10 @@ impl<'a> TextFrag<'a> {
     }
     /// The text string associated with this fragment.
     pub fn utf16_text(self) -> &'a [u16] {
+        if self.0.ulTextLen == 0 {
+            // Fragments such as silence don't need to have any text:
+            return &[];
+        }
         assert!(
             !self.0.pTextStart.is_null(),
             "Text fragment should not be null"
@@ -70,12 +97,67 @@ impl<'a> TextFrag<'a> {
     pub fn state(self) -> &'a SPVSTATE {
         &self.0.State
     }
+    /// The current XML attribute state with typed getters.
+    pub fn frag_state(self) -> FragState<'a> {
+        FragState(&self.0.State)
+    }
+
+    /// If this fragment represents a pause (its action is `SPVA_Silence`) then
+    /// this returns the duration of the pause in milliseconds.
+    ///
+    /// SAPI uses this for the `<silence>` XML tag and SSML `<break>`.
+    pub fn silence_msecs(self) -> Option<u32> {
+        let state = self.frag_state();
+        (state.action() == Spva::Silence).then_some(state.silence_msecs())
+    }
+
+    /// If this fragment represents a bookmark (its action is `SPVA_Bookmark`)
+    /// then this returns the bookmark's name. The name should not be spoken.
+    pub fn bookmark(self) -> Option<&'a [u16]> {
+        (self.frag_state().action() == Spva::Bookmark).then(|| self.utf16_text())
+    }
 
     /// Iterator over this fragment and all following fragments.
     pub fn iter(self) -> TextFragIter<'a> {
         TextFragIter(Some(self))
     }
 
+    /// Collect the text of this fragment and all following fragments into a
+    /// string with a space after each fragment. Bookmark names are skipped.
+    ///
+    /// The returned entries are sorted and can be used to translate `char`
+    /// indexes in the string back to the original text, see
+    /// [`text::OffsetEntry::find_original_offset`].
+    pub fn to_owned_text(self) -> (String, Vec<text::OffsetEntry<'a>>) {
+        let mut text = String::new();
+        let mut entries = Vec::new();
+        let mut char_count = 0;
+        for frag in self.iter() {
+            let start = char_count;
+            if frag.bookmark().is_none() {
+                for c in char::decode_utf16(frag.utf16_text().iter().copied()) {
+                    text.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                    char_count += 1;
+                }
+            }
+            entries.push(text::OffsetEntry {
+                frag,
+                chars: start..char_count,
+                offset_in_original_text: frag.offset_in_original_text(),
+            });
+            text.push(' ');
+            char_count += 1;
+        }
+        (text, entries)
+    }
+
+    /// Iterator over this fragment and all following fragments where
+    /// consecutive fragments that should be spoken the same way are grouped
+    /// together, see [`FragState::same_run`].
+    pub fn runs(self) -> FragRuns<'a> {
+        FragRuns(self.iter().peekable())
+    }
+
     /// Debug formatting that includes information about all fragments with this
     /// fragment as the first in the list.
     pub fn debug_list(self) -> impl std::fmt::Debug + 'a {
@@ -96,7 +178,155 @@ impl std::fmt::Debug for TextFrag<'_> {
         f.debug_struct("TextFrag")
             .field("text", &String::from_utf16_lossy(self.utf16_text()))
             .field("offset_in_original_text", &self.offset_in_original_text())
-            .field("state", self.state())
+            .field("state", &self.frag_state())
+            .finish()
+    }
+}
+
+/// What an engine should do with a text fragment.
+///
+/// # References
+///
+/// - [SPVACTIONS (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720892(v=vs.85))
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Spva {
+    /// Speak the text normally.
+    Speak,
+    /// Insert silence, see [`FragState::silence_msecs`].
+    Silence,
+    /// Speak the phonemes in `pPhoneIds` instead of the text.
+    Pronounce,
+    /// The text is the name of a bookmark and shouldn't be spoken.
+    Bookmark,
+    /// Spell the text one character at a time.
+    SpellOut,
+    /// The start of a new section, the text is the section's name.
+    Section,
+    /// An unknown XML tag, the text contains the whole tag.
+    ParseUnknownTag,
+    /// An action that didn't exist when this library was written.
+    Other(i32),
+}
+impl From<SPVACTIONS> for Spva {
+    fn from(action: SPVACTIONS) -> Self {
+        match action {
+            SPVA_Speak => Self::Speak,
+            SPVA_Silence => Self::Silence,
+            SPVA_Pronounce => Self::Pronounce,
+            SPVA_Bookmark => Self::Bookmark,
+            SPVA_SpellOut => Self::SpellOut,
+            SPVA_Section => Self::Section,
+            SPVA_ParseUnknownTag => Self::ParseUnknownTag,
+            other => Self::Other(other.0),
+        }
+    }
+}
+
+/// The `<context>` XML state of a text fragment, which can help engines
+/// normalize text like dates and addresses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FragContext<'a> {
+    pub category: Option<&'a [u16]>,
+    pub before: Option<&'a [u16]>,
+    pub after: Option<&'a [u16]>,
+}
+
+/// Typed getters for a text fragment's XML attribute state (`SPVSTATE`).
+///
+/// # References
+///
+/// - [SPVSTATE (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720876(v=vs.85))
+#[derive(Clone, Copy)]
+pub struct FragState<'a>(&'a SPVSTATE);
+impl<'a> FragState<'a> {
+    /// The raw state.
+    pub fn raw(self) -> &'a SPVSTATE {
+        self.0
+    }
+    pub fn action(self) -> Spva {
+        Spva::from(self.0.eAction)
+    }
+    /// Windows language id (LCID) of the text, or `0` if the client didn't
+    /// specify a language. Can be converted using
+    /// [`utils::lcid_to_language_code`].
+    pub fn lang_id(self) -> u16 {
+        self.0.LangID
+    }
+    /// Speaking rate adjustment from `-10` (slowest) to `10` (fastest), which
+    /// should be combined with the rate from `ISpTTSEngineSite::GetRate`.
+    pub fn rate_adj(self) -> i32 {
+        self.0.RateAdj
+    }
+    /// Volume from `0` to `100`, which should be combined with the volume from
+    /// `ISpTTSEngineSite::GetVolume`.
+    pub fn volume(self) -> u16 {
+        self.0.Volume.min(100) as u16
+    }
+    /// Pitch adjustment of the middle pitch from `-10` to `10`.
+    pub fn pitch(self) -> i32 {
+        self.0.PitchAdj.MiddleAdj
+    }
+    /// Adjustment of the pitch range from `-10` to `10`.
+    pub fn pitch_range(self) -> i32 {
+        self.0.PitchAdj.RangeAdj
+    }
+    /// Emphasis adjustment, where a non-zero value means that the text should
+    /// be emphasized.
+    pub fn emph_adj(self) -> i32 {
+        self.0.EmphAdj
+    }
+    /// Duration of silence in milliseconds, only used for the
+    /// [`Spva::Silence`] action.
+    pub fn silence_msecs(self) -> u32 {
+        self.0.SilenceMSecs
+    }
+    pub fn context(self) -> FragContext<'a> {
+        let to_slice = |text: PCWSTR| {
+            // Safety: SAPI keeps the strings alive while the fragment is used.
+            (!text.is_null()).then(|| unsafe { text.as_wide() })
+        };
+        FragContext {
+            category: to_slice(self.0.Context.pCategory),
+            before: to_slice(self.0.Context.pBefore),
+            after: to_slice(self.0.Context.pAfter),
+        }
+    }
+}
+impl FragState<'_> {
+    /// Check if fragments with these states can be synthesized together, which
+    /// is the case if they have the same action, language and prosody.
+    ///
+    /// Silence, bookmarks, sections and unknown tags are never part of the
+    /// same run as another fragment.
+    pub fn same_run(self, other: FragState<'_>) -> bool {
+        matches!(
+            self.action(),
+            Spva::Speak | Spva::Pronounce | Spva::SpellOut
+        ) && self.action() == other.action()
+            && self.lang_id() == other.lang_id()
+            && self.rate_adj() == other.rate_adj()
+            && self.volume() == other.volume()
+            && self.pitch() == other.pitch()
+            && self.pitch_range() == other.pitch_range()
+            && self.emph_adj() == other.emph_adj()
+    }
+}
+impl std::fmt::Debug for FragState<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let context = self.context();
+        let lossy = |text: Option<&[u16]>| text.map(String::from_utf16_lossy);
+        f.debug_struct("FragState")
+            .field("action", &self.action())
+            .field("lang_id", &format_args!("{:#x}", self.lang_id()))
+            .field("rate_adj", &self.rate_adj())
+            .field("volume", &self.volume())
+            .field("pitch", &self.pitch())
+            .field("pitch_range", &self.pitch_range())
+            .field("emph_adj", &self.emph_adj())
+            .field("silence_msecs", &self.silence_msecs())
+            .field("context_category", &lossy(context.category))
+            .field("context_before", &lossy(context.before))
+            .field("context_after", &lossy(context.after))
             .finish()
     }
 }
@@ -109,6 +339,60 @@ impl<'a> IntoIterator for TextFrag<'a> {
     }
 }
 
+/// Consecutive text fragments that should be spoken the same way, returned by
+/// [`TextFrag::runs`].
+#[derive(Clone, Debug)]
+pub struct FragRun<'a> {
+    /// The state of the first fragment, which matches the other fragments
+    /// according to [`FragState::same_run`].
+    pub state: FragState<'a>,
+    /// Never empty.
+    pub fragments: Vec<TextFrag<'a>>,
+}
+impl FragRun<'_> {
+    /// The text of all fragments, joined with spaces.
+    pub fn utf16_text(&self) -> Vec<u16> {
+        let mut text = Vec::new();
+        for (index, frag) in self.fragments.iter().enumerate() {
+            if index > 0 {
+                text.push(u16::from(b' '));
+            }
+            text.extend_from_slice(frag.utf16_text());
+        }
+        text
+    }
+    /// Range of UTF-16 offsets in the text passed to `ISpVoice::Speak` that
+    /// this run covers, from the start of the first fragment to the end of the
+    /// last one.
+    pub fn original_text_range(&self) -> std::ops::Range<u32> {
+        let start = self
+            .fragments
+            .first()
+            .map_or(0, |frag| frag.offset_in_original_text());
+        let end = self.fragments.last().map_or(0, |frag| {
+            frag.offset_in_original_text() + frag.utf16_text().len() as u32
+        });
+        start..end.max(start)
+    }
+}
+
+/// Iterator returned by [`TextFrag::runs`].
+#[derive(Clone, Debug)]
+pub struct FragRuns<'a>(std::iter::Peekable<TextFragIter<'a>>);
+impl<'a> Iterator for FragRuns<'a> {
+    type Item = FragRun<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.0.next()?;
+        let state = first.frag_state();
+        let mut fragments = vec![first];
+        while let Some(frag) = self.0.next_if(|frag| state.same_run(frag.frag_state())) {
+            fragments.push(frag);
+        }
+        Some(FragRun { state, fragments })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct TextFragIter<'a>(Option<TextFrag<'a>>);
 impl<'a> TextFragIter<'a> {
@@ -135,13 +419,238 @@ pub enum SpeechFormat {
     Wave(WAVEFORMATEX),
 }
 
+/// Sample rates of the standard SAPI PCM formats (`SPSF_8kHz8BitMono` to
+/// `SPSF_48kHz16BitStereo`).
+const STANDARD_SAMPLE_RATES: [u32; 9] = [
+    8_000, 11_025, 12_000, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000,
+];
+
+impl SpeechFormat {
+    /// The standard SAPI PCM formats in the same order as the `SPSTREAMFORMAT`
+    /// enum: for each sample rate 8 bit mono, 8 bit stereo, 16 bit mono and 16
+    /// bit stereo.
+    pub const STANDARD_PCM_FORMATS: [SpeechFormat; 36] = {
+        let mut formats = [SpeechFormat::DebugText; 36];
+        let mut i = 0;
+        while i < formats.len() {
+            let bits_per_sample = if i % 4 < 2 { 8 } else { 16 };
+            let channels = if i % 2 == 0 { 1 } else { 2 };
+            formats[i] = SpeechFormat::pcm(STANDARD_SAMPLE_RATES[i / 4], channels, bits_per_sample);
+            i += 1;
+        }
+        formats
+    };
+
+    /// An uncompressed PCM format with 16 bit samples.
+    pub const fn pcm_i16(samples_per_sec: u32, channels: u16) -> Self {
+        Self::pcm(samples_per_sec, channels, 16)
+    }
+
+    /// An uncompressed PCM format. The block alignment and average byte rate
+    /// are computed from the other values.
+    pub const fn pcm(samples_per_sec: u32, channels: u16, bits_per_sample: u16) -> Self {
+        Self::uncompressed(WAVE_FORMAT_PCM, samples_per_sec, channels, bits_per_sample)
+    }
+
+    /// An IEEE floating point format with 32 bit samples between `-1.0` and
+    /// `1.0`.
+    pub const fn pcm_f32(samples_per_sec: u32, channels: u16) -> Self {
+        Self::uncompressed(WAVE_FORMAT_IEEE_FLOAT, samples_per_sec, channels, 32)
+    }
+
+    const fn uncompressed(
+        format_tag: u32,
+        samples_per_sec: u32,
+        channels: u16,
+        bits_per_sample: u16,
+    ) -> Self {
+        let block_align = channels * bits_per_sample.div_ceil(8);
+        Self::Wave(WAVEFORMATEX {
+            wFormatTag: format_tag as _,
+            nChannels: channels,
+            nSamplesPerSec: samples_per_sec,
+            nAvgBytesPerSec: samples_per_sec * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: bits_per_sample,
+            cbSize: 0,
+        })
+    }
+
+    /// `true` if this is a wave format with IEEE floating point samples.
+    pub fn is_float(&self) -> bool {
+        match self {
+            Self::DebugText => false,
+            Self::Wave(info) => {
+                let format_tag = info.wFormatTag;
+                u32::from(format_tag) == WAVE_FORMAT_IEEE_FLOAT
+            }
+        }
+    }
+
+    /// How different this format is from `target`, where `0` means that they
+    /// are equal. Can be used to find the closest supported format in
+    /// [`SafeTtsEngine::get_output_format`].
+    ///
+    /// A different encoding is worse than a different channel count, which is
+    /// worse than a different bit depth, which is worse than a different
+    /// sample rate. Returns [`u64::MAX`] if only one of the formats is
+    /// [`SpeechFormat::DebugText`].
+    pub fn distance(&self, target: &SpeechFormat) -> u64 {
+        match (self, target) {
+            (Self::DebugText, Self::DebugText) => 0,
+            (Self::Wave(info), Self::Wave(target)) => {
+                let (tag, channels, rate, bits) = (
+                    info.wFormatTag,
+                    info.nChannels,
+                    info.nSamplesPerSec,
+                    info.wBitsPerSample,
+                );
+                let (target_tag, target_channels, target_rate, target_bits) = (
+                    target.wFormatTag,
+                    target.nChannels,
+                    target.nSamplesPerSec,
+                    target.wBitsPerSample,
+                );
+                let mut distance = u64::from(rate.abs_diff(target_rate));
+                distance += 100_000 * u64::from(bits.abs_diff(target_bits));
+                distance += 10_000_000 * u64::from(channels.abs_diff(target_channels));
+                if tag != target_tag {
+                    distance += 1_000_000_000_000;
+                }
+                distance
+            }
+            _ => u64::MAX,
+        }
+    }
+
+    /// The format in `supported` that is closest to `target` according to
+    /// [`Self::distance`]. If `target` is `None` then the first supported
+    /// format is returned. Returns `None` if `supported` is empty.
+    pub fn closest(
+        supported: impl IntoIterator<Item = SpeechFormat>,
+        target: Option<&SpeechFormat>,
+    ) -> Option<SpeechFormat> {
+        let mut supported = supported.into_iter();
+        match target {
+            Some(target) => supported.min_by_key(|format| format.distance(target)),
+            None => supported.next(),
+        }
+    }
+
+    /// Number of interleaved audio channels. Returns `1` for
+    /// [`SpeechFormat::DebugText`].
+    pub fn channels(&self) -> u16 {
+        match self {
+            Self::DebugText => 1,
+            Self::Wave(info) => info.nChannels.max(1),
+        }
+    }
+
+    /// Number of audio frames per second. Returns `0` for
+    /// [`SpeechFormat::DebugText`].
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Self::DebugText => 0,
+            Self::Wave(info) => info.nSamplesPerSec,
+        }
+    }
+
+    /// Size in bytes of one audio frame (one sample for every channel).
+    /// Returns `1` for [`SpeechFormat::DebugText`].
+    pub fn block_align(&self) -> u16 {
+        match self {
+            Self::DebugText => 1,
+            Self::Wave(info) => info.nBlockAlign,
+        }
+    }
+
+    /// Number of zeroed bytes needed to represent `ms` milliseconds of silence
+    /// in this format. The result is a multiple of [`Self::block_align`].
+    /// Returns `0` for [`SpeechFormat::DebugText`].
+    pub fn silence_bytes(&self, ms: u32) -> usize {
+        self.bytes_for_duration(Duration::from_millis(u64::from(ms))) as usize
+    }
+
+    /// Number of bytes of audio that are played during `duration`, rounded
+    /// down to a multiple of [`Self::block_align`]. Returns `0` for
+    /// [`SpeechFormat::DebugText`].
+    pub fn bytes_for_duration(&self, duration: Duration) -> u64 {
+        match self {
+            Self::DebugText => 0,
+            Self::Wave(info) => {
+                let block_align = u128::from(info.nBlockAlign.max(1));
+                let bytes = u128::from(info.nAvgBytesPerSec) * duration.as_nanos() / 1_000_000_000;
+                (bytes / block_align * block_align) as u64
+            }
+        }
+    }
+}
+
+/// Compares the encoding, channel count, sample rate, bit depth and block
+/// alignment of wave formats.
+impl PartialEq for SpeechFormat {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::DebugText, Self::DebugText) => true,
+            (Self::Wave(a), Self::Wave(b)) => {
+                let fields = |info: &WAVEFORMATEX| {
+                    (
+                        info.wFormatTag,
+                        info.nChannels,
+                        info.nSamplesPerSec,
+                        info.wBitsPerSample,
+                        info.nBlockAlign,
+                    )
+                };
+                fields(a) == fields(b)
+            }
+            _ => false,
+        }
+    }
+}
+impl Eq for SpeechFormat {}
+
+/// `WAVE_FORMAT_EXTENSIBLE` from `Win32_Media_KernelStreaming`, which we don't
+/// otherwise need.
+const WAVE_FORMAT_EXTENSIBLE: u32 = 0xFFFE;
+
+/// Prints a `wFormatTag` value with its name, for example `PCM (1)`.
+struct FormatTagDebug(u16);
+impl std::fmt::Debug for FormatTagDebug {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match u32::from(self.0) {
+            WAVE_FORMAT_PCM => "PCM",
+            WAVE_FORMAT_IEEE_FLOAT => "IEEE_FLOAT",
+            WAVE_FORMAT_EXTENSIBLE => "EXTENSIBLE",
+            _ => "unknown",
+        };
+        write!(f, "{name} ({})", self.0)
+    }
+}
+
+/// The type of the samples in a wave format, for example `i16`. The actual
+/// type of `WAVE_FORMAT_EXTENSIBLE` formats is stored after the
+/// `WAVEFORMATEX` so it is reported as unknown.
+fn sample_type_name(info: &WAVEFORMATEX) -> &'static str {
+    match (u32::from({ info.wFormatTag }), { info.wBitsPerSample }) {
+        (WAVE_FORMAT_PCM, 8) => "u8",
+        (WAVE_FORMAT_PCM, 16) => "i16",
+        (WAVE_FORMAT_PCM, 24) => "i24",
+        (WAVE_FORMAT_PCM, 32) => "i32",
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => "f32",
+        (WAVE_FORMAT_IEEE_FLOAT, 64) => "f64",
+        _ => "unknown",
+    }
+}
+
 impl std::fmt::Debug for SpeechFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::DebugText => write!(f, "DebugText"),
             Self::Wave(info) => f
                 .debug_struct("Wave")
-                .field("wFormatTag", &{ info.wFormatTag })
+                .field("wFormatTag", &FormatTagDebug(info.wFormatTag))
+                .field("sampleType", &format_args!("{}", sample_type_name(info)))
                 .field("nChannels", &{ info.nChannels })
                 .field("nSamplesPerSec", &{ info.nSamplesPerSec })
                 .field("nAvgBytesPerSec", &{ info.nAvgBytesPerSec })
@@ -153,6 +662,182 @@ impl std::fmt::Debug for SpeechFormat {
     }
 }
 
+/// Lets another thread stop an ongoing [`SafeTtsEngine::speak`] call, see
+/// [`SafeTtsEngine::cancellation_token`]. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Ask the engine to stop speaking as soon as possible.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    /// Clear the flag so that the next speak call isn't cancelled.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Time spent in each phase of a [`SafeTtsEngine::speak`] call, reported
+/// through [`SafeTtsEngine::on_metrics`]. Phases that an engine doesn't have
+/// are zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeakMetrics {
+    /// Detecting the languages of the text, including creating the detector.
+    pub detect: Duration,
+    /// Loading models or creating synthesizers and selecting their voices.
+    pub model_load: Duration,
+    /// Generating audio.
+    pub synthesis: Duration,
+    /// Converting the generated audio to the output format, for example
+    /// resampling it.
+    pub resample: Duration,
+    /// Writing audio to the output site, which includes waiting for the
+    /// client to consume it.
+    pub write: Duration,
+    /// The whole `speak` call.
+    pub total: Duration,
+}
+
+/// A callback that receives [`SpeakMetrics`], see
+/// [`TtsVoice::set_metrics_callback`].
+type MetricsCallback = Arc<dyn Fn(SpeakMetrics) + Send + Sync>;
+
+thread_local! {
+    /// Callback of the [`TtsVoice`] that is speaking on this thread.
+    static METRICS_CALLBACK: RefCell<Option<MetricsCallback>> = const { RefCell::new(None) };
+}
+
+/// Sets [`METRICS_CALLBACK`] until dropped, then restores the previous
+/// callback.
+struct MetricsCallbackScope(Option<MetricsCallback>);
+impl MetricsCallbackScope {
+    fn enter(callback: Option<MetricsCallback>) -> Self {
+        Self(METRICS_CALLBACK.replace(callback))
+    }
+}
+impl Drop for MetricsCallbackScope {
+    fn drop(&mut self) {
+        METRICS_CALLBACK.set(self.0.take());
+    }
+}
+
+/// Collects [`SpeakMetrics`] during a [`SafeTtsEngine::speak`] call and
+/// reports them to [`SafeTtsEngine::on_metrics`] and to the
+/// [`TtsVoice::set_metrics_callback`] callback when dropped, so that metrics
+/// are reported even if `speak` returns early. Nothing is reported if
+/// `speak` panicked.
+pub struct MetricsRecorder<'a, E: SafeTtsEngine + ?Sized> {
+    engine: &'a E,
+    started: Instant,
+    metrics: SpeakMetrics,
+}
+impl<'a, E: SafeTtsEngine + ?Sized> MetricsRecorder<'a, E> {
+    pub fn new(engine: &'a E) -> Self {
+        Self {
+            engine,
+            started: Instant::now(),
+            metrics: SpeakMetrics::default(),
+        }
+    }
+}
+impl<E: SafeTtsEngine + ?Sized> Deref for MetricsRecorder<'_, E> {
+    type Target = SpeakMetrics;
+
+    fn deref(&self) -> &Self::Target {
+        &self.metrics
+    }
+}
+impl<E: SafeTtsEngine + ?Sized> DerefMut for MetricsRecorder<'_, E> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.metrics
+    }
+}
+impl<E: SafeTtsEngine + ?Sized> Drop for MetricsRecorder<'_, E> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        self.metrics.total = self.started.elapsed();
+        self.engine.on_metrics(self.metrics);
+        // Cloned so that the callback can speak again:
+        let callback = METRICS_CALLBACK.with_borrow(Clone::clone);
+        if let Some(callback) = callback {
+            callback(self.metrics);
+        }
+    }
+}
+
+/// Flags that were passed to `ISpTTSEngine::Speak`.
+///
+/// # References
+///
+/// - [SPEAKFLAGS (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms717252(v=vs.85))
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpeakFlags(u32);
+impl SpeakFlags {
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+    const fn contains(self, flag: SPEAKFLAGS) -> bool {
+        self.0 & flag.0 as u32 != 0
+    }
+
+    /// The engine should speak all punctuation (e.g., "This is a sentence."
+    /// should be expanded to "This is a sentence period").
+    pub const fn speak_punctuation(self) -> bool {
+        self.contains(SPF_NLP_SPEAK_PUNC)
+    }
+    /// The client said that the text is XML (SAPI XML or SSML).
+    pub const fn is_xml(self) -> bool {
+        self.contains(SPF_IS_XML)
+    }
+    /// The client said that the text isn't XML and should be spoken as is.
+    pub const fn is_not_xml(self) -> bool {
+        self.contains(SPF_IS_NOT_XML)
+    }
+    /// Queued audio from earlier calls should be discarded before speaking.
+    pub const fn purge_before_speak(self) -> bool {
+        self.contains(SPF_PURGEBEFORESPEAK)
+    }
+
+    /// Check if some text should be parsed as XML. If the client didn't
+    /// specify [`is_xml`](Self::is_xml) or [`is_not_xml`](Self::is_not_xml)
+    /// then the text is treated as XML if its first non-whitespace character
+    /// is `<`, which is how SAPI auto detects XML.
+    pub fn detect_xml(self, text_utf16: &[u16]) -> bool {
+        if self.is_xml() {
+            true
+        } else if self.is_not_xml() {
+            false
+        } else {
+            char::decode_utf16(text_utf16.iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .find(|c| !c.is_whitespace())
+                == Some('<')
+        }
+    }
+}
+impl std::fmt::Debug for SpeakFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpeakFlags")
+            .field("bits", &format_args!("{:#x}", self.0))
+            .field("speak_punctuation", &self.speak_punctuation())
+            .field("is_xml", &self.is_xml())
+            .field("is_not_xml", &self.is_not_xml())
+            .field("purge_before_speak", &self.purge_before_speak())
+            .finish()
+    }
+}
+
 /// Used by [`WindowsTtsEngine`] to implement COM interfaces such as
 /// [`ISpTTSEngine`](windows::Win32::Media::Speech::ISpTTSEngine).
 ///
@@ -166,24 +851,30 @@ pub trait SafeTtsEngine: Send + 'static {
     ///
     /// For more info, see:
     /// [ISpObjectWithToken (SAPI 5.4) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ee450882(v=vs.85))
+    ///
+    /// This is only called when SAPI creates the engine. Engines that are used
+    /// as a library, for example through [`speak_text`], never get a token and
+    /// should fall back to default settings.
     fn set_object_token(&self, _token: &ISpObjectToken) -> windows_core::Result<()> {
         Ok(())
     }
 
     /// Renders the specified text fragment list in the specified output format.
     ///
-    /// If `speak_punctuation` is `true` then the engine should speak all
-    /// punctuation (e.g., "This is a sentence." should be expanded to "This is
-    /// a sentence period").
+    /// `speak_flags` specifies how the text should be handled, for example
+    /// [`SpeakFlags::speak_punctuation`].
     ///
     /// `wave_format` is guaranteed to be one that the engine specified as
     /// supported in a previous [`SafeTtsEngine::get_output_format`] call.
     ///
     /// Audio data and events should be written to `output_site`.
+    ///
+    /// `token` is the voice's object token, or `None` if the engine is used
+    /// without SAPI, for example through [`speak_text`].
     fn speak(
         &self,
-        _token: &ISpObjectToken,
-        speak_punctuation: bool,
+        token: Option<&ISpObjectToken>,
+        speak_flags: SpeakFlags,
         wave_format: SpeechFormat,
         text_fragments: Option<TextFrag<'_>>,
         output_site: &ISpTTSEngineSite,
@@ -194,11 +885,222 @@ pub trait SafeTtsEngine: Send + 'static {
     ///
     /// If `target_format` is `None` then the caller does not care about the
     /// target format and the engine can return any format that it supports.
+    ///
+    /// `token` is `None` if the engine is used without SAPI.
+    ///
+    /// The default implementation picks the closest of the
+    /// [`SafeTtsEngine::supported_formats`] using [`SpeechFormat::closest`].
     fn get_output_format(
         &self,
-        _token: &ISpObjectToken,
+        token: Option<&ISpObjectToken>,
         target_format: Option<SpeechFormat>,
-    ) -> windows_core::Result<SpeechFormat>;
+    ) -> windows_core::Result<SpeechFormat> {
+        SpeechFormat::closest(self.supported_formats(token), target_format.as_ref())
+            .ok_or_else(|| windows::Win32::Foundation::E_FAIL.into())
+    }
+
+    /// All formats that the engine can write audio in, with the engine's
+    /// preferred (native) format first.
+    ///
+    /// Include [`SpeechFormat::DebugText`] if the engine supports it.
+    ///
+    /// The default implementation only supports 16 kHz mono PCM.
+    fn supported_formats(&self, token: Option<&ISpObjectToken>) -> Vec<SpeechFormat> {
+        let _ = token;
+        vec![SpeechFormat::pcm_i16(16_000, 1)]
+    }
+
+    /// Called before [`SafeTtsEngine::speak`] when the client set
+    /// [`SpeakFlags::purge_before_speak`], for example after it stopped
+    /// speaking. Engines that cache per-utterance state or queue audio should
+    /// reset that state and drop the queued audio here.
+    fn purge(&self) {}
+
+    /// A token that can be used to stop the engine while it is speaking, for
+    /// example from another thread when the engine is used through
+    /// [`speak_text`]. Engines that return a token should check it in their
+    /// inner loops, such as before detecting the language of or synthesizing
+    /// each part of the text, and return early when it is cancelled.
+    ///
+    /// The token is reset before every `ISpTTSEngine::Speak` call and is
+    /// cancelled when SAPI asks the engine to abort. The output site also
+    /// reports `SPVES_ABORT` from `GetActions` while the token is cancelled,
+    /// so engines that only poll the output site stop as well.
+    fn cancellation_token(&self) -> Option<&CancellationToken> {
+        None
+    }
+
+    /// Called with the time spent in each phase after every
+    /// [`speak`](Self::speak) call, for example to benchmark engines.
+    /// Engines record the metrics using a [`MetricsRecorder`].
+    ///
+    /// Does nothing by default.
+    fn on_metrics(&self, _metrics: SpeakMetrics) {}
+
+    /// Return `true` if the engine sends `SPEI_START_INPUT_STREAM` and
+    /// `SPEI_END_INPUT_STREAM` events itself.
+    ///
+    /// Otherwise these events are sent automatically before and after
+    /// [`SafeTtsEngine::speak`] is called, with the end event placed after
+    /// all audio that the engine wrote to the output site.
+    fn manages_stream_events(&self) -> bool {
+        false
+    }
+
+    /// Synthesize `text` without SAPI and return the bytes that
+    /// [`SafeTtsEngine::speak`] wrote to its output site.
+    ///
+    /// The text is passed as a single fragment with default XML state. The
+    /// output site only asks the engine to abort if the
+    /// [`SafeTtsEngine::cancellation_token`] is cancelled, never asks it to
+    /// skip, uses the default rate and volume and doesn't want any events.
+    /// The cancellation token is reset after speaking, so a cancel that
+    /// happens right before this call stops it instead of being lost.
+    ///
+    /// `wave_format` should be a format returned by
+    /// [`SafeTtsEngine::get_output_format`].
+    fn synthesize_to_vec(
+        &self,
+        token: Option<&ISpObjectToken>,
+        speak_flags: SpeakFlags,
+        wave_format: SpeechFormat,
+        text: &str,
+    ) -> windows_core::Result<Vec<u8>> {
+        synthesize_with_memory_site(self, token, speak_flags, wave_format, text, false)
+            .map(|(audio, _)| audio)
+    }
+}
+
+/// Implementation of [`SafeTtsEngine::synthesize_to_vec`] that can also
+/// collect the events that the engine sends.
+fn synthesize_with_memory_site<E: SafeTtsEngine + ?Sized>(
+    engine: &E,
+    token: Option<&ISpObjectToken>,
+    speak_flags: SpeakFlags,
+    wave_format: SpeechFormat,
+    text: &str,
+    collect_events: bool,
+) -> windows_core::Result<(Vec<u8>, Vec<events::CollectedEvent>)> {
+    let text = text.encode_utf16().collect::<Vec<u16>>();
+    let frag = SPVTEXTFRAG {
+        pNext: std::ptr::null_mut(),
+        State: SPVSTATE {
+            eAction: SPVA_Speak,
+            Volume: 100,
+            ..Default::default()
+        },
+        pTextStart: PCWSTR::from_raw(text.as_ptr()),
+        ulTextLen: text.len() as u32,
+        ulTextSrcOffset: 0,
+    };
+    // Safety: the fragment and its text outlive the returned value.
+    let text_fragments = unsafe { TextFrag::new(&frag) };
+
+    let cancel = engine.cancellation_token();
+    let (site, output, events) = private_impls::memory_site(cancel.cloned(), collect_events);
+    let result = engine.speak(token, speak_flags, wave_format, text_fragments, &site);
+    if let Some(cancel) = cancel {
+        cancel.reset();
+    }
+    result?;
+    let output = std::mem::take(&mut *output.lock().unwrap_or_else(|e| e.into_inner()));
+    let events = std::mem::take(&mut *events.lock().unwrap_or_else(|e| e.into_inner()));
+    Ok((output, events))
+}
+
+/// An engine used without COM or SAPI together with the audio format it
+/// writes, for example in command line tools.
+///
+/// The engine works as if no voice token was selected, so language detection
+/// and voice selection use the engine's defaults.
+pub struct TtsVoice<E> {
+    engine: E,
+    format: SpeechFormat,
+    metrics_callback: Option<MetricsCallback>,
+}
+impl<E: SafeTtsEngine> TtsVoice<E> {
+    /// Negotiate the audio format with the engine, see
+    /// [`SafeTtsEngine::get_output_format`]. If `format` is `None` then the
+    /// engine's preferred format is used.
+    pub fn new(engine: E, format: Option<SpeechFormat>) -> windows_core::Result<Self> {
+        let _com = utils::HasCoInitialized::new()?;
+        let format = engine.get_output_format(None, format)?;
+        Ok(Self {
+            engine,
+            format,
+            metrics_callback: None,
+        })
+    }
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+    /// The format of the audio returned by [`Self::speak`].
+    pub fn format(&self) -> SpeechFormat {
+        self.format
+    }
+    /// Synthesize `text` and return the audio in [`Self::format`].
+    ///
+    /// COM is initialized for the current thread if it wasn't already, since
+    /// engines might use COM or WinRT APIs.
+    pub fn speak(&self, text: &str) -> windows_core::Result<Vec<u8>> {
+        let _com = utils::HasCoInitialized::new()?;
+        let _metrics = MetricsCallbackScope::enter(self.metrics_callback.clone());
+        self.engine
+            .synthesize_to_vec(None, SpeakFlags::default(), self.format, text)
+    }
+    /// Like [`Self::speak`] but also returns the events that the engine sent,
+    /// such as word boundaries and bookmarks.
+    pub fn speak_with_events(
+        &self,
+        text: &str,
+    ) -> windows_core::Result<(Vec<u8>, Vec<events::CollectedEvent>)> {
+        let _com = utils::HasCoInitialized::new()?;
+        let _metrics = MetricsCallbackScope::enter(self.metrics_callback.clone());
+        synthesize_with_memory_site(
+            &self.engine,
+            None,
+            SpeakFlags::default(),
+            self.format,
+            text,
+            true,
+        )
+    }
+    /// Call `callback` with the [`SpeakMetrics`] of every [`Self::speak`]
+    /// call, in addition to [`SafeTtsEngine::on_metrics`]. Only engines that
+    /// record metrics with a [`MetricsRecorder`] report them.
+    pub fn set_metrics_callback(
+        &mut self,
+        callback: impl Fn(SpeakMetrics) + Send + Sync + 'static,
+    ) {
+        self.metrics_callback = Some(Arc::new(callback));
+    }
+    /// A handle that can be moved to another thread to stop an ongoing
+    /// [`Self::speak`] call. If it is cancelled while no call is ongoing then
+    /// the next call stops immediately. Returns `None` if the engine doesn't
+    /// support cancellation, see [`SafeTtsEngine::cancellation_token`].
+    pub fn cancellation_token(&self) -> Option<CancellationToken> {
+        self.engine.cancellation_token().cloned()
+    }
+}
+
+/// Synthesize text in-process without COM or SAPI and return the audio in the
+/// format that the engine picked for `format`, see
+/// [`SafeTtsEngine::get_output_format`].
+///
+/// The engine works as if no voice token was selected, so language detection
+/// and voice selection use the engine's defaults.
+///
+/// COM is initialized for the current thread if it wasn't already, since
+/// engines might use COM or WinRT APIs.
+pub fn speak_text(
+    engine: &impl SafeTtsEngine,
+    text: &str,
+    format: SpeechFormat,
+) -> windows_core::Result<(SpeechFormat, Vec<u8>)> {
+    let _com = utils::HasCoInitialized::new()?;
+    let format = engine.get_output_format(None, Some(format))?;
+    let audio = engine.synthesize_to_vec(None, SpeakFlags::default(), format, text)?;
+    Ok((format, audio))
 }
 
 mod private_impls {
@@ -207,26 +1109,31 @@ mod private_impls {
     //! to call.
 
     use crate::{
+        events::{CollectedEvent, EventSink},
         utils::{catch_unwind_and_fail, safe_catch_unwind},
-        SafeTtsEngine, SpeechFormat, TextFrag,
+        CancellationToken, SafeTtsEngine, SpeakFlags, SpeechFormat, TextFrag,
     };
     use core::ffi::c_void;
     use std::{
         mem::ManuallyDrop,
         ptr::{self, null_mut},
-        sync::{Arc, OnceLock},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex, OnceLock,
+        },
     };
 
     use windows::Win32::{
         Foundation::{
-            BOOL, CLASS_E_NOAGGREGATION, E_FAIL, E_INVALIDARG, E_NOINTERFACE, E_NOTIMPL,
-            E_OUTOFMEMORY, E_POINTER,
+            BOOL, CLASS_E_NOAGGREGATION, E_FAIL, E_INVALIDARG, E_NOINTERFACE, E_OUTOFMEMORY,
+            E_POINTER,
         },
         Media::{
             Audio::WAVEFORMATEX,
             Speech::{
-                ISpObjectToken, ISpObjectWithToken, ISpObjectWithToken_Impl, ISpTTSEngine,
-                ISpTTSEngineSite, ISpTTSEngine_Impl, SPF_NLP_SPEAK_PUNC, SPVTEXTFRAG,
+                ISpEventSink_Impl, ISpObjectToken, ISpObjectWithToken, ISpObjectWithToken_Impl,
+                ISpTTSEngine, ISpTTSEngineSite, ISpTTSEngineSite_Impl, ISpTTSEngine_Impl, SPEVENT,
+                SPVES_ABORT, SPVES_CONTINUE, SPVSKIPTYPE, SPVST_SENTENCE, SPVTEXTFRAG,
             },
         },
         System::Com::{CoTaskMemAlloc, IClassFactory, IClassFactory_Impl},
@@ -241,6 +1148,11 @@ mod private_impls {
         pub safe static SPDFID_WaveFormatEx: GUID;
     }
 
+    /// Module references held because of [`IClassFactory::LockServer`] calls.
+    /// These are shared between all factories since a client might release
+    /// the factory before it unlocks the server.
+    static SERVER_LOCKS: Mutex<Vec<Arc<()>>> = Mutex::new(Vec::new());
+
     #[implement(IClassFactory)]
     pub struct WindowsTtsEngineFactory {
         pub(super) tts_engine_class_id: GUID,
@@ -310,10 +1222,215 @@ mod private_impls {
             Ok(())
         }
 
-        fn LockServer(&self, _flock: BOOL) -> windows_core::Result<()> {
-            Err(E_NOTIMPL.into())
+        /// Keep the COM server loaded even if no objects are alive, this lets
+        /// clients avoid the cost of loading the DLL again for every
+        /// utterance. Each lock must be matched by an unlock before the DLL
+        /// can be unloaded.
+        ///
+        /// - [IClassFactory::LockServer - Win32 apps | Microsoft Learn](https://learn.microsoft.com/en-us/windows/win32/api/unknwn/nf-unknwn-iclassfactory-lockserver)
+        fn LockServer(&self, flock: BOOL) -> windows_core::Result<()> {
+            catch_unwind_and_fail(|| {
+                let Some(module_ref) = &self.module_ref else {
+                    // Not tracking module references so we can't be unloaded anyway.
+                    return Ok(());
+                };
+                let mut locks = SERVER_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+                if flock.as_bool() {
+                    locks.push(module_ref.clone());
+                } else if let Some(index) =
+                    locks.iter().position(|lock| Arc::ptr_eq(lock, module_ref))
+                {
+                    locks.swap_remove(index);
+                } else {
+                    log::warn!("IClassFactory::LockServer unlocked more times than it was locked");
+                }
+                log::debug!("LockServer({}), locks: {}", flock.as_bool(), locks.len());
+                Ok(())
+            })
+        }
+    }
+
+    /// Forwards everything to SAPI's output site while counting the bytes of
+    /// audio that are written, so that the `SPEI_END_INPUT_STREAM` event can
+    /// be placed after all audio.
+    ///
+    /// Also keeps the engine's [`CancellationToken`] in sync with SAPI's abort
+    /// action.
+    #[implement(ISpTTSEngineSite)]
+    struct CountingSite {
+        inner: ISpTTSEngineSite,
+        written_bytes: Arc<AtomicU64>,
+        cancel: Option<CancellationToken>,
+    }
+    impl ISpEventSink_Impl for CountingSite_Impl {
+        fn AddEvents(&self, peventarray: *const SPEVENT, ulcount: u32) -> windows_core::Result<()> {
+            unsafe { self.inner.AddEvents(peventarray, ulcount) }
+        }
+
+        fn GetEventInterest(&self, pulleventinterest: *mut u64) -> windows_core::Result<()> {
+            unsafe { self.inner.GetEventInterest(pulleventinterest) }
+        }
+    }
+    impl ISpTTSEngineSite_Impl for CountingSite_Impl {
+        fn GetActions(&self) -> u32 {
+            let actions = unsafe { self.inner.GetActions() };
+            let Some(cancel) = &self.cancel else {
+                return actions;
+            };
+            if actions & SPVES_ABORT.0 as u32 != 0 {
+                cancel.cancel();
+            }
+            if cancel.is_cancelled() {
+                actions | SPVES_ABORT.0 as u32
+            } else {
+                actions
+            }
+        }
+
+        fn Write(&self, pbuff: *const c_void, cb: u32) -> windows_core::Result<u32> {
+            let written = unsafe { self.inner.Write(pbuff, cb) }?;
+            self.written_bytes
+                .fetch_add(u64::from(written), Ordering::Relaxed);
+            Ok(written)
+        }
+
+        fn GetRate(&self) -> windows_core::Result<i32> {
+            unsafe { self.inner.GetRate() }
+        }
+
+        fn GetVolume(&self) -> windows_core::Result<u16> {
+            unsafe { self.inner.GetVolume() }
+        }
+
+        fn GetSkipInfo(
+            &self,
+            petype: *mut SPVSKIPTYPE,
+            plnumitems: *mut i32,
+        ) -> windows_core::Result<()> {
+            unsafe { self.inner.GetSkipInfo(petype, plnumitems) }
+        }
+
+        fn CompleteSkip(&self, ulnumskipped: i32) -> windows_core::Result<()> {
+            unsafe { self.inner.CompleteSkip(ulnumskipped) }
+        }
+    }
+
+    /// An output site that collects all written audio in memory, used by
+    /// [`SafeTtsEngine::synthesize_to_vec`].
+    #[implement(ISpTTSEngineSite)]
+    struct MemorySite {
+        output: Arc<Mutex<Vec<u8>>>,
+        cancel: Option<CancellationToken>,
+        /// Only wants events if this is `true`.
+        collect_events: bool,
+        events: Arc<Mutex<Vec<CollectedEvent>>>,
+    }
+    impl ISpEventSink_Impl for MemorySite_Impl {
+        fn AddEvents(&self, peventarray: *const SPEVENT, ulcount: u32) -> windows_core::Result<()> {
+            if !self.collect_events || ulcount == 0 {
+                return Ok(());
+            }
+            if peventarray.is_null() {
+                return Err(E_POINTER.into());
+            }
+            let events = unsafe { std::slice::from_raw_parts(peventarray, ulcount as usize) };
+            self.events
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend(
+                    events
+                        .iter()
+                        .map(|event| unsafe { CollectedEvent::from_event(event) }),
+                );
+            Ok(())
+        }
+
+        fn GetEventInterest(&self, pulleventinterest: *mut u64) -> windows_core::Result<()> {
+            if pulleventinterest.is_null() {
+                return Err(E_POINTER.into());
+            }
+            unsafe { *pulleventinterest = if self.collect_events { u64::MAX } else { 0 } };
+            Ok(())
         }
     }
+    impl ISpTTSEngineSite_Impl for MemorySite_Impl {
+        fn GetActions(&self) -> u32 {
+            if self
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled)
+            {
+                SPVES_ABORT.0 as u32
+            } else {
+                SPVES_CONTINUE.0 as u32
+            }
+        }
+
+        fn Write(&self, pbuff: *const c_void, cb: u32) -> windows_core::Result<u32> {
+            if cb == 0 {
+                return Ok(0);
+            }
+            if pbuff.is_null() {
+                return Err(E_POINTER.into());
+            }
+            let data = unsafe { std::slice::from_raw_parts(pbuff.cast::<u8>(), cb as usize) };
+            self.output
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend_from_slice(data);
+            Ok(cb)
+        }
+
+        fn GetRate(&self) -> windows_core::Result<i32> {
+            Ok(0)
+        }
+
+        fn GetVolume(&self) -> windows_core::Result<u16> {
+            Ok(100)
+        }
+
+        fn GetSkipInfo(
+            &self,
+            petype: *mut SPVSKIPTYPE,
+            plnumitems: *mut i32,
+        ) -> windows_core::Result<()> {
+            if petype.is_null() || plnumitems.is_null() {
+                return Err(E_POINTER.into());
+            }
+            unsafe {
+                *petype = SPVST_SENTENCE;
+                *plnumitems = 0;
+            }
+            Ok(())
+        }
+
+        fn CompleteSkip(&self, _ulnumskipped: i32) -> windows_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Create an output site that collects everything written to it in the
+    /// returned buffers. Events are only collected if `collect_events` is
+    /// `true`. The site asks the engine to abort when `cancel` is cancelled.
+    pub(super) fn memory_site(
+        cancel: Option<CancellationToken>,
+        collect_events: bool,
+    ) -> (
+        ISpTTSEngineSite,
+        Arc<Mutex<Vec<u8>>>,
+        Arc<Mutex<Vec<CollectedEvent>>>,
+    ) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let site = MemorySite {
+            output: output.clone(),
+            cancel,
+            collect_events,
+            events: events.clone(),
+        }
+        .into();
+        (site, output, events)
+    }
 
     #[implement(ISpTTSEngine, ISpObjectWithToken)]
     pub struct WindowsTtsEngine {
@@ -356,8 +1473,7 @@ mod private_impls {
             poutputsite: Ref<'_, ISpTTSEngineSite>,
         ) -> windows_core::Result<()> {
             catch_unwind_and_fail(move || {
-                // Replace "." with " period "
-                let speak_punctuation = (dwspeakflags as i32) & SPF_NLP_SPEAK_PUNC.0 != 0;
+                let speak_flags = SpeakFlags::from_bits(dwspeakflags);
 
                 let format_id = unsafe { *rguidformatid };
 
@@ -380,20 +1496,48 @@ mod private_impls {
                     SpeechFormat::DebugText
                 };
 
-                self.engine.speak(
-                    self.token.get().ok_or_else(|| {
-                        log::error!(
-                            "ISpTTSEngine::Speak called before ISpObjectWithToken::SetObjectToken"
-                        );
-                        E_FAIL
-                    })?,
-                    speak_punctuation,
-                    wave_format,
-                    frag_list,
-                    poutputsite.unwrap(),
-                )?;
+                let token = Some(self.token.get().ok_or_else(|| {
+                    log::error!(
+                        "ISpTTSEngine::Speak called before ISpObjectWithToken::SetObjectToken"
+                    );
+                    E_FAIL
+                })?);
+                let output_site = poutputsite.unwrap();
 
-                Ok(())
+                if speak_flags.purge_before_speak() {
+                    log::debug!("Speak - Purging state before speaking");
+                    self.engine.purge();
+                }
+
+                let cancel = self.engine.cancellation_token();
+                if let Some(cancel) = cancel {
+                    cancel.reset();
+                }
+                let written_bytes = Arc::new(AtomicU64::new(0));
+                let counting_site: ISpTTSEngineSite = CountingSite {
+                    inner: output_site.clone(),
+                    written_bytes: written_bytes.clone(),
+                    cancel: cancel.cloned(),
+                }
+                .into();
+
+                if self.engine.manages_stream_events() {
+                    return self.engine.speak(
+                        token,
+                        speak_flags,
+                        wave_format,
+                        frag_list,
+                        &counting_site,
+                    );
+                }
+
+                let event_sink = EventSink::new(output_site)?;
+                event_sink.start_input_stream()?;
+
+                self.engine
+                    .speak(token, speak_flags, wave_format, frag_list, &counting_site)?;
+
+                event_sink.end_input_stream(written_bytes.load(Ordering::Relaxed))
             })
         }
 
@@ -438,14 +1582,15 @@ mod private_impls {
                     None => None,
                 };
 
+                log::debug!("GetOutputFormat: {target_format:?}");
                 match self.engine.get_output_format(
-                    self.token.get().ok_or_else(|| {
+                    Some(self.token.get().ok_or_else(|| {
                         log::error!(
                             "ISpTTSEngine::GetOutputFormat called before \
                             ISpObjectWithToken::SetObjectToken"
                         );
                         E_FAIL
-                    })?,
+                    })?),
                     target_format,
                 ) {
                     Err(e) => {
@@ -546,3 +1691,103 @@ impl Drop for WindowsTtsEngineFactory {
         }));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::SilentEngine;
+    use windows::Win32::System::Com::IClassFactory;
+
+    fn factory(module: &Arc<()>) -> IClassFactory {
+        WindowsTtsEngineFactory::new(GUID::zeroed(), Some(module.clone()), SilentEngine::default)
+            .into()
+    }
+
+    #[test]
+    fn lock_server_keeps_a_module_reference_until_unlocked() {
+        let module = Arc::new(());
+        let first = factory(&module);
+        unsafe { first.LockServer(true) }.unwrap();
+        unsafe { first.LockServer(true) }.unwrap();
+        drop(first);
+        // Each lock outlives the factory it was made with:
+        assert_eq!(Arc::strong_count(&module), 3);
+
+        let second = factory(&module);
+        unsafe { second.LockServer(false) }.unwrap();
+        unsafe { second.LockServer(false) }.unwrap();
+        assert_eq!(Arc::strong_count(&module), 2);
+        // Extra unlocks are ignored:
+        unsafe { second.LockServer(false) }.unwrap();
+        drop(second);
+        assert_eq!(Arc::strong_count(&module), 1);
+    }
+
+    #[test]
+    fn metrics_callback_gets_metrics_of_every_call() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut voice = TtsVoice::new(SilentEngine::default(), None).unwrap();
+        voice.set_metrics_callback({
+            let calls = calls.clone();
+            move |metrics| calls.lock().unwrap().push(metrics)
+        });
+        voice.speak("abc").unwrap();
+        voice.speak_with_events("abc").unwrap();
+        assert_eq!(calls.lock().unwrap().len(), 2);
+        // The callback is only used while the voice is speaking:
+        drop(MetricsRecorder::new(voice.engine()));
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn metrics_are_not_reported_when_speak_panics() {
+        let calls = Arc::new(AtomicBool::new(false));
+        let callback: MetricsCallback = {
+            let calls = calls.clone();
+            Arc::new(move |_: SpeakMetrics| calls.store(true, Ordering::Relaxed))
+        };
+        let _scope = MetricsCallbackScope::enter(Some(callback));
+        let engine = SilentEngine::default();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _metrics = MetricsRecorder::new(&engine);
+            panic!("speak failed");
+        }));
+        assert!(result.is_err());
+        assert!(!calls.load(Ordering::Relaxed));
+
+        drop(MetricsRecorder::new(&engine));
+        assert!(calls.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn cancelling_before_speaking_stops_the_next_call() {
+        let voice = TtsVoice::new(SilentEngine::default(), None).unwrap();
+        let cancel = voice.cancellation_token().unwrap();
+        std::thread::spawn(move || cancel.cancel()).join().unwrap();
+        assert!(voice.speak("abc").unwrap().is_empty());
+        // The token is reset afterwards:
+        assert_eq!(voice.speak("abc").unwrap().len(), 480 * 2);
+    }
+
+    #[test]
+    fn stereo_16_bit_format_has_four_byte_frames() {
+        let format = SpeechFormat::pcm_i16(22_050, 2);
+        assert_eq!(format.channels(), 2);
+        assert_eq!(format.block_align(), 4);
+        assert_eq!(format.silence_bytes(10), 882);
+        // 88.2 bytes are rounded down to whole frames:
+        assert_eq!(format.silence_bytes(1), 88);
+        assert_eq!(format.silence_bytes(3), 264);
+    }
+
+    #[test]
+    fn stereo_output_writes_whole_frames() {
+        let engine = SilentEngine::default();
+        let format = SpeechFormat::pcm_i16(16_000, 2);
+        let audio = engine
+            .synthesize_to_vec(None, SpeakFlags::default(), format, "abc")
+            .unwrap();
+        // 30 ms at 16 kHz with 4 bytes per frame:
+        assert_eq!(audio.len(), 480 * 4);
+    }
+}
@@ -2,14 +2,246 @@
     not(any(not(feature = "disable_logging_in_release"), debug_assertions)),
     expect(dead_code)
 )]
+//! A logger that can be used inside DLLs where there is no console to print
+//! to.
+//!
+//! By default messages at the debug level or more important are appended to a
+//! `.debug.log` file next to the DLL, but only if that file already exists.
+//! Release builds don't log anything unless that file exists or
+//! [`LOG_ENV_VAR`] is set, so that users can enable logging when reporting an
+//! issue without recompiling.
+//! Building with the `disable_logging_in_release` feature removes logging from
+//! release builds completely.
+//!
+//! Logging can be configured using environment variables:
+//!
+//! - [`LOG_ENV_VAR`]: a comma separated list with a level (`off`, `error`,
+//!   `warn`, `info`, `debug` or `trace`) and/or a destination (`file`,
+//!   `stderr` or `debugger`). For example `LEJ77_TTS_LOG=trace,debugger`.
+//!   `LEJ77_TTS_LOG=1` enables logging with the default level and
+//!   destination.
+//!   Parts like `target=level` override the level for a module and its
+//!   sub modules, for example
+//!   `LEJ77_TTS_LOG=warn,windows_tts_engine::detect_languages=trace`. The
+//!   most specific target wins.
+//!   The `debugger` destination uses `OutputDebugStringW` so that logs can be
+//!   viewed live with tools like DebugView.
+//! - [`LOG_FILE_ENV_VAR`]: path to a log file to use instead of the default
+//!   one. The file is created if it doesn't exist.
+//! - [`LOG_MAX_SIZE_ENV_VAR`]: the log file is rotated when it grows larger
+//!   than this many bytes. Defaults to 5 MB.
+//! - [`LOG_BACKUPS_ENV_VAR`]: how many rotated log files to keep, named
+//!   `.debug.log.1` (newest) to `.debug.log.N` (oldest). Defaults to 3.
 
-use std::{path::PathBuf, sync::OnceLock};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
 
 #[cfg(any(not(feature = "disable_logging_in_release"), debug_assertions))]
-use crate::utils::{get_current_dll_path, safe_catch_unwind};
+use crate::utils::{current_dll_path, safe_catch_unwind, to_utf16};
+
+/// Environment variable that configures the log level and destination.
+pub const LOG_ENV_VAR: &str = "LEJ77_TTS_LOG";
+/// Environment variable that configures the path of the log file.
+pub const LOG_FILE_ENV_VAR: &str = "LEJ77_TTS_LOG_FILE";
+/// Environment variable that configures [`LogConfig::max_file_size`].
+pub const LOG_MAX_SIZE_ENV_VAR: &str = "LEJ77_TTS_LOG_MAX_SIZE";
+/// Environment variable that configures [`LogConfig::max_backups`].
+pub const LOG_BACKUPS_ENV_VAR: &str = "LEJ77_TTS_LOG_BACKUPS";
+
+/// Re-check the log file's size after this many writes, since other processes
+/// might be writing to the same file.
+const WRITES_BETWEEN_SIZE_CHECKS: u32 = 100;
+
+/// Where [`DllLogger`] writes its messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogDestination {
+    /// Append to a log file.
+    File,
+    /// Write to the standard error stream of the host process.
+    Stderr,
+    /// Send to an attached debugger using `OutputDebugStringW`.
+    Debugger,
+}
+impl FromStr for LogDestination {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "file" => Self::File,
+            "stderr" => Self::Stderr,
+            "debugger" | "debug_string" | "outputdebugstring" => Self::Debugger,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Logger options, read from environment variables.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Level used for targets that don't match any of the
+    /// [`directives`](Self::directives).
+    pub level: log::LevelFilter,
+    /// Levels for specific targets (usually module paths), parsed from parts
+    /// like `windows_tts_engine::detect_languages=trace`.
+    pub directives: Vec<(String, log::LevelFilter)>,
+    pub destination: LogDestination,
+    /// Custom log file path. If this is `None` then a file next to the DLL is
+    /// used, but only if it already exists.
+    pub file_path: Option<PathBuf>,
+    /// Rotate the log file when it grows larger than this many bytes.
+    pub max_file_size: u64,
+    /// Number of rotated log files to keep.
+    pub max_backups: u32,
+}
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: if cfg!(debug_assertions) {
+                log::LevelFilter::Debug
+            } else {
+                // Only log in release builds if the user asked for it:
+                log::LevelFilter::Off
+            },
+            directives: Vec::new(),
+            destination: LogDestination::File,
+            file_path: None,
+            max_file_size: 5 * 1024 * 1024,
+            max_backups: 3,
+        }
+    }
+}
+impl LogConfig {
+    /// Read the environment variables listed in the [module
+    /// documentation](self). Uses the default value for anything that isn't
+    /// specified.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(value) = std::env::var(LOG_ENV_VAR) {
+            // Setting the variable at all enables logging in release builds:
+            if config.level == log::LevelFilter::Off {
+                config.level = log::LevelFilter::Debug;
+            }
+            for part in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                if let Some((target, level)) = part.split_once('=') {
+                    if let Ok(level) = level.trim().parse::<log::LevelFilter>() {
+                        config.directives.push((target.trim().to_owned(), level));
+                    }
+                } else if let Ok(level) = part.parse::<log::LevelFilter>() {
+                    config.level = level;
+                } else if let Ok(destination) = part.parse::<LogDestination>() {
+                    config.destination = destination;
+                }
+            }
+        }
+        if let Some(path) = std::env::var_os(LOG_FILE_ENV_VAR).filter(|v| !v.is_empty()) {
+            config.file_path = Some(PathBuf::from(path));
+        }
+        if let Some(size) = std::env::var(LOG_MAX_SIZE_ENV_VAR)
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+        {
+            config.max_file_size = size;
+        }
+        if let Some(backups) = std::env::var(LOG_BACKUPS_ENV_VAR)
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+        {
+            config.max_backups = backups;
+        }
+        config
+    }
+
+    /// The level that is enabled for a log target, taken from the directive
+    /// with the longest matching target or [`level`](Self::level) if no
+    /// directive matches.
+    pub fn level_for(&self, target: &str) -> log::LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(name, _)| {
+                target
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    /// The most verbose level enabled for any target.
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.directives
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+/// Cached information about the log file, so that we don't need to query the
+/// file system for every message.
+#[derive(Debug)]
+struct LogFileState {
+    /// Our estimate of the file's size in bytes, `None` if unknown.
+    size: Option<u64>,
+    writes_since_size_check: u32,
+}
+
+/// Path of the `index`th backup of a log file, for example `.debug.log.1`.
+fn backup_path(log_path: &Path, index: u32) -> PathBuf {
+    let mut path = OsString::from(log_path.as_os_str());
+    path.push(format!(".{index}"));
+    PathBuf::from(path)
+}
+
+/// Shift all backups one step and move the current log file to the first
+/// backup, the oldest backup is removed.
+fn rotate_log_file(log_path: &Path, max_backups: u32) {
+    if max_backups == 0 {
+        let _ = std::fs::remove_file(log_path);
+        return;
+    }
+    let _ = std::fs::remove_file(backup_path(log_path, max_backups));
+    for index in (1..max_backups).rev() {
+        let _ = std::fs::rename(
+            backup_path(log_path, index),
+            backup_path(log_path, index + 1),
+        );
+    }
+    let _ = std::fs::rename(log_path, backup_path(log_path, 1));
+}
+
+/// An ISO 8601 UTC timestamp and the current thread id, for example
+/// `2025-01-31T13:45:10.123Z [tid 4321]`.
+#[cfg(any(not(feature = "disable_logging_in_release"), debug_assertions))]
+fn line_prefix() -> String {
+    use windows::Win32::System::{SystemInformation::GetSystemTime, Threading::GetCurrentThreadId};
+
+    let time = unsafe { GetSystemTime() };
+    let thread_id = unsafe { GetCurrentThreadId() };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z [tid {thread_id}]",
+        time.wYear,
+        time.wMonth,
+        time.wDay,
+        time.wHour,
+        time.wMinute,
+        time.wSecond,
+        time.wMilliseconds
+    )
+}
+
+/// The `.debug.log` file next to the DLL.
+#[cfg(any(not(feature = "disable_logging_in_release"), debug_assertions))]
+fn default_log_path() -> Option<PathBuf> {
+    Some(current_dll_path().ok()?.with_extension("debug.log"))
+}
 
 pub struct DllLogger {
+    config: OnceLock<LogConfig>,
     log_path: OnceLock<Option<PathBuf>>,
+    file_state: Mutex<LogFileState>,
     init: std::sync::Once,
 }
 impl DllLogger {
@@ -19,31 +251,113 @@ impl DllLogger {
     )]
     pub const fn new() -> Self {
         Self {
+            config: OnceLock::new(),
             log_path: OnceLock::new(),
+            file_state: Mutex::new(LogFileState {
+                size: None,
+                writes_since_size_check: 0,
+            }),
             init: std::sync::Once::new(),
         }
     }
-    pub fn write_to_log(&self, _args: core::fmt::Arguments<'_>) {
+    pub fn config(&self) -> &LogConfig {
+        self.config.get_or_init(|| {
+            #[cfg_attr(
+                all(feature = "disable_logging_in_release", not(debug_assertions)),
+                expect(unused_mut)
+            )]
+            let mut config = LogConfig::from_env();
+            // The default log file enables logging in release builds, unless
+            // the environment variable turned logging off:
+            #[cfg(any(not(feature = "disable_logging_in_release"), debug_assertions))]
+            if config.level == log::LevelFilter::Off
+                && std::env::var_os(LOG_ENV_VAR).is_none()
+                && default_log_path().is_some_and(|path| path.exists())
+            {
+                config.level = log::LevelFilter::Debug;
+            }
+            config
+        })
+    }
+    pub fn write_to_log(&self, args: core::fmt::Arguments<'_>) {
+        self.write_message(args, false);
+    }
+    /// Write a message and wait until it has been written to disk, so that it
+    /// isn't lost if the process or system crashes right after.
+    pub fn write_to_log_synced(&self, args: core::fmt::Arguments<'_>) {
+        self.write_message(args, true);
+    }
+    /// Write a message to the configured destination. The log file is opened
+    /// for every message and nothing is buffered in process, so once this
+    /// returns the message has been handed to the OS. If `sync` is `true` we
+    /// also wait for the OS to write it to disk.
+    fn write_message(&self, _args: core::fmt::Arguments<'_>, _sync: bool) {
         #[cfg(any(not(feature = "disable_logging_in_release"), debug_assertions))]
         safe_catch_unwind::<_, ()>(std::panic::AssertUnwindSafe(|| {
+            let config = self.config();
+            let message = format!("{} {_args}\n", line_prefix());
+            match config.destination {
+                LogDestination::Stderr => {
+                    let _ = std::io::Write::write_all(&mut std::io::stderr(), message.as_bytes());
+                    return;
+                }
+                LogDestination::Debugger => {
+                    let message = to_utf16(&message);
+                    unsafe {
+                        windows::Win32::System::Diagnostics::Debug::OutputDebugStringW(
+                            windows_core::PCWSTR::from_raw(message.as_ptr()),
+                        )
+                    };
+                    return;
+                }
+                LogDestination::File => {}
+            }
+
             let Some(log_path) = self.log_path.get_or_init(|| {
-                let mut buffer = [0; windows::Win32::Foundation::MAX_PATH as usize];
-                Some(
-                    std::path::PathBuf::from(
-                        String::from_utf16(get_current_dll_path(&mut buffer).ok()?).ok()?,
-                    )
-                    .with_extension("debug.log"),
-                )
+                if let Some(path) = &config.file_path {
+                    return Some(path.clone());
+                }
+                default_log_path()
             }) else {
                 return;
             };
 
+            let mut state = self.file_state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.writes_since_size_check >= WRITES_BETWEEN_SIZE_CHECKS {
+                state.size = None;
+            }
+            let size = match state.size {
+                Some(size) => size,
+                None => {
+                    state.writes_since_size_check = 0;
+                    std::fs::metadata(log_path).map_or(0, |info| info.len())
+                }
+            };
+            // Only create the default log file if the user asked for it:
+            let mut create = config.file_path.is_some();
+            let size = if size > 0 && size + message.len() as u64 > config.max_file_size {
+                rotate_log_file(log_path, config.max_backups);
+                // The user wanted logging before we rotated the file:
+                create = true;
+                0
+            } else {
+                size
+            };
+
             if let Ok(mut file) = std::fs::OpenOptions::new()
-                .create(false)
+                .create(create)
                 .append(true)
                 .open(log_path)
             {
-                let _ = std::io::Write::write_all(&mut file, format!("{_args}\n").as_bytes());
+                if std::io::Write::write_all(&mut file, message.as_bytes()).is_ok() {
+                    if _sync {
+                        let _ = file.sync_all();
+                    }
+                    state.size = Some(size + message.len() as u64);
+                    state.writes_since_size_check += 1;
+                }
+            } else {
+                state.size = None;
             }
         }));
     }
@@ -51,16 +365,24 @@ impl DllLogger {
         #[cfg(any(not(feature = "disable_logging_in_release"), debug_assertions))]
         self.init.call_once(|| {
             safe_catch_unwind::<_, ()>(|| {
+                // Logging is opt-in, so only panics are written when it is off:
+                let enabled = self.config().max_level() != log::LevelFilter::Off;
                 if let Err(e) = log::set_logger(self) {
-                    self.write_to_log(format_args!("Failed to install logger: {e}"));
+                    if enabled {
+                        self.write_to_log(format_args!("Failed to install logger: {e}"));
+                    }
                 } else {
-                    log::set_max_level(log::LevelFilter::Debug);
-                    self.write_to_log(format_args!("installed logger"));
+                    log::set_max_level(self.config().max_level());
+                    if enabled {
+                        self.write_to_log(format_args!("installed logger"));
+                    }
                 }
 
                 let prev = std::panic::take_hook();
                 std::panic::set_hook(Box::new(move |info| {
-                    self.write_to_log(format_args!(
+                    // The panic message is the most important part of a bug
+                    // report, so ensure it survives if the host crashes:
+                    self.write_to_log_synced(format_args!(
                         "-----------\n\
                         {info}\n\
                         ------------"
@@ -73,7 +395,7 @@ impl DllLogger {
 }
 impl log::Log for DllLogger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::Level::Debug
+        metadata.level() <= self.config().level_for(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
@@ -0,0 +1,768 @@
+//! Build [Speech Synthesis Markup Language
+//! (SSML)](https://www.w3.org/TR/speech-synthesis/) from text fragments so that
+//! synthesizers that understand SSML can apply the XML state themselves, and
+//! parse SSML for synthesizers that don't understand it, see [`parse_ssml`].
+//!
+//! SAPI parses the XML itself before it calls engines, but clients that use
+//! an engine in-process can pass SSML with
+//! [`SpeakFlags::is_xml`](crate::SpeakFlags::is_xml). Use
+//! [`SsmlTextFrags`] to turn it into fragments like the ones SAPI creates.
+//!
+//! # References
+//!
+//! - [SSML overview - Speech service - Azure AI services | Microsoft Learn](https://learn.microsoft.com/en-us/azure/ai-services/speech-service/speech-synthesis-markup)
+//! - [SPVSTATE (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720876(v=vs.85))
+
+use std::{fmt::Write, marker::PhantomData, ops::Range};
+
+use windows::Win32::Media::Speech::{
+    SPVA_Silence, SPVA_Speak, SPVA_SpellOut, SPVSTATE, SPVTEXTFRAG,
+};
+use windows_core::PCWSTR;
+
+use crate::{
+    text::JoinedText,
+    utils::{multiplier_to_sapi_rate, sapi_rate_to_multiplier},
+    TextFrag,
+};
+
+/// Escape text so that it can be placed inside an XML element or attribute.
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Attributes for a `<prosody>` element that applies a fragment's rate,
+/// pitch and volume. Returns `None` if the state uses the default values.
+pub fn prosody_attributes(state: &SPVSTATE) -> Option<String> {
+    let mut attributes = String::new();
+    if state.RateAdj != 0 {
+        let rate = sapi_rate_to_multiplier(state.RateAdj);
+        write!(attributes, " rate=\"{rate:.2}\"").unwrap();
+    }
+    if state.PitchAdj.MiddleAdj != 0 {
+        write!(
+            attributes,
+            " pitch=\"{:+}st\"",
+            state.PitchAdj.MiddleAdj.clamp(-10, 10)
+        )
+        .unwrap();
+    }
+    if state.Volume != 100 {
+        write!(attributes, " volume=\"{}\"", state.Volume.min(100)).unwrap();
+    }
+    (!attributes.is_empty()).then_some(attributes)
+}
+
+/// Build an SSML document for a range of the joined text. Every fragment's
+/// text is wrapped in a `<prosody>` element if its state changes the rate,
+/// pitch or volume.
+///
+/// `lang` should be a language code like `"en-US"`.
+pub fn build_ssml(joined_text: &JoinedText<'_>, range: Range<usize>, lang: &str) -> String {
+    let mut ssml = format!(
+        "<speak version=\"1.0\" xmlns=\"http://www.w3.org/2001/10/synthesis\" xml:lang=\"{}\">",
+        escape_xml(lang)
+    );
+    for (frag, frag_range) in joined_text.fragments_in(range) {
+        let text = escape_xml(&String::from_utf16_lossy(&joined_text.utf16()[frag_range]));
+        match prosody_attributes(frag.state()) {
+            Some(attributes) => write!(ssml, "<prosody{attributes}>{text}</prosody> ").unwrap(),
+            None => write!(ssml, "{text} ").unwrap(),
+        }
+    }
+    ssml.push_str("</speak>");
+    ssml
+}
+
+/// Prosody of parsed SSML text, using the same units as SAPI's `SPVSTATE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prosody {
+    /// Rate adjustment from `-10` to `10`, see
+    /// [`sapi_rate_to_multiplier`].
+    pub rate: i32,
+    /// Pitch adjustment in semitones from `-10` to `10`.
+    pub pitch: i32,
+    /// Volume from `0` to `100`.
+    pub volume: u16,
+    /// The text is inside an `<emphasis>` element.
+    pub emphasis: bool,
+}
+impl Default for Prosody {
+    fn default() -> Self {
+        Self {
+            rate: 0,
+            pitch: 0,
+            volume: 100,
+            emphasis: false,
+        }
+    }
+}
+impl Prosody {
+    /// Apply the attributes of a `<prosody>` element. Values are relative to
+    /// the current prosody and invalid values are ignored.
+    fn apply_attributes(&mut self, attributes: &[(String, String)]) {
+        for (name, value) in attributes {
+            let value = value.trim();
+            match name.as_str() {
+                "rate" => {
+                    if let Some(multiplier) = parse_rate(value) {
+                        let steps = multiplier_to_sapi_rate(multiplier);
+                        self.rate = (self.rate + steps).clamp(-10, 10);
+                    }
+                }
+                "pitch" => {
+                    if let Some(semitones) = parse_pitch(value) {
+                        self.pitch = (self.pitch + semitones.round() as i32).clamp(-10, 10);
+                    }
+                }
+                "volume" => {
+                    if let Some(volume) = parse_volume(value, f64::from(self.volume)) {
+                        self.volume = volume.round().clamp(0.0, 100.0) as u16;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// What should be done with a [`SsmlSegment`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsmlAction {
+    /// Speak the text.
+    Speak,
+    /// Speak the text as described by the `interpret-as` attribute of a
+    /// `<say-as>` element, for example `"characters"`, `"cardinal"` or
+    /// `"date"`.
+    SayAs { interpret_as: String },
+    /// Insert silence, the segment's text is empty.
+    Break { milliseconds: u32 },
+}
+
+/// Text with a single prosody and action, created by [`parse_ssml`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SsmlSegment {
+    pub text: String,
+    pub prosody: Prosody,
+    pub action: SsmlAction,
+    /// Byte offset in the SSML where the segment's text or `<break>` starts.
+    pub offset: usize,
+}
+impl SsmlSegment {
+    /// The state of a text fragment with this segment's text. `base` is the
+    /// state of the fragment that contained the SSML, the prosody is applied
+    /// relative to it.
+    ///
+    /// `<say-as>` elements that spell out text use the `SPVA_SpellOut`
+    /// action, other values of `interpret-as` are spoken normally.
+    pub fn state(&self, base: &SPVSTATE) -> SPVSTATE {
+        let mut state = *base;
+        state.RateAdj = (base.RateAdj + self.prosody.rate).clamp(-10, 10);
+        state.PitchAdj.MiddleAdj = (base.PitchAdj.MiddleAdj + self.prosody.pitch).clamp(-10, 10);
+        state.Volume = base.Volume.min(100) * u32::from(self.prosody.volume) / 100;
+        if self.prosody.emphasis {
+            state.EmphAdj = 1;
+        }
+        match &self.action {
+            SsmlAction::Speak => {}
+            SsmlAction::SayAs { interpret_as } => {
+                if matches!(
+                    interpret_as.as_str(),
+                    "characters" | "spell-out" | "letters"
+                ) {
+                    state.eAction = SPVA_SpellOut;
+                }
+            }
+            SsmlAction::Break { milliseconds } => {
+                state.eAction = SPVA_Silence;
+                state.SilenceMSecs = *milliseconds;
+            }
+        }
+        state
+    }
+}
+
+/// Parse a relative or absolute SSML rate to a speed multiplier.
+fn parse_rate(value: &str) -> Option<f64> {
+    let multiplier = match value {
+        "x-slow" => 0.5,
+        "slow" => 0.75,
+        "medium" | "default" => 1.0,
+        "fast" => 1.5,
+        "x-fast" => 2.0,
+        _ => {
+            if let Some(percent) = value.strip_suffix('%') {
+                let percent = percent.parse::<f64>().ok()?;
+                if value.starts_with(['+', '-']) {
+                    1.0 + percent / 100.0
+                } else {
+                    percent / 100.0
+                }
+            } else {
+                value.parse::<f64>().ok()?
+            }
+        }
+    };
+    (multiplier > 0.0).then_some(multiplier)
+}
+
+/// Parse a relative SSML pitch to semitones. Absolute frequencies in hertz
+/// aren't supported since the voice's normal pitch is unknown.
+fn parse_pitch(value: &str) -> Option<f64> {
+    Some(match value {
+        "x-low" => -6.0,
+        "low" => -3.0,
+        "medium" | "default" => 0.0,
+        "high" => 3.0,
+        "x-high" => 6.0,
+        _ => {
+            if let Some(semitones) = value.strip_suffix("st") {
+                semitones.parse::<f64>().ok()?
+            } else if let Some(percent) = value.strip_suffix('%') {
+                let factor = 1.0 + percent.parse::<f64>().ok()? / 100.0;
+                if factor <= 0.0 {
+                    return None;
+                }
+                12.0 * factor.log2()
+            } else {
+                return None;
+            }
+        }
+    })
+}
+
+/// Parse an SSML volume to a value between `0` and `100`. Relative values
+/// are applied to `current`.
+fn parse_volume(value: &str, current: f64) -> Option<f64> {
+    Some(match value {
+        "silent" => 0.0,
+        "x-soft" => 20.0,
+        "soft" => 40.0,
+        "medium" => 60.0,
+        "loud" => 80.0,
+        "x-loud" | "default" => 100.0,
+        _ => {
+            if let Some(decibels) = value.strip_suffix("dB") {
+                current * 10_f64.powf(decibels.parse::<f64>().ok()? / 20.0)
+            } else if let Some(percent) = value.strip_suffix('%') {
+                current * (1.0 + percent.parse::<f64>().ok()? / 100.0)
+            } else if value.starts_with(['+', '-']) {
+                current + value.parse::<f64>().ok()?
+            } else {
+                value.parse::<f64>().ok()?
+            }
+        }
+    })
+}
+
+/// Parse the duration of a `<break>` element.
+fn parse_break(attributes: &[(String, String)]) -> u32 {
+    let time = attributes.iter().find(|(name, _)| name == "time");
+    if let Some((_, time)) = time {
+        let time = time.trim();
+        let milliseconds = if let Some(ms) = time.strip_suffix("ms") {
+            ms.trim().parse::<f64>().ok()
+        } else if let Some(s) = time.strip_suffix('s') {
+            s.trim().parse::<f64>().ok().map(|s| s * 1000.0)
+        } else {
+            None
+        };
+        if let Some(milliseconds) = milliseconds {
+            return milliseconds.max(0.0) as u32;
+        }
+    }
+    let strength = attributes.iter().find(|(name, _)| name == "strength");
+    match strength.map(|(_, strength)| strength.trim()) {
+        Some("none") => 0,
+        Some("x-weak") => 100,
+        Some("weak") => 250,
+        Some("strong") => 750,
+        Some("x-strong") => 1000,
+        _ => 500,
+    }
+}
+
+/// Replace XML entities like `&amp;` and `&#x41;` with the characters they
+/// represent. Unknown entities are kept as is.
+pub fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// A start or end tag found by [`parse_ssml`].
+struct Tag {
+    name: String,
+    attributes: Vec<(String, String)>,
+    is_end: bool,
+    is_self_closing: bool,
+}
+impl Tag {
+    /// Parse the content between `<` and `>`.
+    fn parse(content: &str) -> Option<Self> {
+        let (is_end, content) = match content.strip_prefix('/') {
+            Some(content) => (true, content),
+            None => (false, content),
+        };
+        let (is_self_closing, content) = match content.strip_suffix('/') {
+            Some(content) => (true, content),
+            None => (false, content),
+        };
+        let content = content.trim();
+        let name_end = content
+            .find(|c: char| c.is_whitespace())
+            .unwrap_or(content.len());
+        let name = content[..name_end].to_ascii_lowercase();
+        let valid_name = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'));
+        if !valid_name || content.contains('<') {
+            return None;
+        }
+
+        let mut attributes = Vec::new();
+        let mut rest = content[name_end..].trim_start();
+        while let Some(equals) = rest.find('=') {
+            let attribute = rest[..equals].trim().to_ascii_lowercase();
+            let value = rest[equals + 1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            let value_end = value[1..].find(quote)? + 1;
+            attributes.push((attribute, unescape_xml(&value[1..value_end])));
+            rest = value[value_end + 1..].trim_start();
+        }
+        Some(Self {
+            name,
+            attributes,
+            is_end,
+            is_self_closing,
+        })
+    }
+}
+
+/// Parse an SSML document into segments of text with the prosody and action
+/// that applies to them, so that synthesizers that don't understand SSML can
+/// apply it themselves.
+///
+/// Supports `<prosody>` (`rate`, `pitch` and `volume`), `<break>` (`time`
+/// and `strength`), `<say-as>` (`interpret-as`) and `<emphasis>`. Other
+/// elements are ignored but their text is kept. Comments, processing
+/// instructions and declarations are skipped. Malformed markup is treated as
+/// text.
+///
+/// Namespace prefixes aren't supported, so `<ssml:prosody>` is ignored.
+pub fn parse_ssml(ssml: &str) -> Vec<SsmlSegment> {
+    struct Element {
+        name: String,
+        prosody: Prosody,
+        interpret_as: Option<String>,
+    }
+    let mut stack: Vec<Element> = Vec::new();
+    let mut segments: Vec<SsmlSegment> = Vec::new();
+
+    let push_text =
+        |segments: &mut Vec<SsmlSegment>, stack: &[Element], text: &str, offset: usize| {
+            if text.is_empty() {
+                return;
+            }
+            let text = unescape_xml(text);
+            let prosody = stack.last().map(|e| e.prosody).unwrap_or_default();
+            let action = match stack.iter().rev().find_map(|e| e.interpret_as.clone()) {
+                Some(interpret_as) => SsmlAction::SayAs { interpret_as },
+                None => SsmlAction::Speak,
+            };
+            if let Some(previous) = segments.last_mut() {
+                if previous.prosody == prosody && previous.action == action {
+                    previous.text.push_str(&text);
+                    return;
+                }
+            }
+            segments.push(SsmlSegment {
+                text,
+                prosody,
+                action,
+                offset,
+            });
+        };
+
+    let mut rest = ssml;
+    while !rest.is_empty() {
+        let position = ssml.len() - rest.len();
+        let Some(start) = rest.find('<') else {
+            push_text(&mut segments, &stack, rest, position);
+            break;
+        };
+        push_text(&mut segments, &stack, &rest[..start], position);
+        rest = &rest[start..];
+        let position = position + start;
+
+        // Skip markup that doesn't affect the text:
+        let skip_until = if rest.starts_with("<!--") {
+            Some("-->")
+        } else if rest.starts_with("<?") {
+            Some("?>")
+        } else if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").unwrap_or(rest.len());
+            push_text(
+                &mut segments,
+                &stack,
+                &escape_xml(&rest[9..end]),
+                position + 9,
+            );
+            Some("]]>")
+        } else if rest.starts_with("<!") {
+            Some(">")
+        } else {
+            None
+        };
+        if let Some(terminator) = skip_until {
+            rest = match rest.find(terminator) {
+                Some(end) => &rest[end + terminator.len()..],
+                None => "",
+            };
+            continue;
+        }
+
+        let Some(tag) = rest[1..]
+            .find('>')
+            .and_then(|end| Some((Tag::parse(&rest[1..end + 1])?, end + 2)))
+        else {
+            // Not a valid tag, so treat the `<` as text:
+            push_text(&mut segments, &stack, "&lt;", position);
+            rest = &rest[1..];
+            continue;
+        };
+        let (tag, tag_len) = tag;
+        rest = &rest[tag_len..];
+
+        if tag.is_end {
+            if let Some(index) = stack.iter().rposition(|e| e.name == tag.name) {
+                stack.truncate(index);
+            }
+            continue;
+        }
+
+        let parent = stack.last();
+        let mut element = Element {
+            name: tag.name,
+            prosody: parent.map(|e| e.prosody).unwrap_or_default(),
+            interpret_as: None,
+        };
+        match element.name.as_str() {
+            "prosody" => element.prosody.apply_attributes(&tag.attributes),
+            "emphasis" => {
+                let level = tag.attributes.iter().find(|(name, _)| name == "level");
+                element.prosody.emphasis = !matches!(
+                    level.map(|(_, level)| level.trim()),
+                    Some("none" | "reduced")
+                );
+            }
+            "say-as" => {
+                element.interpret_as = tag
+                    .attributes
+                    .iter()
+                    .find(|(name, _)| name == "interpret-as")
+                    .map(|(_, value)| value.trim().to_owned());
+            }
+            "break" => {
+                segments.push(SsmlSegment {
+                    text: String::new(),
+                    prosody: element.prosody,
+                    action: SsmlAction::Break {
+                        milliseconds: parse_break(&tag.attributes),
+                    },
+                    offset: position,
+                });
+            }
+            _ => {}
+        }
+        if !tag.is_self_closing && element.name != "break" {
+            stack.push(element);
+        }
+    }
+    segments
+}
+
+/// Text fragments created by parsing the SSML in other fragments with
+/// [`parse_ssml`]. Engines can handle them like the fragments that SAPI
+/// creates when it parses XML.
+pub struct SsmlTextFrags<'a> {
+    _texts: Vec<Vec<u16>>,
+    frags: Box<[SPVTEXTFRAG]>,
+    /// Fragments that aren't spoken are copied with their state, which can
+    /// point to strings owned by the original fragments.
+    _source: PhantomData<TextFrag<'a>>,
+}
+impl<'a> SsmlTextFrags<'a> {
+    /// Parse the text of every fragment that should be spoken, other
+    /// fragments are kept as is. Source offsets point into the text of the
+    /// fragment that contained the SSML.
+    pub fn parse(text_fragments: Option<TextFrag<'a>>) -> Self {
+        let mut texts = Vec::new();
+        let mut parts = Vec::new();
+        for frag in text_fragments.into_iter().flat_map(TextFrag::iter) {
+            if frag.state().eAction != SPVA_Speak {
+                texts.push(frag.utf16_text().to_vec());
+                parts.push((*frag.state(), frag.offset_in_original_text()));
+                continue;
+            }
+            let ssml = String::from_utf16_lossy(frag.utf16_text());
+            for segment in parse_ssml(&ssml) {
+                let offset = ssml[..segment.offset].encode_utf16().count() as u32;
+                texts.push(segment.text.encode_utf16().collect());
+                parts.push((
+                    segment.state(frag.state()),
+                    frag.offset_in_original_text() + offset,
+                ));
+            }
+        }
+        let mut frags = texts
+            .iter()
+            .zip(parts)
+            .map(|(text, (state, offset))| SPVTEXTFRAG {
+                pNext: std::ptr::null_mut(),
+                State: state,
+                pTextStart: PCWSTR::from_raw(text.as_ptr()),
+                ulTextLen: text.len() as u32,
+                ulTextSrcOffset: offset,
+            })
+            .collect::<Box<[_]>>();
+        // The boxed slice is never moved or resized, so these pointers stay
+        // valid for as long as `self` lives:
+        for index in 1..frags.len() {
+            let next: *mut SPVTEXTFRAG = &mut frags[index];
+            frags[index - 1].pNext = next;
+        }
+        Self {
+            _texts: texts,
+            frags,
+            _source: PhantomData,
+        }
+    }
+
+    /// The first fragment in the list.
+    pub fn first(&self) -> Option<TextFrag<'_>> {
+        // Safety: the fragments and their text live as long as `self`.
+        self.frags
+            .first()
+            .and_then(|frag| unsafe { TextFrag::new(frag) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{speak_state, TestFrags};
+
+    fn texts(segments: &[SsmlSegment]) -> Vec<&str> {
+        segments.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    #[test]
+    fn prosody_attributes_are_parsed() {
+        let segments = parse_ssml(
+            "<speak>Hi <prosody rate=\"120%\" pitch=\"+2st\" volume=\"50\">there</prosody></speak>",
+        );
+        assert_eq!(texts(&segments), ["Hi ", "there"]);
+        assert_eq!(segments[0].prosody, Prosody::default());
+        assert_eq!(
+            segments[1].prosody,
+            Prosody {
+                rate: multiplier_to_sapi_rate(1.2),
+                pitch: 2,
+                volume: 50,
+                emphasis: false,
+            }
+        );
+        assert_eq!(segments[1].prosody.rate, 5);
+    }
+
+    #[test]
+    fn nested_prosody_is_relative_to_the_parent() {
+        let segments = parse_ssml(
+            "<prosody volume=\"50\" pitch=\"low\"><prosody volume=\"+10\" pitch=\"-1st\">a</prosody>b</prosody>c",
+        );
+        assert_eq!(texts(&segments), ["a", "b", "c"]);
+        assert_eq!(
+            (segments[0].prosody.volume, segments[0].prosody.pitch),
+            (60, -4)
+        );
+        assert_eq!(
+            (segments[1].prosody.volume, segments[1].prosody.pitch),
+            (50, -3)
+        );
+        assert_eq!(segments[2].prosody, Prosody::default());
+    }
+
+    #[test]
+    fn invalid_prosody_values_are_ignored() {
+        let segments = parse_ssml("<prosody rate=\"-200%\" pitch=\"200Hz\">a</prosody>");
+        assert_eq!(segments[0].prosody, Prosody::default());
+    }
+
+    #[test]
+    fn breaks_use_time_or_strength() {
+        let segments = parse_ssml(
+            "a<break time=\"300ms\"/>b<break time=\"1.5s\"/><break strength=\"strong\"/><break/>",
+        );
+        let actions = segments.iter().map(|s| &s.action).collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                &SsmlAction::Speak,
+                &SsmlAction::Break { milliseconds: 300 },
+                &SsmlAction::Speak,
+                &SsmlAction::Break { milliseconds: 1500 },
+                &SsmlAction::Break { milliseconds: 750 },
+                &SsmlAction::Break { milliseconds: 500 },
+            ]
+        );
+        assert_eq!(texts(&segments), ["a", "", "b", "", "", ""]);
+    }
+
+    #[test]
+    fn say_as_and_emphasis_are_kept() {
+        let segments = parse_ssml(
+            "<say-as interpret-as=\"characters\">abc</say-as> \
+            <emphasis>x</emphasis><emphasis level=\"reduced\">y</emphasis>",
+        );
+        assert_eq!(texts(&segments), ["abc", " ", "x", "y"]);
+        assert_eq!(
+            segments[0].action,
+            SsmlAction::SayAs {
+                interpret_as: "characters".to_owned()
+            }
+        );
+        assert_eq!(segments[1].action, SsmlAction::Speak);
+        assert!(segments[2].prosody.emphasis);
+        assert!(!segments[3].prosody.emphasis);
+    }
+
+    #[test]
+    fn unknown_tags_are_ignored_but_their_text_is_kept() {
+        let segments = parse_ssml(
+            "<?xml version=\"1.0\"?><speak><!-- comment --><voice name=\"x\">A &amp; B</voice> \
+            <foo/>C<![CDATA[ <D> ]]></speak>",
+        );
+        assert_eq!(texts(&segments), ["A & B C <D> "]);
+        assert_eq!(segments[0].action, SsmlAction::Speak);
+    }
+
+    #[test]
+    fn malformed_markup_is_text() {
+        assert_eq!(
+            texts(&parse_ssml("1 < 2 &unknown; &#x41;")),
+            ["1 < 2 &unknown; A"]
+        );
+    }
+
+    #[test]
+    fn segment_offsets_point_into_the_ssml() {
+        let ssml = "<speak>ab<break/><prosody rate=\"fast\">cd</prosody></speak>";
+        let segments = parse_ssml(ssml);
+        let offsets = segments.iter().map(|s| s.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, [7, 9, 38]);
+        assert!(ssml[segments[2].offset..].starts_with("cd"));
+    }
+
+    #[test]
+    fn xml_escaping_round_trips() {
+        let text = "<a href='x'>\"&\"</a>";
+        assert_eq!(unescape_xml(&escape_xml(text)), text);
+    }
+
+    #[test]
+    fn segment_state_is_relative_to_the_fragment() {
+        let base = SPVSTATE {
+            RateAdj: 2,
+            Volume: 50,
+            ..speak_state()
+        };
+        let segment = &parse_ssml("<prosody rate=\"120%\" volume=\"50\">a</prosody>")[0];
+        let state = segment.state(&base);
+        assert_eq!(state.RateAdj, 7);
+        assert_eq!(state.Volume, 25);
+        assert_eq!(state.eAction, SPVA_Speak);
+
+        let spelled = &parse_ssml("<say-as interpret-as=\"characters\">a</say-as>")[0];
+        assert_eq!(spelled.state(&base).eAction, SPVA_SpellOut);
+        let date = &parse_ssml("<say-as interpret-as=\"date\">a</say-as>")[0];
+        assert_eq!(date.state(&base).eAction, SPVA_Speak);
+
+        let pause = &parse_ssml("<break time=\"20ms\"/>")[0];
+        let state = pause.state(&base);
+        assert_eq!((state.eAction, state.SilenceMSecs), (SPVA_Silence, 20));
+    }
+
+    #[test]
+    fn ssml_text_frags_replace_the_markup() {
+        let source = TestFrags::speak("<speak>ab<break time=\"10ms\"/>c&amp;d</speak>");
+        let frags = SsmlTextFrags::parse(source.first());
+        let parsed = frags
+            .first()
+            .into_iter()
+            .flat_map(TextFrag::iter)
+            .map(|frag| {
+                (
+                    String::from_utf16_lossy(frag.utf16_text()),
+                    frag.silence_msecs(),
+                    frag.offset_in_original_text(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parsed,
+            [
+                ("ab".to_owned(), None, 7),
+                (String::new(), Some(10), 9),
+                ("c&d".to_owned(), None, 29),
+            ]
+        );
+    }
+}
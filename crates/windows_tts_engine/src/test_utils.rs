@@ -0,0 +1,224 @@
+//! Helpers shared by the unit tests of this crate.
+
+use core::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use windows::Win32::{
+    Foundation::E_POINTER,
+    Media::Speech::{
+        ISpEventSink_Impl, ISpObjectToken, ISpTTSEngineSite, ISpTTSEngineSite_Impl, SPVA_Speak,
+        SPEVENT, SPVES_ABORT, SPVES_CONTINUE, SPVSKIPTYPE, SPVSTATE, SPVST_SENTENCE, SPVTEXTFRAG,
+    },
+};
+use windows_core::{implement, PCWSTR};
+
+use crate::{
+    audio::AudioWriter, CancellationToken, MetricsRecorder, SafeTtsEngine, SpeakFlags,
+    SpeechFormat, TextFrag, TextFragIter,
+};
+
+pub(crate) use crate::private_impls::memory_site;
+
+/// Fragment state used by SAPI for plain text without any XML.
+pub(crate) fn speak_state() -> SPVSTATE {
+    SPVSTATE {
+        eAction: SPVA_Speak,
+        Volume: 100,
+        ..Default::default()
+    }
+}
+
+/// A fragment list that owns its text, like the one SAPI passes to
+/// `ISpTTSEngine::Speak`.
+pub(crate) struct TestFrags {
+    _texts: Vec<Vec<u16>>,
+    frags: Box<[SPVTEXTFRAG]>,
+}
+impl TestFrags {
+    /// One fragment for each text and state. The source offsets are set as if
+    /// the original text was the texts joined with a single space, which is
+    /// also how [`JoinedText`](crate::text::JoinedText) joins them.
+    pub(crate) fn new(parts: &[(&str, SPVSTATE)]) -> Self {
+        let texts = parts
+            .iter()
+            .map(|(text, _)| text.encode_utf16().collect::<Vec<u16>>())
+            .collect::<Vec<_>>();
+        let mut offset = 0;
+        let mut frags = texts
+            .iter()
+            .zip(parts)
+            .map(|(text, (_, state))| {
+                let frag = SPVTEXTFRAG {
+                    pNext: std::ptr::null_mut(),
+                    State: *state,
+                    pTextStart: PCWSTR::from_raw(text.as_ptr()),
+                    ulTextLen: text.len() as u32,
+                    ulTextSrcOffset: offset,
+                };
+                offset += text.len() as u32 + 1;
+                frag
+            })
+            .collect::<Box<[_]>>();
+        // The boxed slice is never moved or resized, so these pointers stay
+        // valid for as long as `self` lives:
+        for index in 1..frags.len() {
+            let next: *mut SPVTEXTFRAG = &mut frags[index];
+            frags[index - 1].pNext = next;
+        }
+        Self {
+            _texts: texts,
+            frags,
+        }
+    }
+
+    /// A single fragment that should be spoken.
+    pub(crate) fn speak(text: &str) -> Self {
+        Self::new(&[(text, speak_state())])
+    }
+
+    /// The first fragment in the list.
+    pub(crate) fn first(&self) -> Option<TextFrag<'_>> {
+        // Safety: the fragments and their text live as long as `self`.
+        self.frags
+            .first()
+            .and_then(|frag| unsafe { TextFrag::new(frag) })
+    }
+}
+
+/// An engine that writes 10 ms of silence for every UTF-16 code unit of text
+/// it is asked to speak.
+#[derive(Debug, Default)]
+pub(crate) struct SilentEngine {
+    pub(crate) cancel: CancellationToken,
+}
+impl SafeTtsEngine for SilentEngine {
+    fn speak(
+        &self,
+        _token: Option<&ISpObjectToken>,
+        _speak_flags: SpeakFlags,
+        wave_format: SpeechFormat,
+        text_fragments: Option<TextFrag<'_>>,
+        output_site: &ISpTTSEngineSite,
+    ) -> windows_core::Result<()> {
+        let _metrics = MetricsRecorder::new(self);
+        let mut writer = AudioWriter::new(output_site);
+        for frag in TextFragIter::new(text_fragments) {
+            if self.cancel.is_cancelled() {
+                return Ok(());
+            }
+            let len = frag.utf16_text().len() as u32;
+            if writer
+                .write_silence(wave_format.silence_bytes(10 * len))?
+                .abort()
+            {
+                return Ok(());
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn supported_formats(&self, _token: Option<&ISpObjectToken>) -> Vec<SpeechFormat> {
+        vec![SpeechFormat::pcm_i16(16_000, 1)]
+    }
+
+    fn cancellation_token(&self) -> Option<&CancellationToken> {
+        Some(&self.cancel)
+    }
+}
+
+/// Everything that a [`TestSite`] received.
+#[derive(Debug, Default)]
+pub(crate) struct TestSiteLog {
+    /// The number of bytes that each `Write` call was asked to write.
+    pub(crate) write_sizes: Vec<usize>,
+    /// The bytes that the site accepted.
+    pub(crate) output: Vec<u8>,
+}
+
+/// An output site that accepts at most `max_write` bytes per `Write` call and
+/// asks the engine to abort once it has been written to `abort_after_writes`
+/// times.
+#[implement(ISpTTSEngineSite)]
+pub(crate) struct TestSite {
+    max_write: u32,
+    abort_after_writes: Option<usize>,
+    log: Arc<Mutex<TestSiteLog>>,
+}
+impl TestSite {
+    pub(crate) fn new(
+        max_write: u32,
+        abort_after_writes: Option<usize>,
+    ) -> (ISpTTSEngineSite, Arc<Mutex<TestSiteLog>>) {
+        let log = Arc::new(Mutex::new(TestSiteLog::default()));
+        let site = Self {
+            max_write,
+            abort_after_writes,
+            log: log.clone(),
+        }
+        .into();
+        (site, log)
+    }
+}
+impl ISpEventSink_Impl for TestSite_Impl {
+    fn AddEvents(&self, _peventarray: *const SPEVENT, _ulcount: u32) -> windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn GetEventInterest(&self, pulleventinterest: *mut u64) -> windows_core::Result<()> {
+        if pulleventinterest.is_null() {
+            return Err(E_POINTER.into());
+        }
+        unsafe { *pulleventinterest = 0 };
+        Ok(())
+    }
+}
+impl ISpTTSEngineSite_Impl for TestSite_Impl {
+    fn GetActions(&self) -> u32 {
+        let writes = self.log.lock().unwrap().write_sizes.len();
+        if self.abort_after_writes.is_some_and(|limit| writes >= limit) {
+            SPVES_ABORT.0 as u32
+        } else {
+            SPVES_CONTINUE.0 as u32
+        }
+    }
+
+    fn Write(&self, pbuff: *const c_void, cb: u32) -> windows_core::Result<u32> {
+        if pbuff.is_null() {
+            return Err(E_POINTER.into());
+        }
+        let accepted = cb.min(self.max_write);
+        let data = unsafe { std::slice::from_raw_parts(pbuff.cast::<u8>(), accepted as usize) };
+        let mut log = self.log.lock().unwrap();
+        log.write_sizes.push(cb as usize);
+        log.output.extend_from_slice(data);
+        Ok(accepted)
+    }
+
+    fn GetRate(&self) -> windows_core::Result<i32> {
+        Ok(0)
+    }
+
+    fn GetVolume(&self) -> windows_core::Result<u16> {
+        Ok(100)
+    }
+
+    fn GetSkipInfo(
+        &self,
+        petype: *mut SPVSKIPTYPE,
+        plnumitems: *mut i32,
+    ) -> windows_core::Result<()> {
+        if petype.is_null() || plnumitems.is_null() {
+            return Err(E_POINTER.into());
+        }
+        unsafe {
+            *petype = SPVST_SENTENCE;
+            *plnumitems = 0;
+        }
+        Ok(())
+    }
+
+    fn CompleteSkip(&self, _ulnumskipped: i32) -> windows_core::Result<()> {
+        Ok(())
+    }
+}
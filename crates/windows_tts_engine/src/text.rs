@@ -0,0 +1,1172 @@
+//! Collect text fragments into a single UTF-16 string and map positions in
+//! that string back to the text that was originally passed to
+//! `ISpVoice::Speak`.
+
+use std::{ops::Range, time::Instant};
+
+use crate::{
+    detect_languages::{
+        equal_language_codes, has_multiple_languages, DetectedLanguage, DetectionError,
+        DetectorBackend, LinguaDetectionService,
+    },
+    utils::lcid_to_language_code,
+    CancellationToken, SpeakFlags, Spva, TextFrag, TextFragIter,
+};
+
+const fn is_high_surrogate(unit: u16) -> bool {
+    matches!(unit, 0xD800..=0xDBFF)
+}
+const fn is_low_surrogate(unit: u16) -> bool {
+    matches!(unit, 0xDC00..=0xDFFF)
+}
+
+/// Check if some UTF-16 text is empty or only contains whitespace (or nul
+/// characters), in which case there is nothing to speak.
+pub fn is_blank(text: &[u16]) -> bool {
+    char::decode_utf16(text.iter().copied())
+        .all(|c| c.is_ok_and(|c| c.is_whitespace() || c == '\0'))
+}
+
+/// Find the words in some UTF-16 text. The returned ranges don't include
+/// whitespace or punctuation at the start or end of a word.
+pub fn word_ranges(text: &[u16]) -> Vec<Range<usize>> {
+    let mut words = Vec::new();
+    let mut current: Option<Range<usize>> = None;
+    let mut index = 0;
+    for c in char::decode_utf16(text.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let len = c.len_utf16();
+        if c.is_whitespace() {
+            words.extend(current.take());
+        } else if c.is_alphanumeric() {
+            match &mut current {
+                Some(word) => word.end = index + len,
+                None => current = Some(index..index + len),
+            }
+        }
+        index += len;
+    }
+    words.extend(current);
+    words
+}
+
+/// Find the sentences in some UTF-16 text. A sentence ends after `.`, `!` or
+/// `?` (and their full width variants) followed by whitespace, or at a line
+/// break. The returned ranges don't include whitespace at the start or end of
+/// a sentence.
+pub fn sentence_ranges(text: &[u16]) -> Vec<Range<usize>> {
+    let mut sentences = Vec::new();
+    let mut current: Option<Range<usize>> = None;
+    let mut ended = false;
+    let mut index = 0;
+    for c in char::decode_utf16(text.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let len = c.len_utf16();
+        if c.is_whitespace() {
+            if ended || c == '\n' {
+                sentences.extend(current.take());
+            }
+        } else {
+            match &mut current {
+                Some(sentence) => sentence.end = index + len,
+                None => current = Some(index..index + len),
+            }
+            ended = matches!(c, '.' | '!' | '?' | '。' | '！' | '？');
+        }
+        index += len;
+    }
+    sentences.extend(current);
+    sentences
+}
+
+/// Split some UTF-16 text into chunks of at most `max_len` code units. Chunks
+/// end after a sentence when possible, otherwise after whitespace and only as
+/// a last resort inside a word. Chunks never split a surrogate pair.
+pub fn sentence_chunks(text: &[u16], max_len: usize) -> Vec<Range<usize>> {
+    let max_len = max_len.max(2);
+    let mut chunks = Vec::new();
+    let mut chunk_start = 0;
+    let mut sentence_end = None;
+    let mut space_end = None;
+    let mut previous = None;
+    let mut index = 0;
+    for c in char::decode_utf16(text.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let len = c.len_utf16();
+        if index + len - chunk_start > max_len {
+            let split = sentence_end.or(space_end).unwrap_or(index);
+            chunks.push(chunk_start..split);
+            chunk_start = split;
+            sentence_end = None;
+            space_end = None;
+        }
+        index += len;
+        if c.is_whitespace() {
+            space_end = Some(index);
+            if c == '\n' || matches!(previous, Some('.' | '!' | '?' | '。' | '！' | '？')) {
+                sentence_end = Some(index);
+            }
+        }
+        previous = Some(c);
+    }
+    if chunk_start < index {
+        chunks.push(chunk_start..index);
+    }
+    chunks
+}
+
+/// Spoken names of punctuation characters in English.
+const ENGLISH_PUNCTUATION: &[(char, &str)] = &[
+    ('.', "period"),
+    (',', "comma"),
+    ('!', "exclamation mark"),
+    ('?', "question mark"),
+    (':', "colon"),
+    (';', "semicolon"),
+    ('-', "dash"),
+    ('_', "underscore"),
+    ('/', "slash"),
+    ('\\', "backslash"),
+    ('@', "at"),
+    ('#', "hash"),
+    ('$', "dollar"),
+    ('%', "percent"),
+    ('&', "ampersand"),
+    ('*', "asterisk"),
+    ('+', "plus"),
+    ('=', "equals"),
+    ('(', "open parenthesis"),
+    (')', "close parenthesis"),
+    ('[', "open bracket"),
+    (']', "close bracket"),
+    ('{', "open brace"),
+    ('}', "close brace"),
+    ('<', "less than"),
+    ('>', "greater than"),
+    ('\'', "apostrophe"),
+    ('"', "quote"),
+];
+const GERMAN_PUNCTUATION: &[(char, &str)] = &[
+    ('.', "Punkt"),
+    (',', "Komma"),
+    ('!', "Ausrufezeichen"),
+    ('?', "Fragezeichen"),
+    (':', "Doppelpunkt"),
+    (';', "Semikolon"),
+    ('-', "Bindestrich"),
+    ('_', "Unterstrich"),
+    ('/', "Schrägstrich"),
+    ('\\', "Backslash"),
+    ('@', "at"),
+    ('#', "Raute"),
+    ('$', "Dollar"),
+    ('%', "Prozent"),
+    ('&', "und-Zeichen"),
+    ('*', "Sternchen"),
+    ('+', "plus"),
+    ('=', "gleich"),
+    ('(', "Klammer auf"),
+    (')', "Klammer zu"),
+    ('[', "eckige Klammer auf"),
+    (']', "eckige Klammer zu"),
+    ('{', "geschweifte Klammer auf"),
+    ('}', "geschweifte Klammer zu"),
+    ('<', "kleiner als"),
+    ('>', "größer als"),
+    ('\'', "Apostroph"),
+    ('"', "Anführungszeichen"),
+];
+const SWEDISH_PUNCTUATION: &[(char, &str)] = &[
+    ('.', "punkt"),
+    (',', "komma"),
+    ('!', "utropstecken"),
+    ('?', "frågetecken"),
+    (':', "kolon"),
+    (';', "semikolon"),
+    ('-', "bindestreck"),
+    ('_', "understreck"),
+    ('/', "snedstreck"),
+    ('\\', "omvänt snedstreck"),
+    ('@', "snabel-a"),
+    ('#', "fyrkant"),
+    ('$', "dollar"),
+    ('%', "procent"),
+    ('&', "och-tecken"),
+    ('*', "asterisk"),
+    ('+', "plus"),
+    ('=', "lika med"),
+    ('(', "vänsterparentes"),
+    (')', "högerparentes"),
+    ('[', "vänster hakparentes"),
+    (']', "höger hakparentes"),
+    ('{', "vänster klammerparentes"),
+    ('}', "höger klammerparentes"),
+    ('<', "mindre än"),
+    ('>', "större än"),
+    ('\'', "apostrof"),
+    ('"', "citattecken"),
+];
+const FRENCH_PUNCTUATION: &[(char, &str)] = &[
+    ('.', "point"),
+    (',', "virgule"),
+    ('!', "point d'exclamation"),
+    ('?', "point d'interrogation"),
+    (':', "deux-points"),
+    (';', "point-virgule"),
+    ('-', "tiret"),
+    ('_', "tiret bas"),
+    ('/', "barre oblique"),
+    ('\\', "barre oblique inversée"),
+    ('@', "arobase"),
+    ('#', "dièse"),
+    ('$', "dollar"),
+    ('%', "pour cent"),
+    ('&', "esperluette"),
+    ('*', "astérisque"),
+    ('+', "plus"),
+    ('=', "égal"),
+    ('(', "parenthèse ouvrante"),
+    (')', "parenthèse fermante"),
+    ('[', "crochet ouvrant"),
+    (']', "crochet fermant"),
+    ('{', "accolade ouvrante"),
+    ('}', "accolade fermante"),
+    ('<', "inférieur à"),
+    ('>', "supérieur à"),
+    ('\'', "apostrophe"),
+    ('"', "guillemet"),
+];
+const SPANISH_PUNCTUATION: &[(char, &str)] = &[
+    ('.', "punto"),
+    (',', "coma"),
+    ('!', "signo de exclamación"),
+    ('?', "signo de interrogación"),
+    (':', "dos puntos"),
+    (';', "punto y coma"),
+    ('-', "guion"),
+    ('_', "guion bajo"),
+    ('/', "barra"),
+    ('\\', "barra invertida"),
+    ('@', "arroba"),
+    ('#', "almohadilla"),
+    ('$', "dólar"),
+    ('%', "por ciento"),
+    ('&', "et"),
+    ('*', "asterisco"),
+    ('+', "más"),
+    ('=', "igual"),
+    ('(', "abre paréntesis"),
+    (')', "cierra paréntesis"),
+    ('[', "abre corchete"),
+    (']', "cierra corchete"),
+    ('{', "abre llave"),
+    ('}', "cierra llave"),
+    ('<', "menor que"),
+    ('>', "mayor que"),
+    ('\'', "apóstrofo"),
+    ('"', "comillas"),
+];
+
+/// Spoken names of punctuation characters in the language of a code like
+/// `"de-DE"` or `"sv"`. Falls back to English for languages without a table.
+pub fn punctuation_names(lang_code: &str) -> &'static [(char, &'static str)] {
+    const TABLES: &[(&str, &[(char, &str)])] = &[
+        ("en", ENGLISH_PUNCTUATION),
+        ("de", GERMAN_PUNCTUATION),
+        ("sv", SWEDISH_PUNCTUATION),
+        ("fr", FRENCH_PUNCTUATION),
+        ("es", SPANISH_PUNCTUATION),
+    ];
+    TABLES
+        .iter()
+        .find(|(code, _)| equal_language_codes(code, lang_code))
+        .map_or(ENGLISH_PUNCTUATION, |(_, names)| names)
+}
+
+/// Find the name of a punctuation character in a table returned by
+/// [`punctuation_names`].
+fn find_punctuation_name(names: &'static [(char, &'static str)], c: char) -> Option<&'static str> {
+    names
+        .iter()
+        .find(|(punctuation, _)| *punctuation == c)
+        .map(|(_, name)| *name)
+}
+
+/// English name of a punctuation character, used when the character should be
+/// read aloud.
+pub fn punctuation_name(c: char) -> Option<&'static str> {
+    find_punctuation_name(ENGLISH_PUNCTUATION, c)
+}
+
+/// Expand text so that every character is spoken on its own, for example
+/// "ab1." becomes "a b 1 period". Whitespace is dropped and punctuation is
+/// replaced by its name in `names`, see [`punctuation_names`].
+///
+/// Punctuation is always named here, even if
+/// [`SpeakFlags::speak_punctuation`](crate::SpeakFlags::speak_punctuation) is
+/// `false`, since spelling out text should read every character.
+///
+/// Returns the expanded text together with the index in `text` that each
+/// UTF-16 code unit of the expanded text came from.
+pub fn spell_out(text: &[u16], names: &'static [(char, &'static str)]) -> (Vec<u16>, Vec<usize>) {
+    let mut expanded = Vec::new();
+    let mut source_indexes = Vec::new();
+    let mut index = 0;
+    for c in char::decode_utf16(text.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let len = c.len_utf16();
+        if !c.is_whitespace() {
+            if !expanded.is_empty() {
+                expanded.push(' ' as u16);
+                source_indexes.push(index);
+            }
+            if let Some(name) = find_punctuation_name(names, c) {
+                for unit in name.encode_utf16() {
+                    expanded.push(unit);
+                    source_indexes.push(index);
+                }
+            } else {
+                let mut buffer = [0; 2];
+                for (offset, &unit) in c.encode_utf16(&mut buffer).iter().enumerate() {
+                    expanded.push(unit);
+                    source_indexes.push(index + offset);
+                }
+            }
+        }
+        index += len;
+    }
+    (expanded, source_indexes)
+}
+
+/// Replace punctuation with its name in `names` surrounded by spaces, for
+/// example "Hi, you." becomes "Hi comma  you period " in English. Apostrophes
+/// between letters are kept so that words like "don't" are read normally.
+///
+/// Used when
+/// [`SpeakFlags::speak_punctuation`](crate::SpeakFlags::speak_punctuation) is
+/// `true`.
+///
+/// Returns the expanded text together with the index in `text` that each
+/// UTF-16 code unit of the expanded text came from.
+pub fn expand_punctuation(
+    text: &[u16],
+    names: &'static [(char, &'static str)],
+) -> (Vec<u16>, Vec<usize>) {
+    let mut expanded = Vec::with_capacity(text.len());
+    let mut source_indexes = Vec::with_capacity(text.len());
+    let mut chars = char::decode_utf16(text.iter().copied())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .peekable();
+    let mut previous = None;
+    let mut index = 0;
+    while let Some(c) = chars.next() {
+        let len = c.len_utf16();
+        let in_word = c == '\''
+            && previous.is_some_and(char::is_alphabetic)
+            && chars.peek().is_some_and(|next| next.is_alphabetic());
+        match find_punctuation_name(names, c) {
+            Some(name) if !in_word => {
+                for unit in [' ' as u16]
+                    .into_iter()
+                    .chain(name.encode_utf16())
+                    .chain([' ' as u16])
+                {
+                    expanded.push(unit);
+                    source_indexes.push(index);
+                }
+            }
+            _ => {
+                let mut buffer = [0; 2];
+                for (offset, &unit) in c.encode_utf16(&mut buffer).iter().enumerate() {
+                    expanded.push(unit);
+                    source_indexes.push(index + offset);
+                }
+            }
+        }
+        previous = Some(c);
+        index += len;
+    }
+    (expanded, source_indexes)
+}
+
+/// A position in a [`JoinedText`] translated back to the fragment it came
+/// from.
+#[derive(Debug, Clone, Copy)]
+pub struct SourcePosition<'a> {
+    /// The fragment that contains the position.
+    pub frag: TextFrag<'a>,
+    /// Index of the UTF-16 code unit inside the fragment's text.
+    pub index_in_frag: usize,
+    /// Offset of the UTF-16 code unit inside the text that was originally
+    /// passed to `ISpVoice::Speak`.
+    pub offset_in_original_text: u32,
+}
+
+/// A range of the text originally passed to `ISpVoice::Speak`, this is the
+/// format SAPI expects in word and sentence boundary events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceRange {
+    /// Offset of the first UTF-16 code unit in the original text.
+    pub offset: u32,
+    /// Number of UTF-16 code units in the original text.
+    pub len: u32,
+}
+
+/// A fragment's part of the text created by [`TextFrag::to_owned_text`].
+#[derive(Debug, Clone)]
+pub struct OffsetEntry<'a> {
+    /// The fragment that the text came from.
+    pub frag: TextFrag<'a>,
+    /// Range of `char` indexes (not bytes) in the owned text.
+    pub chars: Range<usize>,
+    /// Offset of the fragment's first UTF-16 code unit in the text that was
+    /// originally passed to `ISpVoice::Speak`.
+    pub offset_in_original_text: u32,
+}
+impl OffsetEntry<'_> {
+    /// Translate a `char` index in the owned text to a UTF-16 offset in the
+    /// text that was originally passed to `ISpVoice::Speak`.
+    ///
+    /// Returns `None` if the index isn't inside this entry's range.
+    pub fn original_offset(&self, char_index: usize) -> Option<u32> {
+        if !self.chars.contains(&char_index) {
+            return None;
+        }
+        let utf16_index: usize = char::decode_utf16(self.frag.utf16_text().iter().copied())
+            .take(char_index - self.chars.start)
+            .map(|c| c.map_or(1, char::len_utf16))
+            .sum();
+        Some(self.offset_in_original_text + utf16_index as u32)
+    }
+
+    /// Find the entry that contains a `char` index and translate it using
+    /// [`Self::original_offset`].
+    pub fn find_original_offset(entries: &[Self], char_index: usize) -> Option<u32> {
+        let entry = entries.get(entries.partition_point(|entry| entry.chars.end <= char_index))?;
+        entry.original_offset(char_index)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FragSpan<'a> {
+    frag: TextFrag<'a>,
+    /// Index of the fragment's first UTF-16 code unit in the joined text.
+    start: usize,
+    /// Length of the fragment's text, not including the separator after it.
+    len: usize,
+    /// If the fragment's text was changed before it was joined then this
+    /// contains the index in the fragment's original text for every UTF-16
+    /// code unit in the joined text.
+    source_indexes: Option<Vec<usize>>,
+}
+
+/// Part of the joined text that should be spoken, or an action that should
+/// happen between such parts. Created by [`JoinedText::split_at_actions`].
+#[derive(Debug, Clone)]
+pub enum SpeakSegment<'a> {
+    /// Synthesize this range of the joined text.
+    Text(DetectedLanguage),
+    /// Write silence to the output, requested by a fragment with the
+    /// `SPVA_Silence` action.
+    Silence { milliseconds: u32 },
+    /// Notify the client that the audio reached a bookmark, requested by a
+    /// fragment with the `SPVA_Bookmark` action.
+    Bookmark { name: &'a [u16] },
+}
+
+/// A part of a [`JoinedText`] labeled with its language and the voice that
+/// speaks it. Created by [`JoinedText::transcript`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptSpan {
+    /// Range of UTF-16 code units in the joined text.
+    pub range: Range<usize>,
+    /// The same text in the text originally passed to `ISpVoice::Speak`,
+    /// `None` if the span only covers separators between fragments.
+    pub source: Option<SourceRange>,
+    /// The joined text in this span, with spelled out fragments and
+    /// punctuation already expanded.
+    pub text: String,
+    /// The detected or specified languages, with the most certain languages
+    /// earlier in the list. Empty if the default voice should be used.
+    pub languages: Vec<String>,
+    /// Name of the voice that speaks this span.
+    pub voice: Option<String>,
+}
+
+/// The text of a fragment list joined with a space after each fragment.
+///
+/// Fragments with the `SPVA_SpellOut` action are expanded using
+/// [`spell_out`]. Punctuation in other fragments is expanded using
+/// [`expand_punctuation`] if
+/// [`SpeakFlags::speak_punctuation`](crate::SpeakFlags::speak_punctuation) is
+/// `true`. Punctuation is named in the fragment's language, or in English if
+/// the fragment doesn't specify a language.
+///
+/// Language detection and synthesis operate on the joined text, so indexes
+/// found in it need to be translated using [`JoinedText::source_position`] or
+/// [`JoinedText::source_range`] before they are reported to the client.
+#[derive(Debug, Clone)]
+pub struct JoinedText<'a> {
+    utf16: Vec<u16>,
+    /// Sorted by start index.
+    spans: Vec<FragSpan<'a>>,
+}
+impl<'a> JoinedText<'a> {
+    pub fn new(text_fragments: Option<TextFrag<'a>>, speak_flags: SpeakFlags) -> Self {
+        let mut utf16 = Vec::new();
+        let mut spans = Vec::new();
+        for frag in TextFragIter::new(text_fragments) {
+            let start = utf16.len();
+            let mut source_indexes = None;
+            let punctuation = punctuation_names(
+                lcid_to_language_code(frag.frag_state().lang_id()).unwrap_or("en"),
+            );
+            if frag.bookmark().is_some() {
+                // Bookmark names shouldn't be spoken
+            } else if frag.frag_state().action() == Spva::SpellOut {
+                let (expanded, indexes) = spell_out(frag.utf16_text(), punctuation);
+                utf16.extend(expanded);
+                source_indexes = Some(indexes);
+            } else if speak_flags.speak_punctuation() {
+                let (expanded, indexes) = expand_punctuation(frag.utf16_text(), punctuation);
+                utf16.extend(expanded);
+                source_indexes = Some(indexes);
+            } else {
+                utf16.extend_from_slice(frag.utf16_text());
+            }
+            spans.push(FragSpan {
+                frag,
+                start,
+                len: utf16.len() - start,
+                source_indexes,
+            });
+            utf16.push(' ' as u16);
+        }
+        Self { utf16, spans }
+    }
+
+    /// The joined text, UTF-16 encoded without a trailing nul character.
+    pub fn utf16(&self) -> &[u16] {
+        &self.utf16
+    }
+
+    /// Find the fragment that a UTF-16 index in the joined text belongs to.
+    ///
+    /// Returns `None` for the separators that were inserted between fragments
+    /// and for indexes outside the joined text.
+    pub fn source_position(&self, index: usize) -> Option<SourcePosition<'a>> {
+        let span_index = self
+            .spans
+            .partition_point(|span| span.start + span.len <= index);
+        let span = self.spans.get(span_index)?;
+        if index < span.start {
+            return None;
+        }
+        let index_in_frag = match &span.source_indexes {
+            Some(source_indexes) => source_indexes[index - span.start],
+            None => index - span.start,
+        };
+        Some(SourcePosition {
+            frag: span.frag,
+            index_in_frag,
+            offset_in_original_text: span.frag.offset_in_original_text() + index_in_frag as u32,
+        })
+    }
+
+    /// The fragments that overlap a range of the joined text together with
+    /// the part of the range that belongs to each fragment. Separators between
+    /// fragments aren't included.
+    pub fn fragments_in(
+        &self,
+        range: Range<usize>,
+    ) -> impl Iterator<Item = (TextFrag<'a>, Range<usize>)> + '_ {
+        self.spans.iter().filter_map(move |span| {
+            let start = span.start.max(range.start);
+            let end = (span.start + span.len).min(range.end);
+            (start < end).then_some((span.frag, start..end))
+        })
+    }
+
+    /// Split the joined text into ranges of consecutive fragments that have
+    /// the same language id (`SPVSTATE::LangID`), for example because of an
+    /// SSML `xml:lang` attribute.
+    ///
+    /// The ranges are sorted and cover the whole joined text. The language
+    /// code is `None` when the client didn't specify a language (a `LangID` of
+    /// zero) or when it isn't a language we know about.
+    pub fn language_tagged_ranges(&self) -> Vec<(Range<usize>, Option<&'static str>)> {
+        let mut ranges: Vec<(Range<usize>, Option<&'static str>)> = Vec::new();
+        for span in &self.spans {
+            // Include the separator after the fragment:
+            let end = span.start + span.len + 1;
+            let lang_id = span.frag.frag_state().lang_id();
+            let lang = match lang_id {
+                0 => None,
+                _ => {
+                    let code = lcid_to_language_code(lang_id);
+                    if code.is_none() {
+                        log::debug!("Ignored unknown language id {lang_id:#x} for text fragment");
+                    }
+                    code
+                }
+            };
+            match ranges.last_mut() {
+                // Bookmarks and silence have no text, so don't split at them:
+                Some((range, previous)) if *previous == lang || span.len == 0 => range.end = end,
+                _ => {
+                    let start = ranges.last().map_or(0, |(range, _)| range.end);
+                    ranges.push((start..end, lang));
+                }
+            }
+        }
+        ranges
+    }
+
+    /// Find the languages of the whole joined text, as sorted ranges that can
+    /// be passed to [`Self::split_at_actions`].
+    ///
+    /// - If `forced_language` is set then it is used for all text.
+    /// - Otherwise languages that the client specified are used, see
+    ///   [`Self::language_tagged_ranges`], and only the language of untagged
+    ///   text is detected.
+    /// - Detection is skipped if `voice_languages` only contains one language
+    ///   (ignoring regions), in which case untagged ranges have no languages
+    ///   and should use the default voice.
+    ///
+    /// `prepare_detection_service` is only called if some text needs to be
+    /// detected and is given `voice_languages`, so engines can create the
+    /// detector lazily and keep it between calls.
+    ///
+    /// Returns `None` if `cancel` was cancelled before all text was detected.
+    pub fn detect_language_ranges<S, G>(
+        &self,
+        forced_language: Option<String>,
+        voice_languages: &[S],
+        prepare_detection_service: impl FnOnce(&[S]) -> Result<G, DetectionError>,
+        cancel: &CancellationToken,
+    ) -> Option<Vec<DetectedLanguage>>
+    where
+        S: AsRef<str>,
+        G: std::ops::Deref<Target = Option<LinguaDetectionService>>,
+    {
+        let text_utf16 = self.utf16();
+        if is_blank(text_utf16) {
+            // Only silence or bookmarks, so don't bother detecting languages:
+            return Some(Vec::new());
+        }
+        if let Some(lang) = forced_language {
+            log::debug!("Speak - Skipped language detection since the voice always uses {lang:?}");
+            return Some(vec![DetectedLanguage {
+                start: 0,
+                end: text_utf16.len().saturating_sub(1),
+                languages: vec![lang],
+                scores: Vec::new(),
+            }]);
+        }
+
+        let has_multiple_languages = has_multiple_languages(
+            voice_languages
+                .iter()
+                .map(AsRef::as_ref)
+                // ignore difference between `en-US` and `en-GB`:
+                .map(|lang| {
+                    lang.split_once(['_', '-'])
+                        .map(|(prefix, _)| prefix)
+                        .unwrap_or(lang)
+                }),
+        );
+        // Prefer languages that the client specified, for example using
+        // SSML's `xml:lang` attribute, and only detect the language of
+        // untagged text:
+        let tagged_ranges = self.language_tagged_ranges();
+        let needs_detection = has_multiple_languages
+            && tagged_ranges
+                .iter()
+                .any(|(range, lang)| lang.is_none() && !is_blank(&text_utf16[range.clone()]));
+        if !has_multiple_languages {
+            log::debug!("Speak - Skipped language detection since only one language is installed");
+        }
+
+        let detection_guard = needs_detection.then(|| {
+            prepare_detection_service(voice_languages)
+                .expect("Failed to find language detection service")
+        });
+        let detection_service = detection_guard.as_deref().and_then(Option::as_ref);
+
+        let mut ranges = Vec::with_capacity(tagged_ranges.len());
+        for (range, lang) in tagged_ranges {
+            if cancel.is_cancelled() {
+                return None;
+            }
+            match (lang, &detection_service) {
+                (Some(lang), _) => {
+                    log::debug!(
+                        "Speak - Using language {lang:?} specified for range {}..{}",
+                        range.start,
+                        range.end
+                    );
+                    ranges.push(DetectedLanguage {
+                        start: range.start,
+                        end: range.end - 1,
+                        languages: vec![lang.to_owned()],
+                        scores: Vec::new(),
+                    });
+                }
+                (None, Some(detection_service)) if !is_blank(&text_utf16[range.clone()]) => {
+                    let started_lang_detect = Instant::now();
+                    let detected = detection_service
+                        .recognize_text(&text_utf16[range.clone()])
+                        .expect("Failed to recognize text language");
+                    log::debug!(
+                        "Speak - Detected languages{} (duration: {:?})",
+                        if cfg!(not(feature = "lingua")) {
+                            ""
+                        } else {
+                            match detection_service.backend() {
+                                DetectorBackend::Lingua => " using the Lingua library",
+                                DetectorBackend::Microsoft => " using Microsoft Language Detection",
+                            }
+                        },
+                        started_lang_detect.elapsed()
+                    );
+                    if detected.is_empty() {
+                        log::debug!("Speak - No language detected, using the default language");
+                    }
+                    let detected = DetectedLanguage::cover(detected, range.len());
+                    ranges.extend(detected.into_iter().map(|mut detected| {
+                        detected.start += range.start;
+                        detected.end += range.start;
+                        detected
+                    }));
+                }
+                (None, _) => ranges.push(DetectedLanguage {
+                    start: range.start,
+                    end: range.end - 1,
+                    languages: Vec::new(),
+                    scores: Vec::new(),
+                }),
+            }
+        }
+        Some(DetectedLanguage::coalesce(ranges))
+    }
+
+    /// Clamp an inclusive range to the joined text and widen it so that it
+    /// doesn't split a surrogate pair.
+    ///
+    /// Returns `None` if the range doesn't overlap the text at all.
+    pub fn snap_to_char_boundaries(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        let last = self.utf16.len().checked_sub(1)?;
+        let mut end = end.min(last);
+        let mut start = start;
+        if start > end {
+            return None;
+        }
+        if start > 0
+            && is_low_surrogate(self.utf16[start])
+            && is_high_surrogate(self.utf16[start - 1])
+        {
+            start -= 1;
+        }
+        if end < last && is_high_surrogate(self.utf16[end]) && is_low_surrogate(self.utf16[end + 1])
+        {
+            end += 1;
+        }
+        Some((start, end))
+    }
+
+    /// Indexes in the joined text where silence should be inserted or
+    /// bookmarks should be reported.
+    pub fn actions(&self) -> impl Iterator<Item = (usize, SpeakSegment<'a>)> + '_ {
+        self.spans.iter().filter_map(|span| {
+            let action = if let Some(milliseconds) = span.frag.silence_msecs() {
+                SpeakSegment::Silence { milliseconds }
+            } else {
+                SpeakSegment::Bookmark {
+                    name: span.frag.bookmark()?,
+                }
+            };
+            Some((span.start, action))
+        })
+    }
+
+    /// Split detected language ranges so that silence and bookmark fragments
+    /// are handled at the right place between the synthesized text.
+    ///
+    /// The ranges should be sorted and not overlap.
+    pub fn split_at_actions(&self, ranges: Vec<DetectedLanguage>) -> Vec<SpeakSegment<'a>> {
+        let mut actions = self.actions().peekable();
+        let mut segments = Vec::new();
+        for range in ranges {
+            let mut start = range.start;
+            while let Some((index, _)) = actions.peek() {
+                let index = *index;
+                if index > range.end {
+                    break;
+                }
+                if index > start {
+                    segments.push(SpeakSegment::Text(DetectedLanguage {
+                        start,
+                        end: index - 1,
+                        languages: range.languages.clone(),
+                        scores: range.scores.clone(),
+                    }));
+                    start = index;
+                }
+                segments.extend(actions.next().map(|(_, action)| action));
+            }
+            if start <= range.end {
+                segments.push(SpeakSegment::Text(DetectedLanguage { start, ..range }));
+            }
+        }
+        segments.extend(actions.map(|(_, action)| action));
+        segments
+    }
+
+    /// Split text segments that are longer than `max_len` UTF-16 code units
+    /// into several segments using [`sentence_chunks`], so that they can be
+    /// synthesized one at a time. Other segments are kept as is.
+    pub fn split_long_segments(
+        &self,
+        segments: Vec<SpeakSegment<'a>>,
+        max_len: usize,
+    ) -> Vec<SpeakSegment<'a>> {
+        let mut result = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let range = match segment {
+                SpeakSegment::Text(range)
+                    if range.start < self.utf16.len() && range.end - range.start >= max_len =>
+                {
+                    range
+                }
+                other => {
+                    result.push(other);
+                    continue;
+                }
+            };
+            let end = range.end.min(self.utf16.len() - 1);
+            for chunk in sentence_chunks(&self.utf16[range.start..=end], max_len) {
+                result.push(SpeakSegment::Text(DetectedLanguage {
+                    start: range.start + chunk.start,
+                    end: range.start + chunk.end - 1,
+                    languages: range.languages.clone(),
+                    scores: range.scores.clone(),
+                }));
+            }
+        }
+        result
+    }
+
+    /// Translate an inclusive range of the joined text to a range of the text
+    /// originally passed to `ISpVoice::Speak`.
+    ///
+    /// Returns `None` if the range only covers separators.
+    pub fn source_range(&self, start: usize, end: usize) -> Option<SourceRange> {
+        let (start, end) = self.snap_to_char_boundaries(start, end)?;
+        let first = (start..=end).find_map(|index| self.source_position(index))?;
+        let last = (start..=end)
+            .rev()
+            .find_map(|index| self.source_position(index))?;
+        Some(SourceRange {
+            offset: first.offset_in_original_text,
+            len: last.offset_in_original_text + 1 - first.offset_in_original_text,
+        })
+    }
+
+    /// Split the joined text into the parts that are synthesized for some
+    /// detected language ranges and label each part with the voice that
+    /// `select_voice` picks for it. This can be used for subtitles or to
+    /// check language detection without synthesizing any audio.
+    ///
+    /// Like when speaking, the ranges are split at silence and bookmark
+    /// fragments, clamped to the text and parts that only contain whitespace
+    /// are skipped.
+    pub fn transcript(
+        &self,
+        ranges: Vec<DetectedLanguage>,
+        mut select_voice: impl FnMut(&DetectedLanguage) -> Option<String>,
+    ) -> Vec<TranscriptSpan> {
+        self.split_at_actions(ranges)
+            .into_iter()
+            .filter_map(|segment| {
+                let SpeakSegment::Text(range) = segment else {
+                    return None;
+                };
+                let (start, end) = self.snap_to_char_boundaries(range.start, range.end)?;
+                let text = &self.utf16[start..=end];
+                if is_blank(text) {
+                    return None;
+                }
+                Some(TranscriptSpan {
+                    range: start..end + 1,
+                    source: self.source_range(start, end),
+                    text: String::from_utf16_lossy(text),
+                    voice: select_voice(&range),
+                    languages: range.languages,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{speak_state, TestFrags};
+    use windows::Win32::Media::Speech::SPVSTATE;
+
+    fn utf16(text: &str) -> Vec<u16> {
+        text.encode_utf16().collect()
+    }
+
+    #[test]
+    fn word_ranges_skip_punctuation() {
+        assert_eq!(
+            word_ranges(&utf16("Hello, world! It's 42.")),
+            [0..5, 7..12, 14..18, 19..21]
+        );
+    }
+
+    #[test]
+    fn word_ranges_count_utf16_code_units() {
+        // The emoji is two code units and isn't part of any word:
+        assert_eq!(word_ranges(&utf16("a 😀b  c")), [0..1, 4..5, 7..8]);
+    }
+
+    #[test]
+    fn sentence_ranges_end_at_punctuation_followed_by_whitespace() {
+        assert_eq!(
+            sentence_ranges(&utf16(" One. Two!  3.5 is\nnext")),
+            [1..5, 6..10, 12..18, 19..23]
+        );
+    }
+
+    #[test]
+    fn sentence_chunks_prefer_sentence_ends() {
+        assert_eq!(
+            sentence_chunks(&utf16("One two. Three four five."), 12),
+            [0..9, 9..20, 20..25]
+        );
+    }
+
+    #[test]
+    fn sentence_chunks_split_words_as_last_resort() {
+        assert_eq!(sentence_chunks(&utf16("abcdefgh"), 3), [0..3, 3..6, 6..8]);
+    }
+
+    #[test]
+    fn sentence_chunks_keep_surrogate_pairs() {
+        assert_eq!(sentence_chunks(&utf16("a😀😀"), 2), [0..1, 1..3, 3..5]);
+    }
+
+    /// The expanded text as a string together with its source indexes.
+    fn expanded((text, source_indexes): (Vec<u16>, Vec<usize>)) -> (String, Vec<usize>) {
+        assert_eq!(text.len(), source_indexes.len());
+        (String::from_utf16_lossy(&text), source_indexes)
+    }
+
+    #[test]
+    fn punctuation_names_match_language_with_region() {
+        let period = |lang| find_punctuation_name(punctuation_names(lang), '.');
+        assert_eq!(period("de-DE"), Some("Punkt"));
+        assert_eq!(period("sv"), Some("punkt"));
+        assert_eq!(period("fr-CA"), Some("point"));
+        assert_eq!(period("es-MX"), Some("punto"));
+    }
+
+    #[test]
+    fn punctuation_names_fall_back_to_english() {
+        for lang in ["pt-BR", "ja", ""] {
+            assert_eq!(
+                find_punctuation_name(punctuation_names(lang), ','),
+                Some("comma")
+            );
+        }
+    }
+
+    #[test]
+    fn spell_out_maps_units_to_source_indexes() {
+        let names = punctuation_names("en");
+        assert_eq!(
+            expanded(spell_out(&utf16("a😀 ,"), names)),
+            ("a 😀 comma".to_owned(), vec![0, 1, 1, 2, 4, 4, 4, 4, 4, 4])
+        );
+    }
+
+    #[test]
+    fn expand_punctuation_maps_units_to_source_indexes() {
+        let names = punctuation_names("en");
+        let (text, source_indexes) = expanded(expand_punctuation(&utf16("Hi, don't."), names));
+        assert_eq!(text, "Hi comma  don't period ");
+        assert_eq!(source_indexes[..2], [0, 1]);
+        assert!(source_indexes[2..9].iter().all(|&index| index == 2));
+        assert_eq!(source_indexes[9..15], [3, 4, 5, 6, 7, 8]);
+        assert!(source_indexes[15..].iter().all(|&index| index == 9));
+    }
+
+    #[test]
+    fn expand_punctuation_names_quotes_outside_words() {
+        let names = punctuation_names("en");
+        let (text, source_indexes) = expanded(expand_punctuation(&utf16("'ok'"), names));
+        assert_eq!(text, " apostrophe ok apostrophe ");
+        assert_eq!(source_indexes[12..14], [1, 2]);
+        assert_eq!(source_indexes.last(), Some(&3));
+    }
+
+    #[test]
+    fn source_range_maps_words_to_original_text() {
+        let frags = TestFrags::new(&[("Hello", speak_state()), ("big world", speak_state())]);
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        assert_eq!(String::from_utf16_lossy(joined.utf16()), "Hello big world ");
+
+        for word in word_ranges(joined.utf16()) {
+            let source = joined.source_range(word.start, word.end - 1).unwrap();
+            assert_eq!(source.offset as usize, word.start);
+            assert_eq!(source.len as usize, word.len());
+        }
+        // The separator between the fragments isn't part of the original text:
+        assert_eq!(joined.source_range(5, 5), None);
+    }
+
+    #[test]
+    fn transcript_labels_spans_with_language_and_voice() {
+        let english = SPVSTATE {
+            LangID: 0x0409,
+            ..speak_state()
+        };
+        let german = SPVSTATE {
+            LangID: 0x0407,
+            ..speak_state()
+        };
+        let frags = TestFrags::new(&[
+            ("Hello there", english),
+            ("Guten Tag", german),
+            ("and goodbye", english),
+        ]);
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        let ranges = joined
+            .language_tagged_ranges()
+            .into_iter()
+            .map(|(range, lang)| DetectedLanguage {
+                start: range.start,
+                end: range.end - 1,
+                languages: lang.into_iter().map(str::to_owned).collect(),
+                scores: Vec::new(),
+            })
+            .collect();
+
+        let transcript = joined.transcript(ranges, |range| {
+            let voice = match range.languages.first()?.as_str() {
+                "en-US" => "Zira",
+                "de-DE" => "Hedda",
+                _ => return None,
+            };
+            Some(voice.to_owned())
+        });
+        let spans = transcript
+            .iter()
+            .map(|span| {
+                (
+                    span.range.clone(),
+                    span.source.map(|source| (source.offset, source.len)),
+                    span.text.as_str(),
+                    span.languages
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>(),
+                    span.voice.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                (
+                    0..12,
+                    Some((0, 11)),
+                    "Hello there ",
+                    vec!["en-US"],
+                    Some("Zira")
+                ),
+                (
+                    12..22,
+                    Some((12, 9)),
+                    "Guten Tag ",
+                    vec!["de-DE"],
+                    Some("Hedda")
+                ),
+                (
+                    22..34,
+                    Some((22, 11)),
+                    "and goodbye ",
+                    vec!["en-US"],
+                    Some("Zira")
+                ),
+            ]
+        );
+    }
+
+    /// Tagged English text followed by untagged text.
+    fn tagged_and_untagged_frags() -> TestFrags {
+        let english = SPVSTATE {
+            LangID: 0x0409,
+            ..speak_state()
+        };
+        TestFrags::new(&[("Hello there", english), ("Guten Tag", speak_state())])
+    }
+
+    fn no_detection_service(
+        _: &[&str],
+    ) -> Result<Box<Option<LinguaDetectionService>>, DetectionError> {
+        unreachable!("the language shouldn't be detected")
+    }
+
+    fn spans(ranges: &[DetectedLanguage]) -> Vec<(usize, usize, Vec<&str>)> {
+        ranges
+            .iter()
+            .map(|range| {
+                let languages = range.languages.iter().map(String::as_str).collect();
+                (range.start, range.end, languages)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detect_language_ranges_use_forced_language() {
+        let frags = tagged_and_untagged_frags();
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        let ranges = joined
+            .detect_language_ranges(
+                Some("sv-SE".to_owned()),
+                &["en-US", "de-DE"],
+                no_detection_service,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+        assert_eq!(spans(&ranges), [(0, 21, vec!["sv-SE"])]);
+    }
+
+    #[test]
+    fn detect_language_ranges_skip_detection_for_a_single_language() {
+        let frags = tagged_and_untagged_frags();
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        let ranges = joined
+            .detect_language_ranges(
+                None,
+                &["en-US", "en-GB"],
+                no_detection_service,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+        assert_eq!(spans(&ranges), [(0, 11, vec!["en-US"]), (12, 21, vec![])]);
+    }
+
+    #[test]
+    fn detect_language_ranges_stop_when_cancelled() {
+        let frags = tagged_and_untagged_frags();
+        let joined = JoinedText::new(frags.first(), SpeakFlags::default());
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let ranges = joined.detect_language_ranges(None, &["en-US"], no_detection_service, &cancel);
+        assert!(ranges.is_none());
+    }
+}
@@ -0,0 +1,142 @@
+//! Read and write values stored in a SAPI object token, for example a voice's
+//! configuration.
+//!
+//! # References
+//!
+//! - [ISpDataKey (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms717039(v=vs.85))
+
+use windows::Win32::{
+    Foundation::ERROR_FILE_NOT_FOUND,
+    Media::Speech::{ISpDataKey, ISpObjectToken},
+    System::Com::CoTaskMemFree,
+};
+use windows_core::{w, HSTRING, PWSTR};
+
+use crate::utils::SPERR_NOT_FOUND;
+
+/// Check if an error means that a value or key doesn't exist.
+fn is_not_found(error: &windows_core::Error) -> bool {
+    error.code() == SPERR_NOT_FOUND || error.code() == ERROR_FILE_NOT_FOUND.to_hresult()
+}
+
+/// Convert a string allocated by SAPI and free it.
+fn take_sapi_string(value: PWSTR) -> windows_core::Result<String> {
+    let text = unsafe { value.to_string() };
+    unsafe { CoTaskMemFree(Some(value.as_ptr().cast())) };
+    Ok(text?)
+}
+
+/// Read a string value from a data key. Returns `Ok(None)` if the value
+/// doesn't exist.
+fn read_string(key: &ISpDataKey, name: &str) -> windows_core::Result<Option<String>> {
+    match unsafe { key.GetStringValue(&HSTRING::from(name)) } {
+        Ok(value) => take_sapi_string(value).map(Some),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read values stored in an object token's registry key.
+///
+/// All methods return `Ok(None)` if the value doesn't exist. Use an empty
+/// name to read the key's default value.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenReader<'a> {
+    token: &'a ISpObjectToken,
+}
+impl<'a> TokenReader<'a> {
+    pub fn new(token: &'a ISpObjectToken) -> Self {
+        Self { token }
+    }
+
+    /// The token's id, which is the full path of its registry key.
+    pub fn id(self) -> windows_core::Result<String> {
+        take_sapi_string(unsafe { self.token.GetId() }?)
+    }
+
+    /// Read a string value, for example
+    /// [`FORCED_LANGUAGE_VALUE`](crate::voices::FORCED_LANGUAGE_VALUE).
+    pub fn string_value(self, name: &str) -> windows_core::Result<Option<String>> {
+        read_string(self.token, name)
+    }
+
+    /// Read a binary value.
+    pub fn data(self, name: &str) -> windows_core::Result<Option<Vec<u8>>> {
+        let name = HSTRING::from(name);
+        let mut len = 0_u32;
+        // Query the size of the value first:
+        match unsafe { self.token.GetData(&name, &mut len, std::ptr::null_mut()) } {
+            Ok(()) => {}
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut data = vec![0_u8; len as usize];
+        unsafe { self.token.GetData(&name, &mut len, data.as_mut_ptr()) }?;
+        data.truncate(len as usize);
+        Ok(Some(data))
+    }
+
+    /// Read a value from the token's `Attributes` sub key, for example
+    /// `"Name"` or `"Language"`.
+    pub fn attribute(self, name: &str) -> windows_core::Result<Option<String>> {
+        let attributes = match unsafe { self.token.OpenKey(w!("Attributes")) } {
+            Ok(attributes) => attributes,
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        read_string(&attributes, name)
+    }
+}
+
+/// Write values to an object token's registry key, used when registering a
+/// voice. See [`VoiceKeyData::write_with_token_category`](crate::voices::VoiceKeyData::write_with_token_category).
+///
+/// Use an empty name to write the key's default value.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenWriter<'a> {
+    token: &'a ISpObjectToken,
+}
+impl<'a> TokenWriter<'a> {
+    pub fn new(token: &'a ISpObjectToken) -> Self {
+        Self { token }
+    }
+
+    pub fn set_string_value(self, name: &str, value: &str) -> windows_core::Result<()> {
+        unsafe {
+            self.token
+                .SetStringValue(&HSTRING::from(name), &HSTRING::from(value))
+        }
+    }
+
+    pub fn set_data(self, name: &str, data: &[u8]) -> windows_core::Result<()> {
+        unsafe {
+            self.token
+                .SetData(&HSTRING::from(name), data.len() as u32, data.as_ptr())
+        }
+    }
+
+    /// Remove a value. Succeeds if the value doesn't exist.
+    pub fn remove_value(self, name: &str) -> windows_core::Result<()> {
+        match unsafe { self.token.DeleteValue(&HSTRING::from(name)) } {
+            Err(e) if !is_not_found(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the token's `Attributes` sub key with the specified values, so
+    /// that attributes from an older version of the voice aren't left behind.
+    pub fn set_attributes<'b>(
+        self,
+        attributes: impl IntoIterator<Item = (&'b str, &'b str)>,
+    ) -> windows_core::Result<()> {
+        match unsafe { self.token.DeleteKey(w!("Attributes")) } {
+            Err(e) if !is_not_found(&e) => return Err(e),
+            _ => {}
+        }
+        let key = unsafe { self.token.CreateKey(w!("Attributes")) }?;
+        for (name, value) in attributes {
+            unsafe { key.SetStringValue(&HSTRING::from(name), &HSTRING::from(value)) }?;
+        }
+        Ok(())
+    }
+}
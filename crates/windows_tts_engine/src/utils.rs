@@ -1,17 +1,90 @@
 use std::{
     any::Any,
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
+    io::{self, Read, Write},
+    marker::PhantomData,
+    os::windows::ffi::OsStringExt,
     panic::{catch_unwind, AssertUnwindSafe, UnwindSafe},
+    path::PathBuf,
 };
 
 use windows::Win32::{
-    Foundation::{HMODULE, MAX_PATH},
-    System::LibraryLoader::{
-        GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
-        GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+    Foundation::{HMODULE, MAX_PATH, RPC_E_CHANGED_MODE},
+    Media::{
+        Audio::{WAVEFORMATEX, WAVE_FORMAT_PCM},
+        Speech::{ISpObjectToken, ISpTTSEngineSite},
+    },
+    System::{
+        Com::{CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED},
+        LibraryLoader::{
+            GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+            GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+        },
     },
 };
-use windows_core::{GUID, PCWSTR};
+use windows_core::{GUID, HRESULT, PCWSTR};
+
+use crate::{audio::AudioWriter, token::TokenReader, SpeechFormat};
+
+/// If an instance of this type exists then it is a promise that the COM library
+/// is initialized on the current thread.
+///
+/// SAPI initializes COM before it calls an engine, but code that uses an
+/// engine as a library (for example through [`speak_text`](crate::speak_text))
+/// might not have done so.
+pub struct HasCoInitialized {
+    /// `true` if [`CoUninitialize`] should be called when this is dropped.
+    uninitialize: bool,
+    /// Marks this type as **not** thread-safe since we need to uninitialize
+    /// the COM library on the same thread we initialized it from.
+    marker: PhantomData<*mut ()>,
+}
+impl HasCoInitialized {
+    /// Initialize COM for the current thread using a single-threaded
+    /// apartment, like SAPI does.
+    ///
+    /// If COM is already initialized then this succeeds without changing
+    /// anything, even if the thread uses a multi-threaded apartment.
+    pub fn new() -> windows::core::Result<Self> {
+        let result = unsafe { CoInitializeEx(None, COINIT_APARTMENTTHREADED) };
+        if result == RPC_E_CHANGED_MODE {
+            // Already initialized with another concurrency model, which we
+            // shouldn't undo:
+            return Ok(Self {
+                uninitialize: false,
+                marker: PhantomData,
+            });
+        }
+        // Note: `S_FALSE` means that COM was already initialized, but that
+        // call must still be balanced by `CoUninitialize`.
+        result.ok()?;
+        Ok(Self {
+            uninitialize: true,
+            marker: PhantomData,
+        })
+    }
+    /// Promise that the COM library is initialized for the current thread.
+    pub fn new_unchecked() -> &'static Self {
+        // Note: leaking zero sized type won't actually leak anything and since
+        // the object won't be dropped we won't call `CoUninitialize`.
+        Box::leak(Box::new(Self {
+            uninitialize: false,
+            marker: PhantomData,
+        }))
+    }
+}
+impl Drop for HasCoInitialized {
+    fn drop(&mut self) {
+        if self.uninitialize {
+            unsafe { CoUninitialize() };
+        }
+    }
+}
+
+/// Returned by SAPI when a value or key doesn't exist.
+///
+/// `0x8004503A` from [SAPI Error Codes (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms718115(v=vs.85))
+pub const SPERR_NOT_FOUND: HRESULT = HRESULT(0x8004503A_u32 as i32);
 
 /// Ensures that dropping the provided value doesn't panic.
 pub fn safe_drop<T>(value: T) {
@@ -105,8 +178,14 @@ pub fn get_current_module() -> windows::core::Result<HMODULE> {
     Ok(module)
 }
 
+/// Maximum length of a path in UTF-16 code units when using the `\\?\` prefix.
+const MAX_LONG_PATH: usize = 32_767;
+
 /// If this code is included inside a DLL file then this will get the path to
 /// that file.
+///
+/// The returned slice includes the nul terminator. Use [`current_dll_path`]
+/// to get an owned path instead.
 pub fn get_current_dll_path(
     buffer: &mut [u16; MAX_PATH as usize],
 ) -> windows::core::Result<&mut [u16]> {
@@ -118,3 +197,542 @@ pub fn get_current_dll_path(
         Ok(&mut buffer[..len as usize + 1])
     }
 }
+
+/// Get the path to the DLL file that contains this code, like
+/// [`get_current_dll_path`] but without a nul terminator.
+///
+/// Paths longer than `MAX_PATH` are supported and paths that aren't valid
+/// Unicode are kept as is, since Windows doesn't require file names to be
+/// well-formed UTF-16.
+pub fn current_dll_path() -> windows::core::Result<PathBuf> {
+    let module = get_current_module()?;
+    let mut buffer = vec![0_u16; MAX_PATH as usize];
+    loop {
+        let len = unsafe { GetModuleFileNameW(Some(module), &mut buffer) } as usize;
+        if len == 0 {
+            return Err(windows::core::Error::from_win32());
+        }
+        if len < buffer.len() {
+            // Don't rely on the terminator being written, only on the length:
+            let path = &buffer[..len];
+            let path = path.strip_suffix(&[0]).unwrap_or(path);
+            return Ok(PathBuf::from(OsString::from_wide(path)));
+        }
+        // The path was truncated, so retry with a larger buffer:
+        if buffer.len() > MAX_LONG_PATH {
+            return Err(windows::core::Error::from_win32());
+        }
+        buffer.resize(buffer.len() * 2, 0);
+    }
+}
+
+/// Read a string value stored in an object token's registry key, for example
+/// [`VoiceKeyData::forced_language`](crate::voices::VoiceKeyData::forced_language).
+///
+/// Returns `Ok(None)` if the value doesn't exist. See [`TokenReader`] for
+/// other kinds of values.
+pub fn read_token_string(
+    token: &ISpObjectToken,
+    value_name: &str,
+) -> windows_core::Result<Option<String>> {
+    TokenReader::new(token).string_value(value_name)
+}
+
+/// Write `len` zeroed bytes to the output site. For PCM audio this is silence.
+///
+/// Engines that write other audio should use
+/// [`AudioWriter::write_silence`] instead so that the silence isn't written
+/// before audio that is still buffered.
+pub fn write_silence(output_site: &ISpTTSEngineSite, len: usize) -> windows_core::Result<()> {
+    let mut writer = AudioWriter::new(output_site);
+    writer.write_silence(len)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write UTF-16 text to the output site, which is what engines should output
+/// for [`SpeechFormat::DebugText`](crate::SpeechFormat::DebugText).
+pub fn write_debug_text(output_site: &ISpTTSEngineSite, text: &[u16]) -> windows_core::Result<()> {
+    let bytes = text
+        .iter()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let mut writer = AudioWriter::new(output_site);
+    writer.write(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Convert mono samples to interleaved samples with `channels` channels by
+/// repeating every sample for each channel.
+pub fn duplicate_channels<T: Copy>(mono: &[T], channels: u16) -> Vec<T> {
+    let channels = usize::from(channels.max(1));
+    let mut interleaved = Vec::with_capacity(mono.len() * channels);
+    for &sample in mono {
+        interleaved.extend(std::iter::repeat_n(sample, channels));
+    }
+    interleaved
+}
+
+/// The speed multiplier at the SAPI rate `10`.
+const SAPI_MAX_RATE_MULTIPLIER: f64 = 1.4;
+
+/// Convert a SAPI rate adjustment between `-10` and `10` to a speed multiplier.
+///
+/// SAPI documents the rate as logarithmic, so each step changes the speed by
+/// the same percentage. Microsoft's voices are about 1.4 times faster at `10`
+/// and 1.4 times slower at `-10`, so each step is about 3.4%.
+///
+/// - [ISpVoice::SetRate (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms720877(v=vs.85))
+pub fn sapi_rate_to_multiplier(sapi_rate: i32) -> f64 {
+    SAPI_MAX_RATE_MULTIPLIER.powf(f64::from(sapi_rate.clamp(-10, 10)) / 10.0)
+}
+
+/// The inverse of [`sapi_rate_to_multiplier`], rounded to the nearest SAPI
+/// rate.
+pub fn multiplier_to_sapi_rate(multiplier: f64) -> i32 {
+    (10.0 * multiplier.ln() / SAPI_MAX_RATE_MULTIPLIER.ln())
+        .round()
+        .clamp(-10.0, 10.0) as i32
+}
+
+/// Linearly fade in the first `fade_len` samples and fade out the last
+/// `fade_len` samples of mono audio. This avoids clicks where separately
+/// synthesized audio is joined, since the joins then happen at silence.
+pub fn apply_fade(samples: &mut [i16], fade_len: usize) {
+    fade_with(samples, fade_len, |sample, gain| {
+        (f32::from(sample) * gain).round() as i16
+    });
+}
+
+/// Like [`apply_fade`] but for 32 bit float samples.
+pub fn apply_fade_f32(samples: &mut [f32], fade_len: usize) {
+    fade_with(samples, fade_len, |sample, gain| sample * gain);
+}
+
+fn fade_with<T: Copy>(samples: &mut [T], fade_len: usize, scale: impl Fn(T, f32) -> T) {
+    let fade_len = fade_len.min(samples.len() / 2);
+    if fade_len == 0 {
+        return;
+    }
+    let len = samples.len();
+    for i in 0..fade_len {
+        let gain = i as f32 / fade_len as f32;
+        samples[i] = scale(samples[i], gain);
+        samples[len - 1 - i] = scale(samples[len - 1 - i], gain);
+    }
+}
+
+/// Write audio to a WAV file. `pcm` should contain interleaved samples in
+/// `format`, either integer PCM or IEEE floating point.
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] for
+/// [`SpeechFormat::DebugText`] or if there is too much audio for a WAV file.
+///
+/// # References
+///
+/// - [WAVE PCM soundfile format](http://soundfile.sapp.org/doc/WaveFormat/)
+/// - [Multimedia Programming Interface and Data Specifications 1.0](https://www.mmsp.ece.mcgill.ca/Documents/AudioFormats/WAVE/Docs/riffmci.pdf)
+pub fn write_wav(mut writer: impl Write, format: &SpeechFormat, pcm: &[u8]) -> io::Result<()> {
+    let SpeechFormat::Wave(info) = *format else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "debug text can't be written to a WAV file",
+        ));
+    };
+    let data_len = u32::try_from(pcm.len())
+        .ok()
+        .filter(|len| *len <= u32::MAX - 64)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "too much audio for a WAV file")
+        })?;
+    // Chunks must have an even size, so odd data is followed by a pad byte:
+    let padding = data_len % 2;
+    // Formats other than integer PCM need the `cbSize` field and a `fact`
+    // chunk with the number of samples per channel:
+    let is_pcm = u32::from({ info.wFormatTag }) == WAVE_FORMAT_PCM;
+    let fmt_len: u32 = if is_pcm { 16 } else { 18 };
+    let fact_len: u32 = if is_pcm { 0 } else { 12 };
+    let riff_len = 4 + (8 + fmt_len) + fact_len + (8 + data_len + padding);
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_len.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&fmt_len.to_le_bytes())?;
+    writer.write_all(&{ info.wFormatTag }.to_le_bytes())?;
+    writer.write_all(&{ info.nChannels }.to_le_bytes())?;
+    writer.write_all(&{ info.nSamplesPerSec }.to_le_bytes())?;
+    writer.write_all(&{ info.nAvgBytesPerSec }.to_le_bytes())?;
+    writer.write_all(&{ info.nBlockAlign }.to_le_bytes())?;
+    writer.write_all(&{ info.wBitsPerSample }.to_le_bytes())?;
+    if !is_pcm {
+        writer.write_all(&0_u16.to_le_bytes())?;
+
+        let frames = data_len / u32::from({ info.nBlockAlign }.max(1));
+        writer.write_all(b"fact")?;
+        writer.write_all(&4_u32.to_le_bytes())?;
+        writer.write_all(&frames.to_le_bytes())?;
+    }
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    writer.write_all(pcm)?;
+    if padding != 0 {
+        writer.write_all(&[0])?;
+    }
+    writer.flush()
+}
+
+/// Read a WAV file written by [`write_wav`] or another program. Returns the
+/// format from the `fmt ` chunk and the contents of the `data` chunk. Other
+/// chunks are skipped.
+pub fn read_wav(mut reader: impl Read) -> io::Result<(SpeechFormat, Vec<u8>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let mut header = [0_u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+        return Err(invalid("not a RIFF WAVE file"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk_header = [0_u8; 8];
+        reader.read_exact(&mut chunk_header)?;
+        let chunk_len = u32::from_le_bytes(chunk_header[4..].try_into().unwrap());
+        let mut chunk = vec![0_u8; chunk_len as usize];
+        reader.read_exact(&mut chunk)?;
+        if chunk_len % 2 != 0 {
+            // The pad byte might be missing at the end of the file:
+            let _ = reader.read(&mut [0]);
+        }
+        match &chunk_header[..4] {
+            b"fmt " => {
+                if chunk.len() < 16 {
+                    return Err(invalid("fmt chunk is too small"));
+                }
+                let u16_at = |index: usize| u16::from_le_bytes([chunk[index], chunk[index + 1]]);
+                let u32_at =
+                    |index: usize| u32::from_le_bytes(chunk[index..index + 4].try_into().unwrap());
+                format = Some(SpeechFormat::Wave(WAVEFORMATEX {
+                    wFormatTag: u16_at(0),
+                    nChannels: u16_at(2),
+                    nSamplesPerSec: u32_at(4),
+                    nAvgBytesPerSec: u32_at(8),
+                    nBlockAlign: u16_at(12),
+                    wBitsPerSample: u16_at(14),
+                    cbSize: 0,
+                }));
+            }
+            b"data" => {
+                let format = format.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                return Ok((format, chunk));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Number of samples in each grain that [`pitch_shift`] stretches.
+const PITCH_SHIFT_GRAIN_LEN: usize = 1024;
+/// Distance in samples between grains in the output of [`pitch_shift`].
+const PITCH_SHIFT_HOP_LEN: usize = PITCH_SHIFT_GRAIN_LEN / 4;
+
+/// Change the pitch of mono audio by `semitones` (positive values raise the
+/// pitch) without changing its duration.
+///
+/// The audio is first stretched in time by overlapping windowed grains and
+/// then resampled back to its original length. This is cheap and works for
+/// small adjustments, but larger shifts sound echoey or metallic since grains
+/// aren't aligned to the voice's pitch periods like a proper phase vocoder or
+/// PSOLA implementation would do.
+pub fn pitch_shift(samples: &[f32], semitones: f32) -> Vec<f32> {
+    if semitones == 0.0 || samples.len() < PITCH_SHIFT_GRAIN_LEN {
+        return samples.to_vec();
+    }
+    let ratio = 2_f32.powf(semitones / 12.0);
+
+    // Stretch to `ratio` times the length using overlap-add:
+    let analysis_hop = PITCH_SHIFT_HOP_LEN as f32 / ratio;
+    let stretched_len = (samples.len() as f32 * ratio) as usize + PITCH_SHIFT_GRAIN_LEN;
+    let mut stretched = vec![0.0_f32; stretched_len];
+    let mut weights = vec![0.0_f32; stretched_len];
+    let window = (0..PITCH_SHIFT_GRAIN_LEN)
+        .map(|i| {
+            let phase = i as f32 / PITCH_SHIFT_GRAIN_LEN as f32;
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * phase).cos()
+        })
+        .collect::<Vec<f32>>();
+    for frame in 0.. {
+        let input_start = (frame as f32 * analysis_hop) as usize;
+        let output_start = frame * PITCH_SHIFT_HOP_LEN;
+        if input_start + PITCH_SHIFT_GRAIN_LEN > samples.len()
+            || output_start + PITCH_SHIFT_GRAIN_LEN > stretched_len
+        {
+            break;
+        }
+        for (i, &weight) in window.iter().enumerate() {
+            stretched[output_start + i] += samples[input_start + i] * weight;
+            weights[output_start + i] += weight;
+        }
+    }
+    for (sample, weight) in stretched.iter_mut().zip(&weights) {
+        if *weight > 1e-3 {
+            *sample /= weight;
+        }
+    }
+
+    // Resample back to the original length, which scales the pitch:
+    interpolate(&stretched, ratio, samples.len())
+}
+
+/// Linearly interpolate `len` samples from `samples`, advancing `step` input
+/// samples for every output sample.
+fn interpolate(samples: &[f32], step: f32, len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| {
+            let position = i as f32 * step;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let current = samples.get(index).copied().unwrap_or(0.0);
+            let next = samples.get(index + 1).copied().unwrap_or(current);
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+/// Convert mono audio from `from_rate` to `to_rate` samples per second using
+/// linear interpolation.
+///
+/// This doesn't low-pass filter the audio, so downsampling can cause some
+/// aliasing, but speech has little energy at the frequencies that are
+/// affected.
+pub fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || from_rate == 0 || to_rate == 0 {
+        return samples.to_vec();
+    }
+    let len = (samples.len() as u64 * u64::from(to_rate) / u64::from(from_rate)) as usize;
+    interpolate(samples, from_rate as f32 / to_rate as f32, len)
+}
+
+/// Like [`resample`] but for 16 bit samples.
+pub fn resample_i16(samples: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate {
+        return samples.to_vec();
+    }
+    let samples = samples
+        .iter()
+        .map(|&sample| f32::from(sample))
+        .collect::<Vec<f32>>();
+    resample(&samples, from_rate, to_rate)
+        .into_iter()
+        .map(|sample| sample.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16)
+        .collect()
+}
+
+/// Like [`pitch_shift`] but for 16 bit samples.
+pub fn pitch_shift_i16(samples: &[i16], semitones: f32) -> Vec<i16> {
+    if semitones == 0.0 {
+        return samples.to_vec();
+    }
+    let samples = samples
+        .iter()
+        .map(|&sample| f32::from(sample))
+        .collect::<Vec<f32>>();
+    pitch_shift(&samples, semitones)
+        .into_iter()
+        .map(|sample| sample.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16)
+        .collect()
+}
+
+/// Common Windows language ids (LCIDs) and their BCP-47 language codes.
+///
+/// # References
+///
+/// - [\[MS-LCID\]: Windows Language Code Identifier (LCID) Reference | Microsoft Learn](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-lcid/70feba9f-294e-491e-b6eb-56532684c37f)
+const LCID_LANGUAGE_CODES: &[(u16, &str)] = &[
+    (0x0401, "ar-SA"),
+    (0x0402, "bg-BG"),
+    (0x0403, "ca-ES"),
+    (0x0404, "zh-TW"),
+    (0x0405, "cs-CZ"),
+    (0x0406, "da-DK"),
+    (0x0407, "de-DE"),
+    (0x0408, "el-GR"),
+    (0x0409, "en-US"),
+    (0x040B, "fi-FI"),
+    (0x040C, "fr-FR"),
+    (0x040D, "he-IL"),
+    (0x040E, "hu-HU"),
+    (0x040F, "is-IS"),
+    (0x0410, "it-IT"),
+    (0x0411, "ja-JP"),
+    (0x0412, "ko-KR"),
+    (0x0413, "nl-NL"),
+    (0x0414, "nb-NO"),
+    (0x0415, "pl-PL"),
+    (0x0416, "pt-BR"),
+    (0x0418, "ro-RO"),
+    (0x0419, "ru-RU"),
+    (0x041A, "hr-HR"),
+    (0x041B, "sk-SK"),
+    (0x041D, "sv-SE"),
+    (0x041E, "th-TH"),
+    (0x041F, "tr-TR"),
+    (0x0420, "ur-PK"),
+    (0x0421, "id-ID"),
+    (0x0422, "uk-UA"),
+    (0x0423, "be-BY"),
+    (0x0424, "sl-SI"),
+    (0x0425, "et-EE"),
+    (0x0426, "lv-LV"),
+    (0x0427, "lt-LT"),
+    (0x0429, "fa-IR"),
+    (0x042A, "vi-VN"),
+    (0x042D, "eu-ES"),
+    (0x0436, "af-ZA"),
+    (0x0437, "ka-GE"),
+    (0x0439, "hi-IN"),
+    (0x043E, "ms-MY"),
+    (0x043F, "kk-KZ"),
+    (0x0441, "sw-KE"),
+    (0x0445, "bn-IN"),
+    (0x0449, "ta-IN"),
+    (0x044A, "te-IN"),
+    (0x0452, "cy-GB"),
+    (0x0456, "gl-ES"),
+    (0x0464, "fil-PH"),
+    (0x0804, "zh-CN"),
+    (0x0807, "de-CH"),
+    (0x0809, "en-GB"),
+    (0x080A, "es-MX"),
+    (0x080C, "fr-BE"),
+    (0x0813, "nl-BE"),
+    (0x0816, "pt-PT"),
+    (0x081A, "sr-Latn-RS"),
+    (0x0C04, "zh-HK"),
+    (0x0C07, "de-AT"),
+    (0x0C09, "en-AU"),
+    (0x0C0A, "es-ES"),
+    (0x0C0C, "fr-CA"),
+    (0x1009, "en-CA"),
+    (0x100C, "fr-CH"),
+    (0x1409, "en-NZ"),
+    (0x1809, "en-IE"),
+    (0x4009, "en-IN"),
+];
+
+/// Convert a Windows language id (LCID), for example `0x409`, to a BCP-47
+/// language code like `"en-US"`. Returns `None` for uncommon languages.
+///
+/// Note that voice attributes store LCIDs as hexadecimal strings, so `"409"`
+/// should be parsed using `u16::from_str_radix(lcid, 16)`.
+pub fn lcid_to_language_code(lcid: u16) -> Option<&'static str> {
+    LCID_LANGUAGE_CODES
+        .iter()
+        .find(|(id, _)| *id == lcid)
+        .map(|(_, code)| *code)
+}
+
+/// Convert a BCP-47 language code like `"en-US"` or `"en_us"` to a Windows
+/// language id (LCID). A code without a region, like `"en"`, is mapped to the
+/// first region in the table. Returns `None` for uncommon languages.
+pub fn language_code_to_lcid(code: &str) -> Option<u16> {
+    let code = code.trim().replace('_', "-");
+    LCID_LANGUAGE_CODES
+        .iter()
+        .find(|(_, known)| known.eq_ignore_ascii_case(&code))
+        .or_else(|| {
+            LCID_LANGUAGE_CODES.iter().find(|(_, known)| {
+                known
+                    .split_once('-')
+                    .is_some_and(|(language, _)| language.eq_ignore_ascii_case(&code))
+            })
+        })
+        .map(|(id, _)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_channels_interleaves_frames() {
+        assert_eq!(duplicate_channels(&[1_i16, -2], 2), [1, 1, -2, -2]);
+        assert_eq!(duplicate_channels(&[0.5_f32], 3), [0.5, 0.5, 0.5]);
+        // Zero channels is treated as mono:
+        assert_eq!(duplicate_channels(&[7_i16, 8], 0), [7, 8]);
+    }
+
+    fn wav_round_trip(format: SpeechFormat, pcm: &[u8]) -> Vec<u8> {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, &format, pcm).unwrap();
+        let (read_format, read_pcm) = read_wav(wav.as_slice()).unwrap();
+        assert_eq!(read_format, format);
+        assert_eq!(read_pcm, pcm);
+        wav
+    }
+
+    #[test]
+    fn wav_round_trip_pcm() {
+        let pcm = [1, 0, 2, 0, 0xFF, 0x7F, 0, 0x80];
+        let wav = wav_round_trip(SpeechFormat::pcm_i16(22_050, 2), &pcm);
+        assert_eq!(wav.len(), 44 + pcm.len());
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[4..8], (wav.len() as u32 - 8).to_le_bytes());
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav[40..44], (pcm.len() as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn wav_round_trip_float_has_fact_chunk() {
+        let pcm: Vec<u8> = [0.5_f32, -0.25, 1.0]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let wav = wav_round_trip(SpeechFormat::pcm_f32(16_000, 1), &pcm);
+        assert_eq!(wav.len(), 58 + pcm.len());
+        assert_eq!(&wav[38..42], b"fact");
+        // Number of frames:
+        assert_eq!(wav[46..50], 3_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn wav_odd_data_is_padded() {
+        let pcm = [10, 20, 30];
+        let wav = wav_round_trip(SpeechFormat::pcm(8_000, 1, 8), &pcm);
+        assert_eq!(wav.len(), 44 + 4);
+        assert_eq!(wav.last(), Some(&0));
+        // The data chunk size excludes the pad byte but the RIFF size doesn't:
+        assert_eq!(wav[40..44], 3_u32.to_le_bytes());
+        assert_eq!(wav[4..8], 40_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn wav_rejects_debug_text_and_other_files() {
+        let error = write_wav(Vec::new(), &SpeechFormat::DebugText, &[]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = read_wav(&b"RIFF\0\0\0\0AVI LIST"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sapi_rate_endpoints() {
+        assert_eq!(sapi_rate_to_multiplier(0), 1.0);
+        assert!((sapi_rate_to_multiplier(10) - 1.4).abs() < 1e-9);
+        assert!((sapi_rate_to_multiplier(-10) - 1.0 / 1.4).abs() < 1e-9);
+        // Rates outside the SAPI range are clamped:
+        assert_eq!(sapi_rate_to_multiplier(25), sapi_rate_to_multiplier(10));
+        assert_eq!(sapi_rate_to_multiplier(-25), sapi_rate_to_multiplier(-10));
+    }
+
+    #[test]
+    fn sapi_rate_round_trips_through_multiplier() {
+        for rate in -10..=10 {
+            assert_eq!(multiplier_to_sapi_rate(sapi_rate_to_multiplier(rate)), rate);
+        }
+        assert_eq!(multiplier_to_sapi_rate(3.0), 10);
+        assert_eq!(multiplier_to_sapi_rate(0.1), -10);
+    }
+}
@@ -1,13 +1,68 @@
 //! Register text-to-speech voices/engines with Windows.
 
-use crate::utils::{display_guid, to_utf16};
+use crate::{
+    detect_languages::DetectorBackend,
+    token::TokenWriter,
+    utils::{display_guid, to_utf16},
+};
 use windows::Win32::{
-    Foundation::{ERROR_FILE_NOT_FOUND, E_FAIL},
-    System::Registry::{
-        RegCreateKeyExW, RegDeleteKeyExW, RegSetValueExW, HKEY, KEY_SET_VALUE, REG_SZ,
+    Foundation::{ERROR_FILE_NOT_FOUND, ERROR_NO_MORE_ITEMS, E_FAIL},
+    Media::Speech::{ISpObjectToken, ISpObjectTokenCategory, SpObjectToken, SpObjectTokenCategory},
+    System::{
+        Com::{CoCreateInstance, CLSCTX_ALL},
+        Registry::{
+            RegCreateKeyExW, RegDeleteKeyExW, RegDeleteTreeW, RegDeleteValueW, RegEnumKeyExW,
+            RegGetValueW, RegOpenKeyExW, RegSetValueExW, HKEY, HKEY_CURRENT_USER,
+            HKEY_LOCAL_MACHINE, KEY_READ, KEY_SET_VALUE, REG_SZ, RRF_RT_REG_SZ,
+        },
     },
 };
-use windows_core::{w, Free, GUID, PCWSTR};
+use windows_core::{w, Free, GUID, HSTRING, PCWSTR, PWSTR};
+
+/// Read a string value from the registry. Returns `Ok(None)` if the value or
+/// key doesn't exist.
+fn read_string_value(
+    parent: HKEY,
+    sub_key: PCWSTR,
+    value_name: PCWSTR,
+) -> windows::core::Result<Option<String>> {
+    let mut size = 0_u32;
+    let result = unsafe {
+        RegGetValueW(
+            parent,
+            sub_key,
+            value_name,
+            RRF_RT_REG_SZ,
+            None,
+            None,
+            Some(&mut size),
+        )
+    };
+    if result == ERROR_FILE_NOT_FOUND {
+        return Ok(None);
+    }
+    result.ok()?;
+
+    let mut buffer = vec![0_u16; (size as usize).div_ceil(2)];
+    unsafe {
+        RegGetValueW(
+            parent,
+            sub_key,
+            value_name,
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr().cast()),
+            Some(&mut size),
+        )
+    }
+    .ok()?;
+
+    buffer.truncate(size as usize / 2);
+    while buffer.last() == Some(&0) {
+        buffer.pop();
+    }
+    Ok(Some(String::from_utf16_lossy(&buffer)))
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum ParentRegKey<'a> {
@@ -49,6 +104,128 @@ impl ParentRegKey<'_> {
     }
 }
 
+/// Environment variable that makes `DllRegisterServer` select the registered
+/// voice as the default SAPI voice if it is set to `1`, see
+/// [`VoiceKeyData::set_as_default`]. This is opt-in since it changes the
+/// voice that the user selected.
+pub const SET_DEFAULT_VOICE_ENV_VAR: &str = "LEJ77_TTS_SET_DEFAULT_VOICE";
+
+/// Check if [`SET_DEFAULT_VOICE_ENV_VAR`] is enabled.
+pub fn should_set_default_voice() -> bool {
+    std::env::var(SET_DEFAULT_VOICE_ENV_VAR).is_ok_and(|value| value.trim() == "1")
+}
+
+/// A SAPI voice category, identified both by its category id for the
+/// `ISpObjectTokenCategory` APIs and by the registry key of its `Tokens`
+/// folder for direct registry access.
+#[derive(Debug, Clone, Copy)]
+pub struct VoiceCategory<'a> {
+    /// Example: `"HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Speech\\Voices"`
+    pub category_id: &'a str,
+    pub tokens_key: ParentRegKey<'a>,
+}
+impl VoiceCategory<'static> {
+    /// Voices used by SAPI clients.
+    pub const VOICES: Self = Self {
+        category_id: "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Speech\\Voices",
+        tokens_key: ParentRegKey::Path(
+            HKEY_LOCAL_MACHINE,
+            "SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\",
+        ),
+    };
+    /// Voices used by modern clients, for example the WinRT
+    /// `SpeechSynthesizer`.
+    pub const ONECORE_VOICES: Self = Self {
+        category_id: "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Speech_OneCore\\Voices",
+        tokens_key: ParentRegKey::Path(
+            HKEY_LOCAL_MACHINE,
+            "SOFTWARE\\Microsoft\\Speech_OneCore\\Voices\\Tokens\\",
+        ),
+    };
+    /// Voices used by SAPI clients that are only registered for the current
+    /// user. SAPI lists them together with the voices in
+    /// [`VoiceCategory::VOICES`].
+    pub const VOICES_CURRENT_USER: Self = Self {
+        category_id: "HKEY_CURRENT_USER\\SOFTWARE\\Microsoft\\Speech\\Voices",
+        tokens_key: ParentRegKey::Path(
+            HKEY_CURRENT_USER,
+            "SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\",
+        ),
+    };
+    /// Our own category, used by self-contained installs. Voices are
+    /// registered here and then aliased into the standard categories with
+    /// [`VoiceKeyData::alias_into_categories`], so that uninstalling only
+    /// needs to [`remove`](VoiceCategory::remove) this category and the
+    /// aliases.
+    pub const LEJ77_VOICES: Self = Self {
+        category_id: "HKEY_LOCAL_MACHINE\\SOFTWARE\\Lej77\\TTS\\Voices",
+        tokens_key: ParentRegKey::Path(
+            HKEY_LOCAL_MACHINE,
+            "SOFTWARE\\Lej77\\TTS\\Voices\\Tokens\\",
+        ),
+    };
+}
+impl VoiceCategory<'_> {
+    /// Token id of a voice in this category, for example
+    /// `HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Speech\Voices\Tokens\Lej77_TTS_Multilingual`.
+    pub fn token_id(&self, key_name: &str) -> String {
+        format!("{}\\Tokens\\{key_name}", self.category_id)
+    }
+    /// Open the category with `ISpObjectTokenCategory`. If `create` is `true`
+    /// then the category's registry key is created if it doesn't exist.
+    ///
+    /// The COM library must be initialized on the current thread.
+    pub fn open(&self, create: bool) -> windows::core::Result<ISpObjectTokenCategory> {
+        open_category(self.category_id, create)
+    }
+    /// Delete the category's `Tokens` folder including all voices inside it,
+    /// and then the category's key if it is empty. Succeeds if the category
+    /// doesn't exist.
+    ///
+    /// This should only be used for our own categories like
+    /// [`VoiceCategory::LEJ77_VOICES`].
+    pub fn remove(&self) -> windows::core::Result<()> {
+        let ParentRegKey::Path(hkey, tokens_path) = self.tokens_key else {
+            return Err(windows::core::Error::new(
+                E_FAIL,
+                "Can only remove categories specified by registry paths",
+            ));
+        };
+        let tokens_path = tokens_path.trim_end_matches(['\\', '/']);
+        let tokens_path_utf16 = to_utf16(tokens_path);
+        let result = unsafe { RegDeleteTreeW(hkey, PCWSTR::from_raw(tokens_path_utf16.as_ptr())) };
+        if result != ERROR_FILE_NOT_FOUND {
+            result.ok()?;
+        }
+
+        let Some(category_path) = tokens_path
+            .strip_suffix("Tokens")
+            .map(|path| path.trim_end_matches(['\\', '/']))
+        else {
+            return Ok(());
+        };
+        let category_path = to_utf16(category_path);
+        // Fails if other values or keys were stored in the category, in which
+        // case they are left alone:
+        let result =
+            unsafe { RegDeleteKeyExW(hkey, PCWSTR::from_raw(category_path.as_ptr()), 0, None) };
+        if result != ERROR_FILE_NOT_FOUND {
+            if let Err(e) = result.ok() {
+                log::warn!("Failed to remove category {}: {e}", self.category_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open a category with `ISpObjectTokenCategory`, see [`VoiceCategory::open`].
+fn open_category(category_id: &str, create: bool) -> windows::core::Result<ISpObjectTokenCategory> {
+    let category: ISpObjectTokenCategory =
+        unsafe { CoCreateInstance(&SpObjectTokenCategory, None, CLSCTX_ALL) }?;
+    unsafe { category.SetId(&HSTRING::from(category_id), create) }?;
+    Ok(category)
+}
+
 /// Voice metadata stored in Windows registry. See [`VoiceKeyData`] for more
 /// info.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -63,9 +240,35 @@ pub struct VoiceAttributes {
     pub language: String,
     /// Example: "Microsoft" or "http://espeak.sf.net"
     pub vendor: String,
+    /// Other attribute values, written after the named fields.
+    ///
+    /// Example: `[("SharedPronunciation", ""), ("VoiceType", "Neural")]`
+    pub extra: Vec<(String, String)>,
 }
 impl VoiceAttributes {
+    /// Read the values of an `Attributes` sub key. Missing values are read as
+    /// empty strings. [`extra`](Self::extra) attributes aren't read.
+    pub fn read_from_registry(voice_key: ParentRegKey) -> windows::core::Result<Self> {
+        let mut sub_key_buffer = Vec::new();
+        let sub_key = voice_key.sub_key_path("Attributes", &mut sub_key_buffer);
+        let read = |name: PCWSTR| -> windows::core::Result<String> {
+            Ok(read_string_value(voice_key.parent_handle(), sub_key, name)?.unwrap_or_default())
+        };
+        Ok(Self {
+            name: read(w!("Name"))?,
+            gender: read(w!("Gender"))?,
+            age: read(w!("Age"))?,
+            language: read(w!("Language"))?,
+            vendor: read(w!("Vendor"))?,
+            extra: Vec::new(),
+        })
+    }
+    /// Write the attributes to an `Attributes` sub key. Any existing
+    /// `Attributes` key is removed first so that values from an older version
+    /// of the voice aren't left behind.
     pub fn write_to_registry(&self, voice_key: ParentRegKey) -> windows::core::Result<()> {
+        self.remove_from_registry(voice_key)?;
+
         let mut attributes_key = Default::default();
 
         let mut sub_key_buffer = Vec::new();
@@ -91,8 +294,12 @@ impl VoiceAttributes {
             ("Language", self.language.as_str()),
             ("Vendor", self.vendor.as_str()),
         ];
+        let extra_values = self
+            .extra
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
 
-        for (name, value) in values_to_set {
+        for (name, value) in values_to_set.into_iter().chain(extra_values) {
             let name = to_utf16(name);
             let value = to_utf16(value);
             unsafe {
@@ -111,6 +318,23 @@ impl VoiceAttributes {
 
         Ok(())
     }
+    /// Like [`write_to_registry`](Self::write_to_registry) but writes to the
+    /// `Attributes` key of a voice token using SAPI's APIs.
+    pub fn write_to_token(&self, token: TokenWriter<'_>) -> windows::core::Result<()> {
+        let values_to_set = [
+            ("Name", self.name.as_str()),
+            ("Gender", self.gender.as_str()),
+            ("Age", self.age.as_str()),
+            ("Language", self.language.as_str()),
+            ("Vendor", self.vendor.as_str()),
+        ];
+        let extra_values = self
+            .extra
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+
+        token.set_attributes(values_to_set.into_iter().chain(extra_values))
+    }
     pub fn remove_from_registry(&self, voice_key: ParentRegKey) -> windows::core::Result<()> {
         let mut sub_key_buffer = Vec::new();
         let result = unsafe {
@@ -129,6 +353,44 @@ impl VoiceAttributes {
     }
 }
 
+/// Name of the registry value that stores
+/// [`VoiceKeyData::forced_language`].
+pub const FORCED_LANGUAGE_VALUE: &str = "ForcedLanguage";
+
+/// Name of the registry value that stores
+/// [`VoiceKeyData::language_detector`].
+pub const LANGUAGE_DETECTOR_VALUE: &str = "LanguageDetector";
+
+/// Set a string value of an open registry key or remove it if `value` is
+/// `None`, since it might have been set by an older version of the voice.
+fn set_or_remove_string_value(
+    key: HKEY,
+    name: &str,
+    value: Option<&str>,
+) -> windows::core::Result<()> {
+    let name = to_utf16(name);
+    if let Some(value) = value {
+        let value = to_utf16(value);
+        unsafe {
+            RegSetValueExW(
+                key,
+                PCWSTR::from_raw(name.as_ptr()),
+                None,
+                REG_SZ,
+                Some(value.align_to().1),
+            )
+        }
+        .ok()
+    } else {
+        let result = unsafe { RegDeleteValueW(key, PCWSTR::from_raw(name.as_ptr())) };
+        if result == ERROR_FILE_NOT_FOUND {
+            Ok(())
+        } else {
+            result.ok()
+        }
+    }
+}
+
 /// Registry data associated with a text-to-speech voice.
 ///
 /// # References
@@ -150,10 +412,21 @@ pub struct VoiceKeyData {
     /// [`SafeTtsEngine::set_object_token`] method.
     pub class_id: GUID,
     pub attributes: VoiceAttributes,
+    /// Language code that the engine should use for all text instead of
+    /// detecting the language. Stored in the [`FORCED_LANGUAGE_VALUE`] value
+    /// of the voice's registry key.
+    ///
+    /// Example: "en-US" or "sv"
+    pub forced_language: Option<String>,
+    /// Library the engine should use to detect languages. Stored in the
+    /// [`LANGUAGE_DETECTOR_VALUE`] value of the voice's registry key, the
+    /// engine decides if this is `None`.
+    pub language_detector: Option<DetectorBackend>,
 }
 impl VoiceKeyData {
     /// Create a registry key with data about a voice inside a `Tokens` folder
-    /// specified by a key handle.
+    /// specified by a key handle. If the voice is already registered then its
+    /// key is updated and stale values from older versions are removed.
     pub fn write_to_registry(&self, tokens_key: ParentRegKey) -> windows::core::Result<()> {
         if self.key_name.contains(['/', '\\']) {
             return Err(windows::core::Error::new(
@@ -209,12 +482,288 @@ impl VoiceKeyData {
             .ok()?;
         }
 
+        set_or_remove_string_value(key, FORCED_LANGUAGE_VALUE, self.forced_language.as_deref())?;
+        set_or_remove_string_value(
+            key,
+            LANGUAGE_DETECTOR_VALUE,
+            self.language_detector.map(DetectorBackend::as_str),
+        )?;
+
         self.attributes
             .write_to_registry(ParentRegKey::Handle(key))?;
 
         unsafe { key.free() };
         Ok(())
     }
+    /// Create or update a voice token in a SAPI category using the
+    /// `ISpObjectTokenCategory` and `ISpObjectToken` APIs, which is the
+    /// documented way to register voices. The written values are the same as
+    /// for [`write_to_registry`](Self::write_to_registry).
+    ///
+    /// The COM library must be initialized on the current thread.
+    ///
+    /// # References
+    ///
+    /// - [ISpObjectToken::SetId (SAPI 5.3) | Microsoft Learn](https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ms718805(v=vs.85))
+    pub fn write_with_token_category(&self, category_id: &str) -> windows::core::Result<()> {
+        if self.key_name.contains(['/', '\\']) {
+            return Err(windows::core::Error::new(
+                E_FAIL,
+                "Registry keys can not contain path separators",
+            ));
+        }
+
+        // Creates the category if it doesn't exist yet:
+        let _category = open_category(category_id, true)?;
+        let category_id = HSTRING::from(category_id);
+
+        let token: ISpObjectToken = unsafe { CoCreateInstance(&SpObjectToken, None, CLSCTX_ALL) }?;
+        let token_id = HSTRING::from(format!("{category_id}\\Tokens\\{}", self.key_name));
+        unsafe { token.SetId(&category_id, &token_id, true) }?;
+
+        let writer = TokenWriter::new(&token);
+        writer.set_string_value("", &self.long_name)?;
+        writer.set_string_value("CLSID", &format!("{{{}}}", display_guid(self.class_id)))?;
+        if let Some(forced_language) = &self.forced_language {
+            writer.set_string_value(FORCED_LANGUAGE_VALUE, forced_language)?;
+        } else {
+            // Might have been set by an older version of the voice:
+            writer.remove_value(FORCED_LANGUAGE_VALUE)?;
+        }
+        if let Some(backend) = self.language_detector {
+            writer.set_string_value(LANGUAGE_DETECTOR_VALUE, backend.as_str())?;
+        } else {
+            writer.remove_value(LANGUAGE_DETECTOR_VALUE)?;
+        }
+
+        self.attributes.write_to_token(writer)
+    }
+    /// Make this voice the default voice of a SAPI category using
+    /// `ISpObjectTokenCategory::SetDefaultTokenId`. The voice should already be
+    /// registered in the category.
+    ///
+    /// This should only be used with [`VoiceCategory::VOICES`] since the
+    /// default voice of the OneCore category isn't handled correctly by this
+    /// API.
+    ///
+    /// The COM library must be initialized on the current thread.
+    pub fn set_as_default(&self, category_id: &str) -> windows::core::Result<()> {
+        let category = open_category(category_id, false)?;
+        let token_id = HSTRING::from(format!("{category_id}\\Tokens\\{}", self.key_name));
+        unsafe { category.SetDefaultTokenId(&token_id) }
+    }
+    /// Read a voice from a key inside a `Tokens` folder.
+    ///
+    /// Fails if the key doesn't have a valid `CLSID` value.
+    pub fn read_from_registry(
+        tokens_key: ParentRegKey,
+        key_name: &str,
+    ) -> windows::core::Result<Self> {
+        let mut key_name_buffer = Vec::new();
+        let voice_key = tokens_key.sub_key_path(key_name, &mut key_name_buffer);
+        let parent = tokens_key.parent_handle();
+
+        let class_id = read_string_value(parent, voice_key, w!("CLSID"))?
+            .ok_or_else(|| windows::core::Error::from(ERROR_FILE_NOT_FOUND.to_hresult()))?;
+        let class_id = GUID::try_from(class_id.trim_start_matches('{').trim_end_matches('}'))
+            .map_err(|_| {
+                windows::core::Error::new(E_FAIL, format!("Invalid CLSID for voice: {class_id}"))
+            })?;
+
+        let mut buffer = String::new();
+        Ok(Self {
+            key_name: key_name.to_owned(),
+            long_name: read_string_value(parent, voice_key, PCWSTR::null())?.unwrap_or_default(),
+            class_id,
+            attributes: VoiceAttributes::read_from_registry(
+                tokens_key.join_sub_key(key_name, &mut buffer),
+            )?,
+            forced_language: {
+                let name = to_utf16(FORCED_LANGUAGE_VALUE);
+                read_string_value(parent, voice_key, PCWSTR::from_raw(name.as_ptr()))?
+            },
+            language_detector: {
+                let name = to_utf16(LANGUAGE_DETECTOR_VALUE);
+                read_string_value(parent, voice_key, PCWSTR::from_raw(name.as_ptr()))?.and_then(
+                    |value| {
+                        value
+                            .parse()
+                            .inspect_err(|e| {
+                                log::warn!("Ignored language detector for voice {key_name:?}: {e}")
+                            })
+                            .ok()
+                    },
+                )
+            },
+        })
+    }
+    /// List all voices inside a `Tokens` folder. Keys that can't be read as
+    /// voices are skipped.
+    pub fn list(tokens_key: ParentRegKey) -> windows::core::Result<Vec<Self>> {
+        let mut key = HKEY::default();
+        {
+            let path_buffer;
+            let path = match tokens_key {
+                ParentRegKey::Path(_, prefix) => {
+                    path_buffer = to_utf16(prefix);
+                    PCWSTR::from_raw(path_buffer.as_ptr())
+                }
+                ParentRegKey::Handle(_) => PCWSTR::null(),
+            };
+            unsafe { RegOpenKeyExW(tokens_key.parent_handle(), path, None, KEY_READ, &mut key) }
+                .ok()?;
+        }
+
+        let mut key_names = Vec::new();
+        let result = (|| {
+            for index in 0.. {
+                // Key names can be at most 255 characters long:
+                let mut name = [0_u16; 256];
+                let mut len = name.len() as u32;
+                let result = unsafe {
+                    RegEnumKeyExW(
+                        key,
+                        index,
+                        Some(PWSTR::from_raw(name.as_mut_ptr())),
+                        &mut len,
+                        None,
+                        None,
+                        None,
+                        None,
+                    )
+                };
+                if result == ERROR_NO_MORE_ITEMS {
+                    break;
+                }
+                result.ok()?;
+                key_names.push(String::from_utf16_lossy(&name[..len as usize]));
+            }
+            windows::core::Result::Ok(())
+        })();
+        unsafe { key.free() };
+        result?;
+
+        Ok(key_names
+            .into_iter()
+            .filter_map(|key_name| {
+                Self::read_from_registry(tokens_key, &key_name)
+                    .inspect_err(|e| log::debug!("Skipped voice token {key_name:?}: {e}"))
+                    .ok()
+            })
+            .collect())
+    }
+    /// Remove every voice inside a `Tokens` folder that uses a specific
+    /// text-to-speech engine, including voices registered by older versions
+    /// with different key names. Returns the key names of the removed voices.
+    pub fn remove_all_for_class_id(
+        tokens_key: ParentRegKey,
+        class_id: GUID,
+    ) -> windows::core::Result<Vec<String>> {
+        let voices = match Self::list(tokens_key) {
+            Ok(voices) => voices,
+            Err(e) if e.code() == ERROR_FILE_NOT_FOUND.to_hresult() => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut removed = Vec::new();
+        for voice in voices {
+            if voice.class_id != class_id {
+                continue;
+            }
+            voice.remove_from_registry(tokens_key)?;
+            removed.push(voice.key_name);
+        }
+        Ok(removed)
+    }
+    /// Write the voice to several `Tokens` folders, for example both the
+    /// legacy and the OneCore voice folders. If any write fails then the voice
+    /// is removed from all folders again so that no half-registered voice is
+    /// left behind.
+    pub fn write_to_registries(&self, tokens_keys: &[ParentRegKey]) -> windows::core::Result<()> {
+        for (index, tokens_key) in tokens_keys.iter().enumerate() {
+            if let Err(e) = self.write_to_registry(*tokens_key) {
+                log::error!(
+                    "Failed to register voice {:?}, rolling back: {e}",
+                    self.key_name
+                );
+                for written in &tokens_keys[..=index] {
+                    if let Err(e) = self.remove_from_registry(*written) {
+                        log::warn!("Failed to roll back voice {:?}: {e}", self.key_name);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// Register the voice in several categories using
+    /// [`write_with_token_category`](Self::write_with_token_category). If that
+    /// fails for a category then the registry is written directly with
+    /// [`write_to_registry`](Self::write_to_registry) instead. If both fail
+    /// then the voice is removed from all categories again, like
+    /// [`write_to_registries`](Self::write_to_registries) does.
+    pub fn register_in_categories(
+        &self,
+        categories: &[VoiceCategory<'_>],
+    ) -> windows::core::Result<()> {
+        for (index, category) in categories.iter().enumerate() {
+            let result = self
+                .write_with_token_category(category.category_id)
+                .or_else(|e| {
+                    log::warn!(
+                        "Failed to register voice {:?} in {} using SAPI, writing to the registry instead: {e}",
+                        self.key_name,
+                        category.category_id
+                    );
+                    self.write_to_registry(category.tokens_key)
+                });
+            if let Err(e) = result {
+                log::error!(
+                    "Failed to register voice {:?}, rolling back: {e}",
+                    self.key_name
+                );
+                for written in &categories[..=index] {
+                    if let Err(e) = self.remove_from_registry(written.tokens_key) {
+                        log::warn!("Failed to roll back voice {:?}: {e}", self.key_name);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// Copy a voice that is registered in `source`, usually our own
+    /// [`VoiceCategory::LEJ77_VOICES`], into other categories like
+    /// [`VoiceCategory::VOICES`] so that SAPI clients can find it. SAPI has no
+    /// way to link tokens, so the voice's values are copied using
+    /// [`register_in_categories`](Self::register_in_categories).
+    ///
+    /// Returns the copied voice so that it can later be removed from the
+    /// `targets` with [`remove_from_registries`](Self::remove_from_registries).
+    pub fn alias_into_categories(
+        source: VoiceCategory<'_>,
+        key_name: &str,
+        targets: &[VoiceCategory<'_>],
+    ) -> windows::core::Result<Self> {
+        let voice = Self::read_from_registry(source.tokens_key, key_name)?;
+        voice.register_in_categories(targets)?;
+        Ok(voice)
+    }
+    /// Remove the voice from several `Tokens` folders. Continues removing the
+    /// voice from the other folders if one of them fails, and returns the
+    /// first error.
+    pub fn remove_from_registries(
+        &self,
+        tokens_keys: &[ParentRegKey],
+    ) -> windows::core::Result<()> {
+        let mut result = Ok(());
+        for tokens_key in tokens_keys {
+            if let Err(e) = self.remove_from_registry(*tokens_key) {
+                log::error!("Failed to unregister voice {:?}: {e}", self.key_name);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
     pub fn remove_from_registry(&self, tokens_key: ParentRegKey) -> windows::core::Result<()> {
         {
             let mut buffer = String::new();
@@ -290,3 +839,78 @@ mod private_impls {
 }
 
 pub use private_impls::VoiceTokenEnumerator;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A key under `HKEY_CURRENT_USER` that is removed when dropped.
+    struct TestKey(String);
+    impl TestKey {
+        fn new(name: &str) -> Self {
+            Self(format!(
+                "Software\\windows_tts_engine_tests\\{name}_{}",
+                std::process::id()
+            ))
+        }
+        fn tokens_key(&self) -> ParentRegKey<'_> {
+            ParentRegKey::Path(HKEY_CURRENT_USER, &self.0)
+        }
+    }
+    impl Drop for TestKey {
+        fn drop(&mut self) {
+            let path = to_utf16(&self.0);
+            let _ = unsafe { RegDeleteTreeW(HKEY_CURRENT_USER, PCWSTR::from_raw(path.as_ptr())) };
+        }
+    }
+
+    #[test]
+    fn re_registering_a_voice_removes_stale_values() {
+        let key = TestKey::new("re_register");
+        let old = VoiceKeyData {
+            key_name: "TestVoice".to_owned(),
+            long_name: "Old voice".to_owned(),
+            class_id: GUID::from_u128(0x5a6b_94c2_36d0_4a7e_9c1f_0e2d_3b4a_5c6d),
+            attributes: VoiceAttributes {
+                name: "Old".to_owned(),
+                gender: "Female".to_owned(),
+                age: "Adult".to_owned(),
+                language: "409".to_owned(),
+                vendor: "Test".to_owned(),
+                extra: vec![("VoiceType".to_owned(), "Neural".to_owned())],
+            },
+            forced_language: Some("en-US".to_owned()),
+            language_detector: Some(DetectorBackend::Microsoft),
+        };
+        old.write_to_registry(key.tokens_key()).unwrap();
+
+        let new = VoiceKeyData {
+            long_name: "New voice".to_owned(),
+            attributes: VoiceAttributes {
+                name: "New".to_owned(),
+                gender: "Male".to_owned(),
+                language: "41d".to_owned(),
+                extra: Vec::new(),
+                ..old.attributes.clone()
+            },
+            forced_language: None,
+            language_detector: None,
+            ..old.clone()
+        };
+        new.write_to_registry(key.tokens_key()).unwrap();
+
+        assert_eq!(
+            VoiceKeyData::read_from_registry(key.tokens_key(), "TestVoice").unwrap(),
+            new
+        );
+        // Extra attributes aren't read back, so check the old one directly:
+        let mut buffer = Vec::new();
+        let attributes = key
+            .tokens_key()
+            .sub_key_path("TestVoice\\Attributes", &mut buffer);
+        assert_eq!(
+            read_string_value(HKEY_CURRENT_USER, attributes, w!("VoiceType")).unwrap(),
+            None
+        );
+    }
+}
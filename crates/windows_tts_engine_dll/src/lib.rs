@@ -1,142 +1,351 @@
 //! Defines a COM Server that offers a text-to-speech engine for Windows.
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
 
 use windows::{
-    core::{Interface, GUID, HSTRING},
+    core::{IInspectable, Interface, GUID, HSTRING},
+    Devices::Enumeration::DeviceInformation,
+    Foundation::TypedEventHandler,
     Media::{
-        Playback::{MediaPlayer, MediaPlayerAudioCategory, MediaPlayerState},
-        SpeechSynthesis::SpeechSynthesizer,
+        Core::SpeechCue,
+        Playback::{MediaPlayer, MediaPlayerAudioCategory, MediaPlayerFailedEventArgs},
+        SpeechSynthesis::{SpeechSynthesisStream, SpeechSynthesizer, VoiceInformation},
     },
     Storage::Streams::{DataReader, IInputStream, IRandomAccessStream},
     Win32::{
-        Media::{
-            Audio::{WAVEFORMATEX, WAVE_FORMAT_PCM},
-            Speech::{
-                ISpObjectToken, ISpTTSEngineSite, SPVES_ABORT, SPVES_CONTINUE, SPVES_RATE,
-                SPVES_SKIP, SPVES_VOLUME,
-            },
-        },
-        System::Registry::HKEY_LOCAL_MACHINE,
+        Foundation::E_FAIL,
+        Media::Speech::{ISpObjectToken, ISpTTSEngineSite},
     },
 };
 use windows_tts_engine::{
-    com_server::{
-        dll_export_com_server_fns, ComClassInfo, ComServerPath, ComThreadingModel, SafeTtsComServer,
+    audio::AudioWriter,
+    com_server::{dll_export_com_server_fns, SafeTtsComServer},
+    detect_languages::{
+        has_multiple_languages, DetectionError, DetectorBackend, LinguaDetectionService,
     },
-    detect_languages::{has_multiple_languages, DetectedLanguage, LinguaDetectionService},
+    events::EventSink,
     logging::DllLogger,
-    voices::{ParentRegKey, VoiceAttributes, VoiceKeyData},
-    SafeTtsEngine, SpeechFormat, TextFrag, TextFragIter,
+    ssml::build_ssml,
+    text::{is_blank, JoinedText, SpeakSegment},
+    token::TokenReader,
+    utils::{duplicate_channels, read_token_string, sapi_rate_to_multiplier, write_debug_text},
+    voices::{VoiceAttributes, VoiceKeyData, FORCED_LANGUAGE_VALUE, LANGUAGE_DETECTOR_VALUE},
+    CancellationToken, MetricsRecorder, SafeTtsEngine, SpeakFlags, SpeakMetrics, SpeechFormat,
+    TextFrag,
 };
 
+/// Convert a SAPI rate to a `SpeakingRate`, which must be between `0.5` and
+/// `6.0`.
 fn sapi_rate_to_modern(sapi_rate: i32) -> f64 {
-    match sapi_rate.cmp(&0) {
-        std::cmp::Ordering::Less => 1.0 - (sapi_rate.abs() as f64 / 20.0).clamp(0., 0.5),
-        std::cmp::Ordering::Equal => 1.0,
-        std::cmp::Ordering::Greater => 1.0 + (sapi_rate as f64 / 2.0).clamp(0.0, 5.0),
-    }
+    sapi_rate_to_multiplier(sapi_rate).clamp(0.5, 6.0)
 }
 fn sapi_volume_to_modern(sapi_volume: u16) -> f64 {
     (sapi_volume as f64 / 100.0).clamp(0.0, 1.0)
 }
+/// Convert a SAPI pitch adjustment, where each unit is a semitone, to a
+/// frequency factor between `0` and `2`.
+fn sapi_pitch_to_modern(sapi_pitch: i32) -> f64 {
+    2_f64.powf(f64::from(sapi_pitch.clamp(-10, 10)) / 12.0)
+}
+
+/// Size of the RIFF header at the start of streams created by
+/// [`SpeechSynthesizer`].
+const WAV_HEADER_LEN: u32 = 44;
+/// Number of bytes of audio to read from the synthesized stream at a time.
+/// Smaller chunks mean that the client's actions are checked more often.
+const AUDIO_CHUNK_LEN: u32 = 4096;
+
+/// A word reported by [`SpeechSynthesizer`]'s word boundary metadata.
+struct WordCue {
+    /// When the word is spoken, relative to the start of the audio stream.
+    time: Duration,
+    /// Index of the first UTF-16 character of the word in the synthesized
+    /// text.
+    start: usize,
+    /// Inclusive index of the last UTF-16 character of the word.
+    end: usize,
+}
+
+/// Read word boundaries from a stream created when
+/// [`SpeechSynthesizerOptions::SetIncludeWordBoundaryMetadata`](windows::Media::SpeechSynthesis::SpeechSynthesizerOptions::SetIncludeWordBoundaryMetadata)
+/// was enabled.
+///
+/// # References
+///
+/// - [SpeechSynthesisStream.TimedMetadataTracks Property | Microsoft Learn](https://learn.microsoft.com/en-us/uwp/api/windows.media.speechsynthesis.speechsynthesisstream.timedmetadatatracks?view=winrt-26100)
+fn word_boundary_cues(stream: &SpeechSynthesisStream) -> windows::core::Result<Vec<WordCue>> {
+    let mut words = Vec::new();
+    for track in &stream.TimedMetadataTracks()? {
+        if track.Id()? != "SpeechWord" {
+            continue;
+        }
+        for cue in &track.Cues()? {
+            let cue: SpeechCue = cue.cast()?;
+            let (Ok(start), Ok(end)) = (
+                cue.StartPositionInInput().and_then(|v| v.Value()),
+                cue.EndPositionInInput().and_then(|v| v.Value()),
+            ) else {
+                continue;
+            };
+            words.push(WordCue {
+                // `TimeSpan` is measured in 100-nanosecond units:
+                time: Duration::from_nanos(cue.StartTime()?.Duration.max(0) as u64 * 100),
+                start: start.max(0) as usize,
+                end: end.max(0) as usize,
+            });
+        }
+    }
+    Ok(words)
+}
+
+/// Name of the voice token's registry value that stores the id of the audio
+/// device to play audio on when audio is played directly, see
+/// [`OurTtsEngine::audio_device`].
+pub const AUDIO_DEVICE_VALUE: &str = "AudioDevice";
 
 pub struct OurTtsEngine {
     /// Don't write audio to [`ISpTTSEngineSite`], instead play it directly on
     /// the audio output device. If `true` then the client application can't
     /// save the audio to a file.
     play_audio_directly: bool,
+    /// Synthesize all text in this language instead of detecting the
+    /// language. Can be configured by the voice token's
+    /// [`FORCED_LANGUAGE_VALUE`] registry value.
+    forced_language: Mutex<Option<String>>,
+    /// Library used to detect languages. Can be configured by the voice
+    /// token's [`LANGUAGE_DETECTOR_VALUE`] registry value.
+    language_detector: Mutex<DetectorBackend>,
+    /// Created by [`Self::prepare_detection_service`] and kept so that
+    /// language models aren't loaded again for every call to `speak`.
+    detection_service: Mutex<Option<LinguaDetectionService>>,
+    /// Id of the audio device to use when [`Self::play_audio_directly`] is
+    /// `true`, uses the default device if this is `None`. Can be configured by
+    /// the voice token's [`AUDIO_DEVICE_VALUE`] registry value.
+    ///
+    /// Device ids can be found using `DeviceInformation::FindAllAsync` with
+    /// the `MediaDevice::GetAudioRenderSelector` filter.
+    audio_device: Mutex<Option<String>>,
+    /// Checked between detecting languages and synthesizing parts of the
+    /// text, see [`SafeTtsEngine::cancellation_token`].
+    cancel: CancellationToken,
+}
+impl OurTtsEngine {
+    /// Create the language detector for the languages of the installed voices
+    /// unless it was already created, since loading the lingua models can
+    /// take hundreds of milliseconds.
+    fn prepare_detection_service<S: AsRef<str>>(
+        &self,
+        languages: &[S],
+    ) -> Result<MutexGuard<'_, Option<LinguaDetectionService>>, DetectionError> {
+        let mut guard = self.detection_service.lock().unwrap();
+        if guard.is_none() {
+            let backend = *self.language_detector.lock().unwrap();
+            let started = Instant::now();
+            *guard = Some(LinguaDetectionService::with_backend(backend, languages)?);
+            log::debug!(
+                "Created the {backend:?} language detector (duration: {:?})",
+                started.elapsed()
+            );
+        }
+        Ok(guard)
+    }
 }
 impl SafeTtsEngine for OurTtsEngine {
-    fn set_object_token(&self, _token: &ISpObjectToken) -> windows::core::Result<()> {
+    fn set_object_token(&self, token: &ISpObjectToken) -> windows::core::Result<()> {
         log::debug!("set_object_token");
+        match read_token_string(token, FORCED_LANGUAGE_VALUE) {
+            Ok(Some(lang)) => {
+                log::debug!("Voice is configured to always use the language {lang:?}");
+                *self.forced_language.lock().unwrap() = Some(lang);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read forced language from voice token: {e}"),
+        }
+        let configured_detector = match read_token_string(token, LANGUAGE_DETECTOR_VALUE) {
+            Ok(Some(value)) => value
+                .parse::<DetectorBackend>()
+                .inspect_err(|e| log::warn!("Invalid language detector in voice token: {e}"))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("Failed to read language detector from voice token: {e}");
+                None
+            }
+        };
+        let detector = match configured_detector {
+            Some(backend) => backend,
+            // Voices registered by older versions only have a key name that
+            // ends with "Lingua":
+            None => match TokenReader::new(token).id() {
+                Ok(id) if id.ends_with("Lingua") => DetectorBackend::Lingua,
+                Ok(_) => DetectorBackend::Microsoft,
+                Err(e) => {
+                    log::warn!("Failed to read voice token id: {e}");
+                    DetectorBackend::Microsoft
+                }
+            },
+        };
+        log::debug!("Voice uses the {detector:?} language detector");
+        *self.language_detector.lock().unwrap() = detector;
+        *self.detection_service.lock().unwrap() = None;
+        match read_token_string(token, AUDIO_DEVICE_VALUE) {
+            Ok(Some(device)) => {
+                log::debug!("Voice is configured to play audio on the device {device:?}");
+                *self.audio_device.lock().unwrap() = Some(device);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read audio device from voice token: {e}"),
+        }
+
+        // Load the language detector now so that the first text isn't
+        // delayed by it:
+        if self.forced_language.lock().unwrap().is_none() {
+            match SpeechSynthesizer::AllVoices() {
+                Ok(voices) => {
+                    let languages: Vec<String> = voices
+                        .into_iter()
+                        .filter_map(|voice| voice.Language().ok())
+                        .map(|lang| lang.to_string_lossy())
+                        .collect();
+                    if has_multiple_languages(&languages) {
+                        if let Err(e) = self.prepare_detection_service(&languages) {
+                            log::warn!("Failed to create language detector: {e}");
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Failed to list installed voices: {e}"),
+            }
+        }
         Ok(())
     }
 
     fn speak(
         &self,
-        _token: &ISpObjectToken,
-        _speak_punctuation: bool,
-        _wave_format: SpeechFormat,
+        _token: Option<&ISpObjectToken>,
+        speak_flags: SpeakFlags,
+        wave_format: SpeechFormat,
         text_fragments: Option<TextFrag<'_>>,
         output_site: &ISpTTSEngineSite,
     ) -> windows::core::Result<()> {
-        let text_utf16 = TextFragIter::new(text_fragments)
-            .flat_map(|frag| frag.utf16_text().iter().copied().chain([' ' as u16]))
-            .collect::<Vec<u16>>();
-        log::debug!("Speak: {}", String::from_utf16_lossy(&text_utf16));
-
-        let all_voices = SpeechSynthesizer::AllVoices()?;
-        let has_multiple_languages = has_multiple_languages(
-            (&all_voices)
-                .into_iter()
-                .filter_map(|voice| voice.Language().ok())
-                .map(|hstring| hstring.to_string_lossy())
-                // ignore difference between `en-US` and `en-GB`:
-                .map(|lang| {
-                    lang.split_once(['_', '-'])
-                        .map(|(prefix, _)| prefix.to_owned())
-                        .unwrap_or(lang)
-                }),
+        log::debug!("Speak - {speak_flags:?}");
+        let event_sink = EventSink::new(output_site)?;
+        let mut writer = AudioWriter::with_max_chunk_len(
+            output_site,
+            AUDIO_CHUNK_LEN as usize * usize::from(wave_format.channels()),
         );
+        let mut metrics = MetricsRecorder::new(self);
+
+        let joined_text = JoinedText::new(text_fragments, speak_flags);
+        let text_utf16 = joined_text.utf16();
+        log::debug!("Speak: {}", String::from_utf16_lossy(text_utf16));
+        if let SpeechFormat::DebugText = wave_format {
+            // Output the text we would have synthesized, with spelled out
+            // fragments already expanded:
+            return write_debug_text(output_site, text_utf16);
+        }
+        if is_blank(text_utf16) && joined_text.actions().next().is_none() {
+            log::debug!("Speak - Skipped since there is no text to speak");
+            return Ok(());
+        }
 
-        let detected_language_ranges = if has_multiple_languages {
-            let started_lang_detect = Instant::now();
-
-            let prefer_lingua = cfg!(feature = "lingua")
-                && unsafe { _token.GetId()?.to_string()? }.ends_with("Lingua");
-
-            let detection_service = if prefer_lingua {
-                let output_languages: Vec<String> = (&all_voices)
-                    .into_iter()
-                    .filter_map(|voice| {
-                        Some(
-                            voice
-                                .Language()
-                                .inspect_err(|e| {
-                                    log::warn!("Failed to get language info for voice: {e}")
-                                })
-                                .ok()?
-                                .to_string_lossy(),
-                        )
-                    })
-                    .collect();
-                LinguaDetectionService::with_lingua(&output_languages)
-            } else {
-                LinguaDetectionService::with_microsoft_language_detection()
-            };
-
-            let detected = detection_service
-                .expect("Failed to find language detection service")
-                .recognize_text(&text_utf16)
-                .expect("Failed to recognize text language");
-
-            log::debug!(
-                "Speak - Detected languages{} (duration: {:?})",
-                if cfg!(not(feature = "lingua")) {
-                    ""
-                } else if prefer_lingua {
-                    " using the Lingua library"
-                } else {
-                    " using Microsoft Language Detection"
-                },
-                started_lang_detect.elapsed()
-            );
-            detected
-        } else {
-            log::debug!("Speak - Skipped language detection since only one language is installed");
-            vec![DetectedLanguage {
-                start: 0,
-                end: text_utf16.len().saturating_sub(1),
-                languages: Vec::new(),
-            }]
+        // Enumerate the installed voices and their languages once, since
+        // every language range needs to select a voice:
+        let all_voices: Vec<(VoiceInformation, Option<String>)> = SpeechSynthesizer::AllVoices()?
+            .into_iter()
+            .map(|voice| {
+                let lang = voice
+                    .Language()
+                    .inspect_err(|e| log::warn!("Failed to get language info for voice: {e}"))
+                    .ok()
+                    .map(|lang| lang.to_string_lossy());
+                (voice, lang)
+            })
+            .collect();
+
+        let started_detect = Instant::now();
+        let forced_language = self.forced_language.lock().unwrap().clone();
+        let voice_languages: Vec<String> = all_voices
+            .iter()
+            .filter_map(|(_, lang)| lang.clone())
+            .collect();
+        let Some(detected_language_ranges) = joined_text.detect_language_ranges(
+            forced_language,
+            &voice_languages,
+            |languages| self.prepare_detection_service(languages),
+            &self.cancel,
+        ) else {
+            log::debug!("Speak - Cancelled");
+            return Ok(());
         };
+        metrics.detect += started_detect.elapsed();
 
-        for lang_range in detected_language_ranges {
+        for segment in joined_text.split_at_actions(detected_language_ranges) {
+            if self.cancel.is_cancelled() {
+                log::debug!("Speak - Cancelled");
+                return Ok(());
+            }
+            let mut lang_range = match segment {
+                SpeakSegment::Text(lang_range) => lang_range,
+                SpeakSegment::Silence { milliseconds } => {
+                    log::debug!("Speak - Inserting {milliseconds} ms of silence");
+                    let len = wave_format.silence_bytes(milliseconds);
+                    if writer.write_silence(len)?.abort() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                SpeakSegment::Bookmark { name } => {
+                    log::debug!("Speak - Bookmark: {}", String::from_utf16_lossy(name));
+                    event_sink.bookmark(writer.audio_offset(), name)?;
+                    continue;
+                }
+            };
+            // Detected ranges might not match the joined text exactly, so make
+            // sure they can be used to slice it:
+            let Some((start, end)) =
+                joined_text.snap_to_char_boundaries(lang_range.start, lang_range.end)
+            else {
+                log::warn!(
+                    "Speak - Skipped range {}..={} outside of text with length {}",
+                    lang_range.start,
+                    lang_range.end,
+                    text_utf16.len()
+                );
+                continue;
+            };
+            if lang_range.end >= text_utf16.len() {
+                log::warn!(
+                    "Speak - Clamped range {}..={} to text with length {}",
+                    lang_range.start,
+                    lang_range.end,
+                    text_utf16.len()
+                );
+            }
+            (lang_range.start, lang_range.end) = (start, end);
+            if let Some(source) = joined_text.source_range(lang_range.start, lang_range.end) {
+                log::trace!(
+                    "Speak - Range {}..={} has length {} at offset {} in original text",
+                    lang_range.start,
+                    lang_range.end,
+                    source.len,
+                    source.offset
+                );
+            }
             let text_utf16 = &text_utf16[lang_range.start..=lang_range.end];
+            if is_blank(text_utf16) {
+                log::trace!("Speak - Skipped whitespace between actions");
+                continue;
+            }
+            let started_load = Instant::now();
             let synth = SpeechSynthesizer::new()?;
 
-            if has_multiple_languages {
+            if !lang_range.languages.is_empty() {
                 let mut selected_voice = synth.Voice()?;
                 let mut selected_priority = selected_voice
                     .Language()
@@ -144,14 +353,13 @@ impl SafeTtsEngine for OurTtsEngine {
                     .and_then(|lang| lang_range.get_priority(&lang.to_string_lossy()))
                     .unwrap_or(usize::MAX);
 
-                for voice in &all_voices {
-                    let priority = voice
-                        .Language()
-                        .ok()
-                        .and_then(|lang| lang_range.get_priority(&lang.to_string_lossy()))
+                for (voice, lang) in &all_voices {
+                    let priority = lang
+                        .as_deref()
+                        .and_then(|lang| lang_range.get_priority(lang))
                         .unwrap_or(usize::MAX);
                     if priority < selected_priority {
-                        selected_voice = voice;
+                        selected_voice = voice.clone();
                         selected_priority = priority;
                     }
                 }
@@ -170,21 +378,60 @@ impl SafeTtsEngine for OurTtsEngine {
                 }
             }
 
+            metrics.model_load += started_load.elapsed();
+
+            let started_synthesis = Instant::now();
             let synth_options = synth.Options()?;
+            synth_options.SetIncludeWordBoundaryMetadata(true)?;
             synth_options
                 .SetSpeakingRate(sapi_rate_to_modern(unsafe { output_site.GetRate() }?))?;
             synth_options
                 .SetAudioVolume(sapi_volume_to_modern(unsafe { output_site.GetVolume()? }))?;
+            // The synthesizer can change the pitch natively (SSML overrides
+            // this per fragment):
+            if let Some((frag, _)) = joined_text
+                .fragments_in(lang_range.start..lang_range.end + 1)
+                .next()
+            {
+                synth_options.SetAudioPitch(sapi_pitch_to_modern(frag.frag_state().pitch()))?;
+            }
 
-            let stream = synth
-                .SynthesizeTextToStreamAsync(&HSTRING::from_wide(text_utf16))?
-                .get()?;
-
-            enum Output<'a> {
-                Player(MediaPlayer),
-                Data(&'a [u16]),
+            // Forward XML state as SSML so that the synthesizer can apply
+            // prosody itself:
+            let ssml_stream = if speak_flags.is_xml() {
+                let lang = synth.Voice()?.Language()?.to_string_lossy();
+                let ssml = build_ssml(&joined_text, lang_range.start..lang_range.end + 1, &lang);
+                log::trace!("Speak - SSML: {ssml}");
+                synth
+                    .SynthesizeSsmlToStreamAsync(&HSTRING::from(ssml))
+                    .and_then(|operation| operation.get())
+                    .inspect_err(|e| {
+                        log::warn!("Failed to synthesize SSML, falling back to plain text: {e}")
+                    })
+                    .ok()
+            } else {
+                None
+            };
+            // Word positions in SSML don't match our text:
+            let used_ssml = ssml_stream.is_some();
+            let stream = match ssml_stream {
+                Some(stream) => stream,
+                None => synth
+                    .SynthesizeTextToStreamAsync(&HSTRING::from_wide(text_utf16))?
+                    .get()?,
+            };
+            metrics.synthesis += started_synthesis.elapsed();
+
+            enum Output {
+                /// The receiver gets a message when playback ends or fails.
+                Player(MediaPlayer, mpsc::Receiver<()>),
+                /// Audio that is read from the stream in chunks so that we can
+                /// stop early if the client aborts.
+                Data {
+                    reader: DataReader,
+                    remaining_bytes: u32,
+                },
             }
-            let mut buffer;
             let mut output = if self.play_audio_directly {
                 let rand_stream: IRandomAccessStream = stream.cast()?;
 
@@ -192,66 +439,180 @@ impl SafeTtsEngine for OurTtsEngine {
                 player.SetRealTimePlayback(true)?;
                 player.SetAudioCategory(MediaPlayerAudioCategory::Speech)?;
                 player.SetStreamSource(&rand_stream)?;
+                if let Some(device_id) = self.audio_device.lock().unwrap().clone() {
+                    let device = DeviceInformation::CreateFromIdAsync(&HSTRING::from(&device_id))
+                        .and_then(|operation| operation.get())
+                        .and_then(|device| {
+                            if device.IsEnabled()? {
+                                Ok(device)
+                            } else {
+                                Err(windows::core::Error::new(
+                                    E_FAIL,
+                                    "the audio device is disabled",
+                                ))
+                            }
+                        });
+                    match device.and_then(|device| player.SetAudioDevice(&device)) {
+                        Ok(()) => log::debug!("Speak - Playing audio on device {device_id:?}"),
+                        Err(e) => log::warn!(
+                            "Failed to use audio device {device_id:?}, using the default device instead: {e}"
+                        ),
+                    }
+                }
+
+                let (ended_tx, ended_rx) = mpsc::channel();
+                let failed_tx = ended_tx.clone();
+                player.MediaEnded(&TypedEventHandler::<MediaPlayer, IInspectable>::new(
+                    move |_, _| {
+                        let _ = ended_tx.send(());
+                        Ok(())
+                    },
+                ))?;
+                player.MediaFailed(
+                    &TypedEventHandler::<MediaPlayer, MediaPlayerFailedEventArgs>::new(
+                        move |_, args| {
+                            if let Some(args) = args.as_ref() {
+                                log::error!(
+                                    "Failed to play audio: {}",
+                                    args.ErrorMessage().unwrap_or_default()
+                                );
+                            }
+                            let _ = failed_tx.send(());
+                            Ok(())
+                        },
+                    ),
+                )?;
                 player.Play()?;
 
-                Output::Player(player)
+                Output::Player(player, ended_rx)
             } else {
+                let word_cues = if used_ssml {
+                    Vec::new()
+                } else {
+                    word_boundary_cues(&stream).unwrap_or_else(|e| {
+                        log::warn!("Failed to read word boundary metadata: {e}");
+                        Vec::new()
+                    })
+                };
+
                 let size = stream.Size()? as u32;
                 let stream: IInputStream = stream.cast()?;
                 let reader = DataReader::CreateDataReader(&stream)?;
-                reader.LoadAsync(size)?.get()?;
-
-                buffer = vec![0_u16; size as usize / 2];
-                reader.ReadBytes(unsafe { buffer.as_mut_slice().align_to_mut::<u8>().1 })?;
 
                 // Discard .wav header (44 bytes)
-                Output::Data(&buffer[44..])
+                let mut header = [0_u8; WAV_HEADER_LEN as usize];
+                if reader.LoadAsync(WAV_HEADER_LEN)?.get()? == WAV_HEADER_LEN {
+                    reader.ReadBytes(&mut header)?;
+                }
+                let remaining_bytes = size.saturating_sub(WAV_HEADER_LEN);
+                // Mono audio is copied to every channel the client asked for:
+                let data_len = u64::from(remaining_bytes) * u64::from(wave_format.channels());
+                let audio_offset = writer.audio_offset();
+
+                event_sink.estimated_sentence_boundaries(
+                    &joined_text,
+                    lang_range.start..lang_range.end + 1,
+                    audio_offset,
+                    data_len,
+                    wave_format.block_align(),
+                )?;
+                if word_cues.is_empty() {
+                    event_sink.estimated_word_boundaries(
+                        &joined_text,
+                        lang_range.start..lang_range.end + 1,
+                        audio_offset,
+                        data_len,
+                        wave_format.block_align(),
+                    )?;
+                }
+                for cue in word_cues {
+                    let Some(source) = joined_text
+                        .source_range(lang_range.start + cue.start, lang_range.start + cue.end)
+                    else {
+                        continue;
+                    };
+                    event_sink.word_boundary(
+                        audio_offset + wave_format.bytes_for_duration(cue.time),
+                        source,
+                    )?;
+                }
+                // SpeechSynthesizer doesn't provide viseme metadata so guess
+                // visemes from the text:
+                event_sink.estimated_visemes(
+                    &joined_text,
+                    lang_range.start..lang_range.end + 1,
+                    audio_offset,
+                    data_len,
+                    &wave_format,
+                )?;
+                Output::Data {
+                    reader,
+                    remaining_bytes,
+                }
             };
 
+            let started_write = Instant::now();
             loop {
-                match &mut output {
-                    Output::Player(player) => {
-                        let state = player.CurrentState()?;
-                        if let MediaPlayerState::Stopped | MediaPlayerState::Paused = state {
+                let actions = match &mut output {
+                    Output::Player(_player, ended) => {
+                        // Wake up regularly to check for abort:
+                        match ended.recv_timeout(Duration::from_millis(20)) {
+                            Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+                            Err(RecvTimeoutError::Timeout) => {}
+                        }
+                        writer.actions()
+                    }
+                    Output::Data {
+                        reader,
+                        remaining_bytes,
+                    } => {
+                        let loaded = reader
+                            .LoadAsync((*remaining_bytes).min(AUDIO_CHUNK_LEN))?
+                            .get()?;
+                        if loaded == 0 {
                             break;
                         }
+                        let mut chunk = vec![0_u8; loaded as usize];
+                        reader.ReadBytes(&mut chunk)?;
+                        *remaining_bytes -= loaded;
+
+                        // The synthesizer generates mono audio, so copy it to
+                        // every channel the client asked for:
+                        if wave_format.channels() > 1 {
+                            let mono: Vec<[u8; 2]> = chunk
+                                .chunks_exact(2)
+                                .map(|sample| [sample[0], sample[1]])
+                                .collect();
+                            chunk = duplicate_channels(&mono, wave_format.channels()).concat();
+                        }
 
-                        std::thread::sleep(Duration::from_millis(100));
-                    }
-                    Output::Data(buffer) => {
-                        let written_bytes = unsafe {
-                            output_site
-                                .Write(buffer.as_ptr().cast(), (buffer.len() * 2).min(4096) as u32)
-                        }?;
-                        *buffer = &buffer[written_bytes as usize / 2..];
-                        if buffer.is_empty() {
+                        let actions = writer.write(&chunk)?;
+                        if *remaining_bytes == 0 {
                             break;
                         }
+                        actions
                     }
-                }
+                };
 
-                // Call GetActions as often as possible (returns bitflags):
-                // https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ee431802(v=vs.85)
-                let actions = unsafe { output_site.GetActions() } as i32;
-                if actions == SPVES_CONTINUE.0 {
+                if actions.is_continue() {
                     continue;
                 }
-                if SPVES_ABORT.0 & actions != 0 {
+                if actions.abort() {
                     return Ok(());
                 }
                 // TODO: the following actions change the synthesizer settings
                 // but that doesn't affect already queued sound.
-                if SPVES_SKIP.0 & actions != 0 {
+                if actions.skip() {
                     log::trace!("Skip actions are not implemented");
                 }
-                if SPVES_RATE.0 & actions != 0 {
+                if actions.rate_changed() {
                     // -10 to 10
                     let new_rate = unsafe { output_site.GetRate() }?;
                     let modern_rate = sapi_rate_to_modern(new_rate);
                     log::trace!("New SAPI rate of {new_rate} -> modern rate of {modern_rate}");
                     synth_options.SetSpeakingRate(modern_rate)?;
                 }
-                if SPVES_VOLUME.0 & actions != 0 {
+                if actions.volume_changed() {
                     // 0 to 100
                     let new_volume = unsafe { output_site.GetVolume() }?;
                     let modern_volume = sapi_volume_to_modern(new_volume);
@@ -261,34 +622,28 @@ impl SafeTtsEngine for OurTtsEngine {
                     synth_options.SetAudioVolume(modern_volume)?;
                 }
             }
+            metrics.write += started_write.elapsed();
         }
 
+        writer.flush()?;
         Ok(())
     }
 
-    #[expect(non_snake_case)]
-    fn get_output_format(
-        &self,
-        _token: &ISpObjectToken,
-        target_format: Option<SpeechFormat>,
-    ) -> windows::core::Result<SpeechFormat> {
-        log::debug!("get_output_format: {target_format:?}");
-        if let Some(SpeechFormat::DebugText) = target_format {
-            return Ok(SpeechFormat::DebugText);
-        }
+    fn cancellation_token(&self) -> Option<&CancellationToken> {
+        Some(&self.cancel)
+    }
+
+    fn on_metrics(&self, metrics: SpeakMetrics) {
+        log::debug!("Speak - {metrics:?}");
+    }
 
-        // SPSF_16kHz16BitMono (16kHz 16Bit mono)
-        let nSamplesPerSec = 16_000;
-        let nBlockAlign = 2;
-        Ok(SpeechFormat::Wave(WAVEFORMATEX {
-            wFormatTag: WAVE_FORMAT_PCM as _,
-            nChannels: 1,
-            nBlockAlign,
-            wBitsPerSample: 16,
-            nSamplesPerSec,
-            nAvgBytesPerSec: nSamplesPerSec * (nBlockAlign as u32),
-            cbSize: 0,
-        }))
+    fn supported_formats(&self, _token: Option<&ISpObjectToken>) -> Vec<SpeechFormat> {
+        // SPSF_16kHz16BitMono (16kHz 16Bit mono) or SPSF_16kHz16BitStereo
+        vec![
+            SpeechFormat::pcm_i16(16_000, 1),
+            SpeechFormat::pcm_i16(16_000, 2),
+            SpeechFormat::DebugText,
+        ]
     }
 }
 
@@ -303,7 +658,10 @@ fn multilingual_voice_data() -> VoiceKeyData {
             age: "Adult".to_owned(),
             language: "409".to_owned(), // en-US
             vendor: "Lej77 at GitHub".to_owned(),
+            extra: Vec::new(),
         },
+        forced_language: None,
+        language_detector: None,
     }
 }
 
@@ -319,7 +677,10 @@ fn multilingual_lingua_voice_data() -> VoiceKeyData {
             age: "Adult".to_owned(),
             language: "409".to_owned(), // en-US
             vendor: "Lej77 at GitHub".to_owned(),
+            extra: Vec::new(),
         },
+        forced_language: None,
+        language_detector: Some(DetectorBackend::Lingua),
     }
 }
 
@@ -333,72 +694,32 @@ struct TtsComServer;
 impl SafeTtsComServer for TtsComServer {
     const CLSID_TTS_ENGINE: GUID = CLSID_OUR_TTS_ENGINE;
 
+    const CLASS_NAME: &'static str = "windows_tts_engine";
+
     type TtsEngine = OurTtsEngine;
 
     fn create_engine() -> Self::TtsEngine {
         OurTtsEngine {
             play_audio_directly: false,
+            forced_language: Mutex::new(None),
+            language_detector: Mutex::new(DetectorBackend::Microsoft),
+            detection_service: Mutex::new(None),
+            audio_device: Mutex::new(None),
+            cancel: CancellationToken::new(),
         }
     }
 
-    fn initialize() {
-        static DLL_LOGGER: DllLogger = DllLogger::new();
-        DLL_LOGGER.install()
-    }
-
-    fn register_server() {
-        ComClassInfo {
-            clsid: CLSID_OUR_TTS_ENGINE,
-            class_name: Some("windows_tts_engine".into()),
-            threading_model: ComThreadingModel::Apartment,
-            server_path: ComServerPath::CurrentModule,
-        }
-        .register()
-        .expect("Failed to register COM Class");
-
-        let voices = [
+    fn voices() -> Vec<VoiceKeyData> {
+        vec![
             multilingual_voice_data(),
             #[cfg(feature = "lingua")]
             multilingual_lingua_voice_data(),
-        ];
-        for voice in voices {
-            voice
-                .write_to_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to register voice");
-            voice
-                .write_to_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech_OneCore\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to register voice in modern voice path");
-        }
+        ]
     }
 
-    fn unregister_server() {
-        let voices = [
-            multilingual_voice_data(),
-            #[cfg(feature = "lingua")]
-            multilingual_lingua_voice_data(),
-        ];
-        for voice in voices {
-            voice
-                .remove_from_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech_OneCore\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to unregister voice from modern voice path");
-            voice
-                .remove_from_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to unregister voice");
-        }
-        ComClassInfo::unregister_class_id(CLSID_OUR_TTS_ENGINE)
-            .expect("Failed to unregister text-to-speech engine's COM Class");
+    fn initialize() {
+        static DLL_LOGGER: DllLogger = DllLogger::new();
+        DLL_LOGGER.install()
     }
 }
 
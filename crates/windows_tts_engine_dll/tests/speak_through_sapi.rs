@@ -0,0 +1,155 @@
+//! End-to-end test that registers the engine for the current user and speaks
+//! through a real SAPI `ISpVoice`.
+//!
+//! Ignored by default since it changes the registry and needs the modern
+//! Windows voices. Run it with `cargo test -p windows_tts_engine_dll -- --ignored`.
+#![cfg(windows)]
+
+use std::{path::PathBuf, process::Command};
+
+use windows::{
+    core::{w, Interface, PCWSTR},
+    Win32::{
+        Foundation::HGLOBAL,
+        Media::{
+            Audio::{WAVEFORMATEX, WAVE_FORMAT_PCM},
+            Speech::{
+                ISpObjectToken, ISpStream, ISpVoice, SPDFID_WaveFormatEx, SpObjectToken, SpStream,
+                SpVoice, SPEI_END_INPUT_STREAM, SPEI_RESERVED1, SPEI_RESERVED2,
+                SPEI_START_INPUT_STREAM, SPEI_WORD_BOUNDARY, SPEVENT, SPEVENTENUM,
+                SPF_PURGEBEFORESPEAK,
+            },
+        },
+        System::Com::{
+            CoCreateInstance, CoInitialize, CoUninitialize,
+            StructuredStorage::CreateStreamOnHGlobal, CLSCTX_ALL, STREAM_SEEK_END,
+        },
+    },
+};
+
+/// The voice registered by the engine for the current user.
+const VOICE_TOKEN_ID: PCWSTR =
+    w!("HKEY_CURRENT_USER\\SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\Lej77_TTS_Multilingual");
+
+/// The DLL that cargo builds next to the test executable's `deps` folder.
+fn engine_dll() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    let dll = exe
+        .parent()
+        .and_then(|deps| deps.parent())
+        .unwrap()
+        .join("windows_tts_engine.dll");
+    assert!(dll.exists(), "Engine DLL not found at {}", dll.display());
+    dll
+}
+
+fn regsvr32(args: &[&str]) -> bool {
+    Command::new("regsvr32")
+        .args(["/s", "/n"])
+        .args(args)
+        .arg(engine_dll())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Registers the engine for the current user and unregisters it when
+/// dropped, even if the test fails.
+struct UserRegistration;
+impl UserRegistration {
+    fn new() -> Self {
+        assert!(regsvr32(&["/i:user"]), "Failed to register the engine");
+        Self
+    }
+}
+impl Drop for UserRegistration {
+    fn drop(&mut self) {
+        if !regsvr32(&["/u", "/i:user"]) {
+            eprintln!("Failed to unregister the engine");
+        }
+    }
+}
+
+struct ComInit;
+impl ComInit {
+    fn new() -> Self {
+        unsafe { CoInitialize(None) }.ok().unwrap();
+        Self
+    }
+}
+impl Drop for ComInit {
+    fn drop(&mut self) {
+        unsafe { CoUninitialize() };
+    }
+}
+
+/// Same as the `SPFEI` macro from the SAPI headers.
+fn event_interest(events: &[SPEVENTENUM]) -> u64 {
+    let flag_mask = (1_u64 << SPEI_RESERVED1.0) | (1_u64 << SPEI_RESERVED2.0);
+    events
+        .iter()
+        .fold(flag_mask, |interest, event| interest | (1_u64 << event.0))
+}
+
+#[test]
+#[ignore = "registers the engine for the current user"]
+fn speak_to_memory_stream() {
+    let _registration = UserRegistration::new();
+    let _com = ComInit::new();
+
+    let voice: ISpVoice = unsafe { CoCreateInstance(&SpVoice, None, CLSCTX_ALL) }.unwrap();
+    let token: ISpObjectToken =
+        unsafe { CoCreateInstance(&SpObjectToken, None, CLSCTX_ALL) }.unwrap();
+    unsafe { token.SetId(PCWSTR::null(), VOICE_TOKEN_ID, false) }.unwrap();
+    unsafe { voice.SetVoice(&token) }.unwrap();
+
+    // 16 kHz 16-bit mono, which the engine supports:
+    let format = WAVEFORMATEX {
+        wFormatTag: WAVE_FORMAT_PCM as u16,
+        nChannels: 1,
+        nSamplesPerSec: 16_000,
+        nAvgBytesPerSec: 32_000,
+        nBlockAlign: 2,
+        wBitsPerSample: 16,
+        cbSize: 0,
+    };
+    let memory = unsafe { CreateStreamOnHGlobal(HGLOBAL::default(), true) }.unwrap();
+    let stream: ISpStream = unsafe { CoCreateInstance(&SpStream, None, CLSCTX_ALL) }.unwrap();
+    unsafe { stream.SetBaseStream(&memory, &SPDFID_WaveFormatEx, &format) }.unwrap();
+    unsafe { voice.SetOutput(&stream, false) }.unwrap();
+
+    let interest = event_interest(&[
+        SPEI_START_INPUT_STREAM,
+        SPEI_END_INPUT_STREAM,
+        SPEI_WORD_BOUNDARY,
+    ]);
+    unsafe { voice.SetInterest(interest, interest) }.unwrap();
+
+    unsafe {
+        voice.Speak(
+            w!("Hello from the integration test."),
+            SPF_PURGEBEFORESPEAK.0 as u32,
+            None,
+        )
+    }
+    .unwrap();
+
+    let mut len = 0_u64;
+    unsafe { memory.Seek(0, STREAM_SEEK_END, Some(&mut len as *mut u64)) }.unwrap();
+    assert!(len > 0, "No audio was written");
+    assert_eq!(len % 2, 0, "Audio should contain whole 16-bit samples");
+
+    // None of the requested events have pointers that must be freed:
+    let mut events = vec![SPEVENT::default(); 64];
+    let mut fetched = 0;
+    unsafe { voice.GetEvents(events.len() as u32, events.as_mut_ptr(), &mut fetched) }.unwrap();
+    let event_ids = events[..fetched as usize]
+        .iter()
+        .map(|event| event._bitfield & 0xFFFF)
+        .collect::<Vec<_>>();
+    assert_eq!(event_ids.first(), Some(&SPEI_START_INPUT_STREAM.0));
+    assert_eq!(event_ids.last(), Some(&SPEI_END_INPUT_STREAM.0));
+    assert!(event_ids.contains(&SPEI_WORD_BOUNDARY.0));
+
+    // Release SAPI objects before unregistering the engine:
+    drop((voice, stream, memory, token));
+}
@@ -4,20 +4,26 @@
 //!
 //! - <https://github.com/gexgd0419/NaturalVoiceSAPIAdapter/blob/master/Installer/Install.cpp>
 
-use std::{ffi::OsStr, path::Path};
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{bail, Context};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use windows::{
-    core::{w, Free, PCWSTR},
+    core::{s, w, Free, HRESULT, PCWSTR},
     Win32::{
-        Foundation::MAX_PATH,
+        Foundation::{CloseHandle, HANDLE},
+        Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
         System::{
-            LibraryLoader::GetModuleFileNameW,
+            LibraryLoader::{FreeLibrary, GetProcAddress, LoadLibraryW},
             Registry::{
-                RegCreateKeyExW, RegDeleteKeyExW, RegSetValueExW, HKEY_CURRENT_USER, KEY_SET_VALUE,
-                REG_SZ,
+                RegCreateKeyExW, RegDeleteKeyExW, RegOpenCurrentUser, RegOverridePredefKey,
+                RegSetValueExW, HKEY, HKEY_CLASSES_ROOT, HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE,
+                KEY_ALL_ACCESS, KEY_SET_VALUE, REG_SZ,
             },
+            Threading::{GetCurrentProcess, OpenProcessToken},
         },
     },
 };
@@ -31,7 +37,98 @@ pub fn to_utf16(s: impl AsRef<OsStr>) -> Vec<u16> {
         .collect()
 }
 
-const DLL_NAMES: &[&str] = &["windows_tts_engine.dll", "windows_tts_engine_piper.dll"];
+/// A text-to-speech engine that can be installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    /// Uses the modern Windows text-to-speech API.
+    Windows,
+    /// Uses piper neural network models.
+    Piper,
+}
+impl Engine {
+    fn dll_name(self) -> &'static str {
+        match self {
+            Engine::Windows => "windows_tts_engine.dll",
+            Engine::Piper => "windows_tts_engine_piper.dll",
+        }
+    }
+
+    /// Check if the engine can be installed from a directory. Returns a reason
+    /// if it should be skipped.
+    fn skip_reason(self, dir: &Path, uninstall: bool) -> Option<String> {
+        let dll_path = dir.join(self.dll_name());
+        if !dll_path.exists() {
+            return Some(format!("could not find DLL at {}", dll_path.display()));
+        }
+        if self == Engine::Piper && !uninstall {
+            let models_dir = dir.join(PIPER_MODELS_DIR);
+            if !models_dir.is_dir() {
+                return Some(format!(
+                    "no folder for piper models at {}",
+                    models_dir.display()
+                ));
+            }
+            let (complete, missing) = check_piper_models(&models_dir);
+            let missing_list = missing
+                .iter()
+                .map(|path| format!("\n\t{}", path.display()))
+                .collect::<String>();
+            if complete == 0 {
+                if missing.is_empty() {
+                    return Some(format!(
+                        "no \".onnx.json\" model configs in the folder at {}",
+                        models_dir.display()
+                    ));
+                }
+                return Some(format!(
+                    "no complete piper model, missing files:{missing_list}"
+                ));
+            }
+            if !missing.is_empty() {
+                eprintln!(
+                    "WARNING: some piper models are incomplete and won't work, \
+                    missing files:{missing_list}"
+                );
+            }
+        }
+        None
+    }
+}
+
+/// Check that every `.onnx.json` model config in the piper models folder has
+/// a matching `.onnx` model and `.voice.txt` file.
+///
+/// Returns the number of complete models and the paths of the missing files.
+fn check_piper_models(models_dir: &Path) -> (usize, Vec<PathBuf>) {
+    let mut complete = 0;
+    let mut missing = Vec::new();
+    let Ok(entries) = std::fs::read_dir(models_dir) else {
+        return (complete, missing);
+    };
+    for entry in entries.flatten() {
+        let config_path = entry.path();
+        let Some(base_path) = config_path
+            .to_str()
+            .and_then(|path| path.strip_suffix(".onnx.json"))
+        else {
+            continue;
+        };
+        let required = [
+            PathBuf::from(format!("{base_path}.onnx")),
+            PathBuf::from(format!("{base_path}.voice.txt")),
+        ];
+        let missing_before = missing.len();
+        missing.extend(required.into_iter().filter(|path| !path.is_file()));
+        if missing.len() == missing_before {
+            complete += 1;
+        }
+    }
+    (complete, missing)
+}
+/// Folder next to the piper engine's DLL that contains the piper models.
+const PIPER_MODELS_DIR: &str = "piper_models";
+/// Folder inside `%ProgramFiles%` that files are copied to by default.
+const DEFAULT_INSTALL_DIR_NAME: &str = "Lej77TextToSpeech";
 
 const UNINSTALL_REG_KEY: PCWSTR =
     w!("Software\\Microsoft\\Windows\\CurrentVersion\\Uninstall\\Lej77WindowsTextToSpeechEngine");
@@ -40,38 +137,35 @@ const UNINSTALL_ARGS: &str = " --uninstall";
 /// Register uninstaller with Windows so the user can easily uninstall the
 /// text-to-speech engine.
 ///
+/// `install_dir` should be `None` if the DLLs were registered in place.
+/// `per_user` should be `true` if the DLLs were registered for the current
+/// user only.
+///
 /// # References
 ///
 /// - Adapted from:
 ///   <https://github.com/gexgd0419/NaturalVoiceSAPIAdapter/blob/2573a979a71ee96d3370676dd6f6acb382e4d35e/Installer/Install.cpp#L38-L60>
-fn add_uninstall_registry_key() -> anyhow::Result<()> {
+fn add_uninstall_registry_key(
+    installer_path: &Path,
+    install_dir: Option<&Path>,
+    per_user: bool,
+) -> anyhow::Result<()> {
     // Gather info:
-    let mut uninstall_cmd_line = [0_u16; MAX_PATH as usize + UNINSTALL_ARGS.len()];
-    char::encode_utf16('"', &mut uninstall_cmd_line[..1]);
-
-    let mut len =
-        unsafe { GetModuleFileNameW(None, &mut uninstall_cmd_line[1..MAX_PATH as usize + 1]) };
-    if len == 0 || len == MAX_PATH {
-        return Err(windows::core::Error::from_win32().into());
+    let mut uninstall_cmd_line = OsString::from("\"");
+    uninstall_cmd_line.push(installer_path);
+    uninstall_cmd_line.push("\"");
+    uninstall_cmd_line.push(UNINSTALL_ARGS);
+    if let Some(install_dir) = install_dir {
+        uninstall_cmd_line.push(" --install-dir \"");
+        uninstall_cmd_line.push(install_dir);
+        uninstall_cmd_line.push("\"");
+    } else {
+        uninstall_cmd_line.push(" --in-place");
     }
-
-    char::encode_utf16('"', &mut uninstall_cmd_line[len as usize + 1..][..1]);
-    len += 2; // for quotes
-
-    assert_ne!(
-        uninstall_cmd_line[len as usize - 1],
-        0,
-        "last string character should not be a nul terminator\n\t\
-            string: {}",
-        String::from_utf16_lossy(&uninstall_cmd_line[..len as usize]),
-    );
-    assert_eq!(
-        uninstall_cmd_line[len as usize], 0,
-        "nul terminator should be at end of string"
-    );
-
-    uninstall_cmd_line[len as usize..][..UNINSTALL_ARGS.len() + /* nul byte: */ 1]
-        .copy_from_slice(&to_utf16(UNINSTALL_ARGS));
+    if per_user {
+        uninstall_cmd_line.push(" --per-user");
+    }
+    let uninstall_cmd_line = to_utf16(uninstall_cmd_line);
 
     let version = to_utf16(clap::crate_version!());
     let authors = to_utf16(clap::crate_authors!());
@@ -146,6 +240,239 @@ fn remove_uninstall_registry_key() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The default folder to copy files to: `%ProgramFiles%\Lej77TextToSpeech`,
+/// or `%LOCALAPPDATA%\Programs\Lej77TextToSpeech` when installing for the
+/// current user only.
+fn default_install_dir(per_user: bool) -> PathBuf {
+    if per_user {
+        if let Some(local_app_data) = std::env::var_os("LOCALAPPDATA").filter(|dir| !dir.is_empty())
+        {
+            return Path::new(&local_app_data)
+                .join("Programs")
+                .join(DEFAULT_INSTALL_DIR_NAME);
+        }
+    }
+    let program_files = std::env::var_os("ProgramFiles")
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "C:\\Program Files".into());
+    Path::new(&program_files).join(DEFAULT_INSTALL_DIR_NAME)
+}
+
+/// Check if the installer is running with administrator rights.
+///
+/// # References
+///
+/// - [TOKEN_ELEVATION (winnt.h) - Win32 apps | Microsoft Learn](https://learn.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-token_elevation)
+fn is_elevated() -> bool {
+    let mut token = HANDLE::default();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) }.is_err() {
+        return false;
+    }
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut len = 0;
+    let result = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            Some((&mut elevation as *mut TOKEN_ELEVATION).cast()),
+            size_of::<TOKEN_ELEVATION>() as u32,
+            &mut len,
+        )
+    };
+    let _ = unsafe { CloseHandle(token) };
+    result.is_ok() && elevation.TokenIsElevated != 0
+}
+
+/// Ask the user a yes or no question in the console.
+fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Run the installer again as an administrator with the same arguments and
+/// exit with its exit code.
+///
+/// Only returns if the installer couldn't be restarted, for example because
+/// the user declined the UAC prompt.
+fn relaunch_elevated(exe_path: &Path) -> anyhow::Result<()> {
+    eprintln!("Administrator rights are needed, restarting the installer elevated");
+    let status = runas::Command::new(exe_path)
+        .args(&std::env::args_os().skip(1).collect::<Vec<_>>())
+        .arg("--elevated")
+        .status()
+        .context("Failed to restart the installer as an administrator")?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn copy_dir_all(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the installer, the DLLs and the piper models folder into the install
+/// directory.
+fn copy_files(exe_path: &Path, exe_dir: &Path, install_dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(install_dir).with_context(|| {
+        format!(
+            "Failed to create install directory at {}",
+            install_dir.display()
+        )
+    })?;
+
+    let exe_name = exe_path.file_name().context("Installer has no file name")?;
+    let mut files = vec![exe_name];
+    files.extend(
+        Engine::value_variants()
+            .iter()
+            .map(|engine| OsStr::new(engine.dll_name())),
+    );
+    for file in files {
+        let from = exe_dir.join(file);
+        if !from.exists() {
+            continue;
+        }
+        let to = install_dir.join(file);
+        std::fs::copy(&from, &to)
+            .with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+        println!("Copied {}", to.display());
+    }
+
+    let models_dir = exe_dir.join(PIPER_MODELS_DIR);
+    if models_dir.is_dir() {
+        let to = install_dir.join(PIPER_MODELS_DIR);
+        copy_dir_all(&models_dir, &to).with_context(|| {
+            format!(
+                "Failed to copy {} to {}",
+                models_dir.display(),
+                to.display()
+            )
+        })?;
+        println!("Copied {}", to.display());
+    }
+    Ok(())
+}
+
+/// Remove files that were copied by [`copy_files`]. The installer can't remove
+/// itself while it is running so if it is inside the install directory then it
+/// is removed by a separate process after a short delay.
+fn remove_files(exe_path: &Path, install_dir: &Path) -> anyhow::Result<()> {
+    for engine in Engine::value_variants() {
+        let path = install_dir.join(engine.dll_name());
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+    }
+    let models_dir = install_dir.join(PIPER_MODELS_DIR);
+    if models_dir.is_dir() {
+        std::fs::remove_dir_all(&models_dir)
+            .with_context(|| format!("Failed to remove {}", models_dir.display()))?;
+    }
+
+    if exe_path.parent() == Some(install_dir) {
+        // Wait for this process to exit, then remove the installer and the
+        // (hopefully empty) install directory:
+        let mut script = OsString::from("ping 127.0.0.1 -n 3 > nul & del /q \"");
+        script.push(exe_path);
+        script.push("\" & rmdir \"");
+        script.push(install_dir);
+        script.push("\"");
+        std::process::Command::new("cmd")
+            .arg("/C")
+            .arg(script)
+            .spawn()
+            .context("Failed to start process that removes the installer")?;
+    } else {
+        if let Some(exe_name) = exe_path.file_name() {
+            let _ = std::fs::remove_file(install_dir.join(exe_name));
+        }
+        let _ = std::fs::remove_dir(install_dir);
+    }
+    Ok(())
+}
+
+/// Register or unregister a DLL for the current user only by calling its
+/// `DllRegisterServer` or `DllUnregisterServer` function while
+/// `HKEY_CLASSES_ROOT` is redirected to `HKEY_CURRENT_USER\Software\Classes`
+/// and `HKEY_LOCAL_MACHINE` is redirected to `HKEY_CURRENT_USER`. This doesn't
+/// require administrator rights.
+///
+/// # References
+///
+/// - [RegOverridePredefKey function (winreg.h) - Win32 apps | Microsoft Learn](https://learn.microsoft.com/en-us/windows/win32/api/winreg/nf-winreg-regoverridepredefkey)
+fn register_per_user(dll_path: &Path, unregister: bool) -> anyhow::Result<()> {
+    let dll_path_utf16 = to_utf16(dll_path);
+    let module = unsafe { LoadLibraryW(PCWSTR::from_raw(dll_path_utf16.as_ptr())) }
+        .with_context(|| format!("Failed to load DLL at {}", dll_path.display()))?;
+
+    let result = (|| -> anyhow::Result<()> {
+        let function_name = if unregister {
+            s!("DllUnregisterServer")
+        } else {
+            s!("DllRegisterServer")
+        };
+        let function = unsafe { GetProcAddress(module, function_name) }
+            .context("DLL doesn't export the functions of a COM server")?;
+        let function: unsafe extern "system" fn() -> HRESULT =
+            unsafe { std::mem::transmute(function) };
+
+        let mut classes_key = HKEY::default();
+        unsafe {
+            RegCreateKeyExW(
+                HKEY_CURRENT_USER,
+                w!("Software\\Classes"),
+                None,
+                None,
+                Default::default(),
+                KEY_ALL_ACCESS,
+                None,
+                &mut classes_key,
+                None,
+            )
+        }
+        .ok()
+        .context("Failed to open the current user's classes registry key")?;
+        let mut user_key = HKEY::default();
+        unsafe { RegOpenCurrentUser(KEY_ALL_ACCESS.0, &mut user_key) }
+            .ok()
+            .context("Failed to open the current user's registry key")?;
+
+        let result = unsafe { RegOverridePredefKey(HKEY_CLASSES_ROOT, Some(classes_key)) }
+            .ok()
+            .and_then(|_| unsafe { RegOverridePredefKey(HKEY_LOCAL_MACHINE, Some(user_key)) }.ok())
+            .and_then(|_| unsafe { function() }.ok());
+
+        unsafe {
+            let _ = RegOverridePredefKey(HKEY_CLASSES_ROOT, None);
+            let _ = RegOverridePredefKey(HKEY_LOCAL_MACHINE, None);
+            classes_key.free();
+            user_key.free();
+        }
+        result.with_context(|| {
+            format!(
+                "Failed to {} the COM server for the current user",
+                if unregister { "unregister" } else { "register" }
+            )
+        })
+    })();
+
+    let _ = unsafe { FreeLibrary(module) };
+    result
+}
+
 /// Adapted from
 /// <https://github.com/gexgd0419/NaturalVoiceSAPIAdapter/blob/2573a979a71ee96d3370676dd6f6acb382e4d35e/Installer/Install.cpp#L67-L109>
 fn register(dll_path: &Path, regsvr_popups: bool) -> anyhow::Result<()> {
@@ -203,6 +530,24 @@ struct Args {
     /// Show message box popups with result information from "regsvr32".
     #[clap(long)]
     regsvr_popups: bool,
+    /// Folder that the text-to-speech engine is copied to before it is
+    /// registered. Defaults to `%ProgramFiles%\Lej77TextToSpeech`.
+    #[clap(long)]
+    install_dir: Option<PathBuf>,
+    /// Register the text-to-speech engine for the current user only. This
+    /// doesn't require administrator rights.
+    #[clap(long)]
+    per_user: bool,
+    /// Register the DLLs next to the installer without copying them anywhere.
+    #[clap(long, conflicts_with = "install_dir")]
+    in_place: bool,
+    /// Only install (or uninstall) these engines. Can be specified multiple
+    /// times. Defaults to all engines whose DLL is present.
+    #[clap(long, value_enum)]
+    engine: Vec<Engine>,
+    /// Set when the installer restarted itself as an administrator.
+    #[clap(long, hide = true)]
+    elevated: bool,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -214,40 +559,116 @@ fn main() -> anyhow::Result<()> {
         .parent()
         .context("Failed to get directory of current executable")?;
 
+    let mut per_user = args.per_user;
+    if !per_user && !args.elevated && !is_elevated() {
+        if let Err(e) = relaunch_elevated(&exe_path) {
+            eprintln!("{e:?}\n");
+            let action = if args.uninstall {
+                "uninstall"
+            } else {
+                "install"
+            };
+            if !confirm(&format!(
+                "Administrator rights are needed to {action} the text-to-speech engine for all \
+                users, {action} it for the current user only instead?"
+            )) {
+                std::process::exit(1);
+            }
+            per_user = true;
+        }
+    }
+
+    let install_dir = if args.in_place {
+        exe_dir.to_owned()
+    } else {
+        args.install_dir
+            .clone()
+            .unwrap_or_else(|| default_install_dir(per_user))
+    };
+    // No need to copy files if the installer is already inside the install
+    // directory:
+    if !args.in_place && !args.uninstall && install_dir != exe_dir {
+        copy_files(&exe_path, exe_dir, &install_dir)?;
+    }
+    let installed_exe_path = match exe_path.file_name() {
+        Some(exe_name) if !args.in_place => install_dir.join(exe_name),
+        _ => exe_path.clone(),
+    };
+
     let mut first = true;
+    // Set if an installed engine wasn't selected for uninstallation:
+    let mut kept_engine = false;
 
-    for dll_name in DLL_NAMES {
-        let dll_path = exe_dir.join(dll_name);
-        if !dll_path.exists() {
-            eprintln!("Could not find DLL at:\n\t{}", dll_path.display());
-            eprintln!(
-                "Ensure the installer program is in the same folder as the \
-                text-to-speech engine DLL you want to install.\n"
-            );
+    for &engine in Engine::value_variants() {
+        if !args.engine.is_empty() && !args.engine.contains(&engine) {
+            println!("Skipped {engine:?} engine: not selected with --engine");
+            kept_engine |= install_dir.join(engine.dll_name()).exists();
+            continue;
+        }
+        if let Some(reason) = engine.skip_reason(&install_dir, args.uninstall) {
+            eprintln!("Skipped {engine:?} engine: {reason}");
             continue;
         }
+        let dll_path = install_dir.join(engine.dll_name());
 
         let was_first = std::mem::replace(&mut first, false);
 
         if args.uninstall {
-            unregister(&dll_path, args.regsvr_popups)?;
+            if per_user {
+                register_per_user(&dll_path, true)?;
+            } else {
+                unregister(&dll_path, args.regsvr_popups)?;
+            }
         } else {
             if was_first {
                 // Add uninstaller before registering anything.
-                add_uninstall_registry_key()?;
+                add_uninstall_registry_key(
+                    &installed_exe_path,
+                    (!args.in_place).then_some(install_dir.as_path()),
+                    per_user,
+                )?;
+            }
+            if per_user {
+                register_per_user(&dll_path, false)?;
+            } else {
+                register(&dll_path, args.regsvr_popups)?;
             }
-            register(&dll_path, args.regsvr_popups)?;
         }
+        println!(
+            "{} {engine:?} engine",
+            if args.uninstall {
+                "Unregistered"
+            } else {
+                "Registered"
+            }
+        );
     }
 
     if first {
-        eprintln!("No text-to-speech engine DLL could be found, uninstallation failed!\n");
+        eprintln!(
+            "Ensure the installer program is in the same folder as the \
+            text-to-speech engine DLL you want to install.\n"
+        );
+        eprintln!(
+            "No text-to-speech engine could be {}!\n",
+            if args.uninstall {
+                "uninstalled"
+            } else {
+                "installed"
+            }
+        );
         std::process::exit(2);
     }
 
-    if args.uninstall {
+    // Keep the uninstaller and the installed files if some engines are still
+    // registered:
+    if args.uninstall && !kept_engine {
         // Remove uninstaller only when we know we have succeeded:
         remove_uninstall_registry_key()?;
+
+        if !args.in_place {
+            remove_files(&exe_path, &install_dir)?;
+        }
     }
 
     Ok(())
@@ -1,32 +1,49 @@
 //! Defines a COM Server that offers a text-to-speech engine for Windows.
 
 use std::{
-    collections::HashMap, ffi::OsString, os::windows::ffi::OsStringExt, path::PathBuf, sync::Mutex,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, Once, OnceLock,
+    },
     time::Instant,
 };
 
-use piper_rs::synth::PiperSpeechSynthesizer;
+#[cfg(feature = "cuda")]
+use ort::execution_providers::CUDAExecutionProvider;
+#[cfg(feature = "directml")]
+use ort::execution_providers::DirectMLExecutionProvider;
+use ort::{
+    environment::GlobalThreadPoolOptions,
+    execution_providers::{CPUExecutionProvider, ExecutionProvider},
+};
+use piper_rs::synth::{AudioOutputConfig, PiperSpeechSynthesizer};
 use rodio::buffer::SamplesBuffer;
 use windows::{
     core::GUID,
-    Win32::{
-        Foundation::MAX_PATH,
-        Media::{
-            Audio::{WAVEFORMATEX, WAVE_FORMAT_PCM},
-            Speech::{ISpObjectToken, ISpTTSEngineSite, SPVES_ABORT, SPVES_CONTINUE},
-        },
-        System::Registry::HKEY_LOCAL_MACHINE,
-    },
+    Win32::Media::Speech::{ISpObjectToken, ISpTTSEngineSite},
 };
 use windows_tts_engine::{
-    com_server::{
-        dll_export_com_server_fns, ComClassInfo, ComServerPath, ComThreadingModel, SafeTtsComServer,
+    audio::AudioWriter,
+    com_server::{dll_export_com_server_fns, module_ref, SafeTtsComServer},
+    detect_languages::{
+        has_multiple_languages, DetectionError, DetectorBackend, LinguaDetectionService,
     },
-    detect_languages::{has_multiple_languages, DetectedLanguage, LinguaDetectionService},
+    events::EventSink,
     logging::DllLogger,
-    utils::get_current_dll_path,
-    voices::{ParentRegKey, VoiceAttributes, VoiceKeyData},
-    SafeTtsEngine, SpeechFormat, TextFrag, TextFragIter,
+    ssml::SsmlTextFrags,
+    text::{is_blank, JoinedText, SpeakSegment},
+    token::TokenReader,
+    utils::{
+        apply_fade, apply_fade_f32, current_dll_path, duplicate_channels, pitch_shift,
+        pitch_shift_i16, read_token_string, resample, resample_i16, sapi_rate_to_multiplier,
+        write_debug_text,
+    },
+    voices::{VoiceAttributes, VoiceKeyData, FORCED_LANGUAGE_VALUE, LANGUAGE_DETECTOR_VALUE},
+    CancellationToken, MetricsRecorder, SafeTtsEngine, SpeakFlags, SpeakMetrics, SpeechFormat,
+    TextFrag,
 };
 
 /// Copied from [`piper_rs::Language`] since its fields aren't public.
@@ -44,39 +61,465 @@ pub struct Language {
 #[derive(serde::Deserialize, Default)]
 pub struct ModelConfig {
     pub key: Option<String>,
+    /// `None` if the config doesn't specify a language, in which case the
+    /// model's language is unknown.
+    #[serde(default)]
     pub language: Option<Language>,
     pub audio: piper_rs::AudioConfig,
     pub num_speakers: u32,
     pub speaker_id_map: HashMap<String, i64>,
 }
 
+/// Get the path of the `*.onnx` model file that belongs to a `*.onnx.json`
+/// model config. Returns `None` if the path isn't named like a model config.
+fn model_file_for_config(config_path: &Path) -> Option<PathBuf> {
+    if !config_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    {
+        return None;
+    }
+    let model_path = config_path.with_extension("");
+    model_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("onnx"))
+        .then_some(model_path)
+}
+
+/// Name of a voice token registry value. If it is `"1"` then loaded models
+/// are kept in memory after the engine is released, see
+/// [`OurTtsEngine::keep_models_loaded`].
+pub const KEEP_MODELS_LOADED_VALUE: &str = "KeepModelsLoaded";
+
+/// Name of a voice token registry value with the maximum number of UTF-16
+/// code units that are synthesized at a time, see
+/// [`OurTtsEngine::max_chunk_len`].
+pub const MAX_CHUNK_LENGTH_VALUE: &str = "MaxChunkLength";
+
+/// Default value for [`OurTtsEngine::max_chunk_len`].
+pub const DEFAULT_MAX_CHUNK_LEN: usize = 1000;
+
+/// Name of a voice token registry value with the number of models that are
+/// kept loaded, see [`DEFAULT_MODEL_CACHE_CAPACITY`].
+pub const MODEL_CACHE_CAPACITY_VALUE: &str = "ModelCacheCapacity";
+
+/// Name of a voice token registry value that selects how the pitch is
+/// changed, for example `"model"`, `"granular"` or `"off"`. See
+/// [`PitchMethod`].
+pub const PITCH_METHOD_VALUE: &str = "PitchMethod";
+
+/// How the pitch adjustment that clients specify (for example with SSML's
+/// `<prosody pitch>`) is applied to the generated audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PitchMethod {
+    /// Let piper change the pitch using the `pitch` of its
+    /// [`AudioOutputConfig`].
+    #[default]
+    Model,
+    /// Pitch shift the generated audio using [`pitch_shift`]. The shifting is
+    /// quite coarse and can make speech sound metallic.
+    Granular,
+    /// Ignore pitch adjustments.
+    Off,
+}
+impl FromStr for PitchMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "model" => Self::Model,
+            "granular" => Self::Granular,
+            "off" => Self::Off,
+            other => return Err(format!("unknown pitch method {other:?}")),
+        })
+    }
+}
+
+/// Name of a voice token registry value that selects where models are run,
+/// for example `"cpu"`, `"directml"` or `"cuda"`. See
+/// [`OurTtsEngine::execution_provider`].
+pub const EXECUTION_PROVIDER_VALUE: &str = "ExecutionProvider";
+
+/// The onnxruntime execution provider that runs piper models. Providers other
+/// than the CPU need the crate feature with the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecutionProviderKind {
+    #[default]
+    Cpu,
+    #[cfg(feature = "directml")]
+    DirectMl,
+    #[cfg(feature = "cuda")]
+    Cuda,
+}
+impl FromStr for ExecutionProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "cpu" => Self::Cpu,
+            #[cfg(feature = "directml")]
+            "directml" | "dml" => Self::DirectMl,
+            #[cfg(feature = "cuda")]
+            "cuda" => Self::Cuda,
+            other => {
+                return Err(format!(
+                    "unknown execution provider {other:?} or support for it wasn't compiled in"
+                ))
+            }
+        })
+    }
+}
+impl ExecutionProviderKind {
+    /// Configure onnxruntime to use this execution provider and thread counts
+    /// for all models that are loaded afterwards and return the provider that
+    /// is actually used.
+    ///
+    /// onnxruntime's environment is shared by the whole process, so only the
+    /// first call can change these settings.
+    fn init_onnxruntime(self, threads: OnnxThreads) -> Self {
+        static SELECTED: OnceLock<ExecutionProviderKind> = OnceLock::new();
+        let selected = *SELECTED.get_or_init(|| {
+            let (available, provider) = match self {
+                Self::Cpu => {
+                    let provider = CPUExecutionProvider::default();
+                    (provider.is_available(), provider.build())
+                }
+                #[cfg(feature = "directml")]
+                Self::DirectMl => {
+                    let provider = DirectMLExecutionProvider::default();
+                    (provider.is_available(), provider.build())
+                }
+                #[cfg(feature = "cuda")]
+                Self::Cuda => {
+                    let provider = CUDAExecutionProvider::default();
+                    (provider.is_available(), provider.build())
+                }
+            };
+            let (selected, providers) = match available {
+                Ok(true) => (self, vec![provider]),
+                Ok(false) => {
+                    log::warn!("The {self:?} execution provider isn't available, using the CPU");
+                    (Self::Cpu, Vec::new())
+                }
+                Err(e) => {
+                    log::warn!("Failed to check if the {self:?} execution provider is available, using the CPU: {e}");
+                    (Self::Cpu, Vec::new())
+                }
+            };
+            let mut environment = ort::init().with_execution_providers(providers);
+            match threads.global_thread_pool() {
+                Ok(Some(thread_pool)) => {
+                    log::debug!("Running piper models with {threads:?}");
+                    environment = environment.with_global_thread_pool(thread_pool);
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Invalid thread counts {threads:?}: {e}"),
+            }
+            match environment.commit() {
+                Ok(_) => selected,
+                Err(e) => {
+                    log::error!("Failed to configure the {self:?} execution provider: {e}");
+                    Self::Cpu
+                }
+            }
+        });
+        if selected != self {
+            log::warn!("Requested the {self:?} execution provider but using {selected:?}");
+        }
+        log::debug!("Running piper models using the {selected:?} execution provider");
+        selected
+    }
+}
+
+/// Name of a voice token registry value with the number of threads used to run
+/// the operations of a model, see [`OnnxThreads::intra_op`].
+pub const INTRA_OP_THREADS_VALUE: &str = "IntraOpThreads";
+/// Name of a voice token registry value with the number of threads used to run
+/// independent operations in parallel, see [`OnnxThreads::inter_op`].
+pub const INTER_OP_THREADS_VALUE: &str = "InterOpThreads";
+/// Environment variable that configures [`OnnxThreads::intra_op`], the voice
+/// token's [`INTRA_OP_THREADS_VALUE`] takes precedence.
+pub const INTRA_OP_THREADS_ENV_VAR: &str = "LEJ77_TTS_PIPER_INTRA_OP_THREADS";
+/// Environment variable that configures [`OnnxThreads::inter_op`], the voice
+/// token's [`INTER_OP_THREADS_VALUE`] takes precedence.
+pub const INTER_OP_THREADS_ENV_VAR: &str = "LEJ77_TTS_PIPER_INTER_OP_THREADS";
+
+/// Number of threads that onnxruntime uses to run piper models. `None` keeps
+/// onnxruntime's default, which is about one thread per CPU core.
+///
+/// More threads let a long sentence finish sooner (lower latency) but compete
+/// with other programs for the CPU, while fewer threads leave room for other
+/// work (such as a screen reader) at the cost of slower synthesis. On small
+/// machines using fewer threads than cores can be faster overall since the
+/// threads don't oversubscribe the CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OnnxThreads {
+    /// Threads used to parallelize the work inside an operation, this has the
+    /// largest effect on synthesis speed.
+    pub intra_op: Option<usize>,
+    /// Threads used to run independent operations at the same time.
+    pub inter_op: Option<usize>,
+}
+impl OnnxThreads {
+    /// Read [`INTRA_OP_THREADS_ENV_VAR`] and [`INTER_OP_THREADS_ENV_VAR`].
+    pub fn from_env() -> Self {
+        let read = |name: &str| {
+            let value = std::env::var(name).ok()?;
+            value
+                .trim()
+                .parse()
+                .map_err(|e| log::warn!("Invalid thread count in {name}={value:?}: {e}"))
+                .ok()
+        };
+        Self {
+            intra_op: read(INTRA_OP_THREADS_ENV_VAR),
+            inter_op: read(INTER_OP_THREADS_ENV_VAR),
+        }
+    }
+
+    fn global_thread_pool(self) -> ort::Result<Option<GlobalThreadPoolOptions>> {
+        if self == Self::default() {
+            return Ok(None);
+        }
+        let mut options = GlobalThreadPoolOptions::default();
+        if let Some(threads) = self.intra_op {
+            options = options.with_intra_threads(threads)?;
+        }
+        if let Some(threads) = self.inter_op {
+            options = options.with_inter_threads(threads)?;
+        }
+        Ok(Some(options))
+    }
+}
+
+/// Models shared by all engines that keep their models loaded. Its capacity
+/// is set to the capacity of the engine that last used it.
+static RESIDENT_CACHE: Mutex<Option<ModelCache>> = Mutex::new(None);
+
+/// Length of the fade in and fade out at the edges of each synthesized range.
+const FADE_MSECS: usize = 5;
+
+/// Sample rate that most piper models generate audio at. Used as the output
+/// format when the client doesn't ask for a specific one, since we can't know
+/// which model will be used until we know the text's language.
+const NATIVE_SAMPLE_RATE: u32 = 22_050;
+
+/// Default number of models that an engine keeps loaded, see
+/// [`OurTtsEngine::new`]. Can be configured by the voice token's
+/// [`MODEL_CACHE_CAPACITY_VALUE`] registry value.
+pub const DEFAULT_MODEL_CACHE_CAPACITY: usize = 2;
+
+/// Loaded piper models, the least recently used model is evicted when the
+/// cache is full.
+///
+/// Synthesizers are reference counted so a synthesizer that is evicted while
+/// it is being used will stay alive until it is done.
+pub struct ModelCache {
+    /// Most recently used first.
+    entries: Vec<(PathBuf, Arc<PiperSpeechSynthesizer>)>,
+    capacity: usize,
+}
+impl ModelCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Get a cached synthesizer and mark it as recently used.
+    pub fn get(&mut self, path: &Path) -> Option<Arc<PiperSpeechSynthesizer>> {
+        let index = self.entries.iter().position(|(p, _)| p == path)?;
+        let entry = self.entries.remove(index);
+        let synth = entry.1.clone();
+        self.entries.insert(0, entry);
+        Some(synth)
+    }
+
+    /// Add a synthesizer to the cache, evicting the least recently used ones
+    /// if the cache is full.
+    pub fn insert(&mut self, path: PathBuf, synth: Arc<PiperSpeechSynthesizer>) {
+        self.entries.retain(|(p, _)| *p != path);
+        self.entries.insert(0, (path, synth));
+        self.evict();
+    }
+
+    /// Change how many models are kept, evicting the least recently used ones
+    /// if there are too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        for (path, _) in self.entries.drain(self.capacity.min(self.entries.len())..) {
+            log::debug!("Evicted piper model from cache: {}", path.display());
+        }
+    }
+}
+
 pub struct PiperModelInfo {
     /// Path to JSON config.
     pub path: PathBuf,
     pub language: Option<Language>,
 }
 
+/// Convert a SAPI rate between `-10` and `10` to piper's rate percentage,
+/// where `50` is the normal rate and the speed scales with the percentage.
+fn sapi_rate_to_piper(sapi_rate: i32) -> u8 {
+    (50.0 * sapi_rate_to_multiplier(sapi_rate))
+        .round()
+        .clamp(0.0, 100.0) as u8
+}
+
+/// Convert a SAPI pitch between `-10` and `10` to piper's pitch percentage,
+/// where `50` is the normal pitch.
+fn sapi_pitch_to_piper(sapi_pitch: i32) -> u8 {
+    (50 + sapi_pitch.clamp(-10, 10) * 5) as u8
+}
+
+/// Scale PCM samples by a SAPI volume between `0` and `100`.
+fn apply_volume(samples: &mut [u8], is_float: bool, sapi_volume: u16) {
+    if sapi_volume >= 100 {
+        return;
+    }
+    let gain = f32::from(sapi_volume) / 100.0;
+    if is_float {
+        for sample in samples.chunks_exact_mut(4) {
+            let value = f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]);
+            sample.copy_from_slice(&(value * gain).to_le_bytes());
+        }
+    } else {
+        for sample in samples.chunks_exact_mut(2) {
+            let value = f32::from(i16::from_le_bytes([sample[0], sample[1]]));
+            sample.copy_from_slice(&((value * gain) as i16).to_le_bytes());
+        }
+    }
+}
+
 pub struct OurTtsEngine {
     /// Don't write audio to [`ISpTTSEngineSite`], instead play it directly on
     /// the audio output device. If `true` then the client application can't
     /// save the audio to a file.
     play_audio_directly: bool,
-    cache: Mutex<HashMap<PathBuf, PiperSpeechSynthesizer>>,
+    /// How the SAPI pitch adjustment is applied. Can be configured by the
+    /// voice token's [`PITCH_METHOD_VALUE`] registry value.
+    pitch_method: Mutex<PitchMethod>,
+    /// Synthesize all text in this language instead of detecting the
+    /// language. Can be configured by the voice token's
+    /// [`FORCED_LANGUAGE_VALUE`] registry value.
+    forced_language: Mutex<Option<String>>,
+    /// Library used to detect languages. Can be configured by the voice
+    /// token's [`LANGUAGE_DETECTOR_VALUE`] registry value.
+    language_detector: Mutex<DetectorBackend>,
+    /// Created by [`Self::prepare_detection_service`] and kept so that
+    /// language models aren't loaded again for every call to `speak`.
+    detection_service: Mutex<Option<LinguaDetectionService>>,
+    /// Keep loaded models in memory after this engine is released, so that
+    /// later engines don't need to load them again. This also prevents the
+    /// DLL from being unloaded.
+    ///
+    /// Models can use hundreds of megabytes of memory each so this is only
+    /// worth it for clients that speak often. Can be configured by the voice
+    /// token's [`KEEP_MODELS_LOADED_VALUE`] registry value.
+    keep_models_loaded: AtomicBool,
+    /// Where models are run. Can be configured by the voice token's
+    /// [`EXECUTION_PROVIDER_VALUE`] registry value.
+    execution_provider: Mutex<ExecutionProviderKind>,
+    /// Can be configured by environment variables or by the voice token's
+    /// [`INTRA_OP_THREADS_VALUE`] and [`INTER_OP_THREADS_VALUE`] registry
+    /// values.
+    threads: Mutex<OnnxThreads>,
+    /// Long text is split into chunks of at most this many UTF-16 code units
+    /// (preferably at the end of a sentence) that are synthesized and written
+    /// one at a time. Otherwise a long article would need to be synthesized
+    /// completely before any audio is played. Can be configured by the voice
+    /// token's [`MAX_CHUNK_LENGTH_VALUE`] registry value.
+    max_chunk_len: AtomicUsize,
+    cache: Mutex<ModelCache>,
+    /// Checked between detecting languages and synthesizing chunks of text,
+    /// see [`SafeTtsEngine::cancellation_token`].
+    cancel: CancellationToken,
+}
+/// Language codes of the models, used to select the languages that the
+/// lingua detector can detect.
+fn model_languages(models: &[PiperModelInfo]) -> Vec<&str> {
+    models
+        .iter()
+        .filter_map(|info| {
+            if let Some(lang) = &info.language {
+                Some(lang.code.as_str())
+            } else {
+                log::warn!("No language info for model at: {:?}", info.path);
+                None
+            }
+        })
+        .collect()
 }
+
 impl OurTtsEngine {
+    /// Create an engine that keeps at most `cache_capacity` models loaded.
+    /// Each model can use hundreds of megabytes of memory, see
+    /// [`DEFAULT_MODEL_CACHE_CAPACITY`].
+    pub fn new(cache_capacity: usize) -> Self {
+        Self {
+            play_audio_directly: false,
+            pitch_method: Mutex::new(PitchMethod::default()),
+            forced_language: Mutex::new(None),
+            language_detector: Mutex::new(DetectorBackend::Microsoft),
+            detection_service: Mutex::new(None),
+            keep_models_loaded: AtomicBool::new(false),
+            execution_provider: Mutex::new(ExecutionProviderKind::default()),
+            threads: Mutex::new(OnnxThreads::from_env()),
+            max_chunk_len: AtomicUsize::new(DEFAULT_MAX_CHUNK_LEN),
+            cache: Mutex::new(ModelCache::new(cache_capacity)),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Create the language detector for the languages of the models unless
+    /// it was already created, since loading the lingua models can take
+    /// hundreds of milliseconds.
+    fn prepare_detection_service<S: AsRef<str>>(
+        &self,
+        languages: &[S],
+    ) -> Result<MutexGuard<'_, Option<LinguaDetectionService>>, DetectionError> {
+        let mut guard = self.detection_service.lock().unwrap();
+        if guard.is_none() {
+            let backend = *self.language_detector.lock().unwrap();
+            let started = Instant::now();
+            *guard = Some(LinguaDetectionService::with_backend(backend, languages)?);
+            log::debug!(
+                "Created the {backend:?} language detector (duration: {:?})",
+                started.elapsed()
+            );
+        }
+        Ok(guard)
+    }
+
+    /// Run a closure with the cache of loaded models.
+    fn with_cache<R>(&self, f: impl FnOnce(&mut ModelCache) -> R) -> R {
+        if self.keep_models_loaded.load(Ordering::Relaxed) {
+            // The cached models would leak if the DLL was unloaded:
+            static PIN_MODULE: Once = Once::new();
+            PIN_MODULE.call_once(|| std::mem::forget(module_ref().clone()));
+
+            let capacity = self.cache.lock().unwrap().capacity;
+            let mut guard = RESIDENT_CACHE.lock().unwrap();
+            let cache = guard.get_or_insert_with(|| ModelCache::new(capacity));
+            cache.set_capacity(capacity);
+            f(cache)
+        } else {
+            f(&mut self.cache.lock().unwrap())
+        }
+    }
+
     pub fn list_models(&self) -> Option<Vec<PiperModelInfo>> {
         let start_finding = Instant::now();
 
-        let mut model_folder = {
-            let mut buf = [0; MAX_PATH as _];
-            PathBuf::from(<OsString as OsStringExt>::from_wide(
-                get_current_dll_path(&mut buf)
-                    .map_err(|e| log::error!("Failed to get dll path: {e}"))
-                    .ok()?
-                    .strip_suffix(&[0])
-                    .expect("nul terminator"),
-            ))
-        };
+        let mut model_folder = current_dll_path()
+            .map_err(|e| log::error!("Failed to get dll path: {e}"))
+            .ok()?;
         model_folder.pop();
         model_folder.push("piper_models");
         if !model_folder.is_dir() {
@@ -97,12 +540,20 @@ impl OurTtsEngine {
                 }
             };
             let path = entry.path();
-            if path.extension().is_some_and(|ext| ext != "json") || !path.is_file() {
-                log::debug!(
+            let Some(model_path) = model_file_for_config(&path).filter(|_| path.is_file()) else {
+                log::trace!(
                     "Skipped file inside piper_models folder: {}",
                     path.display()
                 );
                 continue;
+            };
+            if !model_path.is_file() {
+                log::trace!(
+                    "Skipped model config without a model file at \"{}\": {}",
+                    model_path.display(),
+                    path.display()
+                );
+                continue;
             }
             let data = match std::fs::read(&path) {
                 Ok(v) => v,
@@ -159,92 +610,245 @@ impl OurTtsEngine {
     }
 }
 impl SafeTtsEngine for OurTtsEngine {
-    fn set_object_token(&self, _token: &ISpObjectToken) -> windows::core::Result<()> {
+    fn set_object_token(&self, token: &ISpObjectToken) -> windows::core::Result<()> {
         log::debug!("set_object_token");
+        match read_token_string(token, FORCED_LANGUAGE_VALUE) {
+            Ok(Some(lang)) => {
+                log::debug!("Voice is configured to always use the language {lang:?}");
+                *self.forced_language.lock().unwrap() = Some(lang);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read forced language from voice token: {e}"),
+        }
+        let configured_detector = match read_token_string(token, LANGUAGE_DETECTOR_VALUE) {
+            Ok(Some(value)) => value
+                .parse::<DetectorBackend>()
+                .inspect_err(|e| log::warn!("Invalid language detector in voice token: {e}"))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("Failed to read language detector from voice token: {e}");
+                None
+            }
+        };
+        let detector = match configured_detector {
+            Some(backend) => backend,
+            // Voices registered by older versions only have a key name that
+            // ends with "LINGUA":
+            None => match TokenReader::new(token).id() {
+                Ok(id) if id.ends_with("LINGUA") => DetectorBackend::Lingua,
+                Ok(_) => DetectorBackend::Microsoft,
+                Err(e) => {
+                    log::warn!("Failed to read voice token id: {e}");
+                    DetectorBackend::Microsoft
+                }
+            },
+        };
+        log::debug!("Voice uses the {detector:?} language detector");
+        *self.language_detector.lock().unwrap() = detector;
+        *self.detection_service.lock().unwrap() = None;
+        match read_token_string(token, KEEP_MODELS_LOADED_VALUE) {
+            Ok(value) => {
+                let keep = value.is_some_and(|value| value.trim() == "1");
+                log::debug!("Voice keeps models loaded: {keep}");
+                self.keep_models_loaded.store(keep, Ordering::Relaxed);
+            }
+            Err(e) => log::warn!("Failed to read keep models loaded from voice token: {e}"),
+        }
+        match read_token_string(token, EXECUTION_PROVIDER_VALUE) {
+            Ok(Some(value)) => match value.parse::<ExecutionProviderKind>() {
+                Ok(provider) => {
+                    log::debug!("Voice is configured to use the {provider:?} execution provider");
+                    *self.execution_provider.lock().unwrap() = provider;
+                }
+                Err(e) => log::warn!("Invalid execution provider in voice token: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read execution provider from voice token: {e}"),
+        }
+        match read_token_string(token, PITCH_METHOD_VALUE) {
+            Ok(Some(value)) => match value.parse::<PitchMethod>() {
+                Ok(method) => {
+                    log::debug!("Voice changes the pitch using the {method:?} method");
+                    *self.pitch_method.lock().unwrap() = method;
+                }
+                Err(e) => log::warn!("Invalid pitch method in voice token: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read pitch method from voice token: {e}"),
+        }
+        match read_token_string(token, MAX_CHUNK_LENGTH_VALUE) {
+            Ok(Some(value)) => match value.trim().parse::<usize>() {
+                Ok(len) if len > 0 => {
+                    log::debug!("Voice synthesizes at most {len} characters at a time");
+                    self.max_chunk_len.store(len, Ordering::Relaxed);
+                }
+                Ok(_) => log::warn!("Invalid max chunk length in voice token: 0"),
+                Err(e) => log::warn!("Invalid max chunk length in voice token: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read max chunk length from voice token: {e}"),
+        }
+        match read_token_string(token, MODEL_CACHE_CAPACITY_VALUE) {
+            Ok(Some(value)) => match value.trim().parse::<usize>() {
+                Ok(capacity) if capacity > 0 => {
+                    log::debug!("Voice keeps at most {capacity} models loaded");
+                    self.cache.lock().unwrap().set_capacity(capacity);
+                }
+                Ok(_) => log::warn!("Invalid model cache capacity in voice token: 0"),
+                Err(e) => log::warn!("Invalid model cache capacity in voice token: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read model cache capacity from voice token: {e}"),
+        }
+        let mut threads = self.threads.lock().unwrap();
+        let threads = &mut *threads;
+        for (value_name, field) in [
+            (INTRA_OP_THREADS_VALUE, &mut threads.intra_op),
+            (INTER_OP_THREADS_VALUE, &mut threads.inter_op),
+        ] {
+            match read_token_string(token, value_name) {
+                Ok(Some(value)) => match value.trim().parse() {
+                    Ok(threads) => *field = Some(threads),
+                    Err(e) => log::warn!("Invalid {value_name} in voice token: {e}"),
+                },
+                Ok(None) => {}
+                Err(e) => log::warn!("Failed to read {value_name} from voice token: {e}"),
+            }
+        }
+
+        // Load the language detector now so that the first text isn't
+        // delayed by it:
+        if self.forced_language.lock().unwrap().is_none() {
+            if let Some(models) = self.list_models() {
+                let languages = model_languages(&models);
+                if has_multiple_languages(&languages) {
+                    if let Err(e) = self.prepare_detection_service(&languages) {
+                        log::warn!("Failed to create language detector: {e}");
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
     fn speak(
         &self,
-        _token: &ISpObjectToken,
-        _speak_punctuation: bool,
-        _wave_format: SpeechFormat,
+        _token: Option<&ISpObjectToken>,
+        speak_flags: SpeakFlags,
+        wave_format: SpeechFormat,
         text_fragments: Option<TextFrag<'_>>,
         output_site: &ISpTTSEngineSite,
     ) -> windows::core::Result<()> {
-        let text_utf16 = TextFragIter::new(text_fragments)
-            .flat_map(|frag| frag.utf16_text().iter().copied().chain([' ' as u16]))
-            .collect::<Vec<u16>>();
-        log::debug!("Speak: {}", String::from_utf16_lossy(&text_utf16));
+        log::debug!("Speak - {speak_flags:?}");
+        let event_sink = EventSink::new(output_site)?;
+        let mut writer = AudioWriter::new(output_site);
+        let mut metrics = MetricsRecorder::new(self);
+
+        // SAPI parses XML before it calls us, but clients that use the engine
+        // in-process can pass SSML that piper doesn't understand:
+        let ssml_frags;
+        let text_fragments = if speak_flags.is_xml() {
+            ssml_frags = SsmlTextFrags::parse(text_fragments);
+            ssml_frags.first()
+        } else {
+            text_fragments
+        };
+        let joined_text = JoinedText::new(text_fragments, speak_flags);
+        let text_utf16 = joined_text.utf16();
+        log::debug!("Speak: {}", String::from_utf16_lossy(text_utf16));
+        if let SpeechFormat::DebugText = wave_format {
+            // Output the text we would have synthesized, with spelled out
+            // fragments already expanded:
+            return write_debug_text(output_site, text_utf16);
+        }
+        if is_blank(text_utf16) && joined_text.actions().next().is_none() {
+            log::debug!("Speak - Skipped since there is no text to speak");
+            return Ok(());
+        }
 
         let Some(models) = self.list_models() else {
             return Ok(());
         };
 
-        let has_multiple_languages = has_multiple_languages(
-            models
-                .iter()
-                .filter_map(|model| model.language.as_ref())
-                .map(|lang| lang.code.as_str())
-                // ignore difference between `en-US` and `en-GB`:
-                .map(|lang| {
-                    lang.split_once(['_', '-'])
-                        .map(|(prefix, _)| prefix)
-                        .unwrap_or(lang)
-                }),
-        );
-
-        let detected_language_ranges = if has_multiple_languages {
-            let started_lang_detect = Instant::now();
-
-            let prefer_lingua = cfg!(feature = "lingua")
-                && unsafe { _token.GetId()?.to_string()? }.ends_with("LINGUA");
-
-            let detection_service = if prefer_lingua {
-                let output_languages: Vec<&str> = models
-                    .iter()
-                    .filter_map(|info| {
-                        if let Some(lang) = &info.language {
-                            Some(lang.code.as_str())
-                        } else {
-                            log::warn!("No language info for model at: {:?}", info.path);
-                            None
-                        }
-                    })
-                    .collect();
-                LinguaDetectionService::with_lingua(&output_languages)
-            } else {
-                LinguaDetectionService::with_microsoft_language_detection()
-            };
-
-            let detected = detection_service
-                .expect("Failed to find language detection service")
-                .recognize_text(&text_utf16)
-                .expect("Failed to recognize text language");
-
-            log::debug!(
-                "Speak - Detected languages{} (duration: {:?})",
-                if cfg!(not(feature = "lingua")) {
-                    ""
-                } else if prefer_lingua {
-                    " using the Lingua library"
-                } else {
-                    " using Microsoft Language Detection"
-                },
-                started_lang_detect.elapsed()
-            );
-            detected
-        } else {
-            log::debug!("Speak - Skipped language detection since only one language is installed");
-            vec![DetectedLanguage {
-                start: 0,
-                end: text_utf16.len().saturating_sub(1),
-                languages: Vec::new(),
-            }]
+        let started_detect = Instant::now();
+        let forced_language = self.forced_language.lock().unwrap().clone();
+        let Some(detected_language_ranges) = joined_text.detect_language_ranges(
+            forced_language,
+            &model_languages(&models),
+            |languages| self.prepare_detection_service(languages),
+            &self.cancel,
+        ) else {
+            log::debug!("Speak - Cancelled");
+            return Ok(());
         };
+        metrics.detect += started_detect.elapsed();
+
+        // Can be changed by the client while we are speaking:
+        let mut rate = unsafe { output_site.GetRate() }?;
+        let mut volume = unsafe { output_site.GetVolume() }?;
 
-        for lang_range in detected_language_ranges {
+        let segments = joined_text.split_long_segments(
+            joined_text.split_at_actions(detected_language_ranges),
+            self.max_chunk_len.load(Ordering::Relaxed),
+        );
+        for segment in segments {
+            if self.cancel.is_cancelled() {
+                log::debug!("Speak - Cancelled");
+                return Ok(());
+            }
+            let mut lang_range = match segment {
+                SpeakSegment::Text(lang_range) => lang_range,
+                SpeakSegment::Silence { milliseconds } => {
+                    log::debug!("Speak - Inserting {milliseconds} ms of silence");
+                    let len = wave_format.silence_bytes(milliseconds);
+                    if writer.write_silence(len)?.abort() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                SpeakSegment::Bookmark { name } => {
+                    log::debug!("Speak - Bookmark: {}", String::from_utf16_lossy(name));
+                    event_sink.bookmark(writer.audio_offset(), name)?;
+                    continue;
+                }
+            };
+            // Detected ranges might not match the joined text exactly, so make
+            // sure they can be used to slice it:
+            let Some((start, end)) =
+                joined_text.snap_to_char_boundaries(lang_range.start, lang_range.end)
+            else {
+                log::warn!(
+                    "Speak - Skipped range {}..={} outside of text with length {}",
+                    lang_range.start,
+                    lang_range.end,
+                    text_utf16.len()
+                );
+                continue;
+            };
+            if lang_range.end >= text_utf16.len() {
+                log::warn!(
+                    "Speak - Clamped range {}..={} to text with length {}",
+                    lang_range.start,
+                    lang_range.end,
+                    text_utf16.len()
+                );
+            }
+            (lang_range.start, lang_range.end) = (start, end);
+            if let Some(source) = joined_text.source_range(lang_range.start, lang_range.end) {
+                log::trace!(
+                    "Speak - Range {}..={} has length {} at offset {} in original text",
+                    lang_range.start,
+                    lang_range.end,
+                    source.len,
+                    source.offset
+                );
+            }
             let text_utf16 = &text_utf16[lang_range.start..=lang_range.end];
+            if is_blank(text_utf16) {
+                log::trace!("Speak - Skipped whitespace between actions");
+                continue;
+            }
 
             let preferred_model = models
                 .iter()
@@ -257,26 +861,32 @@ impl SafeTtsEngine for OurTtsEngine {
                 })
                 .expect("There are at least one model");
 
-            let model = {
-                let mut guard = self.cache.lock().unwrap();
+            let started_load = Instant::now();
+            let synth = self.with_cache(|guard| {
                 if let Some(synth) = guard.get(&preferred_model.path) {
-                    synth.clone_model()
+                    synth
                 } else {
                     let start_read = Instant::now();
+                    self.execution_provider
+                        .lock()
+                        .unwrap()
+                        .init_onnxruntime(*self.threads.lock().unwrap());
                     let model = piper_rs::from_config_path(&preferred_model.path)
                         .expect("Failed to load piper config");
-                    log::debug!("Reading the model took: {:?}", start_read.elapsed());
-
-                    guard.insert(
-                        preferred_model.path.clone(),
-                        PiperSpeechSynthesizer::new(model.clone())
+                    let synth = Arc::new(
+                        PiperSpeechSynthesizer::new(model)
                             .expect("Failed to create piper synthesizer"),
                     );
-                    model
+                    log::debug!("Reading the model took: {:?}", start_read.elapsed());
+
+                    guard.insert(preferred_model.path.clone(), synth.clone());
+                    synth
                 }
-            };
+            });
+            let model = synth.clone_model();
+            metrics.model_load += started_load.elapsed();
 
-            let _start_audio = Instant::now();
+            let started_synthesis = Instant::now();
 
             let audio_info = model
                 .audio_output_info()
@@ -288,16 +898,29 @@ impl SafeTtsEngine for OurTtsEngine {
                     log::error!("Failed to set speaker: {e}");
                 }
             }
-            let synth =
-                PiperSpeechSynthesizer::new(model).expect("Failed to create piper synthesizer");
+            // Use the prosody of the first fragment for the whole range:
+            let state = joined_text
+                .fragments_in(lang_range.start..lang_range.end + 1)
+                .next()
+                .map(|(frag, _)| frag.frag_state());
+            let pitch = state.map_or(0, |state| state.pitch().clamp(-10, 10));
+            let range_rate = (rate + state.map_or(0, |state| state.rate_adj())).clamp(-10, 10);
+            let range_volume = state.map_or(100, |state| state.volume());
+            let pitch_method = *self.pitch_method.lock().unwrap();
+            let model_pitch = pitch_method == PitchMethod::Model && pitch != 0;
+            let output_config = (range_rate != 0 || model_pitch).then(|| AudioOutputConfig {
+                rate: (range_rate != 0).then(|| sapi_rate_to_piper(range_rate)),
+                volume: None,
+                pitch: model_pitch.then(|| sapi_pitch_to_piper(pitch)),
+                appended_silence_ms: None,
+            });
             let audio = synth
-                .synthesize_parallel(String::from_utf16_lossy(text_utf16), None)
+                .synthesize_parallel(String::from_utf16_lossy(text_utf16), output_config)
                 .expect("Failed to synthesize audio using piper");
 
             log::debug!("Piper generating audio with: {audio_info:?}");
 
             if self.play_audio_directly
-                || audio_info.sample_rate != 22050
                 || audio_info.num_channels != 1
                 || audio_info.sample_width != 2
             {
@@ -308,79 +931,180 @@ impl SafeTtsEngine for OurTtsEngine {
                 {
                     let mut samples: Vec<f32> = Vec::new();
                     for result in audio {
+                        if self.cancel.is_cancelled() {
+                            log::debug!("Speak - Cancelled");
+                            return Ok(());
+                        }
                         samples.append(&mut result.expect("Failed to generate samples").into_vec());
                     }
                     log::debug!(
                         "Generating the audio data took: {:?}",
-                        _start_audio.elapsed()
+                        started_synthesis.elapsed()
                     );
+                    metrics.synthesis += started_synthesis.elapsed();
 
                     let (_stream, handle) = rodio::OutputStream::try_default()
                         .expect("Failed to create audio output stream");
                     let sink = rodio::Sink::try_new(&handle).unwrap();
+                    sink.set_volume(
+                        f32::from(volume.min(100)) * f32::from(range_volume) / 10_000.0,
+                    );
 
-                    let buf = SamplesBuffer::new(1, 22050, samples);
+                    let buf = SamplesBuffer::new(
+                        audio_info.num_channels as u16,
+                        audio_info.sample_rate as u32,
+                        samples,
+                    );
                     sink.append(buf);
 
                     sink.sleep_until_end();
                 }
             } else {
-                let mut samples = Vec::new();
-                for result in audio {
-                    samples
-                        .append(&mut result.expect("Failed to generate samples").as_wave_bytes());
-                }
-                let mut buffer = samples.as_slice();
-                loop {
-                    let written_bytes = unsafe {
-                        output_site.Write(buffer.as_ptr().cast(), buffer.len().min(4096) as u32)
-                    }?;
-                    buffer = &buffer[written_bytes as usize..];
-                    if buffer.is_empty() {
-                        break;
+                // The model generates mono audio, so copy it to every channel
+                // the client asked for:
+                let channels = wave_format.channels();
+                // Pitch shift ourselves if the model doesn't (1 unit = 1
+                // semitone):
+                let semitones = if pitch_method == PitchMethod::Granular {
+                    pitch as f32
+                } else {
+                    0.0
+                };
+                // Resample to the rate negotiated in `GetOutputFormat`:
+                let (model_rate, output_rate) =
+                    (audio_info.sample_rate as u32, wave_format.sample_rate());
+                // Fade the edges so that there are no clicks where ranges
+                // are joined:
+                let fade_len = output_rate as usize * FADE_MSECS / 1000;
+                let started_resample;
+                let samples: Vec<u8> = if wave_format.is_float() {
+                    let mut mono = Vec::new();
+                    for result in audio {
+                        if self.cancel.is_cancelled() {
+                            log::debug!("Speak - Cancelled");
+                            return Ok(());
+                        }
+                        let result = result.expect("Failed to generate samples");
+                        mono.extend(pitch_shift(&result.into_vec(), semitones));
+                    }
+                    metrics.synthesis += started_synthesis.elapsed();
+                    started_resample = Instant::now();
+                    let mut mono = resample(&mono, model_rate, output_rate);
+                    apply_fade_f32(&mut mono, fade_len);
+                    duplicate_channels(&mono, channels)
+                        .into_iter()
+                        .flat_map(f32::to_le_bytes)
+                        .collect()
+                } else {
+                    let mut mono = Vec::new();
+                    for result in audio {
+                        if self.cancel.is_cancelled() {
+                            log::debug!("Speak - Cancelled");
+                            return Ok(());
+                        }
+                        let result = result.expect("Failed to generate samples");
+                        let samples: Vec<i16> = result
+                            .as_wave_bytes()
+                            .chunks_exact(2)
+                            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                            .collect();
+                        mono.extend(pitch_shift_i16(&samples, semitones));
                     }
+                    metrics.synthesis += started_synthesis.elapsed();
+                    started_resample = Instant::now();
+                    let mut mono = resample_i16(&mono, model_rate, output_rate);
+                    apply_fade(&mut mono, fade_len);
+                    duplicate_channels(&mono, channels)
+                        .into_iter()
+                        .flat_map(i16::to_le_bytes)
+                        .collect()
+                };
+                metrics.resample += started_resample.elapsed();
+                event_sink.estimated_sentence_boundaries(
+                    &joined_text,
+                    lang_range.start..lang_range.end + 1,
+                    writer.audio_offset(),
+                    samples.len() as u64,
+                    wave_format.block_align(),
+                )?;
+                event_sink.estimated_word_boundaries(
+                    &joined_text,
+                    lang_range.start..lang_range.end + 1,
+                    writer.audio_offset(),
+                    samples.len() as u64,
+                    wave_format.block_align(),
+                )?;
+                // piper-rs doesn't expose the phonemes it speaks or their
+                // timings, so guess them from the text:
+                event_sink.estimated_phonemes(
+                    &joined_text,
+                    lang_range.start..lang_range.end + 1,
+                    writer.audio_offset(),
+                    samples.len() as u64,
+                    &wave_format,
+                )?;
+                // Note: no viseme events yet.
 
-                    // Call GetActions as often as possible (returns bitflags):
-                    // https://learn.microsoft.com/en-us/previous-versions/windows/desktop/ee431802(v=vs.85)
-                    let actions = unsafe { output_site.GetActions() } as i32;
-                    if actions == SPVES_CONTINUE.0 {
+                let started_write = Instant::now();
+                for chunk in samples.chunks(writer.max_chunk_len()) {
+                    // Apply the volume to one chunk at a time so that changes
+                    // take effect immediately:
+                    let mut chunk = chunk.to_vec();
+                    let chunk_volume = (u32::from(volume) * u32::from(range_volume) / 100) as u16;
+                    apply_volume(&mut chunk, wave_format.is_float(), chunk_volume);
+                    let actions = writer.write(&chunk)?;
+                    if actions.is_continue() {
                         continue;
                     }
-                    if SPVES_ABORT.0 & actions != 0 {
+                    if actions.abort() {
                         return Ok(());
                     }
+                    // The new rate is used for the next text that is
+                    // synthesized since this audio was already generated:
+                    if actions.rate_changed() {
+                        rate = unsafe { output_site.GetRate() }?;
+                        log::trace!("New SAPI rate of {rate}");
+                    }
+                    if actions.volume_changed() {
+                        volume = unsafe { output_site.GetVolume() }?;
+                        log::trace!("New SAPI volume of {volume}");
+                    }
                     // TODO: handle other actions
                 }
+                metrics.write += started_write.elapsed();
             }
         }
 
+        writer.flush()?;
         Ok(())
     }
 
-    #[expect(non_snake_case)]
-    fn get_output_format(
-        &self,
-        _token: &ISpObjectToken,
-        target_format: Option<SpeechFormat>,
-    ) -> windows::core::Result<SpeechFormat> {
-        log::debug!("get_output_format: {target_format:?}");
-        if let Some(SpeechFormat::DebugText) = target_format {
-            return Ok(SpeechFormat::DebugText);
-        }
+    fn cancellation_token(&self) -> Option<&CancellationToken> {
+        Some(&self.cancel)
+    }
 
-        // SPSF_16kHz16BitMono (22kHz 16Bit mono)
-        // TODO: some models have other output formats
-        let nSamplesPerSec = 22050;
-        let nBlockAlign = 2;
-        Ok(SpeechFormat::Wave(WAVEFORMATEX {
-            wFormatTag: WAVE_FORMAT_PCM as _,
-            nChannels: 1,
-            nBlockAlign,
-            wBitsPerSample: 16,
-            nSamplesPerSec,
-            nAvgBytesPerSec: nSamplesPerSec * (nBlockAlign as u32),
-            cbSize: 0,
-        }))
+    fn on_metrics(&self, metrics: SpeakMetrics) {
+        log::debug!("Speak - {metrics:?}");
+    }
+
+    fn supported_formats(&self, _token: Option<&ISpObjectToken>) -> Vec<SpeechFormat> {
+        // The model's audio is resampled to the negotiated rate and mono audio
+        // is duplicated for stereo clients. Samples can be 16 bit or float
+        // (which is what piper generates).
+        let mut formats = vec![SpeechFormat::pcm_i16(NATIVE_SAMPLE_RATE, 1)];
+        for format in SpeechFormat::STANDARD_PCM_FORMATS {
+            if let SpeechFormat::Wave(info) = format {
+                if { info.wBitsPerSample } == 16 {
+                    formats.push(format);
+                    formats.push(SpeechFormat::pcm_f32(
+                        format.sample_rate(),
+                        format.channels(),
+                    ));
+                }
+            }
+        }
+        formats.push(SpeechFormat::DebugText);
+        formats
     }
 }
 
@@ -395,7 +1119,10 @@ fn multilingual_voice_data() -> VoiceKeyData {
             age: "Adult".to_owned(),
             language: "409".to_owned(), // en-US
             vendor: "Lej77 at GitHub".to_owned(),
+            extra: Vec::new(),
         },
+        forced_language: None,
+        language_detector: None,
     }
 }
 
@@ -411,7 +1138,10 @@ fn multilingual_lingua_voice_data() -> VoiceKeyData {
             age: "Adult".to_owned(),
             language: "409".to_owned(), // en-US
             vendor: "Lej77 at GitHub".to_owned(),
+            extra: Vec::new(),
         },
+        forced_language: None,
+        language_detector: Some(DetectorBackend::Lingua),
     }
 }
 
@@ -425,74 +1155,25 @@ struct TtsComServer;
 impl SafeTtsComServer for TtsComServer {
     const CLSID_TTS_ENGINE: GUID = CLSID_PIPER_TTS_ENGINE;
 
+    const CLASS_NAME: &'static str = "windows_tts_engine_piper";
+
     type TtsEngine = OurTtsEngine;
 
     fn create_engine() -> Self::TtsEngine {
-        OurTtsEngine {
-            play_audio_directly: false,
-            cache: Mutex::new(HashMap::new()),
-        }
+        OurTtsEngine::new(DEFAULT_MODEL_CACHE_CAPACITY)
     }
 
-    fn initialize() {
-        static DLL_LOGGER: DllLogger = DllLogger::new();
-        DLL_LOGGER.install()
-    }
-
-    fn register_server() {
-        ComClassInfo {
-            clsid: CLSID_PIPER_TTS_ENGINE,
-            class_name: Some("windows_tts_engine_piper".into()),
-            threading_model: ComThreadingModel::Apartment,
-            server_path: ComServerPath::CurrentModule,
-        }
-        .register()
-        .expect("Failed to register COM Class");
-
-        let voices = [
+    fn voices() -> Vec<VoiceKeyData> {
+        vec![
             multilingual_voice_data(),
             #[cfg(feature = "lingua")]
             multilingual_lingua_voice_data(),
-        ];
-        for voice in voices {
-            voice
-                .write_to_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to register voice");
-            voice
-                .write_to_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech_OneCore\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to register voice in modern voice path");
-        }
+        ]
     }
 
-    fn unregister_server() {
-        let voices = [
-            multilingual_voice_data(),
-            #[cfg(feature = "lingua")]
-            multilingual_lingua_voice_data(),
-        ];
-        for voice in voices {
-            voice
-                .remove_from_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech_OneCore\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to unregister voice from modern voices path");
-            voice
-                .remove_from_registry(ParentRegKey::Path(
-                    HKEY_LOCAL_MACHINE,
-                    "SOFTWARE\\Microsoft\\Speech\\Voices\\Tokens\\",
-                ))
-                .expect("Failed to unregister voice");
-        }
-
-        ComClassInfo::unregister_class_id(CLSID_PIPER_TTS_ENGINE)
-            .expect("Failed to unregister text-to-speech engine's COM Class");
+    fn initialize() {
+        static DLL_LOGGER: DllLogger = DllLogger::new();
+        DLL_LOGGER.install()
     }
 }
 